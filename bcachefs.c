@@ -33,9 +33,14 @@ static void usage(void)
 	     "Superblock commands:\n"
 	     "  format               Format a new filesystem\n"
 	     "  show-super           Dump superblock information to stdout\n"
+	     "  set-fs-option        Set filesystem options, in the superblock with --persist\n"
+	     "  verify-super         Check members of a filesystem agree on the superblock\n"
 	     "\n"
 	     "Repair:\n"
 	     "  fsck                 Check an existing filesystem for errors\n"
+	     "  verify-file          Verify the checksums of every replica of a file\n"
+	     "  check-free-space     Audit free space accounting, without a full fsck\n"
+	     "  explain              Explain an error, and what to do about it\n"
 	     "\n"
 	     "Startup/shutdown, assembly of multi device filesystems:\n"
 #if 0
@@ -47,15 +52,36 @@ static void usage(void)
 	     "\n"
 	     "Commands for managing a running filesystem:\n"
 	     "  fs usage             Show disk usage\n"
+	     "  fs latency           Show latency of filesystem operations\n"
+	     "  fs freeze            Freeze a filesystem, checking that the journal is flushed\n"
+	     "  fs thaw              Thaw a frozen filesystem\n"
+	     "  journal status       Show how full the journal is, and how fast it's flushed\n"
+	     "  umount               Unmount, listing processes keeping the filesystem busy\n"
+	     "  du                   Disk usage, counting reflinked extents once\n"
+	     "  sparesd              Activate spare devices when a device fails\n"
+	     "  quota-warnd          Report usage over quota soft limits\n"
+	     "  discard              Discard empty buckets, or toggle online discard\n"
 	     "\n"
 	     "Commands for managing devices within a running filesystem:\n"
 	     "  device add           Add a new device to an existing filesystem\n"
+	     "  device add-cache     Add cache devices, making a filesystem tiered\n"
 	     "  device remove        Remove a device from an existing filesystem\n"
 	     "  device online        Re-add an existing member to a filesystem\n"
 	     "  device offline       Take a device offline, without removing it\n"
 	     "  device evacuate      Migrate data off of a specific device\n"
 	     "  device set-state     Mark a device as failed\n"
 	     "  device resize        Resize filesystem on a device\n"
+	     "  device resize-journal\n"
+	     "                       Grow the journal on a device\n"
+	     "  device detach        Mark a diverged member failed on the other devices\n"
+	     "  device attach        Reattach a diverged member to the other devices\n"
+	     "  device stats         Show IO done by each device\n"
+	     "  device inspect-layout\n"
+	     "                       Show the superblocks, journal and a bucket map of an\n"
+	     "                       unmounted device\n"
+	     "  disk-groups show     Show disk groups and the devices in them\n"
+	     "  disk-groups create   Create a disk group in an unmounted filesystem\n"
+	     "  disk-groups move     Move a device to another disk group\n"
 	     "\n"
 	     "Commands for managing filesystem data:\n"
 	     "  data rereplicate     Rereplicate degraded data\n"
@@ -65,6 +91,7 @@ static void usage(void)
 	     "  unlock               Unlock an encrypted filesystem prior to running/mounting\n"
 	     "  set-passphrase       Change passphrase on an existing (unmounted) filesystem\n"
 	     "  remove-passphrase    Remove passphrase on an existing (unmounted) filesystem\n"
+	     "  key generate-keyfile Generate a random keyfile, for use instead of a passphrase\n"
 	     "\n"
 	     "Migrate:\n"
 	     "  migrate              Migrate an existing filesystem to bcachefs, in place\n"
@@ -72,14 +99,38 @@ static void usage(void)
 	     "\n"
 	     "Commands for operating on files in a bcachefs filesystem:\n"
 	     "  setattr              Set various per file attributes\n"
+	     "  targets              List the devices and disk groups options can target\n"
+	     "  cp                   Copy files, preserving bcachefs options\n"
+	     "  dedupe               Share the identical data of files\n"
+	     "  check-tree           Check a directory tree of a mounted filesystem\n"
+	     "  watch                Stream changes to a mounted filesystem as JSON\n"
 	     "Debug:\n"
 	     "These commands work on offline, unmounted filesystems\n"
 	     "  dump                 Dump filesystem metadata to a qcow2 image\n"
 	     "  list                 List filesystem metadata in textual form\n"
 	     "  list_journal         List contents of journal\n"
+	     "  apply-journal        Replay the journal of a metadata image\n"
+	     "  compare-images       Diff the btree keys of two metadata images\n"
+	     "  shell                Interactively inspect a filesystem\n"
+	     "  stat                 Show the bcachefs inode of a file\n"
+	     "  orphans              List inodes not reachable from any directory\n"
+	     "  export-checksums     Export the checksum of every extent, by path\n"
+	     "  trace                Stream bcachefs kernel tracepoints\n"
+	     "  bug-report           Collect information for a bug report\n"
+	     "  create-test-image    Create a small filesystem image for testing\n"
+	     "  corrupt              Deliberately corrupt a filesystem image, for testing fsck\n"
+	     "  find-btree-nodes     Scan a device for btree nodes, to find lost roots\n"
+	     "  bench                Run microbenchmarks against a scratch filesystem\n"
+	     "  torture              Stress test a mounted filesystem\n"
+	     "  testdev create       Set up a device-mapper test device on a member\n"
+	     "  testdev set          Inject failures on a test device\n"
+	     "  testdev remove       Tear down a test device\n"
 	     "\n"
 	     "Miscellaneous:\n"
-	     "  version              Display the version of the invoked bcachefs tool\n");
+	     "  version              Display the version of the invoked bcachefs tool\n"
+	     "  installer-api        Probe, format and mount, with JSON output for OS\n"
+	     "                       installers: version, probe, format, mount,\n"
+	     "                       set-passphrase\n");
 }
 
 static char *full_cmd;
@@ -106,6 +157,23 @@ static int fs_cmds(int argc, char *argv[])
 
 	if (!strcmp(cmd, "usage"))
 		return cmd_fs_usage(argc, argv);
+	if (!strcmp(cmd, "latency"))
+		return cmd_fs_latency(argc, argv);
+	if (!strcmp(cmd, "freeze"))
+		return cmd_fs_freeze(argc, argv);
+	if (!strcmp(cmd, "thaw"))
+		return cmd_fs_thaw(argc, argv);
+
+	usage();
+	return 0;
+}
+
+static int journal_cmds(int argc, char *argv[])
+{
+	char *cmd = pop_cmd(&argc, argv);
+
+	if (!strcmp(cmd, "status"))
+		return cmd_journal_status(argc, argv);
 
 	usage();
 	return 0;
@@ -117,6 +185,8 @@ static int device_cmds(int argc, char *argv[])
 
 	if (!strcmp(cmd, "add"))
 		return cmd_device_add(argc, argv);
+	if (!strcmp(cmd, "add-cache"))
+		return cmd_device_add_cache(argc, argv);
 	if (!strcmp(cmd, "remove"))
 		return cmd_device_remove(argc, argv);
 	if (!strcmp(cmd, "online"))
@@ -131,6 +201,74 @@ static int device_cmds(int argc, char *argv[])
 		return cmd_device_resize(argc, argv);
 	if (!strcmp(cmd, "resize-journal"))
 		return cmd_device_resize_journal(argc, argv);
+	if (!strcmp(cmd, "detach"))
+		return cmd_device_detach(argc, argv);
+	if (!strcmp(cmd, "attach"))
+		return cmd_device_attach(argc, argv);
+	if (!strcmp(cmd, "stats"))
+		return cmd_device_stats(argc, argv);
+	if (!strcmp(cmd, "inspect-layout"))
+		return cmd_device_inspect_layout(argc, argv);
+
+	usage();
+	return 0;
+}
+
+static int key_cmds(int argc, char *argv[])
+{
+	char *cmd = pop_cmd(&argc, argv);
+
+	if (!strcmp(cmd, "generate-keyfile"))
+		return cmd_key_generate_keyfile(argc, argv);
+
+	usage();
+	return 0;
+}
+
+static int disk_groups_cmds(int argc, char *argv[])
+{
+	char *cmd = pop_cmd(&argc, argv);
+
+	if (!strcmp(cmd, "show"))
+		return cmd_disk_groups_show(argc, argv);
+	if (!strcmp(cmd, "create"))
+		return cmd_disk_groups_create(argc, argv);
+	if (!strcmp(cmd, "move"))
+		return cmd_disk_groups_move(argc, argv);
+
+	usage();
+	return 0;
+}
+
+static int testdev_cmds(int argc, char *argv[])
+{
+	char *cmd = pop_cmd(&argc, argv);
+
+	if (!strcmp(cmd, "create"))
+		return cmd_testdev_create(argc, argv);
+	if (!strcmp(cmd, "set"))
+		return cmd_testdev_set(argc, argv);
+	if (!strcmp(cmd, "remove"))
+		return cmd_testdev_remove(argc, argv);
+
+	usage();
+	return 0;
+}
+
+static int installer_api_cmds(int argc, char *argv[])
+{
+	char *cmd = pop_cmd(&argc, argv);
+
+	if (!strcmp(cmd, "version"))
+		return cmd_installer_api_version(argc, argv);
+	if (!strcmp(cmd, "probe"))
+		return cmd_installer_api_probe(argc, argv);
+	if (!strcmp(cmd, "format"))
+		return cmd_installer_api_format(argc, argv);
+	if (!strcmp(cmd, "mount"))
+		return cmd_installer_api_mount(argc, argv);
+	if (!strcmp(cmd, "set-passphrase"))
+		return cmd_installer_api_set_passphrase(argc, argv);
 
 	usage();
 	return 0;
@@ -161,13 +299,25 @@ int main(int argc, char *argv[])
 
 	if (!strcmp(cmd, "version"))
 		return cmd_version(argc, argv);
+	if (!strcmp(cmd, "installer-api"))
+		return installer_api_cmds(argc, argv);
 	if (!strcmp(cmd, "format"))
 		return cmd_format(argc, argv);
 	if (!strcmp(cmd, "show-super"))
 		return cmd_show_super(argc, argv);
+	if (!strcmp(cmd, "set-fs-option"))
+		return cmd_set_fs_option(argc, argv);
+	if (!strcmp(cmd, "verify-super"))
+		return cmd_verify_super(argc, argv);
 
 	if (!strcmp(cmd, "fsck"))
 		return cmd_fsck(argc, argv);
+	if (!strcmp(cmd, "verify-file"))
+		return cmd_verify_file(argc, argv);
+	if (!strcmp(cmd, "check-free-space"))
+		return cmd_check_free_space(argc, argv);
+	if (!strcmp(cmd, "explain"))
+		return cmd_explain(argc, argv);
 
 #if 0
 	if (!strcmp(cmd, "assemble"))
@@ -182,9 +332,23 @@ int main(int argc, char *argv[])
 
 	if (!strcmp(cmd, "fs"))
 		return fs_cmds(argc, argv);
+	if (!strcmp(cmd, "journal"))
+		return journal_cmds(argc, argv);
+	if (!strcmp(cmd, "umount"))
+		return cmd_umount(argc, argv);
+	if (!strcmp(cmd, "du"))
+		return cmd_du(argc, argv);
+	if (!strcmp(cmd, "sparesd"))
+		return cmd_sparesd(argc, argv);
+	if (!strcmp(cmd, "quota-warnd"))
+		return cmd_quota_warnd(argc, argv);
+	if (!strcmp(cmd, "discard"))
+		return cmd_discard(argc, argv);
 
 	if (!strcmp(cmd, "device"))
 		return device_cmds(argc, argv);
+	if (!strcmp(cmd, "disk-groups"))
+		return disk_groups_cmds(argc, argv);
 
 	if (!strcmp(cmd, "data"))
 		return data_cmds(argc, argv);
@@ -195,6 +359,8 @@ int main(int argc, char *argv[])
 		return cmd_set_passphrase(argc, argv);
 	if (!strcmp(cmd, "remove-passphrase"))
 		return cmd_remove_passphrase(argc, argv);
+	if (!strcmp(cmd, "key"))
+		return key_cmds(argc, argv);
 
 	if (!strcmp(cmd, "migrate"))
 		return cmd_migrate(argc, argv);
@@ -207,9 +373,47 @@ int main(int argc, char *argv[])
 		return cmd_list(argc, argv);
 	if (!strcmp(cmd, "list_journal"))
 		return cmd_list_journal(argc, argv);
+	if (!strcmp(cmd, "apply-journal"))
+		return cmd_apply_journal(argc, argv);
+	if (!strcmp(cmd, "compare-images"))
+		return cmd_compare_images(argc, argv);
+	if (!strcmp(cmd, "shell"))
+		return cmd_shell(argc, argv);
+	if (!strcmp(cmd, "stat"))
+		return cmd_stat(argc, argv);
+	if (!strcmp(cmd, "orphans"))
+		return cmd_orphans(argc, argv);
+	if (!strcmp(cmd, "export-checksums"))
+		return cmd_export_checksums(argc, argv);
+	if (!strcmp(cmd, "trace"))
+		return cmd_trace(argc, argv);
+	if (!strcmp(cmd, "bug-report"))
+		return cmd_bug_report(argc, argv);
+	if (!strcmp(cmd, "create-test-image"))
+		return cmd_create_test_image(argc, argv);
+	if (!strcmp(cmd, "corrupt"))
+		return cmd_corrupt(argc, argv);
+	if (!strcmp(cmd, "find-btree-nodes"))
+		return cmd_find_btree_nodes(argc, argv);
+	if (!strcmp(cmd, "bench"))
+		return cmd_bench(argc, argv);
+	if (!strcmp(cmd, "torture"))
+		return cmd_torture(argc, argv);
+	if (!strcmp(cmd, "testdev"))
+		return testdev_cmds(argc, argv);
 
 	if (!strcmp(cmd, "setattr"))
 		return cmd_setattr(argc, argv);
+	if (!strcmp(cmd, "targets"))
+		return cmd_targets(argc, argv);
+	if (!strcmp(cmd, "cp"))
+		return cmd_cp(argc, argv);
+	if (!strcmp(cmd, "dedupe"))
+		return cmd_dedupe(argc, argv);
+	if (!strcmp(cmd, "check-tree"))
+		return cmd_check_tree(argc, argv);
+	if (!strcmp(cmd, "watch"))
+		return cmd_watch(argc, argv);
 
 #ifdef BCACHEFS_FUSE
 	if (!strcmp(cmd, "fusemount"))
@@ -0,0 +1,212 @@
+#include <fcntl.h>
+#include <getopt.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <sys/types.h>
+#include <unistd.h>
+
+#include "cmds.h"
+#include "crypto.h"
+#include "libbcachefs.h"
+#include "qcow2.h"
+
+#include "libbcachefs/bcachefs.h"
+#include "libbcachefs/error.h"
+#include "libbcachefs/journal_io.h"
+#include "libbcachefs/super.h"
+#include "libbcachefs/super-io.h"
+
+static bool image_is_qcow2(const char *path)
+{
+	int fd = xopen(path, O_RDONLY);
+	u32 magic = 0;
+	bool ret = pread(fd, &magic, sizeof(magic), 0) == sizeof(magic) &&
+		!memcmp(&magic, "QFI\xfb", 4);
+
+	close(fd);
+	return ret;
+}
+
+/* qcow2 images are converted, raw images copied, so the original is kept: */
+static void copy_image(const char *src, const char *dst)
+{
+	int fd = xopen(src, O_RDONLY);
+	int out_fd = xopen(dst, O_WRONLY|O_CREAT|O_EXCL, 0600);
+
+	if (image_is_qcow2(src)) {
+		qcow2_read_image(fd, out_fd);
+	} else {
+		ssize_t r;
+
+		while ((r = copy_file_range(fd, NULL, out_fd, NULL,
+					    1U << 30, 0)) > 0)
+			;
+		if (r < 0)
+			die("error copying %s to %s: %m", src, dst);
+	}
+
+	if (fsync(out_fd))
+		die("error writing %s: %m", dst);
+
+	close(out_fd);
+	close(fd);
+}
+
+/*
+ * Recovery reads the journal of a clean filesystem too with keep_journal, so
+ * check for one first:
+ */
+static bool fs_is_clean(const char *path)
+{
+	struct bch_opts opts = bch2_opts_empty();
+	struct bch_sb_handle sb;
+	bool clean;
+	int ret;
+
+	opt_set(opts, noexcl,		true);
+	opt_set(opts, nochanges,	true);
+
+	ret = bch2_read_super(path, &opts, &sb);
+	if (ret)
+		die("error opening %s: %s", path, strerror(-ret));
+
+	clean = BCH_SB_CLEAN(sb.sb);
+	bch2_free_super(&sb);
+	return clean;
+}
+
+static void print_journal_keys(struct bch_fs *c)
+{
+	struct journal_keys *keys = &c->journal_keys;
+	struct journal_key *i;
+
+	for (i = keys->d; i < keys->d + keys->nr; i++) {
+		char buf[4096];
+
+		bch2_bkey_val_to_text(&PBUF(buf), c, bkey_i_to_s_c(i->k));
+		printf("seq %llu btree %s l %u: %s\n",
+		       keys->journal_seq_base + i->journal_seq,
+		       bch2_btree_ids[i->btree_id], i->level, buf);
+	}
+}
+
+static void apply_journal_usage(void)
+{
+	puts("bcachefs apply-journal - replay the journal of a metadata image\n"
+	     "Usage: bcachefs apply-journal [OPTION]... <images>...\n"
+	     "\n"
+	     "Replays the dirty journal entries of an unmounted filesystem, or an\n"
+	     "image from bcachefs dump, into its btrees, using the recovery code\n"
+	     "built into this tool, then shuts it down cleanly. Useful to test\n"
+	     "recovery deterministically, and to salvage an image that the kernel\n"
+	     "crashes replaying: errors are reported and replay continues. Fsck\n"
+	     "isn't run. A cleanly shut down filesystem is left alone.\n"
+	     "\n"
+	     "Images are modified in place, unless -o is given.\n"
+	     "\n"
+	     "Options:\n"
+	     "  -o, --output=file           Replay into a raw copy of the image in file,\n"
+	     "                              leaving the image itself alone; required for\n"
+	     "                              qcow2 images. Only for single device\n"
+	     "                              filesystems\n"
+	     "  -n, --dry-run               Replay in memory only, writing nothing\n"
+	     "  -l, --list                  Print each key replayed, in replay order\n"
+	     "  -k, --passphrase-file=file  Read the passphrase of an encrypted filesystem\n"
+	     "                              from file\n"
+	     "  -v, --verbose               Print each step of recovery\n"
+	     "  -h, --help                  display this help and exit\n"
+	     "Report bugs to <linux-bcache@vger.kernel.org>");
+	exit(EXIT_SUCCESS);
+}
+
+int cmd_apply_journal(int argc, char *argv[])
+{
+	static const struct option longopts[] = {
+		{ "output",		required_argument,	NULL, 'o' },
+		{ "dry-run",		no_argument,		NULL, 'n' },
+		{ "list",		no_argument,		NULL, 'l' },
+		{ "passphrase-file",	required_argument,	NULL, 'k' },
+		{ "verbose",		no_argument,		NULL, 'v' },
+		{ "help",		no_argument,		NULL, 'h' },
+		{ NULL }
+	};
+	struct bch_opts opts = bch2_opts_empty();
+	char *output = NULL, *passphrase_file = NULL;
+	bool list = false;
+	int opt, i;
+
+	opt_set(opts, degraded,		true);
+	opt_set(opts, errors,		BCH_ON_ERROR_continue);
+	opt_set(opts, fix_errors,	FSCK_OPT_YES);
+	opt_set(opts, keep_journal,	true);
+
+	while ((opt = getopt_long(argc, argv, "o:nlk:vh", longopts, NULL)) != -1)
+		switch (opt) {
+		case 'o':
+			output = optarg;
+			break;
+		case 'n':
+			opt_set(opts, nochanges, true);
+			break;
+		case 'l':
+			list = true;
+			break;
+		case 'k':
+			passphrase_file = optarg;
+			break;
+		case 'v':
+			opt_set(opts, verbose, true);
+			break;
+		case 'h':
+			apply_journal_usage();
+		}
+	args_shift(optind);
+
+	if (!argc)
+		die("Please supply image(s)");
+
+	if (output) {
+		if (argc != 1)
+			die("--output is only for single device filesystems");
+
+		copy_image(argv[0], output);
+		argv[0] = output;
+	} else {
+		for (i = 0; i < argc; i++)
+			if (image_is_qcow2(argv[i]))
+				die("%s is a qcow2 image: replay into a copy with --output",
+				    argv[i]);
+	}
+
+	if (fs_is_clean(argv[0])) {
+		printf("filesystem is clean, nothing to replay\n");
+		return 0;
+	}
+
+	bch2_add_key_offline(argv[0], passphrase_file);
+
+	struct bch_fs *c = bch2_fs_open(argv, argc, opts);
+	if (IS_ERR(c))
+		die("error replaying %s: %s", argv[0], strerror(-PTR_ERR(c)));
+
+	struct journal_replay *p;
+	u64 first_seq = 0, last_seq = 0, nr_entries = 0;
+
+	list_for_each_entry(p, &c->journal_entries, list) {
+		if (p->ignore)
+			continue;
+		if (!nr_entries++)
+			first_seq = le64_to_cpu(p->j.seq);
+		last_seq = le64_to_cpu(p->j.seq);
+	}
+
+	if (list)
+		print_journal_keys(c);
+
+	printf("replayed %zu keys from %llu journal entries, seq %llu-%llu\n",
+	       c->journal_keys.nr, nr_entries, first_seq, last_seq);
+
+	bch2_fs_stop(c);
+	return 0;
+}
@@ -1,5 +1,9 @@
 #include <dirent.h>
+#include <fcntl.h>
+#include <ftw.h>
+#include <getopt.h>
 #include <stdio.h>
+#include <stdlib.h>
 #include <sys/ioctl.h>
 #include <sys/stat.h>
 #include <sys/types.h>
@@ -7,6 +11,9 @@
 #include <unistd.h>
 
 #include "libbcachefs/bcachefs_ioctl.h"
+#include "libbcachefs/bcachefs.h"
+#include "libbcachefs/disk_groups.h"
+#include "libbcachefs/super-io.h"
 
 #include "cmds.h"
 #include "libbcachefs.h"
@@ -78,15 +85,164 @@ static void do_setattr(char *path, struct bch_opt_strs opts)
 	close(dirfd);
 }
 
+/*
+ * Options only apply to data written after they're set: rewriting each extent
+ * in place, with what was just read from it, makes existing data pick them up
+ * (e.g. be checksummed or compressed), while keeping the inode and its links:
+ */
+static void rewrite_file(const char *path)
+{
+	static char buf[1 << 20];
+	int fd = open(path, O_RDWR);
+	if (fd < 0)
+		die("error opening %s: %m", path);
+
+	u64 size = xfstat(fd).st_size;
+	off_t data = 0, hole;
+
+	while ((data = lseek(fd, data, SEEK_DATA)) >= 0 && data < size) {
+		hole = lseek(fd, data, SEEK_HOLE);
+		if (hole < 0)
+			die("lseek error on %s: %m", path);
+
+		while (data < hole) {
+			size_t n = min_t(u64, hole - data, sizeof(buf));
+
+			xpread(fd, buf, n, data);
+			xpwrite(fd, buf, n, data);
+			data += n;
+		}
+	}
+
+	if (data < 0 && errno != ENXIO)
+		die("lseek error on %s: %m", path);
+
+	if (fsync(fd))
+		die("error writing %s: %m", path);
+	close(fd);
+}
+
+static int rewrite_fn(const char *path, const struct stat *st,
+		      int type, struct FTW *ftw)
+{
+	if (S_ISREG(st->st_mode))
+		rewrite_file(path);
+	return 0;
+}
+
+static void do_rewrite(const char *path)
+{
+	if (nftw(path, rewrite_fn, 64, FTW_PHYS|FTW_MOUNT))
+		die("error walking %s: %m", path);
+}
+
+/* Every value a target option can be set to on this filesystem: */
+static void print_targets(FILE *f, struct bch_sb *sb, dev_names *devs)
+{
+	struct bch_sb_handle h = { .sb = sb };
+	struct bch_sb_field_disk_groups *gi = bch2_sb_get_disk_groups(sb);
+	struct dev_name *d;
+	unsigned i;
+	char buf[256];
+
+	fprintf(f, "%-24s no target\n", "none");
+
+	for (i = 0; i < disk_groups_nr(gi); i++) {
+		if (BCH_GROUP_DELETED(gi->entries + i))
+			continue;
+
+		bch2_disk_path_to_text(&PBUF(buf), &h, i);
+		fprintf(f, "%-24s group:", buf);
+
+		darray_foreach(d, *devs)
+			if (bch2_sb_dev_in_group(sb, d->idx, i))
+				fprintf(f, " %s", d->dev ?: "(offline)");
+		fprintf(f, "\n");
+	}
+
+	darray_foreach(d, *devs)
+		if (d->dev)
+			fprintf(f, "/dev/%-19s device %u\n", d->dev, d->idx);
+}
+
+static bool target_valid(struct bch_sb *sb, dev_names *devs, const char *target)
+{
+	struct bch_sb_handle h = { .sb = sb };
+	char **paths = xcalloc(sb->nr_devices, sizeof(*paths));
+	struct dev_name *d;
+	unsigned i;
+
+	darray_foreach(d, *devs)
+		if (d->dev && d->idx < sb->nr_devices)
+			paths[d->idx] = mprintf("/dev/%s", d->dev);
+
+	bool ret = bch2_sb_target_parse(&h, (const char * const *) paths,
+					sb->nr_devices, target) >= 0;
+
+	for (i = 0; i < sb->nr_devices; i++)
+		free(paths[i]);
+	free(paths);
+	return ret;
+}
+
+static const enum bch_opt_id target_opts[] = {
+	Opt_metadata_target,
+	Opt_foreground_target,
+	Opt_background_target,
+	Opt_promote_target,
+};
+
+/* Checks targets against the filesystem a file is on, before setting any: */
+static void validate_targets(const char *path, struct bch_opt_strs opts)
+{
+	const enum bch_opt_id *id;
+	bool have_targets = false;
+
+	for (id = target_opts; id < target_opts + ARRAY_SIZE(target_opts); id++)
+		have_targets |= opts.by_id[*id] != NULL;
+
+	if (!have_targets)
+		return;
+
+	struct bchfs_handle fs = bcache_fs_open(path);
+	struct bch_sb *sb = bchu_read_super(fs, -1);
+	dev_names devs = bchu_fs_get_devices(fs);
+
+	for (id = target_opts; id < target_opts + ARRAY_SIZE(target_opts); id++) {
+		const char *target = opts.by_id[*id];
+
+		if (!target || target_valid(sb, &devs, target))
+			continue;
+
+		fprintf(stderr, "Invalid %s %s for %s; valid targets are:\n",
+			bch2_opt_table[*id].attr.name, target, path);
+		print_targets(stderr, sb, &devs);
+		exit(EXIT_FAILURE);
+	}
+
+	dev_names_free(&devs);
+	free(sb);
+	bcache_fs_close(fs);
+}
+
 static void setattr_usage(void)
 {
 	puts("bcachefs setattr - set attributes on files in a bcachefs filesystem\n"
 	     "Usage: bcachefs setattr [OPTIONS]... <files>\n"
 	     "\n"
+	     "Options only apply to data written after they're set; data already\n"
+	     "written keeps e.g. the checksum type and compression it was written\n"
+	     "with, until --rewrite rewrites it.\n"
+	     "\n"
 	     "Options:");
 
 	bch2_opts_usage(OPT_INODE);
-	puts("  -h            Display this help and exit\n"
+	puts("  --rewrite     Rewrite the existing data of the files, and of the\n"
+	     "                files under directories, in place, so it has the\n"
+	     "                new options. Files mustn't be written to while\n"
+	     "                they're being rewritten, and reflinked extents stop\n"
+	     "                being shared\n"
+	     "  -h            Display this help and exit\n"
 	     "Report bugs to <linux-bcache@vger.kernel.org>");
 }
 
@@ -94,10 +250,17 @@ int cmd_setattr(int argc, char *argv[])
 {
 	struct bch_opt_strs opts =
 		bch2_cmdline_opts_get(&argc, argv, OPT_INODE);
+	bool rewrite = false;
 	unsigned i;
 
 	for (i = 1; i < argc; i++)
-		if (argv[i][0] == '-') {
+		if (!strcmp(argv[i], "--rewrite")) {
+			rewrite = true;
+			memmove(argv + i, argv + i + 1,
+				sizeof(argv[0]) * (argc - i));
+			argc--;
+			i--;
+		} else if (argv[i][0] == '-') {
 			printf("invalid option %s\n", argv[i]);
 			setattr_usage();
 			exit(EXIT_FAILURE);
@@ -107,8 +270,64 @@ int cmd_setattr(int argc, char *argv[])
 		die("Please supply one or more files");
 
 	for (i = 1; i < argc; i++)
+		validate_targets(argv[i], opts);
+
+	for (i = 1; i < argc; i++) {
 		do_setattr(argv[i], opts);
+		if (rewrite)
+			do_rewrite(argv[i]);
+	}
 	bch2_opt_strs_free(&opts);
 
 	return 0;
 }
+
+static void targets_usage(void)
+{
+	puts("bcachefs targets - list the targets of a filesystem\n"
+	     "Usage: bcachefs targets [OPTION]... <filesystem>\n"
+	     "\n"
+	     "Lists every value the foreground_target, background_target,\n"
+	     "promote_target and metadata_target options can be set to, e.g. with\n"
+	     "bcachefs setattr: each disk group, with the devices in it, and each\n"
+	     "device.\n"
+	     "\n"
+	     "Options:\n"
+	     "  -h, --help                  display this help and exit\n"
+	     "Report bugs to <linux-bcache@vger.kernel.org>");
+	exit(EXIT_SUCCESS);
+}
+
+int cmd_targets(int argc, char *argv[])
+{
+	static const struct option longopts[] = {
+		{ "help",		no_argument,		NULL, 'h' },
+		{ NULL }
+	};
+	int opt;
+
+	while ((opt = getopt_long(argc, argv, "h", longopts, NULL)) != -1)
+		switch (opt) {
+		case 'h':
+			targets_usage();
+		}
+	args_shift(optind);
+
+	char *path = arg_pop();
+	if (!path)
+		die("Please supply a filesystem");
+
+	if (argc)
+		die("Too many arguments");
+
+	struct bchfs_handle fs = bcache_fs_open(path);
+	struct bch_sb *sb = bchu_read_super(fs, -1);
+	dev_names devs = bchu_fs_get_devices(fs);
+
+	print_targets(stdout, sb, &devs);
+
+	dev_names_free(&devs);
+	free(sb);
+	bcache_fs_close(fs);
+	return 0;
+}
@@ -0,0 +1,256 @@
+#include <fcntl.h>
+#include <getopt.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <sys/types.h>
+#include <unistd.h>
+
+#include "cmds.h"
+#include "libbcachefs.h"
+
+#include "libbcachefs/bcachefs.h"
+#include "libbcachefs/alloc_foreground.h"
+#include "libbcachefs/journal.h"
+#include "libbcachefs/super.h"
+#include "libbcachefs/tests.h"
+#include "libbcachefs/util.h"
+
+static const char * const btree_tests[] = {
+	"rand_insert",
+	"rand_insert_multi",
+	"rand_lookup",
+	"rand_mixed",
+	"rand_delete",
+	"seq_insert",
+	"seq_lookup",
+	"seq_overwrite",
+	"seq_delete",
+	NULL
+};
+
+struct bench_opts {
+	u64		nr;
+	unsigned	nr_threads;
+};
+
+/* Same format as the btree perf tests' own output: */
+static void bench_report(const char *name, u64 nr, u64 time,
+			 struct time_stats *stats)
+{
+	char name_buf[20], nr_buf[20], per_sec_buf[20], buf[4096];
+
+	time = max(time, 1ULL);
+
+	scnprintf(name_buf, sizeof(name_buf), "%s:", name);
+	bch2_hprint(&PBUF(nr_buf), nr);
+	bch2_hprint(&PBUF(per_sec_buf), nr * NSEC_PER_SEC / time);
+	printf("%-12s %s with %u threads in %5llu sec, %5llu nsec per iter, %5s per sec\n",
+	       name_buf, nr_buf, 1,
+	       time / NSEC_PER_SEC,
+	       time / nr,
+	       per_sec_buf);
+
+	bch2_time_stats_to_text(&PBUF(buf), stats);
+	printf("%s\n", buf);
+}
+
+/* Each iteration writes a journal entry and waits for it to be flushed: */
+static void bench_journal_flush(struct bch_fs *c, struct bench_opts *opts)
+{
+	struct time_stats stats;
+	u64 i, start = local_clock();
+
+	bch2_time_stats_init(&stats);
+
+	for (i = 0; i < opts->nr; i++) {
+		u64 op_start = local_clock();
+		int ret = bch2_journal_meta(&c->journal);
+
+		if (ret)
+			die("journal flush error: %s", strerror(-ret));
+		bch2_time_stats_update(&stats, op_start);
+	}
+
+	bench_report("journal-flush", opts->nr, local_clock() - start, &stats);
+	bch2_time_stats_exit(&stats);
+}
+
+/* Allocates a bucket and frees it again, without writing to it: */
+static void bench_alloc(struct bch_fs *c, struct bench_opts *opts)
+{
+	struct bch_dev *ca = bch_dev_bkey_exists(c, 0);
+	struct time_stats stats;
+	struct closure cl;
+	u64 i, start = local_clock();
+
+	bch2_time_stats_init(&stats);
+	closure_init_stack(&cl);
+
+	for (i = 0; i < opts->nr; i++) {
+		u64 op_start = local_clock();
+		struct open_bucket *ob;
+
+		while (IS_ERR(ob = bch2_bucket_alloc(c, ca, RESERVE_NONE,
+						     false, &cl))) {
+			if (PTR_ERR(ob) != -OPEN_BUCKETS_EMPTY &&
+			    PTR_ERR(ob) != -FREELIST_EMPTY)
+				die("allocation error: %li", PTR_ERR(ob));
+			closure_sync(&cl);
+		}
+
+		bch2_open_bucket_put(c, ob);
+		bch2_time_stats_update(&stats, op_start);
+	}
+
+	bench_report("alloc", opts->nr, local_clock() - start, &stats);
+	bch2_time_stats_exit(&stats);
+}
+
+/* Prints its own results: */
+static void bench_btree(struct bch_fs *c, const char *test,
+			struct bench_opts *opts)
+{
+	int ret = bch2_btree_perf_test(c, test, opts->nr, opts->nr_threads);
+
+	if (ret)
+		die("%s error: %s", test, strerror(-ret));
+}
+
+static bool bench_valid(const char *test)
+{
+	return !strcmp(test, "journal-flush") ||
+		!strcmp(test, "alloc") ||
+		!strcmp(test, "btree-insert") ||
+		match_string(btree_tests, -1, test) >= 0;
+}
+
+static void bench_run(struct bch_fs *c, const char *test,
+		      struct bench_opts *opts)
+{
+	if (!strcmp(test, "journal-flush"))
+		bench_journal_flush(c, opts);
+	else if (!strcmp(test, "alloc"))
+		bench_alloc(c, opts);
+	else if (!strcmp(test, "btree-insert"))
+		bench_btree(c, "rand_insert", opts);
+	else
+		bench_btree(c, test, opts);
+}
+
+static void bench_usage(void)
+{
+	puts("bcachefs bench - run filesystem microbenchmarks\n"
+	     "Usage: bcachefs bench [OPTION]... <test>...\n"
+	     "\n"
+	     "Formats a scratch filesystem and runs each test against it in turn,\n"
+	     "using the filesystem code built into this tool.\n"
+	     "\n"
+	     "Tests:\n"
+	     "  journal-flush               Write a journal entry and wait for the flush\n"
+	     "  alloc                       Allocate and free a bucket\n"
+	     "  btree-insert                Insert keys at random positions, same as\n"
+	     "                              rand_insert\n"
+	     "  rand_insert, rand_insert_multi, rand_lookup, rand_mixed, rand_delete,\n"
+	     "  seq_insert, seq_lookup, seq_overwrite, seq_delete\n"
+	     "                              The btree perf tests, as run by the kernel's\n"
+	     "                              perf_test sysfs file\n"
+	     "\n"
+	     "Options:\n"
+	     "  -d, --device=path           Scratch device or image file to format, instead\n"
+	     "                              of a temporary image file. Everything on it is\n"
+	     "                              destroyed\n"
+	     "  -s, --size=size             Size of the temporary image (default 1G)\n"
+	     "  -n, --nr=nr                 Iterations of each test (default 10000)\n"
+	     "  -t, --threads=nr            Threads for the btree tests (default 1)\n"
+	     "  -f, --force                 Format the device even if it already has a\n"
+	     "                              filesystem on it\n"
+	     "  -h, --help                  display this help and exit\n"
+	     "Report bugs to <linux-bcache@vger.kernel.org>");
+	exit(EXIT_SUCCESS);
+}
+
+int cmd_bench(int argc, char *argv[])
+{
+	static const struct option longopts[] = {
+		{ "device",		required_argument,	NULL, 'd' },
+		{ "size",		required_argument,	NULL, 's' },
+		{ "nr",			required_argument,	NULL, 'n' },
+		{ "threads",		required_argument,	NULL, 't' },
+		{ "force",		no_argument,		NULL, 'f' },
+		{ "help",		no_argument,		NULL, 'h' },
+		{ NULL }
+	};
+	struct bench_opts opts = { .nr = 10000, .nr_threads = 1 };
+	struct dev_opts dev = dev_opts_default();
+	char tmp_path[] = "/tmp/bcachefs-bench-XXXXXX";
+	u64 size = 1ULL << 30;
+	bool force = false;
+	int opt, i;
+
+	while ((opt = getopt_long(argc, argv, "d:s:n:t:fh",
+				  longopts, NULL)) != -1)
+		switch (opt) {
+		case 'd':
+			dev.path = optarg;
+			break;
+		case 's':
+			if (bch2_strtoull_h(optarg, &size))
+				die("invalid size %s", optarg);
+			break;
+		case 'n':
+			if (kstrtoull(optarg, 10, &opts.nr) || !opts.nr)
+				die("invalid number of iterations %s", optarg);
+			break;
+		case 't':
+			if (kstrtouint(optarg, 10, &opts.nr_threads) ||
+			    !opts.nr_threads)
+				die("invalid number of threads %s", optarg);
+			break;
+		case 'f':
+			force = true;
+			break;
+		case 'h':
+			bench_usage();
+		}
+	args_shift(optind);
+
+	if (!argc)
+		die("Please supply one or more tests");
+
+	for (i = 0; i < argc; i++)
+		if (!bench_valid(argv[i]))
+			die("Unknown test %s", argv[i]);
+
+	if (dev.path) {
+		dev.fd = open_for_format(dev.path, force);
+	} else {
+		dev.fd = mkstemp(tmp_path);
+		if (dev.fd < 0)
+			die("error creating %s: %m", tmp_path);
+		if (ftruncate(dev.fd, size))
+			die("error sizing %s: %m", tmp_path);
+		dev.path = tmp_path;
+	}
+
+	struct bch_opt_strs fs_opt_strs;
+	memset(&fs_opt_strs, 0, sizeof(fs_opt_strs));
+
+	struct bch_sb *sb = bch2_format(fs_opt_strs, bch2_opts_empty(),
+					format_opts_default(), &dev, 1);
+	free(sb);
+
+	struct bch_fs *c = bch2_fs_open(&dev.path, 1, bch2_opts_empty());
+	if (IS_ERR(c))
+		die("error opening %s: %s", dev.path, strerror(-PTR_ERR(c)));
+
+	/* Still open, so it's removed once we're done: */
+	if (dev.path == tmp_path)
+		unlink(tmp_path);
+
+	for (i = 0; i < argc; i++)
+		bench_run(c, argv[i], &opts);
+
+	bch2_fs_stop(c);
+	return 0;
+}
@@ -0,0 +1,406 @@
+#include <dirent.h>
+#include <fcntl.h>
+#include <getopt.h>
+#include <stdio.h>
+#include <sys/stat.h>
+#include <sys/types.h>
+#include <sys/wait.h>
+#include <unistd.h>
+
+#include <uuid/uuid.h>
+
+#include "cmds.h"
+#include "libbcachefs.h"
+#include "libbcachefs/super-io.h"
+
+/*
+ * Run @fn in a child process with stdout and stderr redirected to @name in the
+ * report directory - so that anything that fails (and dies) while collecting
+ * one part of the report doesn't take down the rest:
+ */
+static void report_collect(const char *dir, const char *name,
+			   void (*fn)(void *), void *arg)
+{
+	char *path = mprintf("%s/%s", dir, name);
+	int fd = xopen(path, O_WRONLY|O_CREAT|O_TRUNC, 0600);
+	int status;
+
+	fflush(stdout);
+	fflush(stderr);
+
+	pid_t pid = fork();
+	if (pid < 0)
+		die("fork error: %m");
+
+	if (!pid) {
+		dup2(fd, STDOUT_FILENO);
+		dup2(fd, STDERR_FILENO);
+		fn(arg);
+		exit(EXIT_SUCCESS);
+	}
+
+	close(fd);
+	free(path);
+
+	if (waitpid(pid, &status, 0) < 0)
+		die("waitpid error: %m");
+
+	if (!WIFEXITED(status) || WEXITSTATUS(status))
+		fprintf(stderr, "warning: error collecting %s\n", name);
+}
+
+static void run(char * const argv[])
+{
+	int status;
+
+	fflush(stdout);
+
+	pid_t pid = fork();
+	if (pid < 0)
+		die("fork error: %m");
+
+	if (!pid) {
+		execvp(argv[0], argv);
+		die("error executing %s: %m", argv[0]);
+	}
+
+	if (waitpid(pid, &status, 0) < 0)
+		die("waitpid error: %m");
+
+	if (!WIFEXITED(status) || WEXITSTATUS(status))
+		die("%s failed", argv[0]);
+}
+
+typedef darray(struct bch_sb *) sb_list;
+
+static void collect_supers(void *p)
+{
+	sb_list *sbs = p;
+	struct bch_sb **sb;
+
+	/* The crypt field is left out, so no key material ends up in the report: */
+	darray_foreach(sb, *sbs) {
+		printf("Device %u:\n", (*sb)->dev_idx);
+		bch2_sb_print(*sb, true, ~0U & ~(1U << BCH_SB_FIELD_crypt),
+			      HUMAN_READABLE);
+		putchar('\n');
+	}
+}
+
+static void collect_dmesg(void *p)
+{
+	FILE *f = popen("dmesg", "r");
+	char *line = NULL;
+	size_t n = 0;
+
+	if (!f)
+		die("error running dmesg: %m");
+
+	while (getline(&line, &n, f) >= 0)
+		if (strstr(line, "bcachefs"))
+			fputs(line, stdout);
+
+	free(line);
+	if (pclose(f))
+		die("dmesg failed");
+}
+
+/* Read a sysfs attribute, without dying on attributes that can't be read: */
+static char *sysfs_read(int dirfd, const char *path)
+{
+	char buf[4096];
+	int fd = openat(dirfd, path, O_RDONLY);
+	ssize_t len;
+
+	if (fd < 0)
+		return NULL;
+
+	len = read(fd, buf, sizeof(buf) - 1);
+	close(fd);
+
+	if (len < 0)
+		return NULL;
+
+	buf[len] = '\0';
+	return strdup(strim(buf));
+}
+
+/* These trigger expensive filesystem walks, or are only for testing: */
+static const char * const sysfs_skip[] = {
+	"compression_stats",
+	"perf_test",
+	NULL
+};
+
+static void sysfs_dump_dir(int sysfs_fd, const char *subdir)
+{
+	int dirfd = openat(sysfs_fd, subdir, O_RDONLY|O_DIRECTORY);
+	DIR *dir;
+	struct dirent *d;
+
+	if (dirfd < 0)
+		return;
+
+	dir = fdopendir(dirfd);
+
+	while ((errno = 0), (d = readdir(dir))) {
+		struct stat st;
+		char *v;
+
+		if (fstatat(dirfd, d->d_name, &st, 0) ||
+		    !S_ISREG(st.st_mode) ||
+		    !(st.st_mode & S_IRUSR) ||
+		    match_string(sysfs_skip, -1, d->d_name) >= 0)
+			continue;
+
+		v = sysfs_read(dirfd, d->d_name);
+		if (!v)
+			continue;
+
+		printf("==> %s/%s <==\n%s\n\n", subdir, d->d_name, v);
+		free(v);
+	}
+
+	closedir(dir);
+}
+
+static void collect_sysfs(void *p)
+{
+	struct bchfs_handle *fs = p;
+	DIR *dir = fdopendir(dup(fs->sysfs_fd));
+	struct dirent *d;
+
+	sysfs_dump_dir(fs->sysfs_fd, ".");
+	sysfs_dump_dir(fs->sysfs_fd, "options");
+	sysfs_dump_dir(fs->sysfs_fd, "time_stats");
+	sysfs_dump_dir(fs->sysfs_fd, "internal");
+
+	while ((errno = 0), (d = readdir(dir)))
+		if (!strncmp(d->d_name, "dev-", 4))
+			sysfs_dump_dir(fs->sysfs_fd, d->d_name);
+
+	closedir(dir);
+}
+
+struct dump_args {
+	char		*out;
+	char		**devs;
+	unsigned	nr_devs;
+};
+
+static void collect_metadata(void *p)
+{
+	struct dump_args *args = p;
+	char **argv = xcalloc(args->nr_devs + 4, sizeof(char *));
+	unsigned i;
+
+	argv[0] = "dump";
+	argv[1] = "-o";
+	argv[2] = args->out;
+	for (i = 0; i < args->nr_devs; i++)
+		argv[3 + i] = args->devs[i];
+
+	/* getopt state is left over from parsing our own arguments: */
+	optind = 1;
+	exit(cmd_dump(args->nr_devs + 3, argv));
+}
+
+/* qcow2 dumps can't be redacted, so list keys instead: */
+static void collect_metadata_redacted(void *p)
+{
+	struct dump_args *args = p;
+	char **argv = xcalloc(args->nr_devs + 3, sizeof(char *));
+	unsigned i;
+
+	argv[0] = "list";
+	argv[1] = "--redact-filenames";
+	for (i = 0; i < args->nr_devs; i++)
+		argv[2 + i] = args->devs[i];
+
+	/* getopt state is left over from parsing our own arguments: */
+	optind = 1;
+	exit(cmd_list(args->nr_devs + 2, argv));
+}
+
+/* So that whoever the report is sent to knows what's in it: */
+static void collect_readme(void *p)
+{
+	bool *redact = p;
+
+	puts("super.txt: superblocks, without the crypt field\n"
+	     "dmesg.txt: bcachefs kernel log messages\n"
+	     "sysfs.txt: options and counters, if the filesystem was mounted");
+
+	if (!redact)
+		return;
+
+	if (*redact)
+		puts("metadata.txt: every key, with filenames replaced by a hash keyed\n"
+		     "with a random key that isn't in the report. No image is included.");
+	else
+		puts("metadata.qcow2: a metadata image, see bcachefs dump. It is not\n"
+		     "redacted: it contains every filename in the filesystem.\n"
+		     "dump.log: output from taking the image");
+}
+
+static void bug_report_usage(void)
+{
+	puts("bcachefs bug-report - collect information for a bug report\n"
+	     "Usage: bcachefs bug-report [OPTION]... <mountpoint|devices>\n"
+	     "\n"
+	     "Collects superblocks, recent kernel log messages, and sysfs options and\n"
+	     "counters (if the filesystem is mounted) into a single compressed tarball.\n"
+	     "Key material, i.e. the superblock crypt field, is left out.\n"
+	     "\n"
+	     "Options:\n"
+	     "  -o, --output=file           Output file, default bcachefs-bug-report-<uuid>.tar.gz\n"
+	     "  -m, --metadata              Also include a metadata dump (see bcachefs dump);\n"
+	     "                              only for unmounted filesystems. Note that the\n"
+	     "                              image includes every filename\n"
+	     "      --redact-filenames      Include the metadata as a key listing with\n"
+	     "                              filenames replaced by a hash of the name (see\n"
+	     "                              bcachefs list), instead of a qcow2 image;\n"
+	     "                              implies -m\n"
+	     "  -h, --help                  display this help and exit\n"
+	     "Report bugs to <linux-bcache@vger.kernel.org>");
+	exit(EXIT_SUCCESS);
+}
+
+int cmd_bug_report(int argc, char *argv[])
+{
+	static const struct option longopts[] = {
+		{ "output",		required_argument,	NULL, 'o' },
+		{ "metadata",		no_argument,		NULL, 'm' },
+		{ "redact-filenames",	no_argument,		NULL, 'R' },
+		{ "help",		no_argument,		NULL, 'h' },
+		{ NULL }
+	};
+	struct bchfs_handle fs = { .sysfs_fd = -1, .ioctl_fd = -1 };
+	darray(struct bch_sb_handle) handles;
+	sb_list sbs;
+	char *out = NULL, uuid[40];
+	bool metadata = false, redact = false, mounted;
+	unsigned i;
+	int opt;
+
+	darray_init(handles);
+	darray_init(sbs);
+
+	while ((opt = getopt_long(argc, argv, "o:mh", longopts, NULL)) != -1)
+		switch (opt) {
+		case 'o':
+			out = optarg;
+			break;
+		case 'm':
+			metadata = true;
+			break;
+		case 'R':
+			metadata = redact = true;
+			break;
+		case 'h':
+			bug_report_usage();
+		}
+	args_shift(optind);
+
+	if (!argc)
+		die("Please supply a mountpoint or device(s)");
+
+	struct stat st = xstat(argv[0]);
+
+	if (S_ISDIR(st.st_mode)) {
+		if (argc > 1)
+			die("Too many arguments");
+
+		fs = bcache_fs_open(argv[0]);
+		mounted = true;
+
+		dev_names devs = bchu_fs_get_devices(fs);
+		struct dev_name *dev;
+
+		darray_foreach(dev, devs) {
+			darray_append(sbs, bchu_read_super(fs, dev->idx));
+			free(dev->dev);
+			free(dev->label);
+		}
+		darray_free(devs);
+	} else {
+		for (i = 0; i < argc; i++) {
+			struct bch_opts opts = bch2_opts_empty();
+			struct bch_sb_handle sb;
+
+			opt_set(opts, noexcl,	true);
+			opt_set(opts, nochanges, true);
+
+			int ret = bch2_read_super(argv[i], &opts, &sb);
+			if (ret)
+				die("Error opening %s: %s", argv[i], strerror(-ret));
+
+			darray_append(handles, sb);
+			darray_append(sbs, sb.sb);
+		}
+
+		mounted = dev_mounted(argv[0]);
+		if (mounted) {
+			int idx;
+
+			fs = bchu_fs_open_by_dev(argv[0], &idx);
+		}
+	}
+
+	if (metadata && mounted)
+		die("--metadata requires an unmounted filesystem");
+
+	uuid_unparse(darray_item(sbs, 0)->user_uuid.b, uuid);
+
+	if (!out)
+		out = mprintf("bcachefs-bug-report-%s.tar.gz", uuid);
+
+	char dir[] = "/tmp/bcachefs-bug-report.XXXXXX";
+	if (!mkdtemp(dir))
+		die("error creating temporary directory: %m");
+
+	report_collect(dir, "README.txt", collect_readme,
+		       metadata ? &redact : NULL);
+	report_collect(dir, "super.txt", collect_supers, &sbs);
+	report_collect(dir, "dmesg.txt", collect_dmesg, NULL);
+
+	if (mounted)
+		report_collect(dir, "sysfs.txt", collect_sysfs, &fs);
+
+	if (metadata) {
+		struct dump_args args = {
+			.out		= mprintf("%s/metadata.qcow2", dir),
+			.devs		= argv,
+			.nr_devs	= argc,
+		};
+
+		if (redact)
+			report_collect(dir, "metadata.txt",
+				       collect_metadata_redacted, &args);
+		else
+			report_collect(dir, "dump.log", collect_metadata, &args);
+		free(args.out);
+	}
+
+	run((char *[]) { "tar", "-czf", out, "-C", dir, ".", NULL });
+	run((char *[]) { "rm", "-rf", dir, NULL });
+
+	printf("Bug report written to %s\n", out);
+
+	if (mounted)
+		bcache_fs_close(fs);
+
+	struct bch_sb_handle *h;
+	darray_foreach(h, handles)
+		bch2_free_super(h);
+	darray_free(handles);
+
+	if (S_ISDIR(st.st_mode)) {
+		struct bch_sb **sb;
+
+		darray_foreach(sb, sbs)
+			free(*sb);
+	}
+	darray_free(sbs);
+	return 0;
+}
@@ -0,0 +1,293 @@
+#include <getopt.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <sys/types.h>
+
+#include "cmds.h"
+#include "crypto.h"
+#include "libbcachefs.h"
+
+#include "libbcachefs/bcachefs.h"
+#include "libbcachefs/btree_cache.h"
+#include "libbcachefs/btree_iter.h"
+#include "libbcachefs/buckets.h"
+#include "libbcachefs/error.h"
+#include "libbcachefs/extents.h"
+#include "libbcachefs/super.h"
+
+/*
+ * Free space is what's left after the sectors of each data type accounted to a
+ * device: these are checked against the bucket marks from the alloc btree,
+ * and against what the btrees actually point to. Unlike fsck's mark and sweep,
+ * nothing is repaired, and only three sets of totals are kept.
+ */
+
+static const unsigned checked_data_types[] = {
+	BCH_DATA_btree,
+	BCH_DATA_user,
+	BCH_DATA_cached,
+};
+
+struct dev_sectors {
+	u64		sectors[BCH_DATA_NR];
+};
+
+struct free_space_counts {
+	struct dev_sectors	*devs;
+	u64			stale;
+	u64			stripes;
+};
+
+static void count_ptrs(struct bch_fs *c, struct bkey_s_c k,
+		       unsigned data_type, struct free_space_counts *s)
+{
+	struct bkey_ptrs_c ptrs = bch2_bkey_ptrs_c(k);
+	const union bch_extent_entry *entry;
+	struct extent_ptr_decoded p;
+
+	bkey_for_each_ptr_decode(k.k, ptrs, p, entry) {
+		struct bch_dev *ca = bch_dev_bkey_exists(c, p.ptr.dev);
+
+		/* Stale pointers aren't counted in the bucket marks: */
+		if (ptr_stale(ca, &p.ptr)) {
+			s->stale++;
+			continue;
+		}
+
+		if (data_type == BCH_DATA_btree)
+			s->devs[p.ptr.dev].sectors[BCH_DATA_btree] +=
+				c->opts.btree_node_size;
+		else
+			s->devs[p.ptr.dev].sectors[p.ptr.cached
+				? BCH_DATA_cached
+				: BCH_DATA_user] += ptr_disk_sectors(p);
+	}
+}
+
+/*
+ * Every btree node is pointed to by a key in its parent, or is a root: so
+ * counting the keys of the interior nodes, and the roots, counts every node.
+ */
+static void count_btree_nodes(struct bch_fs *c, enum btree_id id,
+			      struct free_space_counts *s)
+{
+	struct btree_trans trans;
+	struct btree_iter *iter;
+	struct btree *b;
+	unsigned level;
+
+	bch2_trans_init(&trans, c, 0, 0);
+
+	for (level = 1; level < BTREE_MAX_DEPTH; level++) {
+		__for_each_btree_node(&trans, iter, id, POS_MIN, 0, level, 0, b) {
+			struct btree_node_iter node_iter;
+			struct bkey unpacked;
+			struct bkey_s_c k;
+
+			if (b->c.level != level)
+				break;
+
+			for_each_btree_node_key_unpack(b, k, &node_iter, &unpacked)
+				count_ptrs(c, k, BCH_DATA_btree, s);
+		}
+		bch2_trans_iter_put(&trans, iter);
+	}
+
+	b = c->btree_roots[id].b;
+	if (!btree_node_fake(b))
+		count_ptrs(c, bkey_i_to_s_c(&b->key), BCH_DATA_btree, s);
+
+	bch2_trans_exit(&trans);
+}
+
+static void count_data(struct bch_fs *c, enum btree_id id,
+		       struct free_space_counts *s)
+{
+	struct btree_trans trans;
+	struct btree_iter *iter;
+	struct bkey_s_c k;
+	int ret;
+
+	bch2_trans_init(&trans, c, 0, 0);
+
+	for_each_btree_key(&trans, iter, id, POS_MIN,
+			   BTREE_ITER_PREFETCH, k, ret)
+		switch (k.k->type) {
+		case KEY_TYPE_extent:
+		case KEY_TYPE_reflink_v:
+			count_ptrs(c, k, BCH_DATA_user, s);
+			break;
+		case KEY_TYPE_stripe:
+			s->stripes++;
+			break;
+		}
+	bch2_trans_iter_put(&trans, iter);
+
+	bch2_trans_exit(&trans);
+
+	if (ret)
+		die("error walking btree %s: %s", bch2_btree_ids[id], strerror(-ret));
+}
+
+static struct dev_sectors dev_sectors_from_marks(struct bch_dev *ca)
+{
+	struct dev_sectors ret = { { 0 } };
+	u64 b;
+
+	rcu_read_lock();
+	for (b = ca->mi.first_bucket; b < ca->mi.nbuckets; b++) {
+		struct bucket_mark m = bucket(ca, b)->mark;
+
+		ret.sectors[m.data_type]	+= m.dirty_sectors;
+		ret.sectors[BCH_DATA_cached]	+= m.cached_sectors;
+	}
+	rcu_read_unlock();
+
+	return ret;
+}
+
+/* Returns true if the three agree: */
+static bool print_dev_free_space(struct bch_dev *ca, struct dev_sectors *counted,
+				 enum units units)
+{
+	struct dev_sectors marks = dev_sectors_from_marks(ca);
+	struct bch_dev_usage usage = bch2_dev_usage_read(ca);
+	const unsigned *t;
+	bool ok = true;
+
+	printf("Device %u (%s):\n", ca->dev_idx, ca->name);
+	printf("  %-12s %14s %14s %14s\n",
+	       "data type", "accounted", "bucket marks", "counted");
+
+	for (t = checked_data_types;
+	     t < checked_data_types + ARRAY_SIZE(checked_data_types);
+	     t++) {
+		u64 accounted = usage.d[*t].sectors;
+		s64 delta = counted->sectors[*t] - accounted;
+
+		printf("  %-12s %14s", bch2_data_types[*t],
+		       pr_units(accounted, units));
+		printf(" %14s", pr_units(marks.sectors[*t], units));
+		printf(" %14s", pr_units(counted->sectors[*t], units));
+
+		if (delta || marks.sectors[*t] != accounted) {
+			printf("  (counted %s%s)", delta > 0 ? "+" : "",
+			       pr_units(delta, units));
+			ok = false;
+		}
+		printf("\n");
+	}
+
+	printf("  %-12s %14s\n", "free",
+	       pr_units(__dev_buckets_available(ca, usage) * ca->mi.bucket_size,
+			units));
+	return ok;
+}
+
+static void check_free_space_usage(void)
+{
+	puts("bcachefs check-free-space - audit free space accounting\n"
+	     "Usage: bcachefs check-free-space [OPTION]... <devices>...\n"
+	     "\n"
+	     "For each device of an unmounted, cleanly shut down filesystem, compares\n"
+	     "the btree, user and cached sectors accounted to it with the bucket marks\n"
+	     "from the alloc btree, and with the sectors that btree nodes and extents\n"
+	     "actually point to, printing the difference where they disagree. Much\n"
+	     "quicker than fsck, and changes nothing: run fsck to repair.\n"
+	     "\n"
+	     "Erasure coded parity isn't counted. Exits with status 1 if any totals\n"
+	     "disagree.\n"
+	     "\n"
+	     "Options:\n"
+	     "  -H, --human-readable        Human readable units\n"
+	     "  -k, --passphrase-file=file  Read the passphrase of an encrypted filesystem\n"
+	     "                              from file\n"
+	     "  -h, --help                  display this help and exit\n"
+	     "Report bugs to <linux-bcache@vger.kernel.org>");
+	exit(EXIT_SUCCESS);
+}
+
+int cmd_check_free_space(int argc, char *argv[])
+{
+	static const struct option longopts[] = {
+		{ "human-readable",	no_argument,		NULL, 'H' },
+		{ "passphrase-file",	required_argument,	NULL, 'k' },
+		{ "help",		no_argument,		NULL, 'h' },
+		{ NULL }
+	};
+	struct bch_opts opts = bch2_opts_empty();
+	struct free_space_counts s = { NULL };
+	enum units units = SECTORS;
+	char *passphrase_file = NULL;
+	struct bch_dev *ca;
+	bool ok = true;
+	unsigned i;
+	int opt;
+
+	opt_set(opts, nochanges,	true);
+	opt_set(opts, norecovery,	true);
+	opt_set(opts, degraded,		true);
+	opt_set(opts, errors,		BCH_ON_ERROR_continue);
+
+	while ((opt = getopt_long(argc, argv, "Hk:h", longopts, NULL)) != -1)
+		switch (opt) {
+		case 'H':
+			units = HUMAN_READABLE;
+			break;
+		case 'k':
+			passphrase_file = optarg;
+			break;
+		case 'h':
+			check_free_space_usage();
+		}
+	args_shift(optind);
+
+	if (!argc)
+		die("Please supply device(s)");
+
+	bch2_add_key_offline(argv[0], passphrase_file);
+
+	struct bch_fs *c = bch2_fs_open(argv, argc, opts);
+	if (IS_ERR(c))
+		die("error opening %s: %s", argv[0], strerror(-PTR_ERR(c)));
+
+	/* Otherwise the journal has updates the btrees and accounting don't: */
+	if (!c->sb.clean)
+		die("%s wasn't shut down cleanly: mount it once, or run fsck, first",
+		    argv[0]);
+
+	s.devs = xcalloc(c->sb.nr_devices, sizeof(*s.devs));
+
+	for (i = 0; i < BTREE_ID_NR; i++) {
+		count_btree_nodes(c, i, &s);
+
+		if (i == BTREE_ID_extents ||
+		    i == BTREE_ID_reflink ||
+		    i == BTREE_ID_stripes)
+			count_data(c, i, &s);
+	}
+
+	for_each_member_device(ca, c, i) {
+		if (!ca->disk_sb.bdev) {
+			printf("Device %u: offline, not checked\n\n", ca->dev_idx);
+			continue;
+		}
+
+		ok &= print_dev_free_space(ca, s.devs + ca->dev_idx, units);
+		printf("\n");
+	}
+
+	if (s.stale)
+		printf("%llu stale pointers, not counted\n", s.stale);
+	if (s.stripes)
+		printf("%llu erasure coded stripes: parity not counted\n", s.stripes);
+
+	printf("%s\n", ok
+	       ? "Free space accounting matches"
+	       : "Free space accounting doesn't match: run fsck to repair");
+
+	free(s.devs);
+	bch2_fs_stop(c);
+	return !ok;
+}
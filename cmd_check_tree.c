@@ -0,0 +1,251 @@
+#include <dirent.h>
+#include <fcntl.h>
+#include <getopt.h>
+#include <stdarg.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <sys/stat.h>
+#include <sys/types.h>
+#include <unistd.h>
+
+#include "cmds.h"
+#include "libbcachefs.h"
+
+#include "libbcachefs/bcachefs_format.h"
+
+struct link {
+	ino_t		ino;
+	nlink_t		nlink;
+	char		*path;
+};
+
+struct check_tree {
+	dev_t		dev;
+	bool		verbose;
+	unsigned	nr_errors;
+	u64		nr_dirs;
+	u64		nr_files;
+	darray(struct link) links;
+};
+
+static void tree_err(struct check_tree *t, const char *path,
+		     const char *fmt, ...)
+{
+	va_list args;
+
+	printf("%s: ", path);
+	va_start(args, fmt);
+	vprintf(fmt, args);
+	va_end(args);
+	printf("\n");
+
+	t->nr_errors++;
+}
+
+/*
+ * Checks the entries of one directory against the inodes they point to, and
+ * returns the number of subdirectories:
+ */
+static unsigned check_dir(struct check_tree *t, int dirfd, ino_t dir_ino,
+			  const char *path)
+{
+	DIR *dir = fdopendir(dirfd);
+	struct dirent *d;
+	unsigned nr_subdirs = 0;
+
+	if (!dir)
+		die("error opening %s: %m", path);
+
+	while ((errno = 0), (d = readdir(dir))) {
+		if (!strcmp(d->d_name, ".") ||
+		    !strcmp(d->d_name, ".."))
+			continue;
+
+		char *child = mprintf("%s/%s", path, d->d_name);
+		struct stat st;
+
+		if (fstatat(dirfd, d->d_name, &st, AT_SYMLINK_NOFOLLOW)) {
+			tree_err(t, child, "dirent points to missing inode %llu: %m",
+				 (u64) d->d_ino);
+			goto next;
+		}
+
+		/*
+		 * Other filesystems mounted in this one hide the inode the dirent
+		 * points to - a directory, so it's still counted:
+		 */
+		if (st.st_dev != t->dev) {
+			nr_subdirs++;
+			goto next;
+		}
+
+		if (d->d_ino != st.st_ino)
+			tree_err(t, child, "dirent points to inode %llu, but inode is %llu",
+				 (u64) d->d_ino, (u64) st.st_ino);
+
+		if (d->d_type != DT_UNKNOWN &&
+		    d->d_type != IFTODT(st.st_mode))
+			tree_err(t, child, "dirent type %u, but inode type %u",
+				 d->d_type, IFTODT(st.st_mode));
+
+		if (S_ISDIR(st.st_mode)) {
+			nr_subdirs++;
+
+			int fd = openat(dirfd, d->d_name, O_RDONLY|O_DIRECTORY|O_NOFOLLOW);
+			if (fd < 0) {
+				tree_err(t, child, "error opening: %m");
+				goto next;
+			}
+
+			struct stat parent;
+			if (fstatat(fd, "..", &parent, AT_SYMLINK_NOFOLLOW))
+				tree_err(t, child, "error looking up parent: %m");
+			else if (parent.st_ino != dir_ino)
+				tree_err(t, child, "backpointer to inode %llu, should be %llu",
+					 (u64) parent.st_ino, (u64) dir_ino);
+
+			unsigned subdirs = check_dir(t, fd, st.st_ino, child);
+
+			/* Directory link counts are 2 plus one per subdirectory: */
+			if (st.st_nlink != subdirs + 2)
+				tree_err(t, child, "nlink %llu, should be %u",
+					 (u64) st.st_nlink, subdirs + 2);
+		} else {
+			t->nr_files++;
+
+			if (st.st_nlink > 1) {
+				darray_append(t->links, ((struct link) {
+					.ino	= st.st_ino,
+					.nlink	= st.st_nlink,
+					.path	= child,
+				}));
+				child = NULL;
+			} else if (!st.st_nlink) {
+				tree_err(t, child, "nlink 0, but still linked");
+			}
+		}
+
+		if (t->verbose && child)
+			printf("%s\n", child);
+next:
+		free(child);
+	}
+
+	if (errno)
+		die("readdir error on %s: %m", path);
+
+	closedir(dir);
+	t->nr_dirs++;
+	return nr_subdirs;
+}
+
+static int link_cmp(const void *_l, const void *_r)
+{
+	const struct link *l = _l, *r = _r;
+
+	return cmp_int(l->ino, r->ino);
+}
+
+/*
+ * Files with more than one link: unless we walked the whole filesystem, some
+ * of the links may be outside the subtree - so only too many are an error:
+ */
+static void check_links(struct check_tree *t, bool whole_fs)
+{
+	struct link *l = t->links.item, *end = l + darray_size(t->links);
+
+	qsort(l, darray_size(t->links), sizeof(*l), link_cmp);
+
+	while (l < end) {
+		struct link *i = l;
+		u64 nr = 0;
+
+		while (i < end && i->ino == l->ino) {
+			i++;
+			nr++;
+		}
+
+		if (nr > l->nlink || (whole_fs && nr != l->nlink))
+			tree_err(t, l->path, "nlink %llu, but found %llu links",
+				 (u64) l->nlink, nr);
+
+		for (; l < i; l++) {
+			if (t->verbose)
+				printf("%s\n", l->path);
+			free(l->path);
+		}
+	}
+
+	darray_free(t->links);
+}
+
+static void check_tree_usage(void)
+{
+	puts("bcachefs check-tree - check a directory tree of a mounted filesystem\n"
+	     "Usage: bcachefs check-tree [OPTION]... <path>\n"
+	     "\n"
+	     "Walks the directory tree under path, checking that each dirent matches\n"
+	     "the inode it points to, that directories point back to their parent and\n"
+	     "that link counts are correct - for scoping damage without an offline\n"
+	     "fsck. Exits with status 1 if anything is wrong.\n"
+	     "\n"
+	     "Link counts of files with links outside path can only be checked when\n"
+	     "path is the root of the filesystem.\n"
+	     "\n"
+	     "Options:\n"
+	     "  -v, --verbose               List every file checked\n"
+	     "  -h, --help                  display this help and exit\n"
+	     "Report bugs to <linux-bcache@vger.kernel.org>");
+	exit(EXIT_SUCCESS);
+}
+
+int cmd_check_tree(int argc, char *argv[])
+{
+	static const struct option longopts[] = {
+		{ "verbose",		no_argument,		NULL, 'v' },
+		{ "help",		no_argument,		NULL, 'h' },
+		{ NULL }
+	};
+	struct check_tree t = { 0 };
+	int opt;
+
+	while ((opt = getopt_long(argc, argv, "vh", longopts, NULL)) != -1)
+		switch (opt) {
+		case 'v':
+			t.verbose = true;
+			break;
+		case 'h':
+			check_tree_usage();
+		}
+	args_shift(optind);
+
+	char *path = arg_pop();
+	if (!path)
+		die("Please supply a path");
+
+	if (argc)
+		die("Too many arguments");
+
+	/* Check it's on a bcachefs filesystem: */
+	bcache_fs_close(bcache_fs_open(path));
+
+	int fd = xopen(path, O_RDONLY|O_DIRECTORY);
+	struct stat st = xfstat(fd);
+
+	t.dev = st.st_dev;
+	darray_init(t.links);
+
+	unsigned subdirs = check_dir(&t, fd, st.st_ino, path);
+
+	if (st.st_nlink != subdirs + 2)
+		tree_err(&t, path, "nlink %llu, should be %u",
+			 (u64) st.st_nlink, subdirs + 2);
+
+	check_links(&t, st.st_ino == BCACHEFS_ROOT_INO);
+
+	printf("Checked %llu directories, %llu files: %u errors\n",
+	       t.nr_dirs, t.nr_files, t.nr_errors);
+
+	return t.nr_errors != 0;
+}
@@ -0,0 +1,316 @@
+#include <fcntl.h>
+#include <getopt.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <sys/types.h>
+#include <sys/wait.h>
+#include <unistd.h>
+
+#include "cmds.h"
+#include "libbcachefs.h"
+#include "qcow2.h"
+
+#include "libbcachefs/bcachefs.h"
+#include "libbcachefs/btree_iter.h"
+#include "libbcachefs/error.h"
+#include "libbcachefs/super.h"
+
+/*
+ * Both images are normally of the same filesystem, and a filesystem can only
+ * be opened once per process: so each is opened in a child process, which
+ * lists its keys down a pipe, in btree order.
+ */
+struct image_keys {
+	const char	*name;
+	pid_t		pid;
+	FILE		*f;
+
+	char		*line;
+	size_t		line_size;
+	bool		eof;
+
+	unsigned	btree_id;
+	struct bpos	pos;
+	char		*key;
+};
+
+typedef darray(char *) paths;
+
+static void list_image_keys(char **devs, unsigned nr_devs, FILE *out)
+{
+	struct bch_opts opts = bch2_opts_empty();
+	unsigned btree_id;
+
+	opt_set(opts, nochanges,	true);
+	opt_set(opts, norecovery,	true);
+	opt_set(opts, degraded,		true);
+	opt_set(opts, errors,		BCH_ON_ERROR_continue);
+
+	struct bch_fs *c = bch2_fs_open(devs, nr_devs, opts);
+	if (IS_ERR(c))
+		die("error opening %s: %s", devs[0], strerror(-PTR_ERR(c)));
+
+	for (btree_id = 0; btree_id < BTREE_ID_NR; btree_id++) {
+		struct btree_trans trans;
+		struct btree_iter *iter;
+		struct bkey_s_c k;
+		char buf[4096];
+		int ret;
+
+		bch2_trans_init(&trans, c, 0, 0);
+
+		for_each_btree_key(&trans, iter, btree_id, POS_MIN,
+				   BTREE_ITER_PREFETCH, k, ret) {
+			bch2_bkey_val_to_text(&PBUF(buf), c, k);
+			fprintf(out, "%u %llu %llu %u %s\n", btree_id,
+				k.k->p.inode, k.k->p.offset, k.k->p.snapshot, buf);
+		}
+		bch2_trans_iter_put(&trans, iter);
+
+		bch2_trans_exit(&trans);
+	}
+
+	bch2_fs_stop(c);
+}
+
+/* qcow2 images are converted to temporary raw images first: */
+static char *image_to_raw(char *path, paths *tmp)
+{
+	int fd = xopen(path, O_RDONLY);
+	u32 magic = 0;
+
+	if (pread(fd, &magic, sizeof(magic), 0) != sizeof(magic) ||
+	    memcmp(&magic, "QFI\xfb", 4)) {
+		close(fd);
+		return path;
+	}
+
+	char *raw = strdup("/tmp/bcachefs-compare-XXXXXX");
+	int raw_fd = mkstemp(raw);
+	if (raw_fd < 0)
+		die("error creating %s: %m", raw);
+
+	qcow2_read_image(fd, raw_fd);
+
+	close(raw_fd);
+	close(fd);
+	darray_append(*tmp, raw);
+	return raw;
+}
+
+static void image_keys_start(struct image_keys *img, char *spec, paths *tmp)
+{
+	char *devs_str = strdup(spec), *p = devs_str, *dev;
+	paths devs;
+	int fds[2];
+
+	darray_init(devs);
+	while ((dev = strsep(&p, ":")))
+		if (*dev)
+			darray_append(devs, image_to_raw(dev, tmp));
+
+	if (!darray_size(devs))
+		die("%s: no images", spec);
+
+	if (pipe(fds))
+		die("pipe error: %m");
+
+	fflush(stdout);
+
+	img->pid = fork();
+	if (img->pid < 0)
+		die("fork error: %m");
+
+	if (!img->pid) {
+		FILE *out = fdopen(fds[1], "w");
+
+		close(fds[0]);
+		/* Keep messages from opening the filesystem out of the diff: */
+		dup2(STDERR_FILENO, STDOUT_FILENO);
+
+		list_image_keys(devs.item, darray_size(devs), out);
+
+		if (fclose(out))
+			die("write error: %m");
+		exit(EXIT_SUCCESS);
+	}
+
+	close(fds[1]);
+	img->name	= spec;
+	img->f		= fdopen(fds[0], "r");
+
+	darray_free(devs);
+	free(devs_str);
+}
+
+static void image_keys_next(struct image_keys *img)
+{
+	int n;
+
+	if (getline(&img->line, &img->line_size, img->f) < 0) {
+		img->eof = true;
+		return;
+	}
+
+	img->line[strcspn(img->line, "\n")] = '\0';
+
+	if (sscanf(img->line, "%u %llu %llu %u %n", &img->btree_id,
+		   &img->pos.inode, &img->pos.offset, &img->pos.snapshot,
+		   &n) != 4)
+		die("%s: bad key listing %s", img->name, img->line);
+
+	img->key = img->line + n;
+}
+
+static int image_keys_cmp(struct image_keys *l, struct image_keys *r)
+{
+	if (l->eof || r->eof)
+		return l->eof - r->eof;
+
+	return cmp_int(l->btree_id, r->btree_id) ?:
+		bpos_cmp(l->pos, r->pos);
+}
+
+static void image_keys_finish(struct image_keys *img)
+{
+	int status;
+
+	if (waitpid(img->pid, &status, 0) < 0)
+		die("waitpid error: %m");
+
+	if (!WIFEXITED(status) || WEXITSTATUS(status))
+		die("error reading %s", img->name);
+
+	fclose(img->f);
+	free(img->line);
+}
+
+struct btree_diff {
+	u64		added;
+	u64		removed;
+	u64		changed;
+};
+
+static void compare_images_usage(void)
+{
+	puts("bcachefs compare-images - diff the btree keys of two metadata images\n"
+	     "Usage: bcachefs compare-images [OPTION]... <old> <new>\n"
+	     "\n"
+	     "Lists every key that was removed (-), added (+) or changed (- then +)\n"
+	     "between two images, e.g. from bcachefs dump before and after running\n"
+	     "fsck. Images may be qcow2 or raw; the images of a multi device\n"
+	     "filesystem are given separated by colons. Keys still in the journal\n"
+	     "aren't replayed, so aren't compared. Exits with status 1 if the images\n"
+	     "differ.\n"
+	     "\n"
+	     "Options:\n"
+	     "  -b, --btree=btree           Only compare this btree\n"
+	     "  -s, --summary               Only print the number of keys that differ\n"
+	     "                              in each btree\n"
+	     "  -h, --help                  display this help and exit\n"
+	     "Report bugs to <linux-bcache@vger.kernel.org>");
+	exit(EXIT_SUCCESS);
+}
+
+int cmd_compare_images(int argc, char *argv[])
+{
+	static const struct option longopts[] = {
+		{ "btree",		required_argument,	NULL, 'b' },
+		{ "summary",		no_argument,		NULL, 's' },
+		{ "help",		no_argument,		NULL, 'h' },
+		{ NULL }
+	};
+	struct btree_diff diffs[BTREE_ID_NR] = { { 0 } };
+	struct image_keys old = { 0 }, new = { 0 };
+	int btree_id = -1;
+	bool summary = false, differ = false;
+	paths tmp;
+	char **p;
+	unsigned i;
+	int opt;
+
+	while ((opt = getopt_long(argc, argv, "b:sh", longopts, NULL)) != -1)
+		switch (opt) {
+		case 'b':
+			btree_id = read_string_list_or_die(optarg,
+						bch2_btree_ids, "btree id");
+			break;
+		case 's':
+			summary = true;
+			break;
+		case 'h':
+			compare_images_usage();
+		}
+	args_shift(optind);
+
+	if (argc != 2)
+		die("Please supply two images");
+
+	darray_init(tmp);
+
+	image_keys_start(&old, argv[0], &tmp);
+	image_keys_start(&new, argv[1], &tmp);
+
+	image_keys_next(&old);
+	image_keys_next(&new);
+
+	while (!old.eof || !new.eof) {
+		int cmp = image_keys_cmp(&old, &new);
+		struct image_keys *k = cmp <= 0 ? &old : &new;
+		bool changed = !cmp && strcmp(old.key, new.key);
+
+		if (btree_id >= 0 && k->btree_id != btree_id) {
+			/* skip */
+		} else if (!cmp && !changed) {
+			/* same */
+		} else if (!cmp) {
+			diffs[old.btree_id].changed++;
+			if (!summary)
+				printf("-%s: %s\n+%s: %s\n",
+				       bch2_btree_ids[old.btree_id], old.key,
+				       bch2_btree_ids[new.btree_id], new.key);
+		} else if (cmp < 0) {
+			diffs[old.btree_id].removed++;
+			if (!summary)
+				printf("-%s: %s\n", bch2_btree_ids[old.btree_id], old.key);
+		} else {
+			diffs[new.btree_id].added++;
+			if (!summary)
+				printf("+%s: %s\n", bch2_btree_ids[new.btree_id], new.key);
+		}
+
+		if (cmp <= 0)
+			image_keys_next(&old);
+		if (cmp >= 0)
+			image_keys_next(&new);
+	}
+
+	image_keys_finish(&old);
+	image_keys_finish(&new);
+
+	for (i = 0; i < BTREE_ID_NR; i++) {
+		struct btree_diff *d = diffs + i;
+
+		if (!d->added && !d->removed && !d->changed)
+			continue;
+
+		if (!differ && !summary)
+			printf("\n");
+		differ = true;
+
+		printf("%-16s %llu added, %llu removed, %llu changed\n",
+		       bch2_btree_ids[i], d->added, d->removed, d->changed);
+	}
+
+	if (!differ)
+		printf("No differences\n");
+
+	darray_foreach(p, tmp) {
+		unlink(*p);
+		free(*p);
+	}
+	darray_free(tmp);
+
+	return differ;
+}
@@ -0,0 +1,331 @@
+#include <fcntl.h>
+#include <getopt.h>
+#include <stdio.h>
+#include <string.h>
+#include <sys/stat.h>
+#include <sys/types.h>
+#include <unistd.h>
+
+#include "cmds.h"
+#include "libbcachefs.h"
+
+#include "libbcachefs/bcachefs.h"
+#include "libbcachefs/btree_cache.h"
+#include "libbcachefs/btree_io.h"
+#include "libbcachefs/btree_iter.h"
+#include "libbcachefs/checksum.h"
+#include "libbcachefs/error.h"
+#include "libbcachefs/extents.h"
+#include "libbcachefs/journal.h"
+#include "libbcachefs/super.h"
+
+static const char * const corrupt_targets[] = {
+	"sb",
+	"btree-node",
+	"journal",
+	"extent-csum",
+	NULL
+};
+
+enum corrupt_target {
+	CORRUPT_SB,
+	CORRUPT_BTREE_NODE,
+	CORRUPT_JOURNAL,
+	CORRUPT_EXTENT_CSUM,
+};
+
+/* XXX cut and pasted from journal_io.c */
+static struct nonce journal_nonce(const struct jset *jset)
+{
+	return (struct nonce) {{
+		[0] = 0,
+		[1] = ((__le32 *) &jset->seq)[0],
+		[2] = ((__le32 *) &jset->seq)[1],
+		[3] = BCH_NONCE_JOURNAL,
+	}};
+}
+
+/* Location of the structure to corrupt, in bytes from the start of the device: */
+struct corrupt_loc {
+	u64		offset;
+	u64		len;
+};
+
+static struct corrupt_loc btree_node_loc(struct bch_fs *c, enum btree_id id)
+{
+	struct btree *b = c->btree_roots[id].b;
+	struct bkey_ptrs_c ptrs;
+	const struct bch_extent_ptr *ptr;
+
+	if (!b || btree_node_fake(b))
+		die("btree %s is empty", bch2_btree_ids[id]);
+
+	ptrs = bch2_bkey_ptrs_c(bkey_i_to_s_c(&b->key));
+	bkey_for_each_ptr(ptrs, ptr)
+		if (!ptr->dev)
+			return (struct corrupt_loc) {
+				.offset	= ptr->offset << 9,
+				.len	= btree_bytes(c),
+			};
+
+	die("btree %s root has no pointer to device 0", bch2_btree_ids[id]);
+}
+
+/* The most recently written journal bucket: */
+static struct corrupt_loc journal_loc(struct bch_fs *c)
+{
+	struct bch_dev *ca = c->devs[0];
+	unsigned i, newest = 0;
+
+	if (!ca->journal.nr)
+		die("no journal");
+
+	for (i = 0; i < ca->journal.nr; i++)
+		if (ca->journal.bucket_seq[i] > ca->journal.bucket_seq[newest])
+			newest = i;
+
+	return (struct corrupt_loc) {
+		.offset	= bucket_bytes(ca) * ca->journal.buckets[newest],
+		.len	= bucket_bytes(ca),
+	};
+}
+
+/* Data of the first checksummed extent: */
+static struct corrupt_loc extent_loc(struct bch_fs *c)
+{
+	struct btree_trans trans;
+	struct btree_iter *iter;
+	struct bkey_s_c k;
+	struct corrupt_loc loc = { 0 };
+	int ret;
+
+	bch2_trans_init(&trans, c, 0, 0);
+
+	for_each_btree_key(&trans, iter, BTREE_ID_extents, POS_MIN,
+			   BTREE_ITER_PREFETCH, k, ret) {
+		struct bkey_ptrs_c ptrs = bch2_bkey_ptrs_c(k);
+		const union bch_extent_entry *entry;
+		struct extent_ptr_decoded p;
+
+		bkey_for_each_ptr_decode(k.k, ptrs, p, entry)
+			if (!p.ptr.dev && p.crc.csum_type) {
+				loc.offset	= p.ptr.offset << 9;
+				loc.len		= p.crc.compressed_size << 9;
+				break;
+			}
+
+		if (loc.len)
+			break;
+	}
+	bch2_trans_iter_put(&trans, iter);
+	bch2_trans_exit(&trans);
+
+	if (!loc.len)
+		die("no checksummed extents found");
+	return loc;
+}
+
+/* Size of the structure itself, within the region read: */
+static u64 corrupt_struct_bytes(enum corrupt_target target, void *buf)
+{
+	switch (target) {
+	case CORRUPT_SB:
+		return vstruct_bytes((struct bch_sb *) buf);
+	case CORRUPT_BTREE_NODE:
+		return vstruct_bytes((struct btree_node *) buf);
+	case CORRUPT_JOURNAL:
+		return vstruct_bytes((struct jset *) buf);
+	default:
+		return 0;
+	}
+}
+
+static void recompute_csum(struct bch_fs *c, enum corrupt_target target,
+			   void *buf)
+{
+	switch (target) {
+	case CORRUPT_SB: {
+		struct bch_sb *sb = buf;
+
+		sb->csum = csum_vstruct(NULL, BCH_SB_CSUM_TYPE(sb),
+					null_nonce(), sb);
+		break;
+	}
+	case CORRUPT_BTREE_NODE: {
+		struct btree_node *bn = buf;
+
+		bn->csum = csum_vstruct(c, BSET_CSUM_TYPE(&bn->keys),
+					btree_nonce(&bn->keys, 0), bn);
+		break;
+	}
+	case CORRUPT_JOURNAL: {
+		struct jset *j = buf;
+
+		j->csum = csum_vstruct(c, JSET_CSUM_TYPE(j),
+				       journal_nonce(j), j);
+		break;
+	}
+	default:
+		die("can't recompute checksums of extents");
+	}
+}
+
+static void corrupt_usage(void)
+{
+	puts("bcachefs corrupt - deliberately corrupt a filesystem image, for testing fsck\n"
+	     "Usage: bcachefs corrupt [OPTION]... <image>\n"
+	     "\n"
+	     "Flips a bit, or zeroes a range of bytes, in one structure of an unmounted\n"
+	     "single device filesystem. Never use this on a filesystem you care about.\n"
+	     "\n"
+	     "Options:\n"
+	     "  -t, --target=target         What to corrupt:\n"
+	     "                                sb           the primary superblock\n"
+	     "                                btree-node   root node of --btree\n"
+	     "                                journal      the newest journal bucket\n"
+	     "                                extent-csum  data of the first checksummed\n"
+	     "                                             extent\n"
+	     "  -b, --btree=btree           Btree for btree-node (default extents)\n"
+	     "  -o, --offset=bytes          Offset of the corruption within the structure\n"
+	     "                              (default: halfway through)\n"
+	     "  -z, --zero=bytes            Zero a range of bytes instead of flipping a bit\n"
+	     "  -c, --recompute-csum        Recompute the checksum afterwards, so that the\n"
+	     "                              corruption has to be caught by validation;\n"
+	     "                              not for extent-csum\n"
+	     "  -h, --help                  display this help and exit\n"
+	     "Report bugs to <linux-bcache@vger.kernel.org>");
+	exit(EXIT_SUCCESS);
+}
+
+int cmd_corrupt(int argc, char *argv[])
+{
+	static const struct option longopts[] = {
+		{ "target",		required_argument,	NULL, 't' },
+		{ "btree",		required_argument,	NULL, 'b' },
+		{ "offset",		required_argument,	NULL, 'o' },
+		{ "zero",		required_argument,	NULL, 'z' },
+		{ "recompute-csum",	no_argument,		NULL, 'c' },
+		{ "help",		no_argument,		NULL, 'h' },
+		{ NULL }
+	};
+	struct bch_opts opts = bch2_opts_empty();
+	enum btree_id btree_id = BTREE_ID_extents;
+	int target = -1;
+	u64 offset = U64_MAX, zero = 0;
+	bool recompute = false;
+	int opt;
+
+	opt_set(opts, nochanges,	true);
+	opt_set(opts, norecovery,	true);
+	opt_set(opts, degraded,		true);
+	opt_set(opts, errors,		BCH_ON_ERROR_continue);
+	opt_set(opts, fix_errors,	FSCK_OPT_NO);
+
+	while ((opt = getopt_long(argc, argv, "t:b:o:z:ch",
+				  longopts, NULL)) != -1)
+		switch (opt) {
+		case 't':
+			target = read_string_list_or_die(optarg,
+						corrupt_targets, "target");
+			break;
+		case 'b':
+			btree_id = read_string_list_or_die(optarg,
+						bch2_btree_ids, "btree id");
+			break;
+		case 'o':
+			if (bch2_strtoull_h(optarg, &offset))
+				die("invalid offset %s", optarg);
+			break;
+		case 'z':
+			if (bch2_strtoull_h(optarg, &zero) || !zero)
+				die("invalid length %s", optarg);
+			break;
+		case 'c':
+			recompute = true;
+			break;
+		case 'h':
+			corrupt_usage();
+		}
+	args_shift(optind);
+
+	char *path = arg_pop();
+	if (!path)
+		die("Please supply an image");
+
+	if (argc)
+		die("Too many arguments");
+
+	if (target < 0)
+		die("Please supply a --target");
+
+	if (recompute && target == CORRUPT_EXTENT_CSUM)
+		die("can't recompute checksums of extents");
+
+	struct bch_fs *c = bch2_fs_open(&path, 1, opts);
+	if (IS_ERR(c))
+		die("error opening %s: %s", path, strerror(-PTR_ERR(c)));
+
+	if (c->sb.nr_devices != 1)
+		die("only single device filesystems are supported");
+
+	struct corrupt_loc loc;
+
+	switch (target) {
+	case CORRUPT_SB:
+		loc.offset	= le64_to_cpu(c->disk_sb.sb->layout.sb_offset[0]) << 9;
+		loc.len		= 512 << c->disk_sb.sb->layout.sb_max_size_bits;
+		break;
+	case CORRUPT_BTREE_NODE:
+		loc = btree_node_loc(c, btree_id);
+		break;
+	case CORRUPT_JOURNAL:
+		loc = journal_loc(c);
+		break;
+	case CORRUPT_EXTENT_CSUM:
+		loc = extent_loc(c);
+		break;
+	}
+
+	int fd = xopen(path, O_RDWR);
+	void *buf = aligned_alloc(PAGE_SIZE, round_up(loc.len, PAGE_SIZE));
+
+	xpread(fd, buf, loc.len, loc.offset);
+
+	u64 len = corrupt_struct_bytes(target, buf) ?: loc.len;
+	if (len > loc.len)
+		die("%s at %llu has an invalid size", corrupt_targets[target],
+		    loc.offset);
+
+	/* Default to somewhere past the header, so the structure is still found: */
+	if (offset == U64_MAX)
+		offset = len / 2;
+	if (offset + max(zero, 1ULL) > len)
+		die("offset %llu past end of %s (%llu bytes)",
+		    offset, corrupt_targets[target], len);
+
+	if (zero)
+		memset(buf + offset, 0, zero);
+	else
+		((u8 *) buf)[offset] ^= 1;
+
+	if (recompute)
+		recompute_csum(c, target, buf);
+
+	xpwrite(fd, buf, loc.len, loc.offset);
+	if (fsync(fd))
+		die("error writing %s: %m", path);
+	close(fd);
+
+	if (zero)
+		printf("zeroed %llu bytes at offset %llu in %s at %llu%s\n",
+		       zero, offset, corrupt_targets[target], loc.offset,
+		       recompute ? ", checksum recomputed" : "");
+	else
+		printf("flipped bit at offset %llu in %s at %llu%s\n",
+		       offset, corrupt_targets[target], loc.offset,
+		       recompute ? ", checksum recomputed" : "");
+
+	free(buf);
+	bch2_fs_stop(c);
+	return 0;
+}
@@ -0,0 +1,262 @@
+#include <errno.h>
+#include <fcntl.h>
+#include <getopt.h>
+#include <libgen.h>
+#include <stdio.h>
+#include <string.h>
+#include <sys/ioctl.h>
+#include <sys/stat.h>
+#include <sys/types.h>
+#include <sys/xattr.h>
+#include <unistd.h>
+
+#include "cmds.h"
+#include "libbcachefs.h"
+
+#ifndef FICLONE
+#define FICLONE		_IOW(0x94, 9, int)
+#endif
+
+static const char * const reflink_modes[] = {
+	"auto",
+	"always",
+	"never",
+	NULL
+};
+
+enum reflink_mode {
+	REFLINK_AUTO,
+	REFLINK_ALWAYS,
+	REFLINK_NEVER,
+};
+
+struct cp_opts {
+	enum reflink_mode	reflink;
+	const char		*target;
+};
+
+/* Copies the options set on @src, i.e. the bcachefs.* xattrs: */
+static void copy_bcachefs_xattrs(int src, int dst, const char *src_path)
+{
+	char attrs[XATTR_LIST_MAX], *attr;
+	ssize_t attrs_len = flistxattr(src, attrs, sizeof(attrs));
+
+	if (attrs_len < 0) {
+		if (errno == ENOTSUP)
+			return;
+		die("listxattr error on %s: %m", src_path);
+	}
+
+	for (attr = attrs; attr < attrs + attrs_len; attr += strlen(attr) + 1) {
+		char val[XATTR_SIZE_MAX];
+
+		if (!strcmp_prefix(attr, "bcachefs."))
+			continue;
+
+		ssize_t val_len = fgetxattr(src, attr, val, sizeof(val));
+		if (val_len < 0)
+			die("getxattr error on %s: %m", src_path);
+
+		if (fsetxattr(dst, attr, val, val_len, 0)) {
+			/* Copying to another filesystem: */
+			if (errno == ENOTSUP)
+				return;
+			die("setxattr error: %m");
+		}
+	}
+}
+
+static void set_target(int dst, const char *target)
+{
+	if (fsetxattr(dst, "bcachefs.foreground_target",
+		      target, strlen(target), 0) ||
+	    fsetxattr(dst, "bcachefs.background_target",
+		      target, strlen(target), 0))
+		die("error setting target %s: %m", target);
+}
+
+static void copy_range_rw(int src, int dst, u64 offset, u64 len)
+{
+	static char buf[1 << 20];
+
+	while (len) {
+		size_t n = min_t(u64, len, sizeof(buf));
+
+		xpread(src, buf, n, offset);
+		xpwrite(dst, buf, n, offset);
+
+		offset	+= n;
+		len	-= n;
+	}
+}
+
+/* Returns false if copy_file_range() isn't supported between these files: */
+static bool copy_range_kernel(int src, int dst, u64 offset, u64 len)
+{
+	while (len) {
+		loff_t off_in = offset, off_out = offset;
+		ssize_t ret = copy_file_range(src, &off_in, dst, &off_out, len, 0);
+
+		if (ret < 0) {
+			if (errno == EXDEV || errno == ENOSYS ||
+			    errno == EOPNOTSUPP || errno == EINVAL)
+				return false;
+			die("copy_file_range error: %m");
+		}
+		if (!ret)
+			die("copy_file_range error: unexpected end of file");
+
+		offset	+= ret;
+		len	-= ret;
+	}
+
+	return true;
+}
+
+/*
+ * Copies the data of @src, skipping holes. copy_file_range() may itself
+ * reflink, so it's only used when reflinking is allowed:
+ */
+static void copy_data(int src, int dst, u64 size, bool use_kernel)
+{
+	off_t data = 0, hole;
+
+	while ((data = lseek(src, data, SEEK_DATA)) >= 0 && data < size) {
+		hole = lseek(src, data, SEEK_HOLE);
+		if (hole < 0)
+			die("lseek error: %m");
+
+		if (!use_kernel ||
+		    !(use_kernel = copy_range_kernel(src, dst, data, hole - data)))
+			copy_range_rw(src, dst, data, hole - data);
+
+		data = hole;
+	}
+
+	if (data < 0 && errno != ENXIO)
+		die("lseek error: %m");
+
+	if (ftruncate(dst, size))
+		die("ftruncate error: %m");
+}
+
+static void copy_file(const char *src_path, const char *dst_path,
+		      struct cp_opts *opts)
+{
+	int src = xopen(src_path, O_RDONLY);
+	struct stat st = xfstat(src);
+
+	if (!S_ISREG(st.st_mode))
+		die("%s: not a regular file", src_path);
+
+	struct stat dst_st;
+	if (!stat(dst_path, &dst_st) &&
+	    dst_st.st_dev == st.st_dev &&
+	    dst_st.st_ino == st.st_ino)
+		die("%s and %s are the same file", src_path, dst_path);
+
+	int dst = xopen(dst_path, O_WRONLY|O_CREAT|O_TRUNC, st.st_mode & 07777);
+
+	/* Options have to be set while the file is empty to affect placement: */
+	copy_bcachefs_xattrs(src, dst, src_path);
+	if (opts->target)
+		set_target(dst, opts->target);
+
+	if (opts->reflink != REFLINK_NEVER && !ioctl(dst, FICLONE, src))
+		goto out;
+
+	if (opts->reflink == REFLINK_ALWAYS)
+		die("error reflinking %s to %s: %m", src_path, dst_path);
+
+	copy_data(src, dst, st.st_size, opts->reflink != REFLINK_NEVER);
+out:
+	if (fchmod(dst, st.st_mode & 07777))
+		die("fchmod error on %s: %m", dst_path);
+
+	if (close(dst))
+		die("error writing %s: %m", dst_path);
+	close(src);
+}
+
+static void cp_usage(void)
+{
+	puts("bcachefs cp - copy files, preserving bcachefs options\n"
+	     "Usage: bcachefs cp [OPTION]... <source> <dest>\n"
+	     "   or: bcachefs cp [OPTION]... <source>... <directory>\n"
+	     "\n"
+	     "Copies regular files, along with the bcachefs options set on them,\n"
+	     "skipping holes.\n"
+	     "\n"
+	     "Options:\n"
+	     "      --reflink=(auto|always|never)\n"
+	     "                              Share the source's extents instead of copying\n"
+	     "                              data: auto does so if possible (default)\n"
+	     "  -t, --target=target         Write the copy to this device, label or group,\n"
+	     "                              by setting foreground_target and\n"
+	     "                              background_target; implies --reflink=never\n"
+	     "  -h, --help                  display this help and exit\n"
+	     "Report bugs to <linux-bcache@vger.kernel.org>");
+	exit(EXIT_SUCCESS);
+}
+
+int cmd_cp(int argc, char *argv[])
+{
+	static const struct option longopts[] = {
+		{ "reflink",		required_argument,	NULL, 'r' },
+		{ "target",		required_argument,	NULL, 't' },
+		{ "help",		no_argument,		NULL, 'h' },
+		{ NULL }
+	};
+	struct cp_opts opts = { .reflink = REFLINK_AUTO };
+	bool reflink_set = false;
+	int opt;
+
+	while ((opt = getopt_long(argc, argv, "t:h", longopts, NULL)) != -1)
+		switch (opt) {
+		case 'r':
+			opts.reflink = read_string_list_or_die(optarg,
+						reflink_modes, "reflink mode");
+			reflink_set = true;
+			break;
+		case 't':
+			opts.target = optarg;
+			break;
+		case 'h':
+			cp_usage();
+		}
+	args_shift(optind);
+
+	if (argc < 2)
+		die("Please supply a source and a destination");
+
+	/* Reflinked extents stay where they are: */
+	if (opts.target) {
+		if (reflink_set && opts.reflink != REFLINK_NEVER)
+			die("--target can't be used with --reflink=%s",
+			    reflink_modes[opts.reflink]);
+		opts.reflink = REFLINK_NEVER;
+	}
+
+	char *dst = argv[argc - 1];
+	struct stat st;
+	bool dst_is_dir = !stat(dst, &st) && S_ISDIR(st.st_mode);
+	int i;
+
+	if (argc > 2 && !dst_is_dir)
+		die("%s: not a directory", dst);
+
+	for (i = 0; i < argc - 1; i++) {
+		if (dst_is_dir) {
+			char *src = strdup(argv[i]);
+			char *path = mprintf("%s/%s", dst, basename(src));
+
+			copy_file(argv[i], path, &opts);
+			free(path);
+			free(src);
+		} else {
+			copy_file(argv[i], dst, &opts);
+		}
+	}
+
+	return 0;
+}
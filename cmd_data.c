@@ -1,5 +1,7 @@
 
 
+#include <fcntl.h>
+#include <getopt.h>
 #include <stdio.h>
 #include <sys/ioctl.h>
 
@@ -15,9 +17,11 @@ static void data_rereplicate_usage(void)
 	     "Usage: bcachefs data rereplicate filesystem\n"
 	     "\n"
 	     "Walks existing data in a filesystem, writing additional copies\n"
-	     "of any degraded data\n"
+	     "of any degraded data. Send SIGUSR1 to pause, SIGUSR2 to resume\n"
 	     "\n"
 	     "Options:\n"
+	     "      --progress-fd=fd        Also write progress to fd, as a JSON object\n"
+	     "                              per line\n"
 	     "  -h, --help                  display this help and exit\n"
 	     "Report bugs to <linux-bcache@vger.kernel.org>");
 	exit(EXIT_SUCCESS);
@@ -25,10 +29,20 @@ static void data_rereplicate_usage(void)
 
 int cmd_data_rereplicate(int argc, char *argv[])
 {
-	int opt;
+	static const struct option longopts[] = {
+		{ "progress-fd",	required_argument,	NULL, 'P' },
+		{ "help",		no_argument,		NULL, 'h' },
+		{ NULL }
+	};
+	int opt, json_fd = -1;
 
-	while ((opt = getopt(argc, argv, "h")) != -1)
+	while ((opt = getopt_long(argc, argv, "h", longopts, NULL)) != -1)
 		switch (opt) {
+		case 'P':
+			if (kstrtoint(optarg, 10, &json_fd) ||
+			    fcntl(json_fd, F_GETFD) < 0)
+				die("invalid progress fd %s", optarg);
+			break;
 		case 'h':
 			data_rereplicate_usage();
 		}
@@ -41,13 +55,13 @@ int cmd_data_rereplicate(int argc, char *argv[])
 	if (argc)
 		die("too many arguments");
 
-	return bchu_data(bcache_fs_open(fs_path), (struct bch_ioctl_data) {
+	return __bchu_data(bcache_fs_open(fs_path), (struct bch_ioctl_data) {
 		.op		= BCH_DATA_OP_REREPLICATE,
 		.start_btree	= 0,
 		.start_pos	= POS_MIN,
 		.end_btree	= BTREE_ID_NR,
 		.end_pos	= POS_MAX,
-	});
+	}, json_fd);
 }
 
 static void data_job_usage(void)
@@ -55,14 +69,18 @@ static void data_job_usage(void)
 	puts("bcachefs data job\n"
 	     "Usage: bcachefs data job [job} filesystem\n"
 	     "\n"
-	     "Kick off a data job and report progress\n"
+	     "Kick off a data job and report progress. Send SIGUSR1 to pause,\n"
+	     "SIGUSR2 to resume\n"
 	     "\n"
 	     "job: one of scrub, rereplicate, migrate, or rewrite_old_nodes\n"
 	     "\n"
 	     "Options:\n"
-	     "  -b btree                    btree to operate on\n"
-	     "  -s inode:offset       start position\n"
-	     "  -e inode:offset       end position\n"
+	     "  -b btree[range]             btree to operate on, and optionally a range\n"
+	     "                              of keys, as for bcachefs list\n"
+	     "  -s inode:offset:snapshot    start position, or min\n"
+	     "  -e inode:offset:snapshot    end position, or max\n"
+	     "      --progress-fd=fd        Also write progress to fd, as a JSON object\n"
+	     "                              per line\n"
 	     "  -h, --help                  display this help and exit\n"
 	     "Report bugs to <linux-bcache@vger.kernel.org>");
 	exit(EXIT_SUCCESS);
@@ -84,20 +102,36 @@ int cmd_data_job(int argc, char *argv[])
 		.end_btree	= BTREE_ID_NR,
 		.end_pos	= POS_MAX,
 	};
-	int opt;
+	static const struct option longopts[] = {
+		{ "progress-fd",	required_argument,	NULL, 'P' },
+		{ "help",		no_argument,		NULL, 'h' },
+		{ NULL }
+	};
+	int opt, json_fd = -1;
 
-	while ((opt = getopt(argc, argv, "s:e:h")) != -1)
+	while ((opt = getopt_long(argc, argv, "b:s:e:h", longopts, NULL)) != -1)
 		switch (opt) {
-		case 'b':
-			op.start_btree = read_string_list_or_die(optarg,
-						bch2_btree_ids, "btree id");
-			op.end_btree = op.start_btree;
+		case 'P':
+			if (kstrtoint(optarg, 10, &json_fd) ||
+			    fcntl(json_fd, F_GETFD) < 0)
+				die("invalid progress fd %s", optarg);
+			break;
+		case 'b': {
+			struct key_range r = key_range_parse(optarg);
+
+			op.start_btree	= r.btree;
+			op.end_btree	= r.btree;
+			if (r.bounded) {
+				op.start_pos	= r.start;
+				op.end_pos	= r.end;
+			}
 			break;
+		}
 		case 's':
 			op.start_pos	= bpos_parse(optarg);
 			break;
-			op.end_pos	= bpos_parse(optarg);
 		case 'e':
+			op.end_pos	= bpos_parse(optarg);
 			break;
 		case 'h':
 			data_job_usage();
@@ -120,5 +154,5 @@ int cmd_data_job(int argc, char *argv[])
 	if (argc)
 		die("too many arguments");
 
-	return bchu_data(bcache_fs_open(fs_path), op);
+	return __bchu_data(bcache_fs_open(fs_path), op, json_fd);
 }
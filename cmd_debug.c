@@ -1,11 +1,16 @@
 #include <fcntl.h>
+#include <getopt.h>
 #include <string.h>
 #include <sys/stat.h>
 #include <sys/types.h>
 
+#include <sodium/crypto_shorthash_siphash24.h>
+
 #include "cmds.h"
+#include "crypto.h"
 #include "libbcachefs.h"
 #include "qcow2.h"
+#include "sandbox.h"
 #include "tools-util.h"
 
 #include "libbcachefs/bcachefs.h"
@@ -15,10 +20,15 @@
 #include "libbcachefs/btree_iter.h"
 #include "libbcachefs/buckets.h"
 #include "libbcachefs/checksum.h"
+#include "libbcachefs/dirent.h"
 #include "libbcachefs/error.h"
+#include "libbcachefs/extents.h"
 #include "libbcachefs/journal.h"
 #include "libbcachefs/journal_io.h"
 #include "libbcachefs/super.h"
+#include "libbcachefs/xattr.h"
+#include "linux/random.h"
+#include "linux/sort.h"
 
 static void dump_usage(void)
 {
@@ -28,11 +38,52 @@ static void dump_usage(void)
 	     "Options:\n"
 	     "  -o output     Output qcow2 image(s)\n"
 	     "  -f            Force; overwrite when needed\n"
+	     "  -k file       Read the passphrase of an encrypted filesystem from file\n"
+	     "  --rate=rate   Read at most rate bytes per second from each device,\n"
+	     "                e.g. 100M\n"
+	     "  --iops=iops   Do at most iops reads per second from each device\n"
+	     "  --progress-fd=fd\n"
+	     "                Also write progress to fd, as a JSON object per line\n"
+	     "  --sandbox     Read the filesystem under a seccomp filter, once the\n"
+	     "                devices and output files are open; encrypted\n"
+	     "                filesystems must be unlocked first\n"
 	     "  -h            Display this help and exit\n"
 	     "Report bugs to <linux-bcache@vger.kernel.org>");
 }
 
-static void dump_one_device(struct bch_fs *c, struct bch_dev *ca, int fd)
+static int dump_output_open(const char *out, unsigned idx, bool multi,
+			    bool force)
+{
+	int flags = O_WRONLY|O_CREAT|O_TRUNC;
+
+	if (!force)
+		flags |= O_EXCL;
+
+	char *path = multi
+		? mprintf("%s.%u", out, idx)
+		: strdup(out);
+	int fd = xopen(path, flags, 0600);
+	free(path);
+	return fd;
+}
+
+/* Just the member index, without validating anything else: */
+static unsigned raw_dev_idx(const char *path)
+{
+	struct bch_sb sb;
+	int fd = xopen(path, O_RDONLY);
+
+	xpread(fd, &sb, sizeof(sb), BCH_SB_SECTOR << 9);
+	close(fd);
+
+	if (sb.dev_idx >= BCH_SB_MEMBERS_MAX)
+		die("%s: invalid member index %u", path, sb.dev_idx);
+	return sb.dev_idx;
+}
+
+static void dump_one_device(struct bch_fs *c, struct bch_dev *ca, int fd,
+			    struct io_rate_limit limit,
+			    struct progress_fd *progress)
 {
 	struct bch_sb *sb = ca->disk_sb.sb;
 	ranges data;
@@ -99,7 +150,8 @@ static void dump_one_device(struct bch_fs *c, struct bch_dev *ca, int fd)
 	}
 
 	qcow2_write_image(ca->disk_sb.bdev->bd_fd, fd, &data,
-			  max_t(unsigned, btree_bytes(c) / 8, block_bytes(c)));
+			  max_t(unsigned, btree_bytes(c) / 8, block_bytes(c)),
+			  &limit, progress);
 	darray_free(data);
 }
 
@@ -107,10 +159,19 @@ int cmd_dump(int argc, char *argv[])
 {
 	struct bch_opts opts = bch2_opts_empty();
 	struct bch_dev *ca;
-	char *out = NULL;
+	static const struct option longopts[] = {
+		{ "rate",		required_argument,	NULL, 'r' },
+		{ "iops",		required_argument,	NULL, 'i' },
+		{ "progress-fd",	required_argument,	NULL, 'P' },
+		{ "sandbox",		no_argument,		NULL, 'S' },
+		{ NULL }
+	};
+	char *out = NULL, *passphrase_file = NULL;
+	struct io_rate_limit limit = { 0 };
+	struct progress_fd progress = { .fd = -1 };
 	unsigned i, nr_devices = 0;
-	bool force = false;
-	int fd, opt;
+	bool force = false, sandbox = false;
+	int fds[BCH_SB_MEMBERS_MAX], opt;
 
 	opt_set(opts, nochanges,	true);
 	opt_set(opts, norecovery,	true);
@@ -118,7 +179,7 @@ int cmd_dump(int argc, char *argv[])
 	opt_set(opts, errors,		BCH_ON_ERROR_continue);
 	opt_set(opts, fix_errors,	FSCK_OPT_NO);
 
-	while ((opt = getopt(argc, argv, "o:fvh")) != -1)
+	while ((opt = getopt_long(argc, argv, "o:fk:vh", longopts, NULL)) != -1)
 		switch (opt) {
 		case 'o':
 			out = optarg;
@@ -126,9 +187,28 @@ int cmd_dump(int argc, char *argv[])
 		case 'f':
 			force = true;
 			break;
+		case 'k':
+			passphrase_file = optarg;
+			break;
 		case 'v':
 			opt_set(opts, verbose, true);
 			break;
+		case 'r':
+			if (bch2_strtoull_h(optarg, &limit.bytes_per_sec))
+				die("invalid rate %s", optarg);
+			break;
+		case 'i':
+			if (kstrtoull(optarg, 10, &limit.ios_per_sec))
+				die("invalid iops %s", optarg);
+			break;
+		case 'P':
+			if (kstrtoint(optarg, 10, &progress.fd) ||
+			    fcntl(progress.fd, F_GETFD) < 0)
+				die("invalid progress fd %s", optarg);
+			break;
+		case 'S':
+			sandbox = true;
+			break;
 		case 'h':
 			dump_usage();
 			exit(EXIT_SUCCESS);
@@ -141,6 +221,30 @@ int cmd_dump(int argc, char *argv[])
 	if (!argc)
 		die("Please supply device(s) to check");
 
+	for (i = 0; i < ARRAY_SIZE(fds); i++)
+		fds[i] = -1;
+
+	if (sandbox) {
+		sandbox_check_key(passphrase_file);
+
+		/*
+		 * Outputs are named by member index, so that's read from each
+		 * superblock first, without parsing anything else:
+		 */
+		for (i = 0; i < argc; i++) {
+			unsigned idx = argc > 1 ? raw_dev_idx(argv[i]) : 0;
+
+			if (fds[idx] >= 0)
+				die("%s: member %u given twice", argv[i], idx);
+			fds[idx] = dump_output_open(out, idx, argc > 1, force);
+		}
+
+		sandbox_open_devices(argv, argc);
+		sandbox_enter();
+	} else {
+		bch2_add_key_offline(argv[0], passphrase_file);
+	}
+
 	struct bch_fs *c = bch2_fs_open(argv, argc, opts);
 	if (IS_ERR(c))
 		die("error opening %s: %s", argv[0], strerror(-PTR_ERR(c)));
@@ -152,24 +256,21 @@ int cmd_dump(int argc, char *argv[])
 
 	BUG_ON(!nr_devices);
 
-	for_each_online_member(ca, c, i) {
-		int flags = O_WRONLY|O_CREAT|O_TRUNC;
-
-		if (!force)
-			flags |= O_EXCL;
-
-		if (!c->devs[i])
-			continue;
+	for_each_online_member(ca, c, i)
+		if (fds[i] < 0) {
+			if (sandbox)
+				die("member %u has changed index", i);
+			fds[i] = dump_output_open(out, i, nr_devices > 1, force);
+		}
 
-		char *path = nr_devices > 1
-			? mprintf("%s.%u", out, i)
-			: strdup(out);
-		fd = xopen(path, flags, 0600);
-		free(path);
+	for_each_online_member(ca, c, i) {
+		if (progress.fd >= 0)
+			dprintf(progress.fd, "{\"event\":\"device\",\"dev\":%u}\n", i);
 
-		dump_one_device(c, ca, fd);
-		close(fd);
+		dump_one_device(c, ca, fds[i], limit, &progress);
+		close(fds[i]);
 	}
+	progress_done(&progress);
 
 	up_read(&c->gc_lock);
 
@@ -177,6 +278,55 @@ int cmd_dump(int argc, char *argv[])
 	return 0;
 }
 
+static bool redact_filenames;
+static u8 redact_key[crypto_shorthash_siphash24_KEYBYTES];
+
+static u64 redact_hash(const void *p, size_t len)
+{
+	__le64 hash;
+
+	crypto_shorthash_siphash24((void *) &hash, p, len, redact_key);
+	return le64_to_cpu(hash);
+}
+
+/*
+ * Like bch2_bkey_val_to_text(), but optionally with dirent names, and xattr
+ * names and values, replaced by a keyed hash - within one listing the same
+ * name always hashes the same, so keys can still be correlated, but since the
+ * key is random and never printed, names can't be recovered by hashing
+ * guesses. Inline data extents, which may be symlink targets, are hashed too:
+ */
+static void key_to_text(struct printbuf *out, struct bch_fs *c,
+			struct bkey_s_c k)
+{
+	if (redact_filenames && k.k->type == KEY_TYPE_dirent) {
+		struct bkey_s_c_dirent d = bkey_s_c_to_dirent(k);
+
+		bch2_bkey_to_text(out, k.k);
+		pr_buf(out, ": redacted:%016llx -> %llu type %u",
+		       redact_hash(d.v->d_name, bch2_dirent_name_bytes(d)),
+		       d.v->d_inum, d.v->d_type);
+	} else if (redact_filenames && k.k->type == KEY_TYPE_xattr) {
+		struct bkey_s_c_xattr x = bkey_s_c_to_xattr(k);
+
+		bch2_bkey_to_text(out, k.k);
+		pr_buf(out, ": type %u redacted:%016llx:redacted:%016llx",
+		       x.v->x_type,
+		       redact_hash(x.v->x_name, x.v->x_name_len),
+		       redact_hash(xattr_val(x.v), le16_to_cpu(x.v->x_val_len)));
+	} else if (redact_filenames &&
+		   (k.k->type == KEY_TYPE_inline_data ||
+		    k.k->type == KEY_TYPE_indirect_inline_data)) {
+		unsigned bytes = bkey_inline_data_bytes(k.k);
+
+		bch2_bkey_to_text(out, k.k);
+		pr_buf(out, ": datalen %u: redacted:%016llx",
+		       bytes, redact_hash(bkey_inline_data_p(k), bytes));
+	} else {
+		bch2_bkey_val_to_text(out, c, k);
+	}
+}
+
 static void list_keys(struct bch_fs *c, enum btree_id btree_id,
 		      struct bpos start, struct bpos end)
 {
@@ -193,7 +343,7 @@ static void list_keys(struct bch_fs *c, enum btree_id btree_id,
 		if (bkey_cmp(k.k->p, end) > 0)
 			break;
 
-		bch2_bkey_val_to_text(&PBUF(buf), c, k);
+		key_to_text(&PBUF(buf), c, k);
 		puts(buf);
 	}
 	bch2_trans_iter_put(&trans, iter);
@@ -334,7 +484,7 @@ static void print_node_ondisk(struct bch_fs *c, struct btree *b)
 			struct bkey u;
 			char buf[4096];
 
-			bch2_bkey_val_to_text(&PBUF(buf), c, bkey_disassemble(b, k, &u));
+			key_to_text(&PBUF(buf), c, bkey_disassemble(b, k, &u));
 			fprintf(stdout, "    %s\n", buf);
 		}
 	}
@@ -388,7 +538,7 @@ static void list_nodes_keys(struct bch_fs *c, enum btree_id btree_id, unsigned l
 		fputs(buf, stdout);
 
 		for_each_btree_node_key_unpack(b, k, &node_iter, &unpacked) {
-			bch2_bkey_val_to_text(&PBUF(buf), c, k);
+			key_to_text(&PBUF(buf), c, k);
 			putchar('\t');
 			puts(buf);
 		}
@@ -398,20 +548,113 @@ static void list_nodes_keys(struct bch_fs *c, enum btree_id btree_id, unsigned l
 	bch2_trans_exit(&trans);
 }
 
+static int u64_cmp(const void *_l, const void *_r)
+{
+	const u64 *l = _l, *r = _r;
+
+	return cmp_int(*l, *r);
+}
+
+/*
+ * Lists indirect extents with at least @min_refcount references, along with
+ * the number of reflink pointers actually found referring to each:
+ */
+static void list_reflink(struct bch_fs *c, u64 min_refcount)
+{
+	struct btree_trans trans;
+	struct btree_iter *iter;
+	struct bkey_s_c k;
+	darray(u64) starts, ends;
+	size_t i = 0, j = 0;
+	char buf[512];
+	int ret;
+
+	darray_init(starts);
+	darray_init(ends);
+
+	bch2_trans_init(&trans, c, 0, 0);
+
+	for_each_btree_key(&trans, iter, BTREE_ID_extents, POS_MIN,
+			   BTREE_ITER_PREFETCH, k, ret) {
+		if (k.k->type != KEY_TYPE_reflink_p)
+			continue;
+
+		u64 idx = le64_to_cpu(bkey_s_c_to_reflink_p(k).v->idx);
+
+		darray_append(starts, idx);
+		darray_append(ends, idx + k.k->size);
+	}
+	bch2_trans_iter_put(&trans, iter);
+
+	sort(&darray_item(starts, 0), darray_size(starts),
+	     sizeof(u64), u64_cmp, NULL);
+	sort(&darray_item(ends, 0), darray_size(ends),
+	     sizeof(u64), u64_cmp, NULL);
+
+	for_each_btree_key(&trans, iter, BTREE_ID_reflink, POS_MIN,
+			   BTREE_ITER_PREFETCH, k, ret) {
+		if (k.k->type != KEY_TYPE_reflink_v &&
+		    k.k->type != KEY_TYPE_indirect_inline_data)
+			continue;
+
+		/* Both start with the refcount: */
+		u64 refcount = le64_to_cpu(*((__le64 *) k.v));
+
+		/*
+		 * Indirect extents don't overlap, so pointers overlapping this
+		 * one are those starting before its end, less those ending
+		 * before its start:
+		 */
+		while (i < darray_size(starts) &&
+		       darray_item(starts, i) < k.k->p.offset)
+			i++;
+		while (j < darray_size(ends) &&
+		       darray_item(ends, j) <= bkey_start_offset(k.k))
+			j++;
+
+		if (refcount < min_refcount)
+			continue;
+
+		key_to_text(&PBUF(buf), c, k);
+		printf("%s\treferences found %zu\n", buf, i - j);
+	}
+	bch2_trans_iter_put(&trans, iter);
+
+	bch2_trans_exit(&trans);
+	darray_free(starts);
+	darray_free(ends);
+}
+
 static void list_keys_usage(void)
 {
 	puts("bcachefs list - list filesystem metadata to stdout\n"
 	     "Usage: bcachefs list [OPTION]... <devices>\n"
 	     "\n"
 	     "Options:\n"
-	     "  -b (extents|inodes|dirents|xattrs)    Btree to list from\n"
+	     "  -b btree[range]                       Btree to list from, e.g. extents, and\n"
+	     "                                        optionally a range of keys, e.g.\n"
+	     "                                        extents[4096:0..4096:1000] or\n"
+	     "                                        inodes[>=4096]: see the man page\n"
 	     "  -l level                              Btree depth to descend to (0 == leaves)\n"
-	     "  -s inode:offset                       Start position to list from\n"
-	     "  -e inode:offset                       End position\n"
+	     "  -s inode:offset:snapshot              Start position to list from, or min;\n"
+	     "                                        offset and snapshot default to 0\n"
+	     "  -e inode:offset:snapshot              End position, or max\n"
 	     "  -i inode                              List keys for a given inode number\n"
-	     "  -m (keys|formats|nodes|nodes_ondisk|nodes_keys)\n"
-	     "                                        List mode\n"
+	     "  -m (keys|formats|nodes|nodes_ondisk|nodes_keys|reflink)\n"
+	     "                                        List mode; reflink lists indirect\n"
+	     "                                        extents, with the number of\n"
+	     "                                        references found to each\n"
+	     "      --min-refcount=n                  In reflink mode, only list indirect\n"
+	     "                                        extents with a refcount of at least n\n"
 	     "  -f                                    Check (fsck) the filesystem first\n"
+	     "      --redact-filenames                Replace filenames, xattrs and inline\n"
+	     "                                        data with a hash\n"
+	     "  -k, --passphrase-file=file            Read the passphrase of an encrypted\n"
+	     "                                        filesystem from file\n"
+	     "      --sandbox                         Read the filesystem under a seccomp\n"
+	     "                                        filter, once the devices are open;\n"
+	     "                                        encrypted filesystems must be\n"
+	     "                                        unlocked first\n"
 	     "  -v                                    Verbose mode\n"
 	     "  -h                                    Display this help and exit\n"
 	     "Report bugs to <linux-bcache@vger.kernel.org>");
@@ -422,7 +665,8 @@ static void list_keys_usage(void)
 	x(formats)		\
 	x(nodes)		\
 	x(nodes_ondisk)		\
-	x(nodes_keys)
+	x(nodes_keys)		\
+	x(reflink)
 
 enum list_modes {
 #define x(n)	LIST_MODE_##n,
@@ -439,7 +683,17 @@ static const char * const list_modes[] = {
 
 int cmd_list(int argc, char *argv[])
 {
+	static const struct option longopts[] = {
+		{ "redact-filenames",	no_argument,		NULL, 'R' },
+		{ "passphrase-file",	required_argument,	NULL, 'k' },
+		{ "min-refcount",	required_argument,	NULL, 'r' },
+		{ "sandbox",		no_argument,		NULL, 'S' },
+		{ NULL }
+	};
 	struct bch_opts opts = bch2_opts_empty();
+	char *passphrase_file = NULL;
+	u64 min_refcount = 0;
+	bool sandbox = false;
 	enum btree_id btree_id_start	= 0;
 	enum btree_id btree_id_end	= BTREE_ID_NR;
 	enum btree_id btree_id;
@@ -453,13 +707,20 @@ int cmd_list(int argc, char *argv[])
 	opt_set(opts, degraded,		true);
 	opt_set(opts, errors,		BCH_ON_ERROR_continue);
 
-	while ((opt = getopt(argc, argv, "b:l:s:e:i:m:fvh")) != -1)
+	while ((opt = getopt_long(argc, argv, "b:l:s:e:i:m:fk:vh",
+				  longopts, NULL)) != -1)
 		switch (opt) {
-		case 'b':
-			btree_id_start = read_string_list_or_die(optarg,
-						bch2_btree_ids, "btree id");
-			btree_id_end = btree_id_start + 1;
+		case 'b': {
+			struct key_range r = key_range_parse(optarg);
+
+			btree_id_start	= r.btree;
+			btree_id_end	= r.btree + 1;
+			if (r.bounded) {
+				start	= r.start;
+				end	= r.end;
+			}
 			break;
+		}
 		case 'l':
 			if (kstrtouint(optarg, 10, &level) || level >= BTREE_MAX_DEPTH)
 				die("invalid level");
@@ -484,6 +745,20 @@ int cmd_list(int argc, char *argv[])
 			opt_set(opts, fix_errors, FSCK_OPT_YES);
 			opt_set(opts, norecovery, false);
 			break;
+		case 'R':
+			redact_filenames = true;
+			get_random_bytes(redact_key, sizeof(redact_key));
+			break;
+		case 'k':
+			passphrase_file = optarg;
+			break;
+		case 'r':
+			if (kstrtoull(optarg, 10, &min_refcount))
+				die("invalid refcount %s", optarg);
+			break;
+		case 'S':
+			sandbox = true;
+			break;
 		case 'v':
 			opt_set(opts, verbose, true);
 			break;
@@ -496,10 +771,22 @@ int cmd_list(int argc, char *argv[])
 	if (!argc)
 		die("Please supply device(s)");
 
+	if (sandbox) {
+		sandbox_check_key(passphrase_file);
+		sandbox_open_devices(argv, argc);
+		sandbox_enter();
+	} else {
+		bch2_add_key_offline(argv[0], passphrase_file);
+	}
+
 	struct bch_fs *c = bch2_fs_open(argv, argc, opts);
 	if (IS_ERR(c))
 		die("error opening %s: %s", argv[0], strerror(-PTR_ERR(c)));
 
+	if (mode == LIST_MODE_reflink) {
+		list_reflink(c, min_refcount);
+		goto out;
+	}
 
 	for (btree_id = btree_id_start;
 	     btree_id < btree_id_end;
@@ -524,7 +811,7 @@ int cmd_list(int argc, char *argv[])
 			die("Invalid mode");
 		}
 	}
-
+out:
 	bch2_fs_stop(c);
 	return 0;
 }
@@ -536,6 +823,7 @@ static void list_journal_usage(void)
 	     "\n"
 	     "Options:\n"
 	     "  -a            Read entire journal, not just dirty entries\n"
+	     "  -k file       Read the passphrase of an encrypted filesystem from file\n"
 	     "  -h            Display this help and exit\n"
 	     "Report bugs to <linux-bcache@vger.kernel.org>");
 }
@@ -543,6 +831,7 @@ static void list_journal_usage(void)
 int cmd_list_journal(int argc, char *argv[])
 {
 	struct bch_opts opts = bch2_opts_empty();
+	char *passphrase_file = NULL;
 	int opt;
 
 	opt_set(opts, nochanges,	true);
@@ -552,11 +841,14 @@ int cmd_list_journal(int argc, char *argv[])
 	opt_set(opts, fix_errors,	FSCK_OPT_YES);
 	opt_set(opts, keep_journal,	true);
 
-	while ((opt = getopt(argc, argv, "ah")) != -1)
+	while ((opt = getopt(argc, argv, "ak:h")) != -1)
 		switch (opt) {
 		case 'a':
 			opt_set(opts, read_entire_journal, true);
 			break;
+		case 'k':
+			passphrase_file = optarg;
+			break;
 		case 'h':
 			list_journal_usage();
 			exit(EXIT_SUCCESS);
@@ -566,6 +858,8 @@ int cmd_list_journal(int argc, char *argv[])
 	if (!argc)
 		die("Please supply device(s) to open");
 
+	bch2_add_key_offline(argv[0], passphrase_file);
+
 	struct bch_fs *c = bch2_fs_open(argv, argc, opts);
 	if (IS_ERR(c))
 		die("error opening %s: %s", argv[0], strerror(-PTR_ERR(c)));
@@ -0,0 +1,407 @@
+#include <errno.h>
+#include <fcntl.h>
+#include <ftw.h>
+#include <getopt.h>
+#include <linux/fs.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <sys/ioctl.h>
+#include <sys/stat.h>
+#include <sys/types.h>
+#include <unistd.h>
+
+#include <sodium/crypto_generichash_blake2b.h>
+
+#include "ccan/darray/darray.h"
+
+#include "cmds.h"
+#include "libbcachefs.h"
+#include "linux/sort.h"
+
+/*
+ * Files are hashed a block at a time, and blocks with the same hash are
+ * deduplicated with FIDEDUPERANGE, which compares the data before sharing it.
+ * Blocks that are already at the same place on disk - reflinked - are skipped
+ * without asking the kernel, as are blocks of zeroes, better left as holes.
+ */
+
+#define DEDUPE_HASH_BYTES	16
+
+struct dedupe_block {
+	u8		hash[DEDUPE_HASH_BYTES];
+	u32		file;
+	u64		offset;
+	/* U64_MAX if not known, e.g. the block spans two extents: */
+	u64		physical;
+};
+
+struct dedupe_range {
+	u32		src_file;
+	u32		dst_file;
+	u64		src_offset;
+	u64		dst_offset;
+	u64		len;
+};
+
+typedef darray(struct dedupe_range) dedupe_ranges;
+
+struct dedupe_state {
+	unsigned			block_size;
+	bool				dry_run;
+	bool				verbose;
+
+	darray(char *)			files;
+	darray(struct dedupe_block)	blocks;
+
+	/* in bytes: */
+	u64				scanned;
+	u64				shared;
+	u64				duplicate;
+	u64				deduped;
+};
+
+/* nftw() has no private pointer: */
+static struct dedupe_state dedupe;
+
+/* Physical offset of [offset, offset + len), if it's all in one extent: */
+static u64 extent_physical(struct fiemap_extent *e, size_t nr,
+			   u64 offset, u64 len)
+{
+	size_t i;
+
+	for (i = 0; i < nr; i++)
+		if (offset >= e[i].fe_logical &&
+		    offset + len <= e[i].fe_logical + e[i].fe_length)
+			return e[i].fe_flags & (FIEMAP_EXTENT_UNKNOWN|
+						FIEMAP_EXTENT_ENCODED)
+				? U64_MAX
+				: e[i].fe_physical + offset - e[i].fe_logical;
+
+	return U64_MAX;
+}
+
+static bool block_is_zero(const u8 *buf, size_t len)
+{
+	return !buf[0] && !memcmp(buf, buf + 1, len - 1);
+}
+
+static void hash_file(int fd, u32 file, u64 size)
+{
+	darray(struct fiemap_extent) extents;
+	struct fiemap_iter iter;
+	struct fiemap_extent e;
+	u64 logical = U64_MAX, offset;
+	u8 *buf = xmalloc(dedupe.block_size);
+
+	darray_init(extents);
+
+	fiemap_for_each(fd, iter, e) {
+		/* Replicas are reported as extents at the same logical offset: */
+		if (e.fe_logical == logical)
+			continue;
+		logical = e.fe_logical;
+
+		if (!(e.fe_flags & FIEMAP_EXTENT_UNWRITTEN))
+			darray_append(extents, e);
+	}
+
+	/* A partial block at the end can't be deduplicated: */
+	for (offset = 0;
+	     offset + dedupe.block_size <= size;
+	     offset += dedupe.block_size) {
+		struct dedupe_block b = {
+			.file		= file,
+			.offset		= offset,
+			.physical	= extent_physical(&darray_item(extents, 0),
+							  darray_size(extents),
+							  offset, dedupe.block_size),
+		};
+
+		if (pread(fd, buf, dedupe.block_size, offset) != dedupe.block_size)
+			break;
+
+		dedupe.scanned += dedupe.block_size;
+
+		if (block_is_zero(buf, dedupe.block_size))
+			continue;
+
+		crypto_generichash_blake2b(b.hash, sizeof(b.hash),
+					   buf, dedupe.block_size, NULL, 0);
+		darray_append(dedupe.blocks, b);
+	}
+
+	darray_free(extents);
+	free(buf);
+}
+
+static int dedupe_scan_file(const char *path, const struct stat *st,
+			    int type, struct FTW *ftw)
+{
+	if (type == FTW_DNR || type == FTW_NS) {
+		fprintf(stderr, "error reading %s: %m\n", path);
+		return 0;
+	}
+
+	if (!S_ISREG(st->st_mode) || st->st_size < dedupe.block_size)
+		return 0;
+
+	int fd = open(path, O_RDONLY|O_NOATIME);
+	if (fd < 0)
+		fd = open(path, O_RDONLY);
+	if (fd < 0) {
+		fprintf(stderr, "error opening %s: %m\n", path);
+		return 0;
+	}
+
+	hash_file(fd, darray_size(dedupe.files), st->st_size);
+	darray_append(dedupe.files, strdup(path));
+	close(fd);
+	return 0;
+}
+
+static int dedupe_block_cmp(const void *_l, const void *_r)
+{
+	const struct dedupe_block *l = _l, *r = _r;
+
+	return memcmp(l->hash, r->hash, sizeof(l->hash)) ?:
+		cmp_int(l->file, r->file) ?:
+		cmp_int(l->offset, r->offset);
+}
+
+static int dedupe_range_cmp(const void *_l, const void *_r)
+{
+	const struct dedupe_range *l = _l, *r = _r;
+
+	return cmp_int(l->dst_file, r->dst_file) ?:
+		cmp_int(l->src_file, r->src_file) ?:
+		cmp_int(l->dst_offset, r->dst_offset);
+}
+
+/*
+ * Each block is deduplicated against the first block with the same hash, and
+ * runs of adjacent blocks are merged into one range, so that the result is
+ * fewer, bigger extents:
+ */
+static void dedupe_find_ranges(dedupe_ranges *ranges)
+{
+	struct dedupe_block *b, *src = NULL;
+	struct dedupe_range *r, *prev = NULL;
+	dedupe_ranges merged;
+
+	sort(&darray_item(dedupe.blocks, 0), darray_size(dedupe.blocks),
+	     sizeof(darray_item(dedupe.blocks, 0)), dedupe_block_cmp, NULL);
+
+	darray_foreach(b, dedupe.blocks) {
+		if (!src || memcmp(src->hash, b->hash, sizeof(b->hash))) {
+			src = b;
+			continue;
+		}
+
+		if (b->physical != U64_MAX &&
+		    b->physical == src->physical) {
+			dedupe.shared += dedupe.block_size;
+			continue;
+		}
+
+		struct dedupe_range n = {
+			.src_file	= src->file,
+			.dst_file	= b->file,
+			.src_offset	= src->offset,
+			.dst_offset	= b->offset,
+			.len		= dedupe.block_size,
+		};
+
+		darray_append(*ranges, n);
+		dedupe.duplicate += dedupe.block_size;
+	}
+
+	sort(&darray_item(*ranges, 0), darray_size(*ranges),
+	     sizeof(darray_item(*ranges, 0)), dedupe_range_cmp, NULL);
+
+	darray_init(merged);
+
+	darray_foreach(r, *ranges) {
+		if (prev &&
+		    prev->src_file == r->src_file &&
+		    prev->dst_file == r->dst_file &&
+		    prev->src_offset + prev->len == r->src_offset &&
+		    prev->dst_offset + prev->len == r->dst_offset) {
+			prev->len += r->len;
+			continue;
+		}
+
+		darray_append(merged, *r);
+		prev = &darray_item(merged, darray_size(merged) - 1);
+	}
+
+	darray_free(*ranges);
+	*ranges = merged;
+}
+
+/* The kernel may dedupe less than asked for, at most 16MiB at a time: */
+static void dedupe_one(struct dedupe_range *r)
+{
+	const char *src_path = darray_item(dedupe.files, r->src_file);
+	const char *dst_path = darray_item(dedupe.files, r->dst_file);
+	struct {
+		struct file_dedupe_range	range;
+		struct file_dedupe_range_info	info;
+	} d;
+	u64 done = 0;
+
+	if (dedupe.verbose || dedupe.dry_run)
+		printf("%s %llu -> %s %llu: %s\n",
+		       src_path, r->src_offset, dst_path, r->dst_offset,
+		       pr_units(r->len >> 9, HUMAN_READABLE));
+
+	if (dedupe.dry_run)
+		return;
+
+	int src_fd = open(src_path, O_RDONLY);
+	int dst_fd = open(dst_path, O_RDONLY);
+	if (src_fd < 0 || dst_fd < 0) {
+		fprintf(stderr, "error opening %s: %m\n",
+			src_fd < 0 ? src_path : dst_path);
+		goto out;
+	}
+
+	while (done < r->len) {
+		memset(&d, 0, sizeof(d));
+		d.range.src_offset	= r->src_offset + done;
+		d.range.src_length	= min(r->len - done, 16ULL << 20);
+		d.range.dest_count	= 1;
+		d.info.dest_fd		= dst_fd;
+		d.info.dest_offset	= r->dst_offset + done;
+
+		if (ioctl(src_fd, FIDEDUPERANGE, &d)) {
+			if (errno == EOPNOTSUPP)
+				die("this kernel can't deduplicate files on %s (%m): use --dry-run to report duplicates",
+				    src_path);
+			fprintf(stderr, "error deduplicating %s: %m\n", dst_path);
+			break;
+		}
+
+		if (d.info.status == FILE_DEDUPE_RANGE_DIFFERS) {
+			/* Changed since it was hashed, or a hash collision: */
+			if (dedupe.verbose)
+				printf("%s %llu: data differs, skipped\n",
+				       dst_path, d.info.dest_offset);
+			break;
+		}
+
+		if (d.info.status < 0) {
+			fprintf(stderr, "error deduplicating %s: %s\n",
+				dst_path, strerror(-d.info.status));
+			break;
+		}
+
+		if (!d.info.bytes_deduped)
+			break;
+
+		done		+= d.info.bytes_deduped;
+		dedupe.deduped	+= d.info.bytes_deduped;
+	}
+out:
+	if (dst_fd >= 0)
+		close(dst_fd);
+	if (src_fd >= 0)
+		close(src_fd);
+}
+
+static void dedupe_usage(void)
+{
+	puts("bcachefs dedupe - share the identical data of files\n"
+	     "Usage: bcachefs dedupe [OPTION]... <path>...\n"
+	     "\n"
+	     "Hashes every file under each path a block at a time, and deduplicates\n"
+	     "blocks with the same contents with FIDEDUPERANGE, which checks they're\n"
+	     "still the same before sharing them: afterwards, they're stored once, as\n"
+	     "with reflinks. Blocks already shared, and blocks of zeroes, are skipped.\n"
+	     "\n"
+	     "Options:\n"
+	     "  -b, --block-size=size       Size of the blocks compared (default 128k);\n"
+	     "                              smaller finds more duplicates, but makes more,\n"
+	     "                              smaller extents, and uses more memory\n"
+	     "  -n, --dry-run               Only list the duplicate ranges found\n"
+	     "  -v, --verbose               List each range deduplicated\n"
+	     "  -h, --help                  display this help and exit\n"
+	     "Report bugs to <linux-bcache@vger.kernel.org>");
+	exit(EXIT_SUCCESS);
+}
+
+int cmd_dedupe(int argc, char *argv[])
+{
+	static const struct option longopts[] = {
+		{ "block-size",		required_argument,	NULL, 'b' },
+		{ "dry-run",		no_argument,		NULL, 'n' },
+		{ "verbose",		no_argument,		NULL, 'v' },
+		{ "help",		no_argument,		NULL, 'h' },
+		{ NULL }
+	};
+	dedupe_ranges ranges;
+	struct dedupe_range *r;
+	char *path, **f;
+	u64 block_size;
+	int opt;
+
+	dedupe.block_size = 128 << 10;
+
+	while ((opt = getopt_long(argc, argv, "b:nvh", longopts, NULL)) != -1)
+		switch (opt) {
+		case 'b':
+			if (bch2_strtoull_h(optarg, &block_size) ||
+			    block_size < 4096 ||
+			    block_size > (16 << 20) ||
+			    !is_power_of_2(block_size))
+				die("invalid block size %s: must be a power of two from 4k to 16M",
+				    optarg);
+			dedupe.block_size = block_size;
+			break;
+		case 'n':
+			dedupe.dry_run = true;
+			break;
+		case 'v':
+			dedupe.verbose = true;
+			break;
+		case 'h':
+			dedupe_usage();
+		}
+	args_shift(optind);
+
+	if (!argc)
+		die("Please supply a path");
+
+	darray_init(dedupe.files);
+	darray_init(dedupe.blocks);
+	darray_init(ranges);
+
+	while ((path = arg_pop())) {
+		/* Checks that it's bcachefs: */
+		bcache_fs_close(bcache_fs_open(path));
+
+		if (nftw(path, dedupe_scan_file, 64, FTW_PHYS|FTW_MOUNT))
+			die("error walking %s: %m", path);
+	}
+
+	dedupe_find_ranges(&ranges);
+
+	darray_foreach(r, ranges)
+		dedupe_one(r);
+
+	printf("%zu files, %s scanned: ", darray_size(dedupe.files),
+	       pr_units(dedupe.scanned >> 9, HUMAN_READABLE));
+	printf("%s already shared, ", pr_units(dedupe.shared >> 9, HUMAN_READABLE));
+	printf("%s duplicate", pr_units(dedupe.duplicate >> 9, HUMAN_READABLE));
+	if (!dedupe.dry_run)
+		printf(", %s deduplicated",
+		       pr_units(dedupe.deduped >> 9, HUMAN_READABLE));
+	printf("\n");
+
+	darray_foreach(f, dedupe.files)
+		free(*f);
+	darray_free(dedupe.files);
+	darray_free(dedupe.blocks);
+	darray_free(ranges);
+	return 0;
+}
@@ -1,5 +1,6 @@
 #include <errno.h>
 #include <fcntl.h>
+#include <linux/fs.h>
 #include <getopt.h>
 #include <libgen.h>
 #include <stdbool.h>
@@ -14,13 +15,45 @@
 
 #include "libbcachefs/bcachefs.h"
 #include "libbcachefs/bcachefs_ioctl.h"
+#include "libbcachefs/buckets.h"
+#include "libbcachefs/disk_groups.h"
 #include "libbcachefs/journal.h"
 #include "libbcachefs/super-io.h"
 #include "cmds.h"
+#include "crypto.h"
 #include "libbcachefs.h"
 #include "libbcachefs/opts.h"
 #include "tools-util.h"
 
+/* Formats a new member for @fs, with the block and btree node sizes it uses: */
+static void device_format_and_add(struct bchfs_handle fs,
+				  struct dev_opts *dev_opts,
+				  struct format_opts format_opts,
+				  bool force)
+{
+	dev_opts->fd = open_for_format(dev_opts->path, force);
+
+	struct bch_opt_strs fs_opt_strs;
+	memset(&fs_opt_strs, 0, sizeof(fs_opt_strs));
+
+	struct bch_opts fs_opts = bch2_parse_opts(fs_opt_strs);
+
+	opt_set(fs_opts, block_size,
+		read_file_u64(fs.sysfs_fd, "block_size") >> 9);
+	opt_set(fs_opts, btree_node_size,
+		read_file_u64(fs.sysfs_fd, "btree_node_size") >> 9);
+
+	struct bch_sb *sb = bch2_format(fs_opt_strs,
+					fs_opts,
+					format_opts,
+					dev_opts, 1);
+	free(sb);
+	fsync(dev_opts->fd);
+	close(dev_opts->fd);
+
+	bchu_disk_add(fs, dev_opts->path);
+}
+
 static void device_add_usage(void)
 {
 	puts("bcachefs device add - add a device to an existing filesystem\n"
@@ -95,27 +128,189 @@ int cmd_device_add(int argc, char *argv[])
 	struct bchfs_handle fs = bcache_fs_open(fs_path);
 
 	dev_opts.path = dev_path;
-	dev_opts.fd = open_for_format(dev_opts.path, force);
+	device_format_and_add(fs, &dev_opts, format_opts, force);
+	return 0;
+}
 
-	struct bch_opt_strs fs_opt_strs;
-	memset(&fs_opt_strs, 0, sizeof(fs_opt_strs));
+static const char * const cache_modes[] = {
+	"writeback",
+	"writethrough",
+	NULL
+};
 
-	struct bch_opts fs_opts = bch2_parse_opts(fs_opt_strs);
+enum cache_mode {
+	CACHE_WRITEBACK,
+	CACHE_WRITETHROUGH,
+};
 
-	opt_set(fs_opts, block_size,
-		read_file_u64(fs.sysfs_fd, "block_size") >> 9);
-	opt_set(fs_opts, btree_node_size,
-		read_file_u64(fs.sysfs_fd, "btree_node_size") >> 9);
+static void device_add_cache_usage(void)
+{
+	puts("bcachefs device add-cache - add cache devices to a single tier filesystem\n"
+	     "Usage: bcachefs device add-cache [OPTION]... filesystem devices...\n"
+	     "\n"
+	     "Converts a mounted filesystem into a tiered one, in one step:\n"
+	     " - existing members not yet in a disk group are moved to the backing group\n"
+	     " - the new devices are formatted and added in the cache group\n"
+	     " - promote_target is set to the cache group, and background_target to\n"
+	     "   the backing group; foreground_target is set to the cache group in\n"
+	     "   writeback mode, and to the backing group in writethrough mode\n"
+	     "Each step is printed as it's done. Data already written stays where it is\n"
+	     "until it's read (and promoted) or rewritten.\n"
+	     "\n"
+	     "Options:\n"
+	     "  -m, --mode=mode             writeback (default): write to the cache\n"
+	     "                              devices, moving data to the backing devices\n"
+	     "                              in the background; writethrough: only cache\n"
+	     "                              data that is read\n"
+	     "  -g, --group=group           Disk group for the cache devices (default ssd)\n"
+	     "  -G, --backing-group=group   Disk group for the existing members (default\n"
+	     "                              hdd)\n"
+	     "  -M, --metadata              Keep metadata on the cache devices too\n"
+	     "  -D, --discard               Enable discards on the cache devices\n"
+	     "  -r, --rebalance             Make sure rebalance is enabled, which moves\n"
+	     "                              data to the background target\n"
+	     "  -f, --force                 Use devices even if they appear to already be\n"
+	     "                              formatted, and replace targets already set\n"
+	     "  -h, --help                  Display this help and exit\n"
+	     "Report bugs to <linux-bcache@vger.kernel.org>");
+	exit(EXIT_SUCCESS);
+}
 
-	struct bch_sb *sb = bch2_format(fs_opt_strs,
-					fs_opts,
-					format_opts,
-					&dev_opts, 1);
-	free(sb);
-	fsync(dev_opts.fd);
-	close(dev_opts.fd);
+static void set_fs_option(struct bchfs_handle fs, const char *opt,
+			  const char *val)
+{
+	char *attr = mprintf("options/%s", opt);
 
-	bchu_disk_add(fs, dev_opts.path);
+	printf("Setting %s=%s\n", opt, val);
+	write_file_str(fs.sysfs_fd, attr, val);
+	free(attr);
+}
+
+int cmd_device_add_cache(int argc, char *argv[])
+{
+	static const struct option longopts[] = {
+		{ "mode",		required_argument,	NULL, 'm' },
+		{ "group",		required_argument,	NULL, 'g' },
+		{ "backing-group",	required_argument,	NULL, 'G' },
+		{ "metadata",		no_argument,		NULL, 'M' },
+		{ "discard",		no_argument,		NULL, 'D' },
+		{ "rebalance",		no_argument,		NULL, 'r' },
+		{ "force",		no_argument,		NULL, 'f' },
+		{ "help",		no_argument,		NULL, 'h' },
+		{ NULL }
+	};
+	enum cache_mode mode = CACHE_WRITEBACK;
+	char *group = "ssd", *backing_group = "hdd";
+	bool metadata = false, discard = false, rebalance = false, force = false;
+	unsigned i;
+	int opt;
+
+	while ((opt = getopt_long(argc, argv, "m:g:G:MDrfh",
+				  longopts, NULL)) != -1)
+		switch (opt) {
+		case 'm':
+			mode = read_string_list_or_die(optarg, cache_modes, "mode");
+			break;
+		case 'g':
+			group = optarg;
+			break;
+		case 'G':
+			backing_group = optarg;
+			break;
+		case 'M':
+			metadata = true;
+			break;
+		case 'D':
+			discard = true;
+			break;
+		case 'r':
+			rebalance = true;
+			break;
+		case 'f':
+			force = true;
+			break;
+		case 'h':
+			device_add_cache_usage();
+		}
+	args_shift(optind);
+
+	char *fs_path = arg_pop();
+	if (!fs_path)
+		die("Please supply a filesystem");
+
+	if (!argc)
+		die("Please supply one or more cache devices");
+
+	if (!strcmp(group, backing_group))
+		die("The cache and backing groups must differ");
+
+	struct bchfs_handle fs = bcache_fs_open(fs_path);
+	struct bch_sb *sb = bchu_read_super(fs, -1);
+	struct bch_sb_handle sb_handle = { .sb = sb };
+	struct bch_sb_field_members *mi = bch2_sb_get_members(sb);
+
+	if (!force &&
+	    (BCH_SB_FOREGROUND_TARGET(sb) ||
+	     BCH_SB_BACKGROUND_TARGET(sb) ||
+	     BCH_SB_PROMOTE_TARGET(sb)))
+		die("%s already has targets set: use --force to replace them", fs_path);
+
+	if (bch2_disk_path_find(&sb_handle, group) >= 0)
+		die("Disk group %s already exists: choose another with --group", group);
+
+	int backing_idx = bch2_disk_path_find(&sb_handle, backing_group);
+
+	/* Check every member can go in the backing group before changing any: */
+	for (i = 0; i < sb->nr_devices; i++)
+		if (bch2_dev_exists(sb, mi, i) &&
+		    BCH_MEMBER_GROUP(mi->members + i) &&
+		    (backing_idx < 0 || !bch2_sb_dev_in_group(sb, i, backing_idx)))
+			die("Member %u is already in a disk group outside %s: give its group with --backing-group",
+			    i, backing_group);
+
+	for (i = 0; i < sb->nr_devices; i++) {
+		if (!bch2_dev_exists(sb, mi, i) ||
+		    BCH_MEMBER_GROUP(mi->members + i))
+			continue;
+
+		char *attr = mprintf("dev-%u/label", i);
+
+		printf("Moving member %u to disk group %s\n", i, backing_group);
+		write_file_str(fs.sysfs_fd, attr, backing_group);
+		free(attr);
+	}
+
+	for (i = 0; i < argc; i++) {
+		struct dev_opts dev_opts = dev_opts_default();
+
+		dev_opts.path		= argv[i];
+		dev_opts.group		= group;
+		dev_opts.discard	= discard;
+
+		printf("Adding %s in disk group %s\n", argv[i], group);
+		device_format_and_add(fs, &dev_opts,
+				      format_opts_default(), force);
+	}
+
+	/*
+	 * In writethrough mode new writes must still go to the backing devices,
+	 * or they'd be allocated on the cache devices too - and with nothing to
+	 * move them, that would be the only copy:
+	 */
+	set_fs_option(fs, "promote_target", group);
+	set_fs_option(fs, "foreground_target",
+		      mode == CACHE_WRITEBACK ? group : backing_group);
+	set_fs_option(fs, "background_target", backing_group);
+	if (metadata)
+		set_fs_option(fs, "metadata_target", group);
+
+	if (rebalance) {
+		printf("Enabling rebalance\n");
+		write_file_str(fs.sysfs_fd, "rebalance_enabled", "1");
+	}
+
+	free(sb);
+	bcache_fs_close(fs);
 	return 0;
 }
 
@@ -276,7 +471,11 @@ static void device_evacuate_usage(void)
 	puts("bcachefs device evacuate - move data off of a given device\n"
 	     "Usage: bcachefs device evacuate [OPTION]... device\n"
 	     "\n"
+	     "Send SIGUSR1 to pause, SIGUSR2 to resume\n"
+	     "\n"
 	     "Options:\n"
+	     "      --progress-fd=fd        Also write progress to fd, as a JSON object\n"
+	     "                              per line\n"
 	     "  -h, --help                  Display this help and exit\n"
 	     "\n"
 	     "Report bugs to <linux-bcache@vger.kernel.org>");
@@ -284,10 +483,20 @@ static void device_evacuate_usage(void)
 
 int cmd_device_evacuate(int argc, char *argv[])
 {
-	int opt;
+	static const struct option longopts[] = {
+		{ "progress-fd",		required_argument, NULL, 'P' },
+		{ "help",			0, NULL, 'h' },
+		{ NULL }
+	};
+	int opt, json_fd = -1;
 
-	while ((opt = getopt(argc, argv, "h")) != -1)
+	while ((opt = getopt_long(argc, argv, "h", longopts, NULL)) != -1)
 		switch (opt) {
+		case 'P':
+			if (kstrtoint(optarg, 10, &json_fd) ||
+			    fcntl(json_fd, F_GETFD) < 0)
+				die("invalid progress fd %s", optarg);
+			break;
 		case 'h':
 			device_evacuate_usage();
 			exit(EXIT_SUCCESS);
@@ -311,14 +520,14 @@ int cmd_device_evacuate(int argc, char *argv[])
 		bchu_disk_set_state(fs, dev_idx, BCH_MEMBER_STATE_ro, 0);
 	}
 
-	return bchu_data(fs, (struct bch_ioctl_data) {
+	return __bchu_data(fs, (struct bch_ioctl_data) {
 		.op		= BCH_DATA_OP_MIGRATE,
 		.start_btree	= 0,
 		.start_pos	= POS_MIN,
 		.end_btree	= BTREE_ID_NR,
 		.end_pos	= POS_MAX,
 		.migrate.dev	= dev_idx,
-	});
+	}, json_fd);
 }
 
 static void device_set_state_usage(void)
@@ -333,6 +542,9 @@ static void device_set_state_usage(void)
 	     "  -f, --force		    Force, if data redundancy will be degraded\n"
 	     "      --force-if-data-lost    Force, if data will be lost\n"
 	     "  -o, --offline               Set state of an offline device\n"
+	     "      --verify-after-write    With --offline, reread every copy of the\n"
+	     "                              superblock afterwards, checking they were\n"
+	     "                              all updated\n"
 	     "  -h, --help                  display this help and exit\n"
 	     "Report bugs to <linux-bcache@vger.kernel.org>");
 	exit(EXIT_SUCCESS);
@@ -344,13 +556,14 @@ int cmd_device_set_state(int argc, char *argv[])
 		{ "force",			0, NULL, 'f' },
 		{ "force-if-data-lost",		0, NULL, 'F' },
 		{ "offline",			0, NULL, 'o' },
+		{ "verify-after-write",		0, NULL, 'V' },
 		{ "help",			0, NULL, 'h' },
 		{ NULL }
 	};
 	struct bchfs_handle fs;
 	bool by_id = false;
 	int opt, flags = 0, dev_idx;
-	bool offline = false;
+	bool offline = false, verify = false;
 
 	while ((opt = getopt_long(argc, argv, "foh", longopts, NULL)) != -1)
 		switch (opt) {
@@ -364,6 +577,9 @@ int cmd_device_set_state(int argc, char *argv[])
 		case 'o':
 			offline = true;
 			break;
+		case 'V':
+			verify = true;
+			break;
 		case 'h':
 			device_set_state_usage();
 		}
@@ -403,7 +619,12 @@ int cmd_device_set_state(int argc, char *argv[])
 		le64_add_cpu(&sb.sb->seq, 1);
 
 		bch2_super_write(sb.bdev->bd_fd, sb.sb);
+
+		u64 seq = le64_to_cpu(sb.sb->seq);
 		bch2_free_super(&sb);
+
+		if (verify)
+			bch2_super_verify(&dev_str, 1, seq);
 		return 0;
 	}
 
@@ -428,6 +649,216 @@ int cmd_device_set_state(int argc, char *argv[])
 	return 0;
 }
 
+/*
+ * Offline member metadata surgery, for recovering from split brain - i.e. when
+ * members of a multi device filesystem were mounted separately:
+ */
+
+static void read_member_sbs(char *devs[], unsigned nr,
+			    struct bch_sb_handle *sbs)
+{
+	unsigned i;
+
+	for (i = 0; i < nr; i++) {
+		struct bch_opts opts = bch2_opts_empty();
+
+		int ret = bch2_read_super(devs[i], &opts, &sbs[i]);
+		if (ret)
+			die("error opening %s: %s", devs[i], strerror(-ret));
+
+		if (memcmp(&sbs[i].sb->user_uuid,
+			   &sbs[0].sb->user_uuid,
+			   sizeof(sbs[0].sb->user_uuid)))
+			die("%s is not a member of the same filesystem as %s",
+			    devs[i], devs[0]);
+	}
+}
+
+/*
+ * Returns the member entry for the member device sbs[0] in another member's
+ * superblock:
+ */
+static struct bch_member *sb_member_get(struct bch_sb_handle *member,
+					const char *member_path,
+					struct bch_sb_handle *sb,
+					const char *sb_path)
+{
+	struct bch_sb_field_members *mi = bch2_sb_get_members(sb->sb);
+	unsigned idx = member->sb->dev_idx;
+
+	if (!mi || !bch2_dev_exists(sb->sb, mi, idx))
+		die("%s: member %u (%s) does not exist", sb_path, idx, member_path);
+
+	if (memcmp(&mi->members[idx].uuid,
+		   &bch2_sb_get_members(member->sb)->members[idx].uuid,
+		   sizeof(uuid_le)))
+		die("%s: member %u is not %s", sb_path, idx, member_path);
+
+	return mi->members + idx;
+}
+
+static u64 member_sbs_max_seq(struct bch_sb_handle *sbs, unsigned nr)
+{
+	u64 seq = 0;
+	unsigned i;
+
+	for (i = 0; i < nr; i++)
+		seq = max(seq, le64_to_cpu(sbs[i].sb->seq));
+	return seq;
+}
+
+static void device_detach_usage(void)
+{
+	puts("bcachefs device detach - mark a diverged member as failed on the rest of the filesystem\n"
+	     "Usage: bcachefs device detach [OPTION]... <member> <devices>\n"
+	     "\n"
+	     "<member>: member device to detach\n"
+	     "<devices>: remaining members of the (unmounted) filesystem\n"
+	     "\n"
+	     "The member is marked failed in the superblocks of the remaining devices;\n"
+	     "its own superblock is left untouched.\n"
+	     "\n"
+	     "Options:\n"
+	     "  -h, --help                  display this help and exit\n"
+	     "Report bugs to <linux-bcache@vger.kernel.org>");
+	exit(EXIT_SUCCESS);
+}
+
+int cmd_device_detach(int argc, char *argv[])
+{
+	static const struct option longopts[] = {
+		{ "help",			0, NULL, 'h' },
+		{ NULL }
+	};
+	unsigned i;
+	int opt;
+
+	while ((opt = getopt_long(argc, argv, "h", longopts, NULL)) != -1)
+		switch (opt) {
+		case 'h':
+			device_detach_usage();
+		}
+	args_shift(optind);
+
+	if (argc < 2)
+		die("Please supply a member and the remaining devices of the filesystem");
+
+	struct bch_sb_handle *sbs = xcalloc(argc, sizeof(*sbs));
+
+	read_member_sbs(argv, argc, sbs);
+
+	u64 seq = member_sbs_max_seq(sbs, argc) + 1;
+
+	for (i = 1; i < argc; i++) {
+		struct bch_member *m = sb_member_get(&sbs[0], argv[0],
+						     &sbs[i], argv[i]);
+
+		SET_BCH_MEMBER_STATE(m, BCH_MEMBER_STATE_failed);
+		sbs[i].sb->seq = cpu_to_le64(seq);
+	}
+
+	for (i = 1; i < argc; i++)
+		bch2_super_write(sbs[i].bdev->bd_fd, sbs[i].sb);
+
+	printf("Marked member %u (%s) failed on %u devices\n",
+	       sbs[0].sb->dev_idx, argv[0], argc - 1);
+
+	for (i = 0; i < argc; i++)
+		bch2_free_super(&sbs[i]);
+	free(sbs);
+	return 0;
+}
+
+static void device_attach_usage(void)
+{
+	puts("bcachefs device attach - reattach a diverged member to the rest of the filesystem\n"
+	     "Usage: bcachefs device attach [OPTION]... <member> <devices>\n"
+	     "\n"
+	     "<member>: member device to reattach\n"
+	     "<devices>: remaining members of the (unmounted) filesystem\n"
+	     "\n"
+	     "The member's superblock is replaced with the most recent superblock of the\n"
+	     "remaining devices, and the member is marked rw on all devices.\n"
+	     "\n"
+	     "Options:\n"
+	     "  -i, --invalidate            Don't trust data on the member: mark it failed\n"
+	     "                              instead, so that it's not read from\n"
+	     "  -h, --help                  display this help and exit\n"
+	     "Report bugs to <linux-bcache@vger.kernel.org>");
+	exit(EXIT_SUCCESS);
+}
+
+int cmd_device_attach(int argc, char *argv[])
+{
+	static const struct option longopts[] = {
+		{ "invalidate",			0, NULL, 'i' },
+		{ "help",			0, NULL, 'h' },
+		{ NULL }
+	};
+	unsigned i, newest = 1, new_state = BCH_MEMBER_STATE_rw;
+	int opt;
+
+	while ((opt = getopt_long(argc, argv, "ih", longopts, NULL)) != -1)
+		switch (opt) {
+		case 'i':
+			new_state = BCH_MEMBER_STATE_failed;
+			break;
+		case 'h':
+			device_attach_usage();
+		}
+	args_shift(optind);
+
+	if (argc < 2)
+		die("Please supply a member and the remaining devices of the filesystem");
+
+	struct bch_sb_handle *sbs = xcalloc(argc, sizeof(*sbs));
+
+	read_member_sbs(argv, argc, sbs);
+
+	u64 seq = member_sbs_max_seq(sbs, argc) + 1;
+
+	for (i = 1; i < argc; i++) {
+		struct bch_member *m = sb_member_get(&sbs[0], argv[0],
+						     &sbs[i], argv[i]);
+
+		SET_BCH_MEMBER_STATE(m, new_state);
+
+		if (le64_to_cpu(sbs[i].sb->seq) >
+		    le64_to_cpu(sbs[newest].sb->seq))
+			newest = i;
+	}
+
+	for (i = 1; i < argc; i++)
+		sbs[i].sb->seq = cpu_to_le64(seq);
+
+	/* Replace the member's superblock, keeping its own layout: */
+	struct bch_sb *src = sbs[newest].sb;
+	struct bch_sb *dst = sbs[0].sb;
+	size_t bytes = vstruct_bytes(src);
+
+	if (bytes > 512ULL << dst->layout.sb_max_size_bits)
+		die("superblock of %s too big for %s", argv[newest], argv[0]);
+
+	struct bch_sb *sb = xmalloc(bytes);
+	memcpy(sb, src, bytes);
+	sb->dev_idx	= dst->dev_idx;
+	sb->layout	= dst->layout;
+
+	for (i = 1; i < argc; i++)
+		bch2_super_write(sbs[i].bdev->bd_fd, sbs[i].sb);
+	bch2_super_write(sbs[0].bdev->bd_fd, sb);
+
+	printf("Reattached member %u (%s) using superblock from %s, state %s\n",
+	       sb->dev_idx, argv[0], argv[newest],
+	       bch2_member_states[new_state]);
+
+	free(sb);
+	for (i = 0; i < argc; i++)
+		bch2_free_super(&sbs[i]);
+	free(sbs);
+	return 0;
+}
+
 static void device_resize_usage(void)
 {
 	puts("bcachefs device resize \n"
@@ -530,10 +961,29 @@ int cmd_device_resize(int argc, char *argv[])
 	return 0;
 }
 
+/*
+ * The kernel silently does nothing if asked for fewer buckets than the journal
+ * already has:
+ */
+static void resize_journal_check(const char *dev, u64 nbuckets,
+				 u64 dev_nbuckets, unsigned cur_nbuckets)
+{
+	if (nbuckets <= cur_nbuckets)
+		die("journal on %s already has %u buckets; journals can't be shrunk",
+		    dev, cur_nbuckets);
+
+	if (nbuckets >= dev_nbuckets)
+		die("journal of %llu buckets won't fit on %s (%llu buckets)",
+		    nbuckets, dev, dev_nbuckets);
+}
+
 static void device_resize_journal_usage(void)
 {
 	puts("bcachefs device resize-journal \n"
-	     "Usage: bcachefs device resize-journal device [ size ]\n"
+	     "Usage: bcachefs device resize-journal device size\n"
+	     "\n"
+	     "Grows the journal on a device, e.g. to move most journal writes to a\n"
+	     "fast device. Journals can't be shrunk.\n"
 	     "\n"
 	     "Options:\n"
 	     "  -h, --help                  display this help and exit\n"
@@ -565,8 +1015,8 @@ int cmd_device_resize_journal(int argc, char *argv[])
 
 	char *size_arg = arg_pop();
 	if (!size_arg)
-		size = get_size(dev, dev_fd);
-	else if (bch2_strtoull_h(size_arg, &size))
+		die("Please supply a journal size");
+	if (bch2_strtoull_h(size_arg, &size))
 		die("invalid size");
 
 	size >>= 9;
@@ -597,9 +1047,20 @@ int cmd_device_resize_journal(int argc, char *argv[])
 		struct bch_member *m = mi->members + idx;
 
 		u64 nbuckets = size / le16_to_cpu(m->bucket_size);
+		u64 dev_nbuckets = le64_to_cpu(m->nbuckets);
+
+		/* The journal field is per device: */
+		free(sb);
+		sb = bchu_read_super(fs, idx);
+
+		struct bch_sb_field_journal *journal = bch2_sb_get_journal(sb);
+
+		resize_journal_check(dev, nbuckets, dev_nbuckets,
+				     journal ? bch2_nr_journal_buckets(journal) : 0);
 
 		printf("resizing journal on %s to %llu buckets\n", dev, nbuckets);
 		bchu_disk_resize_journal(fs, idx, nbuckets);
+		free(sb);
 	} else {
 		printf("%s is offline - starting:\n", dev);
 
@@ -619,6 +1080,9 @@ int cmd_device_resize_journal(int argc, char *argv[])
 
 		u64 nbuckets = size / le16_to_cpu(resize->mi.bucket_size);
 
+		resize_journal_check(dev, nbuckets, resize->mi.nbuckets,
+				     resize->journal.nr);
+
 		printf("resizing journal on %s to %llu buckets\n", dev, nbuckets);
 		int ret = bch2_set_nr_journal_buckets(c, resize, nbuckets);
 		if (ret)
@@ -629,3 +1093,512 @@ int cmd_device_resize_journal(int argc, char *argv[])
 	}
 	return 0;
 }
+
+static void print_dev_zones(struct dev_name *dev)
+{
+	char *path = mprintf("/dev/%s", dev->dev);
+	int fd = open(path, O_RDONLY);
+
+	free(path);
+	if (fd < 0)
+		return;
+
+	struct dev_queue_info q = dev_queue_info(fd);
+
+	if (q.zoned) {
+		struct dev_zones z = dev_zones_read(fd);
+
+		printf("zoned (%s), %llu zones of %s: %llu empty, %llu open, %llu full",
+		       q.host_managed ? "host-managed" : "host-aware",
+		       z.nr, pr_units(q.zone_sectors, HUMAN_READABLE),
+		       z.empty, z.open, z.full);
+		if (z.other)
+			printf(", %llu other", z.other);
+		putchar('\n');
+	}
+
+	close(fd);
+}
+
+static void print_dev_iodone(struct dev_name *dev, struct bchu_dev_iodone *now,
+			     struct bchu_dev_iodone *prev, unsigned interval,
+			     enum units units)
+{
+	unsigned i;
+
+	printf("%s (device %u):\n", dev->dev ?: "(offline)", dev->idx);
+	if (dev->dev)
+		print_dev_zones(dev);
+	printf("%-16s%16s%16s\n", "", "read", "write");
+
+	for (i = 1; i < BCH_DATA_NR; i++) {
+		u64 r = now->sectors[READ][i];
+		u64 w = now->sectors[WRITE][i];
+
+		/* In interval mode, print the rate over the last interval: */
+		if (prev) {
+			r = (r - prev->sectors[READ][i]) / interval;
+			w = (w - prev->sectors[WRITE][i]) / interval;
+		}
+
+		printf("%-16s%16s", bch2_data_types[i], pr_units(r, units));
+		printf("%16s\n", pr_units(w, units));
+	}
+}
+
+static void device_stats_usage(void)
+{
+	puts("bcachefs device stats - show IO done by each device\n"
+	     "Usage: bcachefs device stats [OPTION]... [filesystem]\n"
+	     "\n"
+	     "Shows the amount of data read and written by each device of a mounted\n"
+	     "filesystem since it was mounted, by data type; and for zoned devices,\n"
+	     "how many zones are empty, open and full.\n"
+	     "\n"
+	     "Options:\n"
+	     "  -i, --interval=seconds      Instead, show per second rates, every\n"
+	     "                              <seconds>, until interrupted\n"
+	     "  -H, --human-readable        Human readable units\n"
+	     "  -h, --help                  display this help and exit\n"
+	     "Report bugs to <linux-bcache@vger.kernel.org>");
+	exit(EXIT_SUCCESS);
+}
+
+int cmd_device_stats(int argc, char *argv[])
+{
+	static const struct option longopts[] = {
+		{ "interval",		required_argument,	NULL, 'i' },
+		{ "human-readable",	no_argument,		NULL, 'H' },
+		{ "help",		no_argument,		NULL, 'h' },
+		{ NULL }
+	};
+	enum units units = BYTES;
+	unsigned interval = 0;
+	int opt;
+
+	while ((opt = getopt_long(argc, argv, "i:Hh", longopts, NULL)) != -1)
+		switch (opt) {
+		case 'i':
+			if (kstrtouint(optarg, 10, &interval) || !interval)
+				die("invalid interval %s", optarg);
+			break;
+		case 'H':
+			units = HUMAN_READABLE;
+			break;
+		case 'h':
+			device_stats_usage();
+		}
+	args_shift(optind);
+
+	char *path = arg_pop() ?: ".";
+
+	if (argc)
+		die("Too many arguments");
+
+	struct bchfs_handle fs = bcache_fs_open(path);
+	dev_names devs = bchu_fs_get_devices(fs);
+	struct bchu_dev_iodone *prev = xcalloc(darray_size(devs), sizeof(*prev));
+	struct dev_name *dev;
+
+	darray_foreach(dev, devs)
+		prev[dev - devs.item] = bchu_dev_iodone_read(fs, dev->idx);
+
+	if (!interval) {
+		darray_foreach(dev, devs)
+			print_dev_iodone(dev, &prev[dev - devs.item],
+					 NULL, 0, units);
+	} else {
+		while (1) {
+			sleep(interval);
+
+			darray_foreach(dev, devs) {
+				struct bchu_dev_iodone now = bchu_dev_iodone_read(fs, dev->idx);
+
+				print_dev_iodone(dev, &now, &prev[dev - devs.item],
+						 interval, units);
+				prev[dev - devs.item] = now;
+			}
+			putchar('\n');
+			fflush(stdout);
+		}
+	}
+
+	darray_foreach(dev, devs) {
+		free(dev->dev);
+		free(dev->label);
+	}
+	darray_free(devs);
+	free(prev);
+	bcache_fs_close(fs);
+	return 0;
+}
+
+/* One character per data type, in the bucket map: */
+static const char bucket_map_chars[BCH_DATA_NR] = {
+	[BCH_DATA_none]		= '.',
+	[BCH_DATA_sb]		= 's',
+	[BCH_DATA_journal]	= 'j',
+	[BCH_DATA_btree]	= 'b',
+	[BCH_DATA_user]		= 'u',
+	[BCH_DATA_cached]	= 'c',
+	[BCH_DATA_parity]	= 'p',
+};
+
+/*
+ * Buckets holding the superblock and journal are taken from the superblock
+ * itself, not the alloc info, so they're shown even if that's wrong:
+ */
+static u8 *dev_bucket_types(struct bch_dev *ca)
+{
+	struct bch_sb_layout *layout = &ca->disk_sb.sb->layout;
+	u64 b, nbuckets = ca->mi.nbuckets;
+	u8 *types = xcalloc(nbuckets, 1);
+	unsigned i;
+
+	rcu_read_lock();
+	for (b = ca->mi.first_bucket; b < nbuckets; b++)
+		types[b] = bucket(ca, b)->mark.data_type;
+	rcu_read_unlock();
+
+	for (b = 0; b < ca->mi.first_bucket; b++)
+		types[b] = BCH_DATA_sb;
+
+	for (i = 0; i < layout->nr_superblocks; i++) {
+		u64 start = le64_to_cpu(layout->sb_offset[i]);
+		u64 end = start + (1ULL << layout->sb_max_size_bits);
+
+		for (b = sector_to_bucket(ca, start);
+		     b <= sector_to_bucket(ca, end - 1) && b < nbuckets;
+		     b++)
+			types[b] = BCH_DATA_sb;
+	}
+
+	for (i = 0; i < ca->journal.nr; i++)
+		if (ca->journal.buckets[i] < nbuckets)
+			types[ca->journal.buckets[i]] = BCH_DATA_journal;
+
+	return types;
+}
+
+static void print_dev_layout(struct bch_dev *ca, unsigned buckets_per_char,
+			     unsigned width)
+{
+	struct bch_sb_layout *layout = &ca->disk_sb.sb->layout;
+	u64 b, nbuckets = ca->mi.nbuckets, counts[BCH_DATA_NR] = { 0 };
+	u8 *types = dev_bucket_types(ca);
+	unsigned i, j;
+
+	printf("Device %u (%s): %llu buckets of %s, first bucket %u\n",
+	       ca->dev_idx, ca->name, nbuckets,
+	       pr_units(ca->mi.bucket_size, HUMAN_READABLE),
+	       ca->mi.first_bucket);
+
+	printf("\nSuperblocks (max size %s):\n",
+	       pr_units(1ULL << layout->sb_max_size_bits, HUMAN_READABLE));
+	for (i = 0; i < layout->nr_superblocks; i++) {
+		u64 offset = le64_to_cpu(layout->sb_offset[i]);
+
+		printf("  %u: sector %llu, bucket %llu\n", i, offset,
+		       (u64) sector_to_bucket(ca, offset));
+	}
+
+	printf("\nJournal: %u buckets\n", ca->journal.nr);
+	for (i = 0; i < ca->journal.nr; i = j) {
+		for (j = i + 1;
+		     j < ca->journal.nr &&
+		     ca->journal.buckets[j] == ca->journal.buckets[j - 1] + 1;
+		     j++)
+			;
+
+		if (j - i == 1)
+			printf("  bucket  %llu\n", ca->journal.buckets[i]);
+		else
+			printf("  buckets %llu-%llu\n", ca->journal.buckets[i],
+			       ca->journal.buckets[j - 1]);
+	}
+
+	for (b = 0; b < nbuckets; b++)
+		counts[types[b]]++;
+
+	printf("\nBuckets by data type:\n");
+	for (i = 0; i < BCH_DATA_NR; i++)
+		if (counts[i])
+			printf("  %c %-12s %12llu\n", bucket_map_chars[i],
+			       bch2_data_types[i], counts[i]);
+
+	printf("\nBucket map, %u buckets per character, by the most common type:\n",
+	       buckets_per_char);
+	for (b = 0; b < nbuckets; b += buckets_per_char) {
+		u64 end = min(b + buckets_per_char, nbuckets), k;
+		unsigned cell[BCH_DATA_NR] = { 0 }, best = 0;
+
+		if (!((b / buckets_per_char) % width))
+			printf("%s%10llu ", b ? "\n" : "", b);
+
+		for (k = b; k < end; k++)
+			cell[types[k]]++;
+
+		/* Anything in use wins over free buckets: */
+		for (i = 1; i < BCH_DATA_NR; i++)
+			if (cell[i] > (best ? cell[best] : 0))
+				best = i;
+
+		putchar(bucket_map_chars[best]);
+	}
+	printf("\n\n");
+
+	free(types);
+}
+
+static void device_inspect_layout_usage(void)
+{
+	puts("bcachefs device inspect-layout - show how a device is laid out\n"
+	     "Usage: bcachefs device inspect-layout [OPTION]... <devices>...\n"
+	     "\n"
+	     "For each device of an unmounted filesystem, shows where the superblocks\n"
+	     "and the journal are, how many buckets hold each type of data, and a map\n"
+	     "of the buckets: s superblock, j journal, b btree, u user data,\n"
+	     "c cached data, p parity, . free.\n"
+	     "\n"
+	     "Options:\n"
+	     "  -b, --buckets-per-char=nr   Buckets shown by each character of the map\n"
+	     "                              (default: enough for 16 lines)\n"
+	     "  -w, --width=nr              Characters per line of the map (default 64)\n"
+	     "  -k, --passphrase-file=file  Read the passphrase of an encrypted filesystem\n"
+	     "                              from file\n"
+	     "  -h, --help                  display this help and exit\n"
+	     "Report bugs to <linux-bcache@vger.kernel.org>");
+	exit(EXIT_SUCCESS);
+}
+
+int cmd_device_inspect_layout(int argc, char *argv[])
+{
+	static const struct option longopts[] = {
+		{ "buckets-per-char",	required_argument,	NULL, 'b' },
+		{ "width",		required_argument,	NULL, 'w' },
+		{ "passphrase-file",	required_argument,	NULL, 'k' },
+		{ "help",		no_argument,		NULL, 'h' },
+		{ NULL }
+	};
+	struct bch_opts opts = bch2_opts_empty();
+	char *passphrase_file = NULL;
+	unsigned buckets_per_char = 0, width = 64, i;
+	struct bch_dev *ca;
+	int opt;
+
+	while ((opt = getopt_long(argc, argv, "b:w:k:h", longopts, NULL)) != -1)
+		switch (opt) {
+		case 'b':
+			if (kstrtouint(optarg, 10, &buckets_per_char) ||
+			    !buckets_per_char)
+				die("invalid number of buckets %s", optarg);
+			break;
+		case 'w':
+			if (kstrtouint(optarg, 10, &width) || !width)
+				die("invalid width %s", optarg);
+			break;
+		case 'k':
+			passphrase_file = optarg;
+			break;
+		case 'h':
+			device_inspect_layout_usage();
+		}
+	args_shift(optind);
+
+	if (!argc)
+		die("Please supply device(s)");
+
+	opt_set(opts, nochanges,	true);
+	opt_set(opts, norecovery,	true);
+	opt_set(opts, degraded,		true);
+	opt_set(opts, errors,		BCH_ON_ERROR_continue);
+
+	bch2_add_key_offline(argv[0], passphrase_file);
+
+	struct bch_fs *c = bch2_fs_open(argv, argc, opts);
+	if (IS_ERR(c))
+		die("error opening %s: %s", argv[0], strerror(-PTR_ERR(c)));
+
+	for_each_online_member(ca, c, i)
+		print_dev_layout(ca, buckets_per_char ?:
+				 max_t(u64, DIV_ROUND_UP(ca->mi.nbuckets, width * 16), 1),
+				 width);
+
+	bch2_fs_stop(c);
+	return 0;
+}
+
+static void discard_range(struct bch_dev *ca, u64 start, u64 nr)
+{
+	int fd = ca->disk_sb.bdev->bd_fd;
+	u64 range[2] = { start << 9, nr << 9 };
+	int ret = S_ISBLK(xfstat(fd).st_mode)
+		? ioctl(fd, BLKDISCARD, range)
+		: fallocate(fd, FALLOC_FL_PUNCH_HOLE|FALLOC_FL_KEEP_SIZE,
+			    range[0], range[1]);
+
+	if (ret)
+		die("error discarding %s sectors %llu-%llu: %m",
+		    ca->name, start, start + nr);
+}
+
+/* Returns sectors discarded: */
+static u64 discard_dev(struct bch_dev *ca, bool dry_run)
+{
+	u8 *types = dev_bucket_types(ca);
+	u64 b, end, nbuckets = ca->mi.nbuckets, ret = 0;
+
+	for (b = ca->mi.first_bucket; b < nbuckets; b = end) {
+		if (types[b] != BCH_DATA_none) {
+			end = b + 1;
+			continue;
+		}
+
+		for (end = b; end < nbuckets && types[end] == BCH_DATA_none; end++)
+			;
+
+		if (!dry_run)
+			discard_range(ca, bucket_to_sector(ca, b),
+				      bucket_to_sector(ca, end - b));
+		ret += bucket_to_sector(ca, end - b);
+	}
+
+	free(types);
+	return ret;
+}
+
+static void discard_set_online(const char *path, bool on)
+{
+	struct bchfs_handle fs = bcache_fs_open(path);
+	dev_names devs = bchu_fs_get_devices(fs);
+	struct dev_name *dev;
+
+	darray_foreach(dev, devs) {
+		char *attr = mprintf("dev-%u/discard", dev->idx);
+		int fd = xopenat(fs.sysfs_fd, attr, O_WRONLY);
+
+		if (write(fd, on ? "1" : "0", 1) != 1)
+			die("error setting discard on %s: %m",
+			    dev->dev ?: "(offline)");
+		close(fd);
+		free(attr);
+
+		printf("%s: discard %s\n", dev->dev ?: "(offline)",
+		       on ? "enabled" : "disabled");
+		free(dev->dev);
+		free(dev->label);
+	}
+	darray_free(devs);
+	bcache_fs_close(fs);
+}
+
+static void discard_usage(void)
+{
+	puts("bcachefs discard - discard the empty buckets of a filesystem\n"
+	     "Usage: bcachefs discard [OPTION]... <devices>...\n"
+	     "   or: bcachefs discard --online=(on|off) <filesystem>\n"
+	     "\n"
+	     "Discards (trims) every empty bucket on the devices of an unmounted\n"
+	     "filesystem, telling the device the space is free. A mounted filesystem\n"
+	     "can't be discarded by hand; instead, with online discard on, buckets\n"
+	     "are discarded as they're reused. --online turns that on or off for\n"
+	     "every device of a mounted filesystem.\n"
+	     "\n"
+	     "Options:\n"
+	     "  -n, --dry-run               Only print how much would be discarded\n"
+	     "      --online=(on|off)       Enable or disable online discard\n"
+	     "  -k, --passphrase-file=file  Read the passphrase of an encrypted filesystem\n"
+	     "                              from file\n"
+	     "  -h, --help                  display this help and exit\n"
+	     "Report bugs to <linux-bcache@vger.kernel.org>");
+	exit(EXIT_SUCCESS);
+}
+
+int cmd_discard(int argc, char *argv[])
+{
+	static const struct option longopts[] = {
+		{ "dry-run",		no_argument,		NULL, 'n' },
+		{ "online",		required_argument,	NULL, 'o' },
+		{ "passphrase-file",	required_argument,	NULL, 'k' },
+		{ "help",		no_argument,		NULL, 'h' },
+		{ NULL }
+	};
+	struct bch_opts opts = bch2_opts_empty();
+	char *passphrase_file = NULL;
+	bool dry_run = false;
+	int online = -1;
+	u64 total = 0;
+	struct bch_dev *ca;
+	unsigned i;
+	int opt;
+
+	while ((opt = getopt_long(argc, argv, "nk:h", longopts, NULL)) != -1)
+		switch (opt) {
+		case 'n':
+			dry_run = true;
+			break;
+		case 'o':
+			if (!strcmp(optarg, "on"))
+				online = 1;
+			else if (!strcmp(optarg, "off"))
+				online = 0;
+			else
+				die("--online must be on or off");
+			break;
+		case 'k':
+			passphrase_file = optarg;
+			break;
+		case 'h':
+			discard_usage();
+		}
+	args_shift(optind);
+
+	if (online >= 0) {
+		char *path = arg_pop();
+		if (!path)
+			die("Please supply a filesystem");
+		if (argc)
+			die("Too many arguments");
+
+		discard_set_online(path, online);
+		return 0;
+	}
+
+	if (!argc)
+		die("Please supply device(s)");
+
+	for (i = 0; i < argc; i++)
+		if (dev_mounted(argv[i]))
+			die("%s is mounted: use --online to have buckets discarded as they're reused",
+			    argv[i]);
+
+	/* Not nochanges, since the devices have to be opened for writing: */
+	opt_set(opts, read_only,	true);
+	opt_set(opts, norecovery,	true);
+
+	bch2_add_key_offline(argv[0], passphrase_file);
+
+	struct bch_fs *c = bch2_fs_open(argv, argc, opts);
+	if (IS_ERR(c))
+		die("error opening %s: %s", argv[0], strerror(-PTR_ERR(c)));
+
+	/* The alloc info is only up to date after a clean shutdown: */
+	if (!c->sb.clean)
+		die("%s wasn't shut down cleanly: mount it once, or run fsck, first",
+		    argv[0]);
+
+	for_each_online_member(ca, c, i) {
+		u64 sectors = discard_dev(ca, dry_run);
+
+		printf("%s: %s %s\n", ca->name,
+		       dry_run ? "would discard" : "discarded",
+		       pr_units(sectors, HUMAN_READABLE));
+		total += sectors;
+	}
+
+	if (argc > 1)
+		printf("total: %s\n", pr_units(total, HUMAN_READABLE));
+
+	bch2_fs_stop(c);
+	return 0;
+}
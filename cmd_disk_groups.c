@@ -0,0 +1,318 @@
+#include <fcntl.h>
+#include <getopt.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <sys/stat.h>
+#include <sys/types.h>
+#include <unistd.h>
+
+#include "cmds.h"
+#include "libbcachefs.h"
+
+#include "libbcachefs/bcachefs.h"
+#include "libbcachefs/disk_groups.h"
+#include "libbcachefs/super-io.h"
+#include "libbcachefs/super.h"
+
+/*
+ * Disk groups are stored in the superblock as a tree of labels - "ssd.fast" is
+ * a group "fast" whose parent is "ssd" - and each member is in at most one
+ * group, and so in its parents too.
+ */
+
+/* The mountpoint of a mounted filesystem, given it or one of its devices: */
+static char *fs_mountpoint(char *path)
+{
+	struct stat st = xstat(path);
+	struct mntent *mount;
+
+	if (S_ISDIR(st.st_mode))
+		return path;
+
+	mount = dev_to_mount(path);
+	return mount ? mount->mnt_dir : NULL;
+}
+
+static struct bch_fs *disk_groups_fs_open(char **devs, unsigned nr_devs)
+{
+	struct bch_opts opts = bch2_opts_empty();
+
+	opt_set(opts, nostart, true);
+
+	/* bch2_fs_open() so that every member's superblock gets updated: */
+	struct bch_fs *c = bch2_fs_open(devs, nr_devs, opts);
+	if (IS_ERR(c))
+		die("error opening %s: %s", devs[0], strerror(-PTR_ERR(c)));
+	return c;
+}
+
+static void print_disk_groups(struct bch_sb *sb, dev_names *devs)
+{
+	struct bch_sb_handle h = { .sb = sb };
+	struct bch_sb_field_members *mi = bch2_sb_get_members(sb);
+	struct bch_sb_field_disk_groups *gi = bch2_sb_get_disk_groups(sb);
+	struct dev_name *d;
+	unsigned i, dev;
+	char buf[256];
+
+	printf("%-24s %s\n", "Group", "Devices");
+
+	for (i = 0; i < disk_groups_nr(gi); i++) {
+		if (BCH_GROUP_DELETED(gi->entries + i))
+			continue;
+
+		bch2_disk_path_to_text(&PBUF(buf), &h, i);
+		printf("%-24s", buf);
+
+		for (dev = 0; dev < sb->nr_devices; dev++)
+			if (bch2_sb_dev_in_group(sb, dev, i))
+				printf(" %u", dev);
+		printf("\n");
+	}
+
+	printf("%-24s", "(none)");
+	for (dev = 0; dev < sb->nr_devices; dev++)
+		if (bch2_dev_exists(sb, mi, dev) &&
+		    !BCH_MEMBER_GROUP(mi->members + dev))
+			printf(" %u", dev);
+	printf("\n\n");
+
+	printf("%-8s %s\n", "Device", "Path");
+	darray_foreach(d, *devs)
+		printf("%-8u %s\n", d->idx, d->dev ?: "(offline)");
+}
+
+static void disk_groups_show_usage(void)
+{
+	puts("bcachefs disk-groups show - show the disk groups of a filesystem\n"
+	     "Usage: bcachefs disk-groups show [OPTION]... <mountpoint>\n"
+	     "   or: bcachefs disk-groups show [OPTION]... <devices>...\n"
+	     "\n"
+	     "Lists each disk group with the index of each device in it, directly\n"
+	     "or through a subgroup, then the devices.\n"
+	     "\n"
+	     "Options:\n"
+	     "  -h, --help                  display this help and exit\n"
+	     "Report bugs to <linux-bcache@vger.kernel.org>");
+	exit(EXIT_SUCCESS);
+}
+
+int cmd_disk_groups_show(int argc, char *argv[])
+{
+	static const struct option longopts[] = {
+		{ "help",		no_argument,		NULL, 'h' },
+		{ NULL }
+	};
+	struct bch_sb *sb;
+	dev_names devs;
+	char *mountpoint;
+	unsigned i;
+	int opt;
+
+	while ((opt = getopt_long(argc, argv, "h", longopts, NULL)) != -1)
+		switch (opt) {
+		case 'h':
+			disk_groups_show_usage();
+		}
+	args_shift(optind);
+
+	if (!argc)
+		die("Please supply a mountpoint or devices");
+
+	if ((mountpoint = fs_mountpoint(argv[0]))) {
+		if (argc > 1)
+			die("Too many arguments");
+
+		struct bchfs_handle fs = bcache_fs_open(mountpoint);
+
+		sb	= bchu_read_super(fs, -1);
+		devs	= bchu_fs_get_devices(fs);
+		bcache_fs_close(fs);
+	} else {
+		struct bch_sb_handle *sbs = xcalloc(argc, sizeof(*sbs));
+		u64 seq = 0;
+
+		darray_init(devs);
+		sb = NULL;
+
+		/* The superblock written most recently has the current groups: */
+		for (i = 0; i < argc; i++) {
+			struct bch_opts opts = bch2_opts_empty();
+
+			opt_set(opts, nochanges, true);
+
+			int ret = bch2_read_super(argv[i], &opts, &sbs[i]);
+			if (ret)
+				die("error opening %s: %s", argv[i], strerror(-ret));
+
+			if (le64_to_cpu(sbs[i].sb->seq) >= seq) {
+				seq = le64_to_cpu(sbs[i].sb->seq);
+				sb = sbs[i].sb;
+			}
+
+			struct dev_name n = {
+				.idx	= sbs[i].sb->dev_idx,
+				.dev	= strdup(argv[i]),
+			};
+			darray_append(devs, n);
+		}
+
+		sb = kmemdup(sb, vstruct_bytes(sb), GFP_KERNEL);
+
+		for (i = 0; i < argc; i++)
+			bch2_free_super(&sbs[i]);
+		free(sbs);
+	}
+
+	print_disk_groups(sb, &devs);
+
+	dev_names_free(&devs);
+	free(sb);
+	return 0;
+}
+
+static void disk_groups_create_usage(void)
+{
+	puts("bcachefs disk-groups create - create a disk group\n"
+	     "Usage: bcachefs disk-groups create [OPTION]... <group> <devices>...\n"
+	     "\n"
+	     "Creates a disk group, and any parent groups it doesn't have yet, e.g.\n"
+	     "hdd and hdd.archive for hdd.archive, in an unmounted filesystem, given\n"
+	     "all of its devices. The kernel can only create groups when a device is\n"
+	     "moved into them: on a mounted filesystem, use bcachefs disk-groups move.\n"
+	     "\n"
+	     "Options:\n"
+	     "  -h, --help                  display this help and exit\n"
+	     "Report bugs to <linux-bcache@vger.kernel.org>");
+	exit(EXIT_SUCCESS);
+}
+
+int cmd_disk_groups_create(int argc, char *argv[])
+{
+	static const struct option longopts[] = {
+		{ "help",		no_argument,		NULL, 'h' },
+		{ NULL }
+	};
+	int opt, ret;
+
+	while ((opt = getopt_long(argc, argv, "h", longopts, NULL)) != -1)
+		switch (opt) {
+		case 'h':
+			disk_groups_create_usage();
+		}
+	args_shift(optind);
+
+	char *group = arg_pop();
+	if (!group)
+		die("Please supply a group");
+
+	if (!argc)
+		die("Please supply the devices of the filesystem");
+
+	if (fs_mountpoint(argv[0]))
+		die("%s is mounted: use bcachefs disk-groups move, which creates the group it moves a device into",
+		    argv[0]);
+
+	struct bch_fs *c = disk_groups_fs_open(argv, argc);
+
+	mutex_lock(&c->sb_lock);
+	if (bch2_disk_path_find(&c->disk_sb, group) >= 0)
+		die("group %s already exists", group);
+
+	ret = bch2_disk_path_find_or_create(&c->disk_sb, group);
+	if (ret < 0)
+		die("error creating group %s: %s", group, strerror(-ret));
+
+	bch2_write_super(c);
+	mutex_unlock(&c->sb_lock);
+
+	bch2_fs_stop(c);
+	return 0;
+}
+
+static void disk_groups_move_usage(void)
+{
+	puts("bcachefs disk-groups move - move a device to another disk group\n"
+	     "Usage: bcachefs disk-groups move [OPTION]... <group> <device> [<devices>...]\n"
+	     "\n"
+	     "Moves a member device into a disk group, creating the group if it\n"
+	     "doesn't exist; none takes it out of its group. On an unmounted\n"
+	     "filesystem, the remaining devices of the filesystem must be given too.\n"
+	     "\n"
+	     "Moving a device doesn't move data already on it: targets that now\n"
+	     "include or exclude it apply to data written from then on, and to data\n"
+	     "moved by rebalance.\n"
+	     "\n"
+	     "Options:\n"
+	     "  -h, --help                  display this help and exit\n"
+	     "Report bugs to <linux-bcache@vger.kernel.org>");
+	exit(EXIT_SUCCESS);
+}
+
+int cmd_disk_groups_move(int argc, char *argv[])
+{
+	static const struct option longopts[] = {
+		{ "help",		no_argument,		NULL, 'h' },
+		{ NULL }
+	};
+	int opt, ret;
+
+	while ((opt = getopt_long(argc, argv, "h", longopts, NULL)) != -1)
+		switch (opt) {
+		case 'h':
+			disk_groups_move_usage();
+		}
+	args_shift(optind);
+
+	char *group = arg_pop();
+	if (!group)
+		die("Please supply a group");
+
+	if (!argc)
+		die("Please supply a device");
+
+	if (dev_to_mount(argv[0])) {
+		if (argc > 1)
+			die("Too many arguments");
+
+		/* The kernel creates the group if need be: */
+		int idx;
+		struct bchfs_handle fs = bchu_fs_open_by_dev(argv[0], &idx);
+		char *attr = mprintf("dev-%u/label", idx);
+
+		int fd = openat(fs.sysfs_fd, attr, O_WRONLY);
+		if (fd < 0)
+			die("error opening %s: %m", attr);
+
+		if (write(fd, group, strlen(group)) < 0)
+			die("error moving %s to group %s: %m", argv[0], group);
+
+		close(fd);
+		free(attr);
+		bcache_fs_close(fs);
+		return 0;
+	}
+
+	struct bch_opts opts = bch2_opts_empty();
+	struct bch_sb_handle sb;
+
+	opt_set(opts, nochanges, true);
+
+	ret = bch2_read_super(argv[0], &opts, &sb);
+	if (ret)
+		die("error opening %s: %s", argv[0], strerror(-ret));
+
+	unsigned idx = sb.sb->dev_idx;
+	bch2_free_super(&sb);
+
+	struct bch_fs *c = disk_groups_fs_open(argv, argc);
+
+	ret = bch2_dev_group_set(c, bch_dev_bkey_exists(c, idx), group);
+	if (ret)
+		die("error moving %s to group %s: %s",
+		    argv[0], group, strerror(-ret));
+
+	bch2_fs_stop(c);
+	return 0;
+}
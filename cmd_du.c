@@ -0,0 +1,209 @@
+#include <fcntl.h>
+#include <ftw.h>
+#include <getopt.h>
+#include <stdio.h>
+#include <string.h>
+#include <sys/stat.h>
+#include <sys/types.h>
+#include <unistd.h>
+
+#include "ccan/darray/darray.h"
+
+#include "cmds.h"
+#include "libbcachefs.h"
+#include "linux/sort.h"
+
+/*
+ * A range of a reflinked extent, by offset on disk. fiemap doesn't say which
+ * device an extent is on, so on multi device filesystems ranges at the same
+ * offset on different devices are counted once:
+ */
+struct shared_range {
+	u64		start;
+	u64		end;
+};
+
+struct du_state {
+	enum units			units;
+	bool				all;
+
+	/* in sectors: */
+	u64				total;
+	u64				exclusive;
+	darray(struct shared_range)	shared;
+};
+
+/* nftw() has no private pointer: */
+static struct du_state du;
+
+static int shared_range_cmp(const void *_l, const void *_r)
+{
+	const struct shared_range *l = _l, *r = _r;
+
+	return cmp_int(l->start, r->start);
+}
+
+/*
+ * Sectors covered by the shared ranges seen, counting ranges referenced more
+ * than once - or overlapping - once:
+ */
+static u64 shared_sectors(void)
+{
+	struct shared_range *r;
+	u64 ret = 0, end = 0;
+
+	sort(&darray_item(du.shared, 0), darray_size(du.shared),
+	     sizeof(darray_item(du.shared, 0)), shared_range_cmp, NULL);
+
+	darray_foreach(r, du.shared) {
+		if (r->end <= end)
+			continue;
+
+		ret += r->end - max(r->start, end);
+		end = r->end;
+	}
+
+	return ret;
+}
+
+static void file_usage(int fd, u64 *total, u64 *exclusive)
+{
+	struct fiemap_iter iter;
+	struct fiemap_extent e;
+	u64 logical = U64_MAX;
+
+	*total = *exclusive = 0;
+
+	fiemap_for_each(fd, iter, e) {
+		u64 sectors = e.fe_length >> 9;
+
+		/* Replicas are reported as extents at the same logical offset: */
+		if (e.fe_logical == logical)
+			continue;
+		logical = e.fe_logical;
+
+		*total += sectors;
+
+		if (e.fe_flags & FIEMAP_EXTENT_SHARED) {
+			struct shared_range r = {
+				.start	= e.fe_physical >> 9,
+				.end	= (e.fe_physical >> 9) + sectors,
+			};
+
+			darray_append(du.shared, r);
+		} else {
+			*exclusive += sectors;
+		}
+	}
+}
+
+static int du_file(const char *path, const struct stat *st,
+		   int type, struct FTW *ftw)
+{
+	u64 total, exclusive;
+
+	if (type == FTW_DNR || type == FTW_NS) {
+		fprintf(stderr, "error reading %s: %m\n", path);
+		return 0;
+	}
+
+	if (!S_ISREG(st->st_mode))
+		return 0;
+
+	int fd = open(path, O_RDONLY|O_NOATIME);
+	if (fd < 0)
+		fd = open(path, O_RDONLY);
+	if (fd < 0) {
+		fprintf(stderr, "error opening %s: %m\n", path);
+		return 0;
+	}
+
+	file_usage(fd, &total, &exclusive);
+	close(fd);
+
+	du.total	+= total;
+	du.exclusive	+= exclusive;
+
+	if (du.all)
+		printf("%12s %12s %12s  %s\n",
+		       pr_units(total, du.units),
+		       pr_units(exclusive, du.units),
+		       "-", path);
+	return 0;
+}
+
+static void du_path(const char *path)
+{
+	du.total	= 0;
+	du.exclusive	= 0;
+	darray_free(du.shared);
+	darray_init(du.shared);
+
+	if (nftw(path, du_file, 64, FTW_PHYS|FTW_MOUNT))
+		die("error walking %s: %m", path);
+
+	printf("%12s %12s %12s  %s\n",
+	       pr_units(du.total, du.units),
+	       pr_units(du.exclusive, du.units),
+	       pr_units(shared_sectors(), du.units),
+	       path);
+}
+
+static void du_usage(void)
+{
+	puts("bcachefs du - disk usage, counting reflinked extents once\n"
+	     "Usage: bcachefs du [OPTION]... <path>...\n"
+	     "\n"
+	     "For each path, prints:\n"
+	     "  Total       sum of the sizes of all the extents of every file\n"
+	     "  Exclusive   extents that are not reflinked\n"
+	     "  Set shared  reflinked extents, counting each part of them once no\n"
+	     "              matter how many files under this path refer to it\n"
+	     "Replicas are counted once. Reflinked extents may still be referenced from\n"
+	     "outside of the path, so deleting it frees at least Exclusive.\n"
+	     "\n"
+	     "Options:\n"
+	     "  -a, --all                   Print a line for every file as well\n"
+	     "  -H, --human-readable        Human readable units\n"
+	     "  -h, --help                  display this help and exit\n"
+	     "Report bugs to <linux-bcache@vger.kernel.org>");
+	exit(EXIT_SUCCESS);
+}
+
+int cmd_du(int argc, char *argv[])
+{
+	static const struct option longopts[] = {
+		{ "all",		no_argument,		NULL, 'a' },
+		{ "human-readable",	no_argument,		NULL, 'H' },
+		{ "help",		no_argument,		NULL, 'h' },
+		{ NULL }
+	};
+	char *path;
+	int opt;
+
+	du.units = BYTES;
+
+	while ((opt = getopt_long(argc, argv, "aHh", longopts, NULL)) != -1)
+		switch (opt) {
+		case 'a':
+			du.all = true;
+			break;
+		case 'H':
+			du.units = HUMAN_READABLE;
+			break;
+		case 'h':
+			du_usage();
+		}
+	args_shift(optind);
+
+	if (!argc)
+		die("Please supply a path");
+
+	printf("%12s %12s %12s  %s\n", "Total", "Exclusive", "Set shared", "Filename");
+
+	while ((path = arg_pop()))
+		du_path(path);
+
+	darray_free(du.shared);
+	return 0;
+}
@@ -0,0 +1,629 @@
+#include <errno.h>
+#include <getopt.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <strings.h>
+
+#include "cmds.h"
+#include "fsck_errors.h"
+#include "libbcachefs.h"
+
+#include "libbcachefs/error.h"
+
+/*
+ * Explanations of the errors users run into: errno values as returned by
+ * mount and the tools, the exit status of fsck, and the errors fsck reports.
+ * The fsck errors are those of fsck_errors.c, so they can also be found by
+ * their message, and a line from dmesg or fsck's output pasted as is.
+ */
+
+enum explain_kind {
+	EXPLAIN_ERRNO,
+	EXPLAIN_FSCK_EXIT,
+	EXPLAIN_FSCK,
+};
+
+static const char * const explain_kinds[] = {
+	[EXPLAIN_ERRNO]		= "errno",
+	[EXPLAIN_FSCK_EXIT]	= "fsck-exit",
+	[EXPLAIN_FSCK]		= "fsck",
+};
+
+struct explain_entry {
+	enum explain_kind	kind;
+	const char		*name;
+	/* errno, or exit status: */
+	int			code;
+	const char		*summary;
+	const char		*explanation;
+	const char		*next_steps[4];
+};
+
+#define FSCK_STEP	"Run bcachefs fsck -p on the unmounted filesystem to repair it"
+#define FSCK_N_STEP	"To see everything that's wrong first, run bcachefs fsck -n"
+#define BUG_STEP	"If it keeps happening, collect a bug report with bcachefs bug-report"
+
+static const struct explain_entry explain_entries[] = {
+	/* errno: */
+	{
+		EXPLAIN_ERRNO, "EROFS", EROFS,
+		"The filesystem is read-only",
+		"Writes are refused because the filesystem is mounted read-only, or "
+		"went read-only after an error it couldn't handle while mounted, "
+		"e.g. an inconsistency found in the btrees, or failing to write to "
+		"enough devices. The kernel log says which.",
+		{ "Check dmesg for the error that made the filesystem read-only",
+		  "Unmount, and run bcachefs fsck -p to repair it",
+		  "If a device failed, replace it and run bcachefs data rereplicate",
+		  NULL },
+	}, {
+		EXPLAIN_ERRNO, "ENOKEY", ENOKEY,
+		"The filesystem is encrypted and its key isn't loaded",
+		"The key of an encrypted filesystem has to be in the kernel keyring "
+		"before it's mounted or opened by the tools.",
+		{ "Run bcachefs unlock on one of its devices",
+		  "Or mount with mount.bcachefs, which asks for the passphrase",
+		  NULL },
+	}, {
+		EXPLAIN_ERRNO, "EINVAL", EINVAL,
+		"Invalid argument: often a bad mount option, or a filesystem that can't be opened",
+		"Mount fails with this for options the kernel doesn't know or values "
+		"it refuses, and when the filesystem can't be started: missing devices "
+		"without -o degraded, incompatible features, or errors found while "
+		"reading the journal and btree roots.",
+		{ "Check dmesg for what the kernel refused",
+		  "If devices are missing, mount with -o degraded",
+		  "If it's errors in the filesystem, run bcachefs fsck -p on it",
+		  NULL },
+	}, {
+		EXPLAIN_ERRNO, "EIO", EIO,
+		"I/O error: data couldn't be read or written, or failed its checksum",
+		"A device returned an error, or data read back didn't match its "
+		"checksum on every replica. bcachefs retries other replicas first, so "
+		"this means no good copy could be read.",
+		{ "Check dmesg, and bcachefs device stats, for which device is failing",
+		  "Find which files are affected with bcachefs verify-file",
+		  "Replace failing devices with bcachefs device evacuate and device remove",
+		  NULL },
+	}, {
+		EXPLAIN_ERRNO, "ENOSPC", ENOSPC,
+		"No space left on the devices",
+		"Space is reserved up front for writes, including for the extra "
+		"replicas and the metadata they need, so this can happen before "
+		"bcachefs fs usage shows the devices completely full, particularly with "
+		"replicas > 1 and devices of different sizes.",
+		{ "Check bcachefs fs usage for which devices and data types are full",
+		  "Delete data, or add a device with bcachefs device add",
+		  NULL },
+	}, {
+		EXPLAIN_ERRNO, "EBUSY", EBUSY,
+		"The device or filesystem is in use",
+		"A device that's already open, by a mounted filesystem or another "
+		"tool, can't be opened exclusively; a filesystem can't be unmounted "
+		"while files on it are open.",
+		{ "Check whether the filesystem is already mounted",
+		  "Run bcachefs umount to list the processes keeping it busy",
+		  NULL },
+	}, {
+		EXPLAIN_ERRNO, "EOPNOTSUPP", EOPNOTSUPP,
+		"Operation not supported by this version of bcachefs",
+		"The running kernel doesn't implement the ioctl or feature used, or "
+		"the filesystem wasn't formatted with the feature needed.",
+		{ "Check the versions with bcachefs version and uname -r",
+		  NULL },
+	}, {
+		EXPLAIN_ERRNO, "EEXIST", EEXIST,
+		"Already exists",
+		"Adding a device that's already a member, creating a disk group that "
+		"already exists, or unlocking a filesystem whose key is already in "
+		"the keyring.",
+		{ NULL },
+	},
+
+	/* fsck exit status: */
+	{
+		EXPLAIN_FSCK_EXIT, "fsck-exit-1", 1,
+		"fsck found errors, and fixed them",
+		"The filesystem is consistent now.",
+		{ NULL },
+	}, {
+		EXPLAIN_FSCK_EXIT, "fsck-exit-2", 2,
+		"A device was mounted read-only while fsck ran",
+		"Repairs were made while the filesystem was mounted read-only, e.g. "
+		"the root filesystem at boot: the mounted filesystem doesn't see them.",
+		{ "Reboot, or unmount and mount the filesystem again",
+		  NULL },
+	}, {
+		EXPLAIN_FSCK_EXIT, "fsck-exit-4", 4,
+		"fsck left errors unfixed",
+		"Either fsck was told not to fix them (-n, or answering no), or they "
+		"can't be repaired automatically.",
+		{ FSCK_STEP,
+		  "If errors remain after that, collect a bug report with bcachefs bug-report",
+		  NULL },
+	}, {
+		EXPLAIN_FSCK_EXIT, "fsck-exit-8", 8,
+		"fsck couldn't run",
+		"The filesystem couldn't be opened, e.g. because a device is mounted "
+		"read-write, is missing, or the key of an encrypted filesystem isn't "
+		"loaded.",
+		{ "Unmount the filesystem, and check that every device is given",
+		  "For an encrypted filesystem, run bcachefs unlock first",
+		  NULL },
+	}, {
+		EXPLAIN_FSCK_EXIT, "fsck-exit-16", 16,
+		"Usage error",
+		"fsck was run with invalid options.",
+		{ "Run bcachefs fsck -h", NULL },
+	},
+
+	/* fsck errors: */
+	{
+		EXPLAIN_FSCK, "dirent_to_missing_inode", 0,
+		"A directory entry points to an inode that doesn't exist",
+		"The file the name referred to is gone, most likely because the inode "
+		"was deleted but the dirent wasn't, after a crash or a bug. fsck "
+		"removes the dirent; the data of the file, if any is left, is lost.",
+		{ FSCK_STEP, NULL },
+	}, {
+		EXPLAIN_FSCK, "dirent_in_missing_dir", 0,
+		"A directory entry belongs to a directory that doesn't exist",
+		"fsck deletes the dirent. The inode it pointed to may then be "
+		"unreachable, and is moved to lost+found.",
+		{ FSCK_STEP, "Afterwards, look in lost+found", NULL },
+	}, {
+		EXPLAIN_FSCK, "dirent_in_non_dir", 0,
+		"A directory entry belongs to an inode that isn't a directory",
+		"fsck deletes the dirent.",
+		{ FSCK_STEP, NULL },
+	}, {
+		EXPLAIN_FSCK, "dirent_d_type_wrong", 0,
+		"The file type recorded in a directory entry doesn't match the inode",
+		"Harmless, but readdir would report the wrong type for the file. fsck "
+		"corrects the dirent.",
+		{ FSCK_STEP, NULL },
+	}, {
+		EXPLAIN_FSCK, "hash_table_key_wrong_offset", 0,
+		"A dirent or xattr isn't where its hash says it should be",
+		"Lookups by name would miss it. fsck moves the key to where it "
+		"belongs.",
+		{ FSCK_STEP, NULL },
+	}, {
+		EXPLAIN_FSCK, "hash_table_key_duplicate", 0,
+		"The same name exists twice in a directory, or the same xattr twice on an inode",
+		"fsck deletes the duplicate.",
+		{ FSCK_STEP, NULL },
+	}, {
+		EXPLAIN_FSCK, "extent_for_missing_inode", 0,
+		"Data, or an xattr, belongs to an inode that doesn't exist",
+		"Left over from a file that wasn't completely deleted. fsck deletes "
+		"the keys, freeing their space.",
+		{ FSCK_STEP, NULL },
+	}, {
+		EXPLAIN_FSCK, "extent_in_non_reg", 0,
+		"An extent belongs to an inode that isn't a regular file or symlink",
+		"fsck deletes the extent.",
+		{ FSCK_STEP, NULL },
+	}, {
+		EXPLAIN_FSCK, "extents_overlapping", 0,
+		"Two extents of a file cover the same range",
+		"The btree is out of order, which shouldn't happen: most likely a bug, "
+		"or metadata written by a newer or buggy version.",
+		{ FSCK_STEP, BUG_STEP, NULL },
+	}, {
+		EXPLAIN_FSCK, "inode_i_sectors_wrong", 0,
+		"The space used by a file, as recorded in its inode, is wrong",
+		"Only what stat and du report is affected. Expected after an unclean "
+		"shutdown; fsck recounts it from the extents.",
+		{ FSCK_STEP, NULL },
+	}, {
+		EXPLAIN_FSCK, "inode_i_size_dirty", 0,
+		"A file's size wasn't updated before the filesystem was marked clean",
+		"fsck truncates the file to the data that was written.",
+		{ FSCK_STEP, NULL },
+	}, {
+		EXPLAIN_FSCK, "inode_i_sectors_dirty", 0,
+		"A file's space used wasn't updated before the filesystem was marked clean",
+		"fsck recounts it from the extents.",
+		{ FSCK_STEP, NULL },
+	}, {
+		EXPLAIN_FSCK, "inode_unlinked_clean", 0,
+		"An inode was deleted but still open when the filesystem was marked clean",
+		"fsck finishes deleting it.",
+		{ FSCK_STEP, NULL },
+	}, {
+		EXPLAIN_FSCK, "inode_i_nlink_wrong", 0,
+		"A file's link count doesn't match the number of names pointing to it",
+		"fsck corrects the link count from the dirents. Files with no names "
+		"left are moved to lost+found.",
+		{ FSCK_STEP, "Afterwards, look in lost+found", NULL },
+	}, {
+		EXPLAIN_FSCK, "inode_backpointer_wrong", 0,
+		"An inode's record of the dirent pointing to it is wrong",
+		"The backpointer is used to find a file's parent directory. fsck "
+		"corrects it.",
+		{ FSCK_STEP, NULL },
+	}, {
+		EXPLAIN_FSCK, "inode_unreachable", 0,
+		"An inode isn't reachable from the root directory",
+		"fsck moves it to lost+found, named by its inode number.",
+		{ FSCK_STEP, "Afterwards, look in lost+found", NULL },
+	}, {
+		EXPLAIN_FSCK, "dir_multiple_links", 0,
+		"A directory has more than one name",
+		"Directories can only have one parent. fsck removes the extra names.",
+		{ FSCK_STEP, NULL },
+	}, {
+		EXPLAIN_FSCK, "dir_loop", 0,
+		"A directory is its own ancestor",
+		"fsck breaks the loop by moving a directory to lost+found.",
+		{ FSCK_STEP, "Afterwards, look in lost+found", NULL },
+	}, {
+		EXPLAIN_FSCK, "root_missing", 0,
+		"The root directory doesn't exist",
+		"fsck recreates it; everything that was in it ends up in lost+found.",
+		{ FSCK_STEP, "Afterwards, look in lost+found", NULL },
+	}, {
+		EXPLAIN_FSCK, "root_not_dir", 0,
+		"The root inode isn't a directory",
+		"fsck recreates the root directory; everything that was in it ends up "
+		"in lost+found.",
+		{ FSCK_STEP, "Afterwards, look in lost+found", NULL },
+	}, {
+		EXPLAIN_FSCK, "inode_nlink_zero_linked", 0,
+		"A file with names pointing to it has a link count of 0",
+		"It would be deleted when last closed. fsck corrects the link count.",
+		{ FSCK_STEP, NULL },
+	}, {
+		EXPLAIN_FSCK, "bucket_ptr_gen_stale", 0,
+		"A pointer to data refers to a bucket that has since been reused",
+		"The data it pointed to was overwritten, so it's lost. Expected to be "
+		"rare; fsck drops the pointer and, if no replicas are left, the "
+		"extent.",
+		{ FSCK_STEP, "Find the affected files with bcachefs verify-file", NULL },
+	}, {
+		EXPLAIN_FSCK, "bucket_ptr_gen_newer", 0,
+		"A pointer refers to a newer generation of a bucket than the alloc info has",
+		"The alloc info is out of date; fsck updates it from the pointers.",
+		{ FSCK_STEP, NULL },
+	}, {
+		EXPLAIN_FSCK, "bucket_ptr_missing_alloc", 0,
+		"A pointer refers to a bucket the alloc btree has no entry for",
+		"fsck recreates the alloc info from the pointers.",
+		{ FSCK_STEP, NULL },
+	}, {
+		EXPLAIN_FSCK, "bucket_data_type_mismatch", 0,
+		"A bucket is used for two kinds of data, e.g. both journal and user data",
+		"Only one of them can be right: fsck keeps the pointers of one type.",
+		{ FSCK_STEP, BUG_STEP, NULL },
+	}, {
+		EXPLAIN_FSCK, "alloc_key_wrong", 0,
+		"The accounting for a bucket or stripe doesn't match what points to it",
+		"Expected after an unclean shutdown, and harmless apart from free "
+		"space being misreported: fsck recomputes it. bcachefs "
+		"check-free-space checks this much faster than a full fsck.",
+		{ FSCK_STEP, NULL },
+	}, {
+		EXPLAIN_FSCK, "stripe_ptr_missing", 0,
+		"A pointer refers to an erasure coded stripe that doesn't exist",
+		"fsck drops the stripe from the pointer; the data is then only as "
+		"redundant as its other replicas.",
+		{ FSCK_STEP, "Then run bcachefs data rereplicate", NULL },
+	}, {
+		EXPLAIN_FSCK, "stripe_ptr_mismatch", 0,
+		"A pointer doesn't match the erasure coded stripe it refers to",
+		"fsck drops the stripe from the pointer; the data is then only as "
+		"redundant as its other replicas.",
+		{ FSCK_STEP, "Then run bcachefs data rereplicate", NULL },
+	}, {
+		EXPLAIN_FSCK, "replicas_not_marked", 0,
+		"Data is stored on a set of devices the superblock doesn't list",
+		"The superblock's list of which devices hold data is used to decide "
+		"whether the filesystem can be mounted degraded. fsck adds the entry.",
+		{ FSCK_STEP, NULL },
+	}, {
+		EXPLAIN_FSCK, "btree_node_unreadable", 0,
+		"A btree node couldn't be read from any replica",
+		"The keys in that node are lost: for the extents btree, file data; "
+		"for the inodes or dirents btree, files. fsck repairs the filesystem "
+		"around the hole.",
+		{ "Check dmesg for I/O errors on the devices",
+		  FSCK_STEP,
+		  "If whole btrees are unreadable, bcachefs find-btree-nodes may find the lost nodes",
+		  NULL },
+	}, {
+		EXPLAIN_FSCK, "btree_node_bad_bounds", 0,
+		"A btree node's key range doesn't fit its parent",
+		"Nodes were lost, or written out of order. fsck repairs the btree's "
+		"topology.",
+		{ FSCK_STEP, NULL },
+	}, {
+		EXPLAIN_FSCK, "btree_root_bad", 0,
+		"A btree root is invalid, or couldn't be read",
+		"Without the root, none of that btree can be read.",
+		{ FSCK_STEP,
+		  "If fsck can't open the filesystem, bcachefs find-btree-nodes can look for the lost roots",
+		  NULL },
+	}, {
+		EXPLAIN_FSCK, "btree_root_bad_bounds", 0,
+		"A btree root's key range doesn't cover every key",
+		"fsck repairs the btree's topology.",
+		{ FSCK_STEP, NULL },
+	}, {
+		EXPLAIN_FSCK, "btree_node_empty", 0,
+		"An interior btree node has no keys",
+		"Its children were lost. fsck repairs the btree's topology.",
+		{ FSCK_STEP, NULL },
+	}, {
+		EXPLAIN_FSCK, "bkey_invalid", 0,
+		"A key in the journal is invalid",
+		"fsck drops the key.",
+		{ FSCK_STEP, BUG_STEP, NULL },
+	}, {
+		EXPLAIN_FSCK, "journal_empty", 0,
+		"No journal entries could be read",
+		"The journal holds the latest updates, and the btree roots: without it "
+		"the filesystem can't be opened normally.",
+		{ "Check dmesg for I/O errors",
+		  "Try mounting with -o norecovery, read-only, to copy data off",
+		  NULL },
+	}, {
+		EXPLAIN_FSCK, "journal_entries_missing", 0,
+		"Journal entries are missing",
+		"Updates in the missing entries are lost, and the btrees may be "
+		"inconsistent as a result.",
+		{ FSCK_N_STEP, FSCK_STEP, NULL },
+	}, {
+		EXPLAIN_FSCK, "journal_entries_mismatch", 0,
+		"Copies of a journal entry on different devices don't match",
+		"One copy is used; the others were written incompletely or are "
+		"corrupt.",
+		{ FSCK_STEP, NULL },
+	}, {
+		EXPLAIN_FSCK, "journal_entry_blacklisted", 0,
+		"A journal entry was found that had been blacklisted",
+		"Entries are blacklisted when they were written but not flushed "
+		"before a crash; they're ignored.",
+		{ FSCK_STEP, NULL },
+	}, {
+		EXPLAIN_FSCK, "sb_clean_missing", 0,
+		"The superblock is marked clean but doesn't have the section recording it",
+		"The journal is replayed as after an unclean shutdown.",
+		{ FSCK_STEP, NULL },
+	}, {
+		EXPLAIN_FSCK, "sb_clean_mismatch", 0,
+		"The superblock and the journal disagree about the last clean shutdown",
+		"Journal entries were written after the filesystem was marked clean, "
+		"e.g. by a tool run against it while mounted. The journal is "
+		"replayed as after an unclean shutdown.",
+		{ FSCK_STEP, NULL },
+	}, {
+		EXPLAIN_FSCK, "key_version_too_high", 0,
+		"A key's version number is higher than the filesystem has handed out",
+		"Versions are used for encryption nonces, so reusing one would be a "
+		"security problem: the recorded maximum is raised.",
+		{ FSCK_STEP, NULL },
+	},
+};
+
+/*
+ * Print @prefix and @s wrapped to 80 columns, with the lines after the first
+ * indented to line up with the end of @prefix:
+ */
+static void print_wrapped(const char *prefix, const char *s)
+{
+	unsigned indent = strlen(prefix);
+
+	printf("%s", prefix);
+	while (*s) {
+		const char *end = s, *p;
+
+		for (p = s; *p && p - s <= 80 - indent; p++)
+			if (*p == ' ')
+				end = p;
+		if (!*p || end == s)
+			end = p;
+		while (*end && *end != ' ')
+			end++;
+
+		printf("%.*s\n", (int) (end - s), s);
+
+		s = end;
+		while (*s == ' ')
+			s++;
+		if (*s)
+			printf("%*s", indent, "");
+	}
+}
+
+static void explain_print(const struct explain_entry *e)
+{
+	const struct fsck_err_type *t = e->kind == EXPLAIN_FSCK
+		? fsck_err_type_by_name(e->name) : NULL;
+	const char * const *step;
+
+	printf("%s (%s", e->name, explain_kinds[e->kind]);
+	if (e->kind == EXPLAIN_ERRNO)
+		printf(" %i: %s", e->code, strerror(e->code));
+	if (t)
+		printf(", %s%s", fsck_err_categories[t->category],
+		       !(t->flags & FSCK_CAN_FIX) ? ", can't be fixed"
+		       : t->flags & FSCK_AUTOFIX_SAFE ? ", safe to fix" : "");
+	printf(")\n");
+
+	print_wrapped("  ", e->summary);
+	printf("\n");
+	print_wrapped("  ", e->explanation);
+
+	if (e->next_steps[0]) {
+		printf("\nNext steps:\n");
+		for (step = e->next_steps; *step; step++)
+			print_wrapped("  - ", *step);
+	}
+}
+
+static void explain_print_json(const struct explain_entry *e)
+{
+	const struct fsck_err_type *t = e->kind == EXPLAIN_FSCK
+		? fsck_err_type_by_name(e->name) : NULL;
+	const char * const *step;
+
+	printf("{\"name\":");
+	json_str(stdout, e->name);
+	printf(",\"kind\":\"%s\"", explain_kinds[e->kind]);
+	if (e->kind != EXPLAIN_FSCK)
+		printf(",\"code\":%i", e->code);
+	if (t) {
+		printf(",\"match\":");
+		json_str(stdout, t->match);
+		printf(",\"category\":\"%s\",\"can_fix\":%s,\"can_ignore\":%s,\"autofix_safe\":%s",
+		       fsck_err_categories[t->category],
+		       t->flags & FSCK_CAN_FIX ? "true" : "false",
+		       t->flags & FSCK_CAN_IGNORE ? "true" : "false",
+		       t->flags & FSCK_AUTOFIX_SAFE ? "true" : "false");
+	}
+	printf(",\"summary\":");
+	json_str(stdout, e->summary);
+	printf(",\"explanation\":");
+	json_str(stdout, e->explanation);
+	printf(",\"next_steps\":[");
+	for (step = e->next_steps; *step; step++) {
+		if (step != e->next_steps)
+			putchar(',');
+		json_str(stdout, *step);
+	}
+	printf("]}");
+}
+
+/*
+ * By name (errno names in any case), by errno number (negative, as the kernel
+ * returns them, or not), or by part of an fsck error message:
+ */
+static const struct explain_entry *explain_lookup(const char *s)
+{
+	const struct explain_entry *e;
+	const struct fsck_err_type *t;
+	char *end;
+	long code;
+
+	for (e = explain_entries; e < explain_entries + ARRAY_SIZE(explain_entries); e++)
+		if (!strcasecmp(s, e->name))
+			return e;
+
+	code = strtol(s, &end, 10);
+	if (*s && !*end) {
+		code = labs(code);
+		for (e = explain_entries; e < explain_entries + ARRAY_SIZE(explain_entries); e++)
+			if (e->kind == EXPLAIN_ERRNO && e->code == code)
+				return e;
+		return NULL;
+	}
+
+	t = fsck_err_type_find(s);
+	if (t)
+		for (e = explain_entries; e < explain_entries + ARRAY_SIZE(explain_entries); e++)
+			if (e->kind == EXPLAIN_FSCK && !strcmp(e->name, t->name))
+				return e;
+
+	return NULL;
+}
+
+static void explain_usage(void)
+{
+	puts("bcachefs explain - explain an error, and what to do about it\n"
+	     "Usage: bcachefs explain [OPTION]... <error>\n"
+	     "\n"
+	     "Explains an error: an errno, by name (EROFS) or number, the exit status\n"
+	     "of fsck (fsck-exit-4), or an error fsck reported, by name or by pasting\n"
+	     "the message from its output or from dmesg. Exits with status 1 if the\n"
+	     "error isn't known.\n"
+	     "\n"
+	     "Options:\n"
+	     "  -j, --json                  Print JSON, for consumption by other tools\n"
+	     "  -l, --list                  List every known error\n"
+	     "  -h, --help                  display this help and exit\n"
+	     "Report bugs to <linux-bcache@vger.kernel.org>");
+	exit(EXIT_SUCCESS);
+}
+
+int cmd_explain(int argc, char *argv[])
+{
+	static const struct option longopts[] = {
+		{ "json",		no_argument,		NULL, 'j' },
+		{ "list",		no_argument,		NULL, 'l' },
+		{ "help",		no_argument,		NULL, 'h' },
+		{ NULL }
+	};
+	const struct explain_entry *e;
+	bool json = false, list = false;
+	char *err = NULL;
+	size_t len = 0;
+	int opt, i;
+
+	while ((opt = getopt_long(argc, argv, "jlh", longopts, NULL)) != -1)
+		switch (opt) {
+		case 'j':
+			json = true;
+			break;
+		case 'l':
+			list = true;
+			break;
+		case 'h':
+			explain_usage();
+		}
+	args_shift(optind);
+
+	if (list) {
+		if (argc)
+			die("Too many arguments");
+
+		if (json)
+			putchar('[');
+		for (e = explain_entries; e < explain_entries + ARRAY_SIZE(explain_entries); e++)
+			if (json) {
+				if (e != explain_entries)
+					putchar(',');
+				explain_print_json(e);
+			} else {
+				printf("%-28s %-10s %s\n", e->name,
+				       explain_kinds[e->kind], e->summary);
+			}
+		if (json)
+			printf("]\n");
+		return 0;
+	}
+
+	if (!argc)
+		die("Please supply an error");
+
+	/* A pasted message may have been split into several arguments: */
+	for (i = 0; i < argc; i++)
+		len += strlen(argv[i]) + 1;
+	err = xcalloc(len, 1);
+	for (i = 0; i < argc; i++) {
+		if (i)
+			strcat(err, " ");
+		strcat(err, argv[i]);
+	}
+
+	e = explain_lookup(strim(err));
+	if (!e) {
+		if (json)
+			printf("null\n");
+		else
+			fprintf(stderr, "No explanation for %s\n", err);
+		free(err);
+		return 1;
+	}
+
+	if (json) {
+		explain_print_json(e);
+		printf("\n");
+	} else {
+		explain_print(e);
+	}
+
+	free(err);
+	return 0;
+}
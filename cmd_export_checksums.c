@@ -0,0 +1,420 @@
+#include <getopt.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <sys/types.h>
+
+#include "ccan/darray/darray.h"
+
+#include "cmds.h"
+#include "crypto.h"
+#include "libbcachefs.h"
+#include "linux/sort.h"
+
+#include "libbcachefs/bcachefs.h"
+#include "libbcachefs/btree_iter.h"
+#include "libbcachefs/dirent.h"
+#include "libbcachefs/error.h"
+#include "libbcachefs/extents.h"
+#include "libbcachefs/super.h"
+
+/*
+ * Every extent already has a checksum of its data, as stored: these are
+ * exported with the path of each file, so that backup and dedupe tools can
+ * tell which parts of which files changed without reading and hashing them.
+ * The checksums are of the data as written to disk, so they only match if it
+ * was compressed and encrypted the same way, with the same nonce.
+ */
+
+static const char * const csum_types[] = {
+	[BCH_CSUM_NONE]				= "none",
+	[BCH_CSUM_CRC32C_NONZERO]		= "crc32c_nonzero",
+	[BCH_CSUM_CRC64_NONZERO]		= "crc64_nonzero",
+	[BCH_CSUM_CHACHA20_POLY1305_80]		= "chacha20_poly1305_80",
+	[BCH_CSUM_CHACHA20_POLY1305_128]	= "chacha20_poly1305_128",
+	[BCH_CSUM_CRC32C]			= "crc32c",
+	[BCH_CSUM_CRC64]			= "crc64",
+};
+
+static const char * const compression_types[] = {
+#define x(t, n) [n] = #t,
+	BCH_COMPRESSION_TYPES()
+#undef x
+};
+
+enum export_format {
+	EXPORT_CSV,
+	EXPORT_SQL,
+};
+
+static const char * const export_formats[] = {
+	"csv",
+	"sql",
+	NULL
+};
+
+/* A dirent, by the inode it points to: */
+struct export_link {
+	u64		inum;
+	u64		dir;
+	char		*name;
+	/* directories only, filled in as needed: */
+	char		*path;
+};
+
+typedef darray(struct export_link) export_links;
+
+static int export_link_cmp(const void *_l, const void *_r)
+{
+	const struct export_link *l = _l, *r = _r;
+
+	return cmp_int(l->inum, r->inum);
+}
+
+static export_links read_links(struct bch_fs *c, bool dirs)
+{
+	struct btree_trans trans;
+	struct btree_iter *iter;
+	struct bkey_s_c k;
+	export_links links;
+	int ret;
+
+	darray_init(links);
+	bch2_trans_init(&trans, c, 0, 0);
+
+	for_each_btree_key(&trans, iter, BTREE_ID_dirents, POS_MIN,
+			   BTREE_ITER_PREFETCH, k, ret) {
+		struct bkey_s_c_dirent d;
+
+		if (k.k->type != KEY_TYPE_dirent)
+			continue;
+
+		d = bkey_s_c_to_dirent(k);
+		if ((d.v->d_type == DT_DIR) != dirs)
+			continue;
+
+		struct export_link l = {
+			.inum	= le64_to_cpu(d.v->d_inum),
+			.dir	= k.k->p.inode,
+			.name	= strndup(d.v->d_name, bch2_dirent_name_bytes(d)),
+		};
+
+		darray_append(links, l);
+	}
+	bch2_trans_iter_put(&trans, iter);
+
+	ret = bch2_trans_exit(&trans) ?: ret;
+	if (ret)
+		die("error walking dirents: %s", strerror(-ret));
+
+	sort(&darray_item(links, 0), darray_size(links),
+	     sizeof(darray_item(links, 0)), export_link_cmp, NULL);
+	return links;
+}
+
+static struct export_link *links_find(export_links *links, u64 inum)
+{
+	struct export_link search = { .inum = inum }, *l;
+
+	l = bsearch(&search, links->item, darray_size(*links),
+		    sizeof(search), export_link_cmp);
+
+	/* Files may have several links: back up to the first */
+	while (l && l > links->item && l[-1].inum == inum)
+		l--;
+	return l;
+}
+
+/* NULL if the directory isn't reachable from the root: */
+static const char *dir_path(export_links *dirs, u64 inum, unsigned depth)
+{
+	struct export_link *l;
+	const char *parent;
+
+	if (inum == BCACHEFS_ROOT_INO)
+		return "";
+
+	l = links_find(dirs, inum);
+	if (!l || depth > 4096)
+		return NULL;
+
+	if (!l->path) {
+		parent = dir_path(dirs, l->dir, depth + 1);
+		if (!parent)
+			return NULL;
+
+		l->path = mprintf("%s/%s", parent, l->name);
+	}
+
+	return l->path;
+}
+
+struct export_state {
+	enum export_format	format;
+	export_links		dirs;
+	export_links		files;
+	u64			rows;
+	u64			unreachable;
+};
+
+static void print_quoted(enum export_format format, const char *s)
+{
+	char q = format == EXPORT_CSV ? '"' : '\'';
+
+	if (format == EXPORT_CSV && !strpbrk(s, ",\"\n")) {
+		fputs(s, stdout);
+		return;
+	}
+
+	putchar(q);
+	for (; *s; s++) {
+		if (*s == q)
+			putchar(q);
+		putchar(*s);
+	}
+	putchar(q);
+}
+
+/*
+ * @offset and @sectors are of the part of the file, and @crc_offset where that
+ * starts in the region the checksum covers:
+ */
+static void print_row(struct export_state *s, const char *path, u64 inum,
+		      u64 offset, u64 sectors, struct bch_extent_crc_unpacked crc,
+		      u64 crc_offset)
+{
+	const u8 *csum = (const u8 *) &crc.csum;
+	unsigned i;
+
+	if (s->format == EXPORT_SQL)
+		printf("INSERT INTO extent_checksums VALUES(");
+
+	print_quoted(s->format, path);
+	printf(",%llu,%llu,%llu,", inum, offset << 9, sectors << 9);
+
+	if (s->format == EXPORT_SQL)
+		putchar('\'');
+	printf("%s", csum_types[crc.csum_type]);
+	if (s->format == EXPORT_SQL)
+		printf("','");
+	else
+		putchar(',');
+
+	for (i = 0; i < bch_crc_bytes[crc.csum_type]; i++)
+		printf("%02x", csum[i]);
+
+	if (s->format == EXPORT_SQL)
+		putchar('\'');
+
+	printf(",%llu,%u,%u,%u,", crc_offset << 9,
+	       crc.compressed_size << 9, crc.uncompressed_size << 9, crc.nonce);
+
+	if (s->format == EXPORT_SQL)
+		printf("'%s');\n", compression_types[crc.compression_type]);
+	else
+		printf("%s\n", compression_types[crc.compression_type]);
+
+	s->rows++;
+}
+
+/* One row per link to the file, for the first pointer of the extent: */
+static void export_extent(struct export_state *s, u64 inum, struct bkey_s_c k,
+			  u64 offset, u64 sectors, u64 skip)
+{
+	struct bkey_ptrs_c ptrs = bch2_bkey_ptrs_c(k);
+	const union bch_extent_entry *entry;
+	struct extent_ptr_decoded p;
+	struct export_link *l;
+
+	bkey_for_each_ptr_decode(k.k, ptrs, p, entry)
+		goto found;
+	return;
+found:
+	l = links_find(&s->files, inum);
+	if (!l) {
+		s->unreachable++;
+		return;
+	}
+
+	for (; l < s->files.item + darray_size(s->files) && l->inum == inum; l++) {
+		const char *dir = dir_path(&s->dirs, l->dir, 0);
+
+		if (!dir) {
+			s->unreachable++;
+			continue;
+		}
+
+		char *path = mprintf("%s/%s", dir, l->name);
+		print_row(s, path, inum, offset, sectors, p.crc, p.crc.offset + skip);
+		free(path);
+	}
+}
+
+/* Reflinked extents are exported as the parts of the indirect extents used: */
+static int export_reflink_p(struct btree_trans *trans, struct export_state *s,
+			    u64 inum, struct bkey_s_c_reflink_p p)
+{
+	struct btree_iter *iter;
+	struct bkey_s_c k;
+	u64 idx = le64_to_cpu(p.v->idx);
+	u64 end = idx + p.k->size;
+	int ret;
+
+	for_each_btree_key(trans, iter, BTREE_ID_reflink, POS(0, idx + 1),
+			   0, k, ret) {
+		u64 start = max(bkey_start_offset(k.k), idx);
+
+		if (start >= end)
+			break;
+
+		if (k.k->type == KEY_TYPE_reflink_v)
+			export_extent(s, inum, k,
+				      bkey_start_offset(p.k) + start - idx,
+				      min(k.k->p.offset, end) - start,
+				      start - bkey_start_offset(k.k));
+	}
+	bch2_trans_iter_put(trans, iter);
+
+	return ret;
+}
+
+static void export_checksums(struct bch_fs *c, struct export_state *s)
+{
+	struct btree_trans trans;
+	struct btree_iter *iter;
+	struct bkey_s_c k;
+	int ret;
+
+	bch2_trans_init(&trans, c, 0, 0);
+
+	for_each_btree_key(&trans, iter, BTREE_ID_extents, POS_MIN,
+			   BTREE_ITER_PREFETCH, k, ret) {
+		switch (k.k->type) {
+		case KEY_TYPE_extent:
+			export_extent(s, k.k->p.inode, k,
+				      bkey_start_offset(k.k), k.k->size, 0);
+			break;
+		case KEY_TYPE_reflink_p:
+			ret = export_reflink_p(&trans, s, k.k->p.inode,
+					       bkey_s_c_to_reflink_p(k));
+			break;
+		}
+
+		if (ret)
+			break;
+	}
+	bch2_trans_iter_put(&trans, iter);
+
+	ret = bch2_trans_exit(&trans) ?: ret;
+	if (ret)
+		die("error walking extents: %s", strerror(-ret));
+}
+
+static void export_checksums_usage(void)
+{
+	puts("bcachefs export-checksums - export the checksums of every extent\n"
+	     "Usage: bcachefs export-checksums [OPTION]... <devices>...\n"
+	     "\n"
+	     "Prints the checksum bcachefs stores for each extent of every file in an\n"
+	     "unmounted, cleanly shut down filesystem, with the path of each link to\n"
+	     "the file, so that backup tools can tell what changed without reading\n"
+	     "it. Checksums are of the data as stored: they only match data\n"
+	     "compressed, and encrypted, the same way.\n"
+	     "\n"
+	     "Columns are path, inode, offset, length, checksum type, checksum,\n"
+	     "offset in the checksummed region, its compressed and uncompressed\n"
+	     "sizes, nonce and compression type; sizes are in bytes.\n"
+	     "\n"
+	     "Options:\n"
+	     "  -f, --format=(csv|sql)      Output format; sql is statements for\n"
+	     "                              sqlite3, e.g. | sqlite3 checksums.db\n"
+	     "                              (default csv)\n"
+	     "  -k, --passphrase-file=file  Read the passphrase of an encrypted filesystem\n"
+	     "                              from file\n"
+	     "  -h, --help                  display this help and exit\n"
+	     "Report bugs to <linux-bcache@vger.kernel.org>");
+	exit(EXIT_SUCCESS);
+}
+
+int cmd_export_checksums(int argc, char *argv[])
+{
+	static const struct option longopts[] = {
+		{ "format",		required_argument,	NULL, 'f' },
+		{ "passphrase-file",	required_argument,	NULL, 'k' },
+		{ "help",		no_argument,		NULL, 'h' },
+		{ NULL }
+	};
+	struct bch_opts opts = bch2_opts_empty();
+	struct export_state s = { .format = EXPORT_CSV };
+	char *passphrase_file = NULL;
+	struct export_link *l;
+	int opt;
+
+	opt_set(opts, nochanges,	true);
+	opt_set(opts, norecovery,	true);
+	opt_set(opts, degraded,		true);
+	opt_set(opts, errors,		BCH_ON_ERROR_continue);
+
+	while ((opt = getopt_long(argc, argv, "f:k:h", longopts, NULL)) != -1)
+		switch (opt) {
+		case 'f':
+			s.format = read_string_list_or_die(optarg,
+						export_formats, "format");
+			break;
+		case 'k':
+			passphrase_file = optarg;
+			break;
+		case 'h':
+			export_checksums_usage();
+		}
+	args_shift(optind);
+
+	if (!argc)
+		die("Please supply device(s)");
+
+	bch2_add_key_offline(argv[0], passphrase_file);
+
+	struct bch_fs *c = bch2_fs_open(argv, argc, opts);
+	if (IS_ERR(c))
+		die("error opening %s: %s", argv[0], strerror(-PTR_ERR(c)));
+
+	/* Otherwise the journal has updates the btrees don't: */
+	if (!c->sb.clean)
+		die("%s wasn't shut down cleanly: mount it once, or run fsck, first",
+		    argv[0]);
+
+	s.dirs	= read_links(c, true);
+	s.files	= read_links(c, false);
+
+	if (s.format == EXPORT_SQL)
+		printf("BEGIN;\n"
+		       "CREATE TABLE IF NOT EXISTS extent_checksums(path TEXT, inum INTEGER, "
+		       "offset INTEGER, length INTEGER, csum_type TEXT, csum TEXT, "
+		       "csum_offset INTEGER, compressed_size INTEGER, "
+		       "uncompressed_size INTEGER, nonce INTEGER, compression TEXT);\n");
+	else
+		printf("path,inum,offset,length,csum_type,csum,csum_offset,"
+		       "compressed_size,uncompressed_size,nonce,compression\n");
+
+	export_checksums(c, &s);
+
+	if (s.format == EXPORT_SQL)
+		printf("CREATE INDEX IF NOT EXISTS extent_checksums_path "
+		       "ON extent_checksums(path);\n"
+		       "COMMIT;\n");
+
+	fprintf(stderr, "%llu rows exported; %llu extents of files not reachable from the root skipped\n",
+		s.rows, s.unreachable);
+
+	darray_foreach(l, s.dirs) {
+		free(l->name);
+		free(l->path);
+	}
+	darray_foreach(l, s.files)
+		free(l->name);
+	darray_free(s.dirs);
+	darray_free(s.files);
+
+	bch2_fs_stop(c);
+	return 0;
+}
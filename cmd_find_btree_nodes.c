@@ -0,0 +1,229 @@
+#include <fcntl.h>
+#include <getopt.h>
+#include <stdio.h>
+#include <string.h>
+#include <sys/stat.h>
+#include <sys/types.h>
+#include <unistd.h>
+
+#include "ccan/darray/darray.h"
+
+#include "cmds.h"
+#include "libbcachefs.h"
+
+#include "libbcachefs/bcachefs.h"
+#include "libbcachefs/bkey_methods.h"
+#include "libbcachefs/btree_io.h"
+#include "libbcachefs/checksum.h"
+#include "libbcachefs/super-io.h"
+#include "linux/sort.h"
+
+struct found_btree_node {
+	u64		offset;		/* sectors */
+	u8		btree_id;
+	u8		level;
+	bool		csum_ok;
+	u64		seq;
+	u64		journal_seq;
+	struct bpos	min_key;
+	struct bpos	max_key;
+};
+
+typedef darray(struct found_btree_node) found_nodes;
+
+static bool found_node_is_root(struct found_btree_node *n)
+{
+	return !bkey_cmp(n->min_key, POS_MIN) &&
+		!bkey_cmp(n->max_key, POS_MAX);
+}
+
+/* Sort by btree, then by level, highest first, then newest first: */
+static int found_node_cmp(const void *_l, const void *_r)
+{
+	const struct found_btree_node *l = _l, *r = _r;
+
+	return  cmp_int(l->btree_id, r->btree_id) ?:
+		cmp_int(r->level, l->level) ?:
+		cmp_int(r->journal_seq, l->journal_seq) ?:
+		cmp_int(l->offset, r->offset);
+}
+
+static void found_node_print(struct found_btree_node *n, unsigned dev_idx)
+{
+	char min[100], max[100];
+
+	bch2_bpos_to_text(&PBUF(min), n->min_key);
+	bch2_bpos_to_text(&PBUF(max), n->max_key);
+
+	printf("btree=%s level=%u dev=%u offset=%llu seq=%llu journal_seq=%llu min=%s max=%s csum=%s\n",
+	       n->btree_id < BTREE_ID_NR ? bch2_btree_ids[n->btree_id] : "unknown",
+	       n->level, dev_idx, n->offset, n->seq, n->journal_seq,
+	       min, max, n->csum_ok ? "ok" : "bad");
+}
+
+/* Checks a node that starts with the right magic number: */
+static bool node_check(struct btree_node *bn, size_t node_bytes,
+		       struct found_btree_node *n)
+{
+	struct bch_csum csum;
+
+	if (vstruct_bytes(bn) > node_bytes ||
+	    BSET_CSUM_TYPE(&bn->keys) >= BCH_CSUM_NR ||
+	    bch2_csum_type_is_encryption(BSET_CSUM_TYPE(&bn->keys)))
+		return false;
+
+	csum = csum_vstruct(NULL, BSET_CSUM_TYPE(&bn->keys),
+			    btree_nonce(&bn->keys, 0), bn);
+
+	n->btree_id	= BTREE_NODE_ID(bn);
+	n->level	= BTREE_NODE_LEVEL(bn);
+	n->csum_ok	= !bch2_crc_cmp(csum, bn->csum);
+	n->seq		= le64_to_cpu(bn->keys.seq);
+	n->journal_seq	= le64_to_cpu(bn->keys.journal_seq);
+	n->min_key	= bn->min_key;
+	n->max_key	= bn->max_key;
+	return true;
+}
+
+static found_nodes scan_device(const char *path, struct bch_sb *sb)
+{
+	unsigned block_bytes	= le16_to_cpu(sb->block_size) << 9;
+	unsigned node_bytes	= BCH_SB_BTREE_NODE_SIZE(sb) << 9;
+	size_t chunk_bytes	= max_t(size_t, 1 << 20, node_bytes);
+	u64 magic		= __bset_magic(sb);
+	int fd			= xopen(path, O_RDONLY);
+	u64 size		= get_size(path, fd);
+	void *chunk		= aligned_alloc(PAGE_SIZE, chunk_bytes);
+	void *node		= aligned_alloc(PAGE_SIZE, node_bytes);
+	found_nodes nodes;
+	u64 offset;
+	size_t i;
+
+	darray_init(nodes);
+
+	for (offset = 0; offset < size; offset += chunk_bytes) {
+		size_t len = min_t(u64, chunk_bytes, size - offset);
+
+		xpread(fd, chunk, len, offset);
+
+		for (i = 0; i + sizeof(struct btree_node) <= len; i += block_bytes) {
+			struct btree_node *bn = chunk + i;
+			struct found_btree_node n = { .offset = (offset + i) >> 9 };
+
+			if (le64_to_cpu(bn->magic) != magic ||
+			    offset + i + node_bytes > size)
+				continue;
+
+			/* The node may extend past the end of this chunk: */
+			xpread(fd, node, node_bytes, offset + i);
+
+			if (node_check(node, node_bytes, &n))
+				darray_append(nodes, n);
+		}
+	}
+
+	free(node);
+	free(chunk);
+	close(fd);
+	return nodes;
+}
+
+static void find_btree_nodes_usage(void)
+{
+	puts("bcachefs find-btree-nodes - scan a device for btree nodes\n"
+	     "Usage: bcachefs find-btree-nodes [OPTION]... <device>\n"
+	     "\n"
+	     "Scans the whole device for btree nodes, checks their checksums, and prints\n"
+	     "one line per node:\n"
+	     "  btree=<btree> level=<level> dev=<idx> offset=<sector> seq=<seq>\n"
+	     "  journal_seq=<seq> min=<pos> max=<pos> csum=<ok|bad>\n"
+	     "sorted by btree, level (highest first) and journal sequence number\n"
+	     "(newest first). Only the first bset of each node is checked.\n"
+	     "\n"
+	     "Options:\n"
+	     "  -b, --btree=btree           Only print nodes of this btree\n"
+	     "  -r, --roots                 Only print candidate roots: nodes at the\n"
+	     "                              highest level found covering the whole keyspace,\n"
+	     "                              with valid checksums\n"
+	     "  -h, --help                  display this help and exit\n"
+	     "Report bugs to <linux-bcache@vger.kernel.org>");
+	exit(EXIT_SUCCESS);
+}
+
+int cmd_find_btree_nodes(int argc, char *argv[])
+{
+	static const struct option longopts[] = {
+		{ "btree",		required_argument,	NULL, 'b' },
+		{ "roots",		no_argument,		NULL, 'r' },
+		{ "help",		no_argument,		NULL, 'h' },
+		{ NULL }
+	};
+	struct bch_opts opts = bch2_opts_empty();
+	struct bch_sb_handle sb;
+	struct found_btree_node *n;
+	int btree_id = -1, opt;
+	bool roots = false;
+
+	while ((opt = getopt_long(argc, argv, "b:rh", longopts, NULL)) != -1)
+		switch (opt) {
+		case 'b':
+			btree_id = read_string_list_or_die(optarg,
+						bch2_btree_ids, "btree id");
+			break;
+		case 'r':
+			roots = true;
+			break;
+		case 'h':
+			find_btree_nodes_usage();
+		}
+	args_shift(optind);
+
+	char *dev = arg_pop();
+	if (!dev)
+		die("Please supply a device");
+
+	if (argc)
+		die("Too many arguments");
+
+	opt_set(opts, noexcl,	true);
+	opt_set(opts, nochanges, true);
+
+	int ret = bch2_read_super(dev, &opts, &sb);
+	if (ret)
+		die("Error opening %s: %s", dev, strerror(-ret));
+
+	/* Node headers, including btree id and level, are encrypted: */
+	if (bch2_sb_get_crypt(sb.sb))
+		die("%s: encrypted filesystems are not supported", dev);
+
+	found_nodes nodes = scan_device(dev, sb.sb);
+
+	sort(&darray_item(nodes, 0), darray_size(nodes),
+	     sizeof(darray_item(nodes, 0)), found_node_cmp, NULL);
+
+	int root_btree = -1, root_level = -1;
+
+	darray_foreach(n, nodes) {
+		if (btree_id >= 0 && n->btree_id != btree_id)
+			continue;
+
+		if (roots) {
+			if (!n->csum_ok || !found_node_is_root(n))
+				continue;
+
+			/* sorted by level, so the first root found is at the highest level: */
+			if (n->btree_id != root_btree) {
+				root_btree = n->btree_id;
+				root_level = n->level;
+			}
+			if (n->level != root_level)
+				continue;
+		}
+
+		found_node_print(n, sb.sb->dev_idx);
+	}
+
+	darray_free(nodes);
+	bch2_free_super(&sb);
+	return 0;
+}
@@ -25,6 +25,7 @@
 #include "cmds.h"
 #include "libbcachefs.h"
 #include "crypto.h"
+#include "sandbox.h"
 #include "libbcachefs/opts.h"
 #include "libbcachefs/super-io.h"
 #include "libbcachefs/util.h"
@@ -33,6 +34,7 @@
 x(0,	replicas,		required_argument)	\
 x(0,	encrypted,		no_argument)		\
 x(0,	no_passphrase,		no_argument)		\
+x(0,	keyfile,		required_argument)	\
 x('L',	label,			required_argument)	\
 x('U',	uuid,			required_argument)	\
 x(0,	fs_size,		required_argument)	\
@@ -44,6 +46,7 @@ x(0,	data_allowed,		required_argument)	\
 x(0,	durability,		required_argument)	\
 x(0,	version,		required_argument)	\
 x(0,	no_initialize,		no_argument)		\
+x(0,	tune,			no_argument)		\
 x('f',	force,			no_argument)		\
 x('q',	quiet,			no_argument)		\
 x('h',	help,			no_argument)
@@ -61,6 +64,8 @@ static void usage(void)
 	     "      --replicas=#            Sets both data and metadata replicas\n"
 	     "      --encrypted             Enable whole filesystem encryption (chacha20/poly1305)\n"
 	     "      --no_passphrase         Don't encrypt master encryption key\n"
+	     "      --keyfile=file          Encrypt master encryption key with a keyfile\n"
+	     "                              instead of a passphrase; implies --encrypted\n"
 	     "  -L, --label=label\n"
 	     "  -U, --uuid=uuid\n"
 	     "      --superblock_size=size\n"
@@ -71,6 +76,10 @@ static void usage(void)
 
 	puts("  -g, --group=label           Disk group\n"
 	     "\n"
+	     "      --tune                  Pick bucket sizes, discard and (with both\n"
+	     "                              rotational and non-rotational devices) disk\n"
+	     "                              groups and targets from how the devices\n"
+	     "                              behave, for those not given; prints why\n"
 	     "  -f, --force\n"
 	     "  -q, --quiet                 Only print errors\n"
 	     "  -h, --help                  Display this help and exit\n"
@@ -100,6 +109,131 @@ static const struct option format_opts[] = {
 };
 #undef x
 
+/* Largest bucket size a member can have, in sectors: */
+#define TUNE_BUCKET_SIZE_MAX	(1U << 15)
+
+static unsigned tune_bucket_size(struct bch_opts fs_opts, struct dev_opts *dev,
+				 struct dev_queue_info *q)
+{
+	struct dev_opts d = *dev;
+	unsigned bucket_size;
+
+	/* Start from what format would pick: */
+	if (!opt_defined(fs_opts, block_size))
+		opt_set(fs_opts, block_size, get_blocksize(dev->path, dev->fd));
+	bch2_pick_bucket_size(fs_opts, &d);
+	bucket_size = d.bucket_size;
+
+	if (q->zoned && q->zone_sectors) {
+		bucket_size = q->zone_sectors;
+		printf("  bucket size %s: one bucket per zone, since zones are written\n"
+		       "    sequentially and reset whole\n",
+		       pr_units(bucket_size, HUMAN_READABLE));
+	} else if (q->discard_granularity > bucket_size) {
+		bucket_size = roundup_pow_of_two(q->discard_granularity);
+		printf("  bucket size %s: discards are done in %s units, and smaller\n"
+		       "    buckets couldn't be discarded whole\n",
+		       pr_units(bucket_size, HUMAN_READABLE),
+		       pr_units(q->discard_granularity, HUMAN_READABLE));
+	} else if (q->rotational && bucket_size < 2048) {
+		bucket_size = 2048;
+		printf("  bucket size %s: on a rotational device, large buckets keep\n"
+		       "    writes sequential\n",
+		       pr_units(bucket_size, HUMAN_READABLE));
+	} else {
+		printf("  bucket size %s: the default for its size\n",
+		       pr_units(bucket_size, HUMAN_READABLE));
+	}
+
+	if (bucket_size > TUNE_BUCKET_SIZE_MAX) {
+		bucket_size = TUNE_BUCKET_SIZE_MAX;
+		printf("    (capped at the maximum bucket size, %s)\n",
+		       pr_units(bucket_size, HUMAN_READABLE));
+	}
+
+	return bucket_size;
+}
+
+/*
+ * Fills in bucket size, discard, groups and targets from the characteristics of
+ * the devices, where they weren't given:
+ */
+static void tune_devices(struct bch_opts fs_opts, struct bch_opt_strs *fs_opt_strs,
+			 struct dev_opts *devs, size_t nr_devs)
+{
+	struct dev_opts *dev;
+	unsigned nr_rotational = 0;
+	bool have_groups = false;
+
+	printf("Tuning for these devices:\n");
+
+	for (dev = devs; dev < devs + nr_devs; dev++) {
+		struct dev_queue_info q = dev_queue_info(dev->fd);
+		u64 size = dev->size ?: get_size(dev->path, dev->fd) >> 9;
+
+		printf("%s: %s, %s", dev->path,
+		       pr_units(size, HUMAN_READABLE),
+		       q.zoned ? "zoned" :
+		       q.rotational ? "rotational" : "non-rotational");
+		if (q.discard_granularity)
+			printf(", discards in %s units",
+			       pr_units(q.discard_granularity, HUMAN_READABLE));
+		if (q.zoned && q.zone_sectors)
+			printf(", %s zones", pr_units(q.zone_sectors, HUMAN_READABLE));
+		putchar('\n');
+
+		if (dev->bucket_size)
+			printf("  bucket size %s: given\n",
+			       pr_units(dev->bucket_size, HUMAN_READABLE));
+		else
+			dev->bucket_size = tune_bucket_size(fs_opts, dev, &q);
+
+		if (!dev->discard && !q.rotational && q.discard_granularity) {
+			dev->discard = true;
+			printf("  discard: buckets are discarded when emptied, so the device\n"
+			       "    knows the space is free\n");
+		}
+
+		nr_rotational += q.rotational;
+		have_groups |= dev->group != NULL;
+	}
+
+	if (nr_rotational && nr_rotational < nr_devs) {
+		if (have_groups) {
+			printf("Mix of rotational and non-rotational devices, but groups\n"
+			       "were given: not picking targets\n");
+		} else {
+			for (dev = devs; dev < devs + nr_devs; dev++)
+				dev->group = dev_queue_info(dev->fd).rotational
+					? "hdd" : "ssd";
+
+			printf("Mix of rotational and non-rotational devices: grouped as\n"
+			       "hdd and ssd; ");
+			if (!fs_opt_strs->foreground_target &&
+			    !fs_opt_strs->background_target &&
+			    !fs_opt_strs->promote_target) {
+				fs_opt_strs->foreground_target	= strdup("ssd");
+				fs_opt_strs->promote_target	= strdup("ssd");
+				fs_opt_strs->background_target	= strdup("hdd");
+				printf("writes go to ssd and are moved to hdd in the\n"
+				       "background, and reads are cached on ssd\n");
+			} else {
+				printf("targets were given\n");
+			}
+		}
+	}
+
+	/* Redundancy trades capacity, so it's only suggested: */
+	if (!opt_defined(fs_opts, data_replicas)) {
+		if (nr_devs >= 3)
+			printf("Suggestion: --replicas=2 to survive losing a device, or\n"
+			       "  --replicas=2 --erasure_code for less overhead on large writes\n");
+		else if (nr_devs == 2)
+			printf("Suggestion: --replicas=2 to survive losing a device\n");
+	}
+	putchar('\n');
+}
+
 u64 read_flag_list_or_die(char *opt, const char * const list[],
 			  const char *msg)
 {
@@ -117,6 +251,8 @@ int cmd_format(int argc, char *argv[])
 	struct format_opts opts	= format_opts_default();
 	struct dev_opts dev_opts = dev_opts_default(), *dev;
 	bool force = false, no_passphrase = false, quiet = false, initialize = true;
+	bool tune = false;
+	char *keyfile = NULL;
 	unsigned v;
 	int opt;
 
@@ -147,6 +283,10 @@ int cmd_format(int argc, char *argv[])
 		case O_no_passphrase:
 			no_passphrase = true;
 			break;
+		case O_keyfile:
+			opts.encrypted = true;
+			keyfile = optarg;
+			break;
 		case O_label:
 		case 'L':
 			opts.label = optarg;
@@ -200,6 +340,9 @@ int cmd_format(int argc, char *argv[])
 		case O_no_initialize:
 			initialize = false;
 			break;
+		case O_tune:
+			tune = true;
+			break;
 		case O_no_opt:
 			darray_append(device_paths, optarg);
 			dev_opts.path = optarg;
@@ -223,14 +366,23 @@ int cmd_format(int argc, char *argv[])
 	if (darray_empty(devices))
 		die("Please supply a device");
 
+	if (keyfile && no_passphrase)
+		die("--keyfile and --no_passphrase are mutually exclusive");
+
 	if (opts.encrypted && !no_passphrase) {
-		opts.passphrase = read_passphrase_twice("Enter passphrase: ");
+		opts.passphrase = keyfile
+			? read_keyfile(keyfile)
+			: read_passphrase_twice("Enter passphrase: ");
 		initialize = false;
 	}
 
 	darray_foreach(dev, devices)
 		dev->fd = open_for_format(dev->path, force);
 
+	if (tune)
+		tune_devices(fs_opts, &fs_opt_strs,
+			     devices.item, darray_size(devices));
+
 	struct bch_sb *sb =
 		bch2_format(fs_opt_strs,
 			    fs_opts,
@@ -277,6 +429,8 @@ static void show_super_usage(void)
 	     "Options:\n"
 	     "  -f, --fields=(fields)       list of sections to print\n"
 	     "  -l, --layout                print superblock layout\n"
+	     "      --sandbox               read and parse the superblock under a seccomp\n"
+	     "                              filter, once the device is open\n"
 	     "  -h, --help                  display this help and exit\n"
 	     "Report bugs to <linux-bcache@vger.kernel.org>");
 	exit(EXIT_SUCCESS);
@@ -287,11 +441,12 @@ int cmd_show_super(int argc, char *argv[])
 	static const struct option longopts[] = {
 		{ "fields",			1, NULL, 'f' },
 		{ "layout",			0, NULL, 'l' },
+		{ "sandbox",			0, NULL, 'S' },
 		{ "help",			0, NULL, 'h' },
 		{ NULL }
 	};
 	unsigned fields = 1 << BCH_SB_FIELD_members;
-	bool print_layout = false;
+	bool print_layout = false, sandbox = false;
 	int opt;
 
 	while ((opt = getopt_long(argc, argv, "f:lh", longopts, NULL)) != -1)
@@ -305,6 +460,9 @@ int cmd_show_super(int argc, char *argv[])
 		case 'l':
 			print_layout = true;
 			break;
+		case 'S':
+			sandbox = true;
+			break;
 		case 'h':
 			show_super_usage();
 			break;
@@ -322,6 +480,11 @@ int cmd_show_super(int argc, char *argv[])
 	opt_set(opts, noexcl,	true);
 	opt_set(opts, nochanges, true);
 
+	if (sandbox) {
+		sandbox_open_devices(&dev, 1);
+		sandbox_enter();
+	}
+
 	struct bch_sb_handle sb;
 	int ret = bch2_read_super(dev, &opts, &sb);
 	if (ret)
@@ -1,6 +1,17 @@
 
+#include <dirent.h>
+#include <errno.h>
+#include <ftw.h>
+#include <getopt.h>
+#include <limits.h>
+#include <pthread.h>
 #include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
 #include <sys/ioctl.h>
+#include <sys/stat.h>
+#include <time.h>
+#include <unistd.h>
 
 #include <uuid/uuid.h>
 
@@ -10,10 +21,16 @@
 
 #include "libbcachefs/bcachefs_ioctl.h"
 #include "libbcachefs/opts.h"
+#include "libbcachefs/super-io.h"
 
 #include "cmds.h"
 #include "libbcachefs.h"
 
+#ifndef FIFREEZE
+#define FIFREEZE	_IOWR('X', 119, int)
+#define FITHAW		_IOWR('X', 120, int)
+#endif
+
 static void print_dev_usage_type(const char *type,
 				 unsigned bucket_size,
 				 u64 buckets, u64 sectors,
@@ -195,26 +212,953 @@ static void print_fs_usage(const char *path, enum units units)
 	bcache_fs_close(fs);
 }
 
+/*
+ * Usage by directory: there's no per directory accounting, so each directory
+ * in the root of the filesystem is walked, in parallel, adding up what stat()
+ * says its files use. Walking is slow on big filesystems, so totals are cached
+ * and reused until they're older than --max-age.
+ */
+
+struct dir_usage {
+	char		*name;
+	/* in bytes: */
+	u64		used;
+	u64		apparent;
+	u64		files;
+};
+
+typedef darray(struct dir_usage) dir_usages;
+
+/* nftw() has no private pointer: */
+static __thread struct dir_usage *dir_usage_cur;
+
+static int dir_usage_file(const char *path, const struct stat *st,
+			  int type, struct FTW *ftw)
+{
+	if (type == FTW_DNR || type == FTW_NS) {
+		fprintf(stderr, "error reading %s: %m\n", path);
+		return 0;
+	}
+
+	/* st_blocks is in 512 byte units, and counts each replica once: */
+	dir_usage_cur->used	+= (u64) st->st_blocks << 9;
+	dir_usage_cur->apparent	+= S_ISREG(st->st_mode) ? st->st_size : 0;
+	dir_usage_cur->files++;
+	return 0;
+}
+
+struct dir_usage_walk {
+	const char	*root;
+	dir_usages	*dirs;
+	unsigned	next;
+};
+
+static void *dir_usage_thread(void *arg)
+{
+	struct dir_usage_walk *w = arg;
+	unsigned i;
+
+	while ((i = __atomic_fetch_add(&w->next, 1, __ATOMIC_RELAXED)) <
+	       darray_size(*w->dirs)) {
+		struct dir_usage *d = &darray_item(*w->dirs, i);
+		char *path = mprintf("%s/%s", w->root, d->name);
+
+		dir_usage_cur = d;
+		if (nftw(path, dir_usage_file, 64, FTW_PHYS|FTW_MOUNT))
+			fprintf(stderr, "error walking %s: %m\n", path);
+		free(path);
+	}
+
+	return NULL;
+}
+
+/* Files in the root itself are totalled as "." */
+static dir_usages dir_usage_walk(const char *root)
+{
+	struct dir_usage_walk w = { .root = root };
+	struct dir_usage files = { .name = strdup(".") };
+	dir_usages dirs;
+	struct dirent *e;
+	struct stat st;
+	unsigned i, nr_threads;
+
+	darray_init(dirs);
+	w.dirs = &dirs;
+
+	DIR *dir = opendir(root);
+	if (!dir)
+		die("error opening %s: %m", root);
+
+	while ((errno = 0), (e = readdir(dir))) {
+		if (!strcmp(e->d_name, ".") || !strcmp(e->d_name, ".."))
+			continue;
+
+		if (fstatat(dirfd(dir), e->d_name, &st, AT_SYMLINK_NOFOLLOW)) {
+			fprintf(stderr, "error statting %s/%s: %m\n", root, e->d_name);
+			continue;
+		}
+
+		if (S_ISDIR(st.st_mode)) {
+			struct dir_usage d = { .name = strdup(e->d_name) };
+
+			darray_append(dirs, d);
+		} else {
+			files.used	+= (u64) st.st_blocks << 9;
+			files.apparent	+= S_ISREG(st.st_mode) ? st.st_size : 0;
+			files.files++;
+		}
+	}
+
+	if (errno)
+		die("error reading %s: %m", root);
+	closedir(dir);
+
+	nr_threads = clamp_t(long, sysconf(_SC_NPROCESSORS_ONLN), 1, 16);
+	nr_threads = min_t(unsigned, nr_threads, darray_size(dirs));
+
+	pthread_t *threads = xcalloc(nr_threads, sizeof(*threads));
+
+	for (i = 0; i < nr_threads; i++)
+		if (pthread_create(&threads[i], NULL, dir_usage_thread, &w))
+			die("error creating thread: %m");
+
+	for (i = 0; i < nr_threads; i++)
+		pthread_join(threads[i], NULL);
+	free(threads);
+
+	darray_append(dirs, files);
+	return dirs;
+}
+
+static void dir_usages_free(dir_usages *dirs)
+{
+	struct dir_usage *d;
+
+	darray_foreach(d, *dirs)
+		free(d->name);
+	darray_free(*dirs);
+}
+
+static char *dir_usage_cache_path(const char *uuid)
+{
+	const char *cache = getenv("XDG_CACHE_HOME");
+	char *home_cache = NULL, *dir, *ret;
+
+	if (!cache || !*cache) {
+		const char *home = getenv("HOME");
+
+		if (!home)
+			return NULL;
+		cache = home_cache = mprintf("%s/.cache", home);
+	}
+
+	dir = mprintf("%s/bcachefs", cache);
+	if (mkdir(cache, 0700) && errno != EEXIST)
+		dir = NULL;
+	else if (mkdir(dir, 0700) && errno != EEXIST)
+		dir = NULL;
+
+	ret = dir ? mprintf("%s/dir-usage-%s", dir, uuid) : NULL;
+	free(dir);
+	free(home_cache);
+	return ret;
+}
+
+/*
+ * The cache is a header line, then a line per directory, its name last:
+ *	<used> <apparent> <files> <name length> <name>
+ * Returns false if it's missing, from another mountpoint, or older than
+ * @max_age:
+ */
+static bool dir_usage_cache_read(const char *cache_path, const char *root,
+				 unsigned max_age, dir_usages *dirs,
+				 time_t *cached)
+{
+	char *cached_root = NULL;
+	size_t len;
+	bool ret = false;
+
+	FILE *f = fopen(cache_path, "r");
+	if (!f)
+		return false;
+
+	if (fscanf(f, "bcachefs dir-usage 1 %ld %zu ", cached, &len) != 2 ||
+	    time(NULL) - *cached > max_age)
+		goto out;
+
+	cached_root = xcalloc(len + 1, 1);
+	if (fread(cached_root, 1, len, f) != len ||
+	    strcmp(cached_root, root))
+		goto out;
+
+	darray_init(*dirs);
+
+	while (1) {
+		struct dir_usage d;
+
+		if (fscanf(f, " %llu %llu %llu %zu ",
+			   &d.used, &d.apparent, &d.files, &len) != 4)
+			break;
+
+		d.name = xcalloc(len + 1, 1);
+		if (fread(d.name, 1, len, f) != len) {
+			free(d.name);
+			dir_usages_free(dirs);
+			goto out;
+		}
+
+		darray_append(*dirs, d);
+	}
+
+	ret = feof(f);
+	if (!ret)
+		dir_usages_free(dirs);
+out:
+	free(cached_root);
+	fclose(f);
+	return ret;
+}
+
+static void dir_usage_cache_write(const char *cache_path, const char *root,
+				  dir_usages *dirs)
+{
+	char *tmp = mprintf("%s.tmp", cache_path);
+	struct dir_usage *d;
+
+	FILE *f = fopen(tmp, "w");
+	if (!f) {
+		fprintf(stderr, "error writing %s: %m\n", tmp);
+		goto out;
+	}
+
+	fprintf(f, "bcachefs dir-usage 1 %ld %zu %s\n",
+		(long) time(NULL), strlen(root), root);
+
+	darray_foreach(d, *dirs)
+		fprintf(f, "%llu %llu %llu %zu %s\n",
+			d->used, d->apparent, d->files, strlen(d->name), d->name);
+
+	if (fclose(f) || rename(tmp, cache_path))
+		fprintf(stderr, "error writing %s: %m\n", cache_path);
+out:
+	free(tmp);
+}
+
+/* Walks up from @path while still on the same filesystem: */
+static void path_to_mountpoint(const char *path, char *mountpoint)
+{
+	struct stat st, parent_st;
+	char *slash;
+
+	if (!realpath(path, mountpoint))
+		die("error resolving %s: %m", path);
+
+	st = xstat(mountpoint);
+
+	while ((slash = strrchr(mountpoint, '/'))) {
+		char c = slash[slash == mountpoint];
+
+		slash[slash == mountpoint] = '\0';
+
+		if (stat(mountpoint, &parent_st) ||
+		    parent_st.st_dev != st.st_dev) {
+			slash[slash == mountpoint] = c;
+			return;
+		}
+
+		if (slash == mountpoint)
+			return;
+	}
+}
+
+static int dir_usage_cmp(const void *_l, const void *_r)
+{
+	const struct dir_usage *l = _l, *r = _r;
+
+	return cmp_int(r->used, l->used) ?: strcmp(l->name, r->name);
+}
+
+static void print_dir_usage(const char *path, enum units units,
+			    unsigned max_age, bool refresh)
+{
+	struct bchfs_handle fs = bcache_fs_open(path);
+	char uuid[40], mountpoint[PATH_MAX];
+	struct dir_usage *d;
+	dir_usages dirs;
+	time_t cached;
+
+	/* The cache is for the whole filesystem even if a subdirectory was given: */
+	path_to_mountpoint(path, mountpoint);
+
+	uuid_unparse(fs.uuid.b, uuid);
+	char *cache_path = dir_usage_cache_path(uuid);
+
+	if (!refresh && cache_path &&
+	    dir_usage_cache_read(cache_path, mountpoint, max_age, &dirs, &cached)) {
+		printf("Filesystem %s, cached %lds ago (--refresh to rescan):\n",
+		       uuid, (long) (time(NULL) - cached));
+	} else {
+		dirs = dir_usage_walk(mountpoint);
+		if (cache_path)
+			dir_usage_cache_write(cache_path, mountpoint, &dirs);
+		printf("Filesystem %s:\n", uuid);
+	}
+
+	sort(&darray_item(dirs, 0), darray_size(dirs),
+	     sizeof(darray_item(dirs, 0)), dir_usage_cmp, NULL);
+
+	printf("%12s %12s %12s  %s\n", "Used", "Apparent", "Files", "Directory");
+
+	darray_foreach(d, dirs)
+		printf("%12s %12s %12llu  %s\n",
+		       pr_units(d->used >> 9, units),
+		       pr_units(d->apparent >> 9, units),
+		       d->files, d->name);
+
+	dir_usages_free(&dirs);
+	free(cache_path);
+	bcache_fs_close(fs);
+}
+
+static void fs_usage_usage(void)
+{
+	puts("bcachefs fs usage - show disk usage\n"
+	     "Usage: bcachefs fs usage [OPTION]... [<mountpoint>...]\n"
+	     "\n"
+	     "Options:\n"
+	     "  -h                          Human readable units\n"
+	     "  -d, --by-directory          Show the space used under each directory in\n"
+	     "                              the root of the filesystem instead, sorted by\n"
+	     "                              use: found by walking them, in parallel\n"
+	     "      --max-age=seconds       Reuse the totals of a walk up to this old\n"
+	     "                              (default 600), cached under $XDG_CACHE_HOME\n"
+	     "  -r, --refresh               Walk the directories again, ignoring the cache\n"
+	     "      --help                  display this help and exit\n"
+	     "Report bugs to <linux-bcache@vger.kernel.org>");
+	exit(EXIT_SUCCESS);
+}
+
 int cmd_fs_usage(int argc, char *argv[])
 {
+	static const struct option longopts[] = {
+		{ "by-directory",	no_argument,		NULL, 'd' },
+		{ "max-age",		required_argument,	NULL, 'm' },
+		{ "refresh",		no_argument,		NULL, 'r' },
+		{ "help",		no_argument,		NULL, 'H' },
+		{ NULL }
+	};
 	enum units units = BYTES;
+	bool by_directory = false, refresh = false;
+	unsigned max_age = 600;
 	char *fs;
 	int opt;
 
-	while ((opt = getopt(argc, argv, "h")) != -1)
+	/* -h is human readable, as it always has been: */
+	while ((opt = getopt_long(argc, argv, "hdr", longopts, NULL)) != -1)
 		switch (opt) {
 		case 'h':
 			units = HUMAN_READABLE;
 			break;
+		case 'd':
+			by_directory = true;
+			break;
+		case 'm':
+			if (kstrtouint(optarg, 10, &max_age))
+				die("invalid max age %s", optarg);
+			break;
+		case 'r':
+			refresh = true;
+			break;
+		case 'H':
+			fs_usage_usage();
 		}
 	args_shift(optind);
 
 	if (!argc) {
-		print_fs_usage(".", units);
+		if (by_directory)
+			print_dir_usage(".", units, max_age, refresh);
+		else
+			print_fs_usage(".", units);
 	} else {
 		while ((fs = arg_pop()))
-			print_fs_usage(fs, units);
+			if (by_directory)
+				print_dir_usage(fs, units, max_age, refresh);
+			else
+				print_fs_usage(fs, units);
 	}
 
 	return 0;
 }
+
+static void fs_latency_usage(void)
+{
+	puts("bcachefs fs latency - show latency of filesystem operations\n"
+	     "Usage: bcachefs fs latency [OPTION]... [filesystem]\n"
+	     "\n"
+	     "Quantiles are approximated by the kernel in sixteenths: p94 is the\n"
+	     "15/16th quantile, the highest one tracked short of the maximum.\n"
+	     "\n"
+	     "Options:\n"
+	     "  -d, --devices               Include IO latency of each device\n"
+	     "  -p, --prometheus            Output in Prometheus text format\n"
+	     "      --help                  display this help and exit\n"
+	     "Report bugs to <linux-bcache@vger.kernel.org>");
+	exit(EXIT_SUCCESS);
+}
+
+/* quantiles reported, as indices into bchu_time_stats.quantiles: */
+static const struct {
+	const char	*name;
+	const char	*prometheus;
+	unsigned	idx;
+} latency_quantiles[] = {
+	{ "p50",	"0.5",		7 },
+	{ "p75",	"0.75",		11 },
+	{ "p94",	"0.9375",	14 },
+};
+
+static void print_time(u64 ns)
+{
+	static const struct {
+		const char	*name;
+		u64		nsecs;
+	} units[] = {
+		{ "ns",		1		},
+		{ "us",		1000ULL		},
+		{ "ms",		1000ULL * 1000	},
+		{ "s",		1000ULL * 1000 * 1000 },
+	};
+	unsigned i = 0;
+
+	while (i + 1 < ARRAY_SIZE(units) && ns >= units[i + 1].nsecs << 1)
+		i++;
+
+	printf(" %7llu %-2s", ns / units[i].nsecs, units[i].name);
+}
+
+static void print_latency(const char *name, struct bchu_time_stats *s)
+{
+	unsigned i;
+
+	printf_pad(40, "%s", name);
+	printf(" %10llu", s->count);
+	for (i = 0; i < ARRAY_SIZE(latency_quantiles); i++)
+		print_time(s->quantiles[latency_quantiles[i].idx]);
+	print_time(s->max_duration);
+	putchar('\n');
+}
+
+static void print_latency_prometheus(const char *uuid, const char *labels,
+				     struct bchu_time_stats *s)
+{
+	unsigned i;
+
+	for (i = 0; i < ARRAY_SIZE(latency_quantiles); i++)
+		printf("bcachefs_latency_seconds{uuid=\"%s\",%s,quantile=\"%s\"} %.9f\n",
+		       uuid, labels, latency_quantiles[i].prometheus,
+		       s->quantiles[latency_quantiles[i].idx] / 1e9);
+	printf("bcachefs_latency_seconds{uuid=\"%s\",%s,quantile=\"1\"} %.9f\n",
+	       uuid, labels, s->max_duration / 1e9);
+	printf("bcachefs_latency_seconds_sum{uuid=\"%s\",%s} %.9f\n",
+	       uuid, labels, (double) s->avg_duration * s->count / 1e9);
+	printf("bcachefs_latency_seconds_count{uuid=\"%s\",%s} %llu\n",
+	       uuid, labels, s->count);
+}
+
+static void print_fs_latency(const char *path, bool devices, bool prometheus)
+{
+	struct bchfs_handle fs = bcache_fs_open(path);
+	struct bchu_time_stats s;
+	const char * const *name;
+	char uuid[40];
+	unsigned i;
+
+	uuid_unparse(fs.uuid.b, uuid);
+
+	if (!prometheus) {
+		printf("Filesystem %s:\n", uuid);
+		printf_pad(40, "operation");
+		printf(" %10s", "count");
+		for (i = 0; i < ARRAY_SIZE(latency_quantiles); i++)
+			printf(" %10s", latency_quantiles[i].name);
+		printf(" %10s\n", "max");
+	}
+
+	for (name = bchu_time_stats_names; *name; name++) {
+		char *attr = mprintf("time_stats/%s", *name);
+		s = bchu_time_stats_read(fs, attr);
+		free(attr);
+
+		if (prometheus) {
+			char *labels = mprintf("op=\"%s\"", *name);
+			print_latency_prometheus(uuid, labels, &s);
+			free(labels);
+		} else {
+			print_latency(*name, &s);
+		}
+	}
+
+	if (devices) {
+		dev_names dev_names = bchu_fs_get_devices(fs);
+		struct dev_name *dev;
+
+		darray_foreach(dev, dev_names) {
+			static const char * const dirs[] = { "read", "write" };
+
+			for (i = 0; i < ARRAY_SIZE(dirs); i++) {
+				char *attr = mprintf("dev-%u/io_latency_stats_%s",
+						     dev->idx, dirs[i]);
+				s = bchu_time_stats_read(fs, attr);
+				free(attr);
+
+				if (prometheus) {
+					char *labels = mprintf("op=\"io_%s\",dev=\"%s\"",
+							       dirs[i], dev->dev ?: "(offline)");
+					print_latency_prometheus(uuid, labels, &s);
+					free(labels);
+				} else {
+					char *label = mprintf("%s (device %u) io %s",
+							      dev->dev ?: "(offline)",
+							      dev->idx, dirs[i]);
+					print_latency(label, &s);
+					free(label);
+				}
+			}
+
+			free(dev->dev);
+			free(dev->label);
+		}
+		darray_free(dev_names);
+	}
+
+	bcache_fs_close(fs);
+}
+
+int cmd_fs_latency(int argc, char *argv[])
+{
+	static const struct option longopts[] = {
+		{ "devices",		0, NULL, 'd' },
+		{ "prometheus",		0, NULL, 'p' },
+		{ "help",		0, NULL, 'H' },
+		{ NULL }
+	};
+	bool devices = false, prometheus = false;
+	char *fs;
+	int opt;
+
+	while ((opt = getopt_long(argc, argv, "dp", longopts, NULL)) != -1)
+		switch (opt) {
+		case 'd':
+			devices = true;
+			break;
+		case 'p':
+			prometheus = true;
+			break;
+		case 'H':
+			fs_latency_usage();
+		}
+	args_shift(optind);
+
+	if (prometheus)
+		puts("# HELP bcachefs_latency_seconds Latency of bcachefs operations\n"
+		     "# TYPE bcachefs_latency_seconds summary");
+
+	if (!argc) {
+		print_fs_latency(".", devices, prometheus);
+	} else {
+		while ((fs = arg_pop()))
+			print_fs_latency(fs, devices, prometheus);
+	}
+
+	return 0;
+}
+
+/*
+ * Current and most recently flushed journal sequence numbers, and whether the
+ * current entry is closed, from sysfs:
+ */
+static void journal_seqs(struct bchfs_handle fs, u64 *seq, u64 *flushed,
+			 bool *closed)
+{
+	char *buf = read_file_str(fs.sysfs_fd, "internal/journal_debug");
+	char *line, *p = buf, state[16];
+	unsigned found = 0;
+
+	while ((line = strsep(&p, "\n"))) {
+		if (sscanf(line, "seq: %llu", seq) == 1)
+			found |= 1;
+		if (sscanf(line, "flushed_seq_ondisk: %llu", flushed) == 1)
+			found |= 2;
+		/* Later "current entry:" lines are about the journal bufs: */
+		if (!(found & 4) &&
+		    sscanf(line, "current entry: %15s", state) == 1) {
+			*closed = !strcmp(state, "closed");
+			found |= 4;
+		}
+	}
+	free(buf);
+
+	if (found != 7)
+		die("error parsing internal/journal_debug");
+}
+
+/*
+ * Closing a journal entry starts the next one right away, so the current seq
+ * is never flushed: everything is on disk once the entry before it is, and
+ * the current one is closed.
+ */
+static bool journal_flushed(struct bchfs_handle fs, u64 *seq, u64 *flushed)
+{
+	bool closed;
+
+	journal_seqs(fs, seq, flushed, &closed);
+	return closed && *flushed + 1 >= *seq;
+}
+
+static void print_freeze_state(struct bchfs_handle fs)
+{
+	dev_names devs = bchu_fs_get_devices(fs);
+	struct dev_name *dev;
+	u64 seq, flushed;
+	bool closed;
+
+	journal_seqs(fs, &seq, &flushed, &closed);
+	printf("journal seq %llu, flushed through seq %llu\n", seq, flushed);
+
+	if (darray_size(devs)) {
+		struct bch_sb *sb = bchu_read_super(fs, darray_item(devs, 0).idx);
+		struct bch_sb_field_clean *clean = bch2_sb_get_clean(sb);
+
+		printf("superblock seq %llu, ", le64_to_cpu(sb->seq));
+		if (BCH_SB_CLEAN(sb) && clean)
+			printf("clean at journal seq %llu\n",
+			       le64_to_cpu(clean->journal_seq));
+		else
+			printf("not clean (journal replay needed on mount)\n");
+		free(sb);
+	}
+
+	darray_foreach(dev, devs) {
+		free(dev->dev);
+		free(dev->label);
+	}
+	darray_free(devs);
+}
+
+static void fs_freeze_usage(void)
+{
+	puts("bcachefs fs freeze - freeze a filesystem, e.g. for taking a snapshot\n"
+	     "Usage: bcachefs fs freeze [OPTION]... <mountpoint>\n"
+	     "\n"
+	     "Blocks new writes and syncs the filesystem, then checks that the journal\n"
+	     "has been flushed so that a snapshot of the underlying devices is\n"
+	     "consistent. A snapshot of a frozen filesystem is not marked clean: its\n"
+	     "journal is replayed on mount, but nothing is lost.\n"
+	     "\n"
+	     "Options:\n"
+	     "  -h, --help                  display this help and exit\n"
+	     "Report bugs to <linux-bcache@vger.kernel.org>");
+	exit(EXIT_SUCCESS);
+}
+
+int cmd_fs_freeze(int argc, char *argv[])
+{
+	static const struct option longopts[] = {
+		{ "help",		0, NULL, 'h' },
+		{ NULL }
+	};
+	u64 seq, flushed;
+	int opt;
+
+	while ((opt = getopt_long(argc, argv, "h", longopts, NULL)) != -1)
+		switch (opt) {
+		case 'h':
+			fs_freeze_usage();
+		}
+	args_shift(optind);
+
+	char *path = arg_pop();
+	if (!path)
+		die("Please supply a mountpoint");
+
+	if (argc)
+		die("Too many arguments");
+
+	struct bchfs_handle fs = bcache_fs_open(path);
+	int fd = xopen(path, O_RDONLY);
+
+	if (ioctl(fd, FIFREEZE, 0))
+		die("error freezing %s: %m", path);
+
+	if (!journal_flushed(fs, &seq, &flushed)) {
+		/* Flushes journal writes that were still in flight: */
+		int flush_fd = xopenat(fs.sysfs_fd, "internal/trigger_journal_flush",
+				       O_WRONLY);
+
+		if (write(flush_fd, "1", 1) != 1)
+			die("error flushing journal: %m");
+		close(flush_fd);
+	}
+
+	if (!journal_flushed(fs, &seq, &flushed)) {
+		if (ioctl(fd, FITHAW, 0))
+			fprintf(stderr, "error thawing %s: %m\n", path);
+		die("journal not flushed after freezing (seq %llu, flushed through %llu); thawed again",
+		    seq, flushed);
+	}
+
+	print_freeze_state(fs);
+
+	close(fd);
+	bcache_fs_close(fs);
+	return 0;
+}
+
+static void fs_thaw_usage(void)
+{
+	puts("bcachefs fs thaw - thaw a frozen filesystem\n"
+	     "Usage: bcachefs fs thaw [OPTION]... <mountpoint>\n"
+	     "\n"
+	     "Options:\n"
+	     "  -h, --help                  display this help and exit\n"
+	     "Report bugs to <linux-bcache@vger.kernel.org>");
+	exit(EXIT_SUCCESS);
+}
+
+int cmd_fs_thaw(int argc, char *argv[])
+{
+	static const struct option longopts[] = {
+		{ "help",		0, NULL, 'h' },
+		{ NULL }
+	};
+	int opt;
+
+	while ((opt = getopt_long(argc, argv, "h", longopts, NULL)) != -1)
+		switch (opt) {
+		case 'h':
+			fs_thaw_usage();
+		}
+	args_shift(optind);
+
+	char *path = arg_pop();
+	if (!path)
+		die("Please supply a mountpoint");
+
+	if (argc)
+		die("Too many arguments");
+
+	struct bchfs_handle fs = bcache_fs_open(path);
+	int fd = xopen(path, O_RDONLY);
+
+	if (ioctl(fd, FITHAW, 0))
+		die("error thawing %s: %m", path);
+
+	print_freeze_state(fs);
+
+	close(fd);
+	bcache_fs_close(fs);
+	return 0;
+}
+
+/* The fields of internal/journal_debug that journal status uses: */
+struct journal_status {
+	u64			dirty_entries;
+	u64			seq;
+	u64			last_seq;
+	u64			flushed_seq;
+	u64			prereserved;
+	u64			prereserved_max;
+	u64			flush_writes;
+	u64			noflush_writes;
+	u64			direct_reclaim;
+	u64			background_reclaim;
+	u64			cur_entry_error;
+	/* sectors: */
+	u64			space_clean;
+	u64			space_total;
+
+	struct bchu_time_stats	write;
+	struct bchu_time_stats	delay;
+	struct bchu_time_stats	flush_seq;
+	struct bchu_time_stats	blocked;
+};
+
+static struct journal_status journal_status_read(struct bchfs_handle fs)
+{
+	struct journal_status s = { 0 };
+	char *buf = read_file_str(fs.sysfs_fd, "internal/journal_debug");
+	char *line, *p = buf;
+	bool in_space = false;
+
+	while ((line = strsep(&p, "\n"))) {
+		/* Indented lines belong to the space: or dev N: section above: */
+		if (line[0] != '\t')
+			in_space = !strcmp(line, "space:");
+		else if (!in_space)
+			continue;
+
+		sscanf(line, "active journal entries: %llu",	&s.dirty_entries);
+		sscanf(line, "seq: %llu",			&s.seq);
+		sscanf(line, "last_seq: %llu",			&s.last_seq);
+		sscanf(line, "flushed_seq_ondisk: %llu",	&s.flushed_seq);
+		sscanf(line, "prereserved: %llu/%llu",
+		       &s.prereserved, &s.prereserved_max);
+		sscanf(line, "nr flush writes: %llu",		&s.flush_writes);
+		sscanf(line, "nr noflush writes: %llu",		&s.noflush_writes);
+		sscanf(line, "nr direct reclaim: %llu",		&s.direct_reclaim);
+		sscanf(line, "nr background reclaim: %llu",	&s.background_reclaim);
+		sscanf(line, "current entry error: %llu",	&s.cur_entry_error);
+		sscanf(line, " clean %*u:%llu",			&s.space_clean);
+		sscanf(line, " total %*u:%llu",			&s.space_total);
+	}
+	free(buf);
+
+	if (!s.seq)
+		die("error parsing internal/journal_debug");
+
+	s.write		= bchu_time_stats_read(fs, "time_stats/journal_write");
+	s.delay		= bchu_time_stats_read(fs, "time_stats/journal_delay");
+	s.flush_seq	= bchu_time_stats_read(fs, "time_stats/journal_flush_seq");
+	s.blocked	= bchu_time_stats_read(fs, "time_stats/blocked_journal");
+	return s;
+}
+
+static unsigned journal_percent_full(struct journal_status *s)
+{
+	return s->space_total
+		? 100 - div64_u64(s->space_clean * 100, s->space_total)
+		: 0;
+}
+
+/*
+ * With @prev, warnings are about what happened since then; otherwise, since
+ * the filesystem was mounted:
+ */
+static void print_journal_status(struct journal_status *s,
+				 struct journal_status *prev,
+				 enum units units)
+{
+	struct journal_status zero = { 0 };
+	unsigned full = journal_percent_full(s);
+	unsigned i;
+
+	if (!prev)
+		prev = &zero;
+
+	printf("%-24s %llu\n",		"seq",			s->seq);
+	printf("%-24s %llu\n",		"flushed seq",		s->flushed_seq);
+	printf("%-24s %llu\n",		"oldest dirty seq",	s->last_seq);
+	printf("%-24s %llu\n",		"dirty entries",	s->dirty_entries);
+	printf("%-24s %u%% (%s",	"full",			full,
+	       pr_units(s->space_total - s->space_clean, units));
+	printf(" of %s)\n",		pr_units(s->space_total, units));
+	printf("%-24s %llu/%llu\n",	"prereserved",
+	       s->prereserved, s->prereserved_max);
+	printf("%-24s %llu flush, %llu noflush\n", "writes",
+	       s->flush_writes, s->noflush_writes);
+	printf("%-24s %llu direct, %llu background\n", "reclaim",
+	       s->direct_reclaim, s->background_reclaim);
+	printf("\n");
+
+	printf_pad(40, "latency");
+	printf(" %10s", "count");
+	for (i = 0; i < ARRAY_SIZE(latency_quantiles); i++)
+		printf(" %10s", latency_quantiles[i].name);
+	printf(" %10s\n", "max");
+
+	print_latency("journal_write",		&s->write);
+	print_latency("journal_delay",		&s->delay);
+	print_latency("journal_flush_seq",	&s->flush_seq);
+	print_latency("blocked_journal",	&s->blocked);
+
+	if (s->cur_entry_error)
+		printf("\nwarning: the journal is in an error state (%llu)\n",
+		       s->cur_entry_error);
+
+	/* Direct reclaim is what writers do when the journal is full: */
+	u64 direct	= s->direct_reclaim - prev->direct_reclaim;
+	u64 background	= s->background_reclaim - prev->background_reclaim;
+	u64 blocked	= s->blocked.count - prev->blocked.count;
+
+	if (full >= 75 ||
+	    blocked ||
+	    direct * 10 > direct + background) {
+		printf("\nwarning: the journal is ");
+		if (full >= 75)
+			printf("%u%% full", full);
+		else
+			printf("filling up");
+		if (blocked)
+			printf(", and writes blocked on it %llu times", blocked);
+		printf(";\nif this persists, grow it with bcachefs device resize-journal\n");
+	}
+}
+
+static void journal_status_usage(void)
+{
+	puts("bcachefs journal status - show how full the journal is, and how fast it's flushed\n"
+	     "Usage: bcachefs journal status [OPTION]... <mountpoint>\n"
+	     "\n"
+	     "Decodes the journal state from sysfs: sequence numbers, how full the\n"
+	     "journal is, prereservations, writes and reclaim, and journal write, flush\n"
+	     "and blocking latencies. Warns when the journal is too small, i.e. chronically\n"
+	     "full, or writes block on it.\n"
+	     "\n"
+	     "Options:\n"
+	     "  -w, --watch=seconds         Print the status every seconds, until interrupted,\n"
+	     "                              with warnings about each interval\n"
+	     "  -H, --human-readable        Human readable units\n"
+	     "  -h, --help                  display this help and exit\n"
+	     "Report bugs to <linux-bcache@vger.kernel.org>");
+	exit(EXIT_SUCCESS);
+}
+
+int cmd_journal_status(int argc, char *argv[])
+{
+	static const struct option longopts[] = {
+		{ "watch",		required_argument,	NULL, 'w' },
+		{ "human-readable",	no_argument,		NULL, 'H' },
+		{ "help",		no_argument,		NULL, 'h' },
+		{ NULL }
+	};
+	enum units units = SECTORS;
+	unsigned watch = 0;
+	int opt;
+
+	while ((opt = getopt_long(argc, argv, "w:Hh", longopts, NULL)) != -1)
+		switch (opt) {
+		case 'w':
+			if (kstrtouint(optarg, 10, &watch) || !watch)
+				die("invalid interval %s", optarg);
+			break;
+		case 'H':
+			units = HUMAN_READABLE;
+			break;
+		case 'h':
+			journal_status_usage();
+		}
+	args_shift(optind);
+
+	char *path = arg_pop() ?: ".";
+	if (argc)
+		die("Too many arguments");
+
+	struct bchfs_handle fs = bcache_fs_open(path);
+	struct journal_status s = journal_status_read(fs), prev;
+
+	print_journal_status(&s, NULL, units);
+
+	while (watch) {
+		sleep(watch);
+
+		prev	= s;
+		s	= journal_status_read(fs);
+
+		printf("\n");
+		print_journal_status(&s, &prev, units);
+		fflush(stdout);
+	}
+
+	bcache_fs_close(fs);
+	return 0;
+}
@@ -1,8 +1,16 @@
 
 #include <getopt.h>
+#include <linux/shrinker.h>
+#include <uuid/uuid.h>
 #include "cmds.h"
+#include "fsck_errors.h"
 #include "libbcachefs/error.h"
 #include "libbcachefs.h"
+#include "libbcachefs/bcachefs.h"
+#include "libbcachefs/btree_gc.h"
+#include "libbcachefs/btree_iter.h"
+#include "libbcachefs/btree_update.h"
+#include "libbcachefs/fsck.h"
 #include "libbcachefs/super.h"
 #include "tools-util.h"
 
@@ -16,20 +24,530 @@ static void usage(void)
 	     "  -n                     Don't repair, only check for errors\n"
 	     "  -y                     Assume \"yes\" to all questions\n"
 	     "  -f                     Force checking even if filesystem is marked clean\n"
+	     " --fix only=list         Fix only these errors, comma separated: categories\n"
+	     "                         (superblock, journal, btree, alloc, stripes,\n"
+	     "                         extents, inodes, dirents), names of errors (see\n"
+	     "                         bcachefs explain -l), or safe, for those fixed\n"
+	     "                         without losing anything\n"
+	     " --passes=list           Run only these passes, comma separated\n"
+	     " --skip-passes=list      Run every pass but these\n"
+	     " --list-passes           List the passes, and what they depend on\n"
+	     " --dry-run=file          Write every change fsck would make to the btrees\n"
+	     "                         to file, for review, and change nothing\n"
+	     " --apply=file            Make the changes of a reviewed --dry-run plan\n"
 	     " --reconstruct_alloc     Reconstruct the alloc btree\n"
+	     " --memory-limit=size     Shrink caches to keep memory use below size,\n"
+	     "                         e.g. 4G\n"
 	     "  -v                     Be verbose\n"
 	     "  -h                     Display this help and exit\n"
 	     "Report bugs to <linux-bcachefs@vger.kernel.org>");
 }
 
+static int fsck_check_alloc_info(struct bch_fs *c)
+{
+	return bch2_gc(c, false, false);
+}
+
+/*
+ * What fsck does after journal replay, which always runs: the checks of
+ * bch2_fsck_full() can only be run all together. Run in this order:
+ */
+static const struct fsck_pass {
+	const char	*name;
+	const char	*desc;
+	/* Passes that have to run first for this one's repairs to be right: */
+	const char	*depends;
+	int		(*fn)(struct bch_fs *);
+} fsck_passes[] = {
+	{
+		"check_alloc_info",
+		"Mark and sweep: check bucket and stripe accounting, pointer "
+		"generations and replicas entries against the btrees",
+		NULL,
+		fsck_check_alloc_info,
+	}, {
+		"check_deleted_inodes",
+		"Finish deleting inodes that were unlinked but still open",
+		NULL,
+		bch2_fsck_walk_inodes_only,
+	}, {
+		"check_fs",
+		"Check inodes, extents, dirents, xattrs, the root directory, the "
+		"directory structure and link counts",
+		"check_alloc_info",
+		bch2_fsck_full,
+	},
+};
+
+static void fsck_list_passes(void)
+{
+	const struct fsck_pass *p;
+
+	printf("%-24s %s\n", "journal_replay",
+	       "Replay the journal: always run, when the filesystem is opened");
+
+	for (p = fsck_passes; p < fsck_passes + ARRAY_SIZE(fsck_passes); p++) {
+		printf("%-24s %s\n", p->name, p->desc);
+		if (p->depends)
+			printf("%-24s depends on: %s\n", "", p->depends);
+	}
+}
+
+static unsigned fsck_passes_parse(char *s)
+{
+	unsigned i, passes = 0;
+	char *name;
+
+	while ((name = strsep(&s, ","))) {
+		for (i = 0; i < ARRAY_SIZE(fsck_passes); i++)
+			if (!strcmp(name, fsck_passes[i].name))
+				break;
+
+		if (i == ARRAY_SIZE(fsck_passes))
+			die("invalid pass %s (see --list-passes)", name);
+
+		passes |= 1U << i;
+	}
+
+	return passes;
+}
+
+static int fsck_run_passes(struct bch_fs *c, unsigned passes)
+{
+	const struct fsck_pass *p;
+	unsigned i;
+	int ret = 0;
+
+	/* Otherwise errors are fixed without asking, as if found while mounted: */
+	clear_bit(BCH_FS_FSCK_DONE, &c->flags);
+
+	for (i = 0; i < ARRAY_SIZE(fsck_passes); i++) {
+		p = fsck_passes + i;
+
+		if (!(passes & (1U << i)))
+			continue;
+
+		if (p->depends && !(passes & fsck_passes_parse(strdupa(p->depends))))
+			fprintf(stderr, "Warning: running %s without %s, which it depends on\n",
+				p->name, p->depends);
+
+		if (c->opts.verbose)
+			printf("%s: running %s\n", c->name, p->name);
+
+		ret = p->fn(c);
+		if (ret) {
+			fprintf(stderr, "%s: error in %s: %s\n", c->name, p->name,
+				ret < 0 ? strerror(-ret) : "errors not fixed");
+			break;
+		}
+	}
+
+	set_bit(BCH_FS_FSCK_DONE, &c->flags);
+	return ret < 0 ? ret : 0;
+}
+
+/* Indexed like fsck_err_types: */
+static bool *fsck_fix_selected;
+
+static void fsck_fix_only_parse(char *s)
+{
+	const struct fsck_err_type *t;
+	char *name;
+	int cat;
+
+	if (strncmp(s, "only=", strlen("only=")))
+		die("invalid --fix %s: should be only=<errors>", s);
+	s += strlen("only=");
+
+	fsck_fix_selected = xcalloc(fsck_err_types_nr, sizeof(bool));
+
+	while ((name = strsep(&s, ","))) {
+		bool found = false;
+
+		cat = match_string(fsck_err_categories, -1, name);
+
+		for (t = fsck_err_types; t < fsck_err_types + fsck_err_types_nr; t++)
+			if ((cat >= 0 && t->category == cat) ||
+			    !strcmp(name, t->name) ||
+			    (!strcmp(name, "safe") && (t->flags & FSCK_AUTOFIX_SAFE))) {
+				fsck_fix_selected[t - fsck_err_types] = true;
+				found = true;
+			}
+
+		if (!found)
+			die("invalid --fix %s: not a category or error", name);
+	}
+}
+
+/*
+ * With --fix only=, errors are asked about, and answered here: the message
+ * asked about is how the error is told apart. Errors not listed in
+ * fsck_errors.c aren't fixed.
+ */
+static bool fsck_fix_only(const char *msg)
+{
+	const struct fsck_err_type *t = fsck_err_type_find(msg);
+
+	return t && fsck_fix_selected[t - fsck_err_types];
+}
+
+/*
+ * Dry runs: the passes are run with nochanges, so that their updates stay in
+ * memory. Every key is written to a temporary file before they run, and then
+ * compared with the btrees; both are in btree order, so the comparison is one
+ * walk over each. The plan is a line per key that changed, with the keys
+ * before and after in hex, and printed as text in comments for review.
+ *
+ * Accounting isn't planned, since mark and sweep only fixes the bucket marks
+ * in memory: it's recomputed when the plan is applied.
+ */
+
+struct plan_key {
+	unsigned	btree_id;
+	bool		eof;
+	u64		buf[BKEY_U64s_MAX];
+};
+
+static inline struct bkey_i *plan_bkey(struct plan_key *p)
+{
+	return (void *) p->buf;
+}
+
+static void plan_key_read(FILE *f, struct plan_key *p)
+{
+	struct bkey_i *k = plan_bkey(p);
+	u32 btree_id;
+
+	if (fread(&btree_id, sizeof(btree_id), 1, f) != 1) {
+		p->eof = true;
+		return;
+	}
+
+	if (fread(&k->k, sizeof(k->k), 1, f) != 1 ||
+	    k->k.u64s < BKEY_U64s ||
+	    fread(&k->v, bkey_val_bytes(&k->k), 1, f) != !!bkey_val_bytes(&k->k))
+		die("error reading keys: %m");
+
+	p->btree_id = btree_id;
+}
+
+static FILE *plan_keys_save(struct bch_fs *c)
+{
+	struct btree_trans trans;
+	struct btree_iter *iter;
+	struct bkey_s_c k;
+	struct plan_key p;
+	unsigned btree_id;
+	int ret;
+
+	FILE *f = tmpfile();
+	if (!f)
+		die("error creating temporary file: %m");
+
+	bch2_trans_init(&trans, c, 0, 0);
+
+	for (btree_id = 0; btree_id < BTREE_ID_NR; btree_id++) {
+		u32 id = btree_id;
+
+		for_each_btree_key(&trans, iter, btree_id, POS_MIN,
+				   BTREE_ITER_PREFETCH|BTREE_ITER_NOT_EXTENTS, k, ret) {
+			bkey_reassemble(plan_bkey(&p), k);
+
+			if (fwrite(&id, sizeof(id), 1, f) != 1 ||
+			    fwrite(p.buf, bkey_bytes(k.k), 1, f) != 1)
+				die("error writing keys: %m");
+		}
+		bch2_trans_iter_put(&trans, iter);
+
+		if (ret)
+			die("error walking btree %s: %s",
+			    bch2_btree_ids[btree_id], strerror(-ret));
+	}
+
+	bch2_trans_exit(&trans);
+
+	rewind(f);
+	return f;
+}
+
+static void plan_hex(FILE *out, struct bkey_i *k)
+{
+	const u8 *b = (void *) k;
+	unsigned i;
+
+	if (!k) {
+		fputs(" -", out);
+		return;
+	}
+
+	putc(' ', out);
+	for (i = 0; i < bkey_bytes(&k->k); i++)
+		fprintf(out, "%02x", b[i]);
+}
+
+static void plan_change(struct bch_fs *c, FILE *out, unsigned btree_id,
+			struct bkey_i *before, struct bkey_i *after)
+{
+	struct bpos pos = (after ?: before)->k.p;
+	char buf[4096];
+
+	fprintf(out, "# %s %llu:%llu:%u\n", bch2_btree_ids[btree_id],
+		pos.inode, pos.offset, pos.snapshot);
+	if (before) {
+		bch2_bkey_val_to_text(&PBUF(buf), c, bkey_i_to_s_c(before));
+		fprintf(out, "# - %s\n", buf);
+	}
+	if (after) {
+		bch2_bkey_val_to_text(&PBUF(buf), c, bkey_i_to_s_c(after));
+		fprintf(out, "# + %s\n", buf);
+	}
+
+	fprintf(out, "change %s", bch2_btree_ids[btree_id]);
+	plan_hex(out, before);
+	plan_hex(out, after);
+	fputc('\n', out);
+}
+
+static unsigned plan_write(struct bch_fs *c, FILE *saved, const char *path)
+{
+	struct btree_trans trans;
+	struct btree_iter *iter;
+	struct bkey_s_c k;
+	struct plan_key *old = xmalloc(sizeof(*old));
+	struct plan_key *new = xmalloc(sizeof(*new));
+	unsigned btree_id, nr = 0;
+	char uuid[40];
+	int ret;
+
+	FILE *out = fopen(path, "w");
+	if (!out)
+		die("error creating %s: %m", path);
+
+	uuid_unparse(c->sb.user_uuid.b, uuid);
+	fprintf(out, "bcachefs fsck plan 1 %s\n"
+		"# Changes fsck would make, a key before (-) and after (+) each:\n"
+		"# make them with bcachefs fsck --apply=%s. Accounting is\n"
+		"# recomputed afterwards, and isn't listed.\n",
+		uuid, path);
+
+	old->eof = false;
+	plan_key_read(saved, old);
+
+	bch2_trans_init(&trans, c, 0, 0);
+
+	for (btree_id = 0; btree_id < BTREE_ID_NR; btree_id++) {
+#define old_in_btree	(!old->eof && old->btree_id == btree_id)
+		for_each_btree_key(&trans, iter, btree_id, POS_MIN,
+				   BTREE_ITER_PREFETCH|BTREE_ITER_NOT_EXTENTS, k, ret) {
+			bkey_reassemble(plan_bkey(new), k);
+
+			while (old_in_btree &&
+			       bpos_cmp(plan_bkey(old)->k.p, k.k->p) < 0) {
+				plan_change(c, out, btree_id, plan_bkey(old), NULL);
+				plan_key_read(saved, old);
+				nr++;
+			}
+
+			if (old_in_btree &&
+			    !bpos_cmp(plan_bkey(old)->k.p, k.k->p)) {
+				if (memcmp(old->buf, new->buf, bkey_bytes(k.k)) ||
+				    plan_bkey(old)->k.u64s != k.k->u64s) {
+					plan_change(c, out, btree_id,
+						    plan_bkey(old), plan_bkey(new));
+					nr++;
+				}
+				plan_key_read(saved, old);
+			} else {
+				plan_change(c, out, btree_id, NULL, plan_bkey(new));
+				nr++;
+			}
+		}
+		bch2_trans_iter_put(&trans, iter);
+
+		if (ret)
+			die("error walking btree %s: %s",
+			    bch2_btree_ids[btree_id], strerror(-ret));
+
+		while (old_in_btree) {
+			plan_change(c, out, btree_id, plan_bkey(old), NULL);
+			plan_key_read(saved, old);
+			nr++;
+		}
+#undef old_in_btree
+	}
+
+	bch2_trans_exit(&trans);
+
+	if (fclose(out))
+		die("error writing %s: %m", path);
+	fclose(saved);
+	free(new);
+	free(old);
+	return nr;
+}
+
+/* Returns NULL for "-": */
+static struct bkey_i *plan_unhex(const char *s, struct plan_key *p)
+{
+	struct bkey_i *k = plan_bkey(p);
+	size_t len = strlen(s);
+	u8 *b = (void *) p->buf;
+	unsigned i;
+
+	if (!strcmp(s, "-"))
+		return NULL;
+
+	if (len % 16 || len / 2 > sizeof(p->buf))
+		die("invalid key %s in plan", s);
+
+	for (i = 0; i < len / 2; i++)
+		if (sscanf(s + i * 2, "%2hhx", &b[i]) != 1)
+			die("invalid key %s in plan", s);
+
+	if (k->k.u64s != len / 16)
+		die("invalid key %s in plan", s);
+	return k;
+}
+
+static bool plan_key_eq(struct bkey_s_c k, struct bkey_i *expected)
+{
+	u64 buf[BKEY_U64s_MAX];
+
+	if (!expected)
+		return k.k->type == KEY_TYPE_deleted;
+
+	bkey_reassemble((void *) buf, k);
+	return k.k->u64s == expected->k.u64s &&
+		!memcmp(buf, expected, bkey_bytes(k.k));
+}
+
+/*
+ * Checks the key at @pos is still @before, and with @apply, replaces it with
+ * @after: keys are replaced exactly, without the splitting of overlapping
+ * extents, since the plan already has every extent changed.
+ */
+static int plan_change_do(struct btree_trans *trans, unsigned btree_id,
+			  struct bpos pos, struct bkey_i *before,
+			  struct bkey_i *after, bool apply)
+{
+	struct btree_iter *iter;
+	struct bkey_s_c k;
+	int ret;
+
+	iter = bch2_trans_get_iter(trans, btree_id, pos,
+				   BTREE_ITER_INTENT|BTREE_ITER_NOT_EXTENTS);
+	k = bch2_btree_iter_peek_slot(iter);
+	ret = bkey_err(k);
+	if (ret)
+		goto out;
+
+	if (!plan_key_eq(k, before)) {
+		ret = -ESTALE;
+		goto out;
+	}
+
+	if (!apply)
+		goto out;
+
+	if (!after) {
+		after = bch2_trans_kmalloc(trans, sizeof(*after));
+		ret = PTR_ERR_OR_ZERO(after);
+		if (ret)
+			goto out;
+
+		bkey_init(&after->k);
+		after->k.p = pos;
+	}
+
+	bch2_trans_update(trans, iter, after, 0);
+out:
+	bch2_trans_iter_put(trans, iter);
+	return ret;
+}
+
+/* Every change is checked first, so that a stale plan changes nothing: */
+static void plan_apply(struct bch_fs *c, const char *path, bool apply)
+{
+	struct plan_key *before_buf = xmalloc(sizeof(*before_buf));
+	struct plan_key *after_buf = xmalloc(sizeof(*after_buf));
+	char *line = NULL, uuid[40], plan_uuid[40];
+	size_t n = 0, nr = 0;
+	int ret;
+
+	FILE *f = fopen(path, "r");
+	if (!f)
+		die("error opening %s: %m", path);
+
+	uuid_unparse(c->sb.user_uuid.b, uuid);
+	if (getline(&line, &n, f) < 0 ||
+	    sscanf(line, "bcachefs fsck plan 1 %39s", plan_uuid) != 1)
+		die("%s isn't an fsck plan", path);
+	if (strcmp(uuid, plan_uuid))
+		die("%s is a plan for filesystem %s, not %s", path, plan_uuid, uuid);
+
+	while (getline(&line, &n, f) >= 0) {
+		char *s = strim(line), *cmd, *btree, *before_hex, *after_hex;
+		struct bkey_i *before, *after;
+
+		if (!*s || *s == '#')
+			continue;
+
+		cmd		= strsep(&s, " ");
+		btree		= strsep(&s, " ");
+		before_hex	= strsep(&s, " ");
+		after_hex	= strsep(&s, " ");
+		if (strcmp(cmd, "change") || !after_hex || s)
+			die("invalid line in plan: %s", line);
+
+		int btree_id = match_string(bch2_btree_ids, -1, btree);
+		if (btree_id < 0)
+			die("invalid btree %s in plan", btree);
+
+		before	= plan_unhex(before_hex, before_buf);
+		after	= plan_unhex(after_hex, after_buf);
+		if (!before && !after)
+			die("invalid line in plan: %s", line);
+
+		struct bpos pos = (after ?: before)->k.p;
+
+		ret = bch2_trans_do(c, NULL, NULL, BTREE_INSERT_NOFAIL,
+			plan_change_do(&trans, btree_id, pos, before, after, apply));
+		if (ret == -ESTALE)
+			die("%s %llu:%llu:%u has changed since the plan was made: "
+			    "make it again", btree, pos.inode, pos.offset, pos.snapshot);
+		if (ret)
+			die("error %s %s %llu:%llu:%u: %s",
+			    apply ? "updating" : "looking up",
+			    btree, pos.inode, pos.offset, pos.snapshot, strerror(-ret));
+		nr++;
+	}
+
+	if (apply)
+		printf("%s: %zu changes made\n", c->name, nr);
+
+	free(line);
+	fclose(f);
+	free(after_buf);
+	free(before_buf);
+}
+
 int cmd_fsck(int argc, char *argv[])
 {
 	static const struct option longopts[] = {
 		{ "reconstruct_alloc",	no_argument,		NULL, 'R' },
+		{ "memory-limit",	required_argument,	NULL, 'M' },
+		{ "fix",		required_argument,	NULL, 'F' },
+		{ "passes",		required_argument,	NULL, 'P' },
+		{ "skip-passes",	required_argument,	NULL, 'S' },
+		{ "list-passes",	no_argument,		NULL, 'L' },
+		{ "dry-run",		required_argument,	NULL, 'D' },
+		{ "apply",		required_argument,	NULL, 'A' },
 		{ NULL }
 	};
 	struct bch_opts opts = bch2_opts_empty();
-	unsigned i;
+	unsigned i, passes = 0, all_passes = (1U << ARRAY_SIZE(fsck_passes)) - 1;
+	bool select_passes = false;
+	char *plan_path = NULL, *apply_path = NULL;
 	int opt, ret = 0;
 
 	opt_set(opts, degraded, true);
@@ -62,6 +580,34 @@ int cmd_fsck(int argc, char *argv[])
 		case 'R':
 			opt_set(opts, reconstruct_alloc, true);
 			break;
+		case 'F':
+			fsck_fix_only_parse(optarg);
+			opt_set(opts, fix_errors, FSCK_OPT_ASK);
+			ask_yn_answer = fsck_fix_only;
+			break;
+		case 'P':
+			passes = fsck_passes_parse(optarg);
+			select_passes = true;
+			break;
+		case 'S':
+			passes = all_passes & ~fsck_passes_parse(optarg);
+			select_passes = true;
+			break;
+		case 'L':
+			fsck_list_passes();
+			exit(EXIT_SUCCESS);
+		case 'D':
+			plan_path = optarg;
+			break;
+		case 'A':
+			apply_path = optarg;
+			break;
+		case 'M':
+			if (bch2_strtoull_h(optarg, &shrinker_memory_limit)) {
+				fprintf(stderr, "invalid memory limit %s\n", optarg);
+				exit(8);
+			}
+			break;
 		case 'v':
 			opt_set(opts, verbose, true);
 			break;
@@ -87,14 +633,66 @@ int cmd_fsck(int argc, char *argv[])
 		}
 	}
 
+	if (plan_path && apply_path)
+		die("--dry-run and --apply are exclusive");
+
+	if (plan_path) {
+		if (!select_passes)
+			passes = all_passes;
+		select_passes = true;
+
+		opt_set(opts, nochanges, true);
+		/* Plan the fixes we'd be asked about, unless --fix only= answers: */
+		if (!ask_yn_answer)
+			opt_set(opts, fix_errors, FSCK_OPT_YES);
+	}
+
+	if (apply_path) {
+		/* Only mark and sweep, to recompute accounting afterwards: */
+		passes = fsck_passes_parse(strdupa("check_alloc_info"));
+		select_passes = true;
+		opt_set(opts, fix_errors, FSCK_OPT_YES);
+	}
+
+	/* Opened without fsck, which would run every pass, to run them after: */
+	if (select_passes)
+		opt_set(opts, fsck, false);
+
 	struct bch_fs *c = bch2_fs_open(argv, argc, opts);
 	if (IS_ERR(c)) {
 		fprintf(stderr, "error opening %s: %s\n", argv[0], strerror(-PTR_ERR(c)));
 		exit(8);
 	}
 
+	if (select_passes) {
+		/* Recovery turns on fsck when upgrading from some versions: */
+		if (c->opts.fsck && plan_path)
+			die("%s: recovery ran fsck while opening, so nothing can be planned",
+			    c->name);
+
+		if (apply_path) {
+			plan_apply(c, apply_path, false);
+			plan_apply(c, apply_path, true);
+		}
+
+		if (c->opts.fsck) {
+			fprintf(stderr, "%s: every pass was run, as required by recovery\n",
+				c->name);
+		} else {
+			FILE *saved = plan_path ? plan_keys_save(c) : NULL;
+
+			if (fsck_run_passes(c, passes))
+				ret |= 8;
+
+			if (plan_path)
+				printf("%s: %u changes planned in %s\n", c->name,
+				       plan_write(c, saved, plan_path), plan_path);
+		}
+	}
+
 	if (test_bit(BCH_FS_ERRORS_FIXED, &c->flags)) {
-		fprintf(stderr, "%s: errors fixed\n", c->name);
+		fprintf(stderr, "%s: errors %s\n", c->name,
+			plan_path ? "would be fixed by the plan" : "fixed");
 		ret |= 1;
 	}
 	if (test_bit(BCH_FS_ERROR, &c->flags)) {
@@ -0,0 +1,448 @@
+/*
+ * Commands for OS installers: non-interactive, and with output in JSON whose
+ * format only ever changes by adding fields. Anything else bumps
+ * INSTALLER_API_VERSION, which every reply starts with.
+ *
+ * Errors are reported on stderr, with a nonzero exit status. Strings that aren't
+ * valid UTF-8, e.g. labels, have each invalid byte replaced with U+FFFD.
+ */
+
+#include <fcntl.h>
+#include <getopt.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <sys/mount.h>
+#include <sys/types.h>
+#include <unistd.h>
+#include <uuid/uuid.h>
+
+#include "cmds.h"
+#include "crypto.h"
+#include "libbcachefs.h"
+
+#include "libbcachefs/checksum.h"
+#include "libbcachefs/super.h"
+#include "libbcachefs/super-io.h"
+
+#define INSTALLER_API_VERSION	1
+
+/*
+ * Where replies go: libbcachefs logs to stdout, so that's pointed at stderr
+ * instead, and the reply is written to the original stdout.
+ */
+static FILE *json_out;
+
+static void json_out_init(void)
+{
+	int fd = dup(STDOUT_FILENO);
+
+	if (fd < 0 || !(json_out = fdopen(fd, "w")))
+		die("error duplicating stdout: %m");
+
+	fflush(stdout);
+	if (dup2(STDERR_FILENO, STDOUT_FILENO) < 0)
+		die("error redirecting stdout: %m");
+}
+
+static void json_uuid(const unsigned char *b)
+{
+	char uuid[40];
+
+	uuid_unparse_lower(b, uuid);
+	json_str(json_out, uuid);
+}
+
+static void json_reply_start(void)
+{
+	fprintf(json_out, "{\"api_version\":%u", INSTALLER_API_VERSION);
+}
+
+static void json_reply_end(void)
+{
+	fprintf(json_out, "}\n");
+}
+
+static char *passphrase_read(const char *passphrase_file)
+{
+	if (!passphrase_file)
+		die("The filesystem is encrypted: please supply --passphrase-file");
+
+	return read_keyfile(passphrase_file);
+}
+
+static void passphrase_free(char *passphrase)
+{
+	if (passphrase) {
+		memzero_explicit(passphrase, strlen(passphrase));
+		free(passphrase);
+	}
+}
+
+static void installer_api_version_usage(void)
+{
+	puts("bcachefs installer-api version - print the installer API version\n"
+	     "Usage: bcachefs installer-api version\n"
+	     "\n"
+	     "Prints {\"api_version\", \"tools_version\"}.\n"
+	     "Report bugs to <linux-bcache@vger.kernel.org>");
+	exit(EXIT_SUCCESS);
+}
+
+int cmd_installer_api_version(int argc, char *argv[])
+{
+	if (argc > 1 && !strcmp(argv[1], "--help"))
+		installer_api_version_usage();
+
+	json_out_init();
+	json_reply_start();
+	fprintf(json_out, ",\"tools_version\":");
+	json_str(json_out, VERSION_STRING);
+	json_reply_end();
+	return 0;
+}
+
+static void probe_dev(const char *path)
+{
+	struct bch_opts opts = bch2_opts_empty();
+	struct bch_sb_handle sb;
+	int fd = xopen(path, O_RDONLY);
+	u64 size = get_size(path, fd);
+
+	close(fd);
+
+	fprintf(json_out, "{\"path\":");
+	json_str(json_out, path);
+	fprintf(json_out, ",\"size\":%llu,\"mounted\":%s", size,
+		dev_mounted((char *) path) ? "true" : "false");
+
+	opt_set(opts, noexcl,		true);
+	opt_set(opts, nochanges,	true);
+
+	if (bch2_read_super(path, &opts, &sb)) {
+		fprintf(json_out, ",\"bcachefs\":false}");
+		return;
+	}
+
+	char *label = strndup((char *) sb.sb->label, sizeof(sb.sb->label));
+
+	fprintf(json_out, ",\"bcachefs\":true,\"uuid\":");
+	json_uuid(sb.sb->user_uuid.b);
+	fprintf(json_out, ",\"label\":");
+	json_str(json_out, label);
+	fprintf(json_out, ",\"version\":%u,\"dev_idx\":%u,\"nr_devices\":%u,\"encrypted\":%s}",
+		le16_to_cpu(sb.sb->version),
+		sb.sb->dev_idx,
+		sb.sb->nr_devices,
+		bch2_sb_is_encrypted(sb.sb) ? "true" : "false");
+
+	free(label);
+	bch2_free_super(&sb);
+}
+
+static void installer_api_probe_usage(void)
+{
+	puts("bcachefs installer-api probe - describe devices\n"
+	     "Usage: bcachefs installer-api probe <devices>...\n"
+	     "\n"
+	     "Prints {\"api_version\", \"devices\": [...]}, with for each device\n"
+	     "\"path\", \"size\" in bytes, \"mounted\" and \"bcachefs\"; and for bcachefs\n"
+	     "members \"uuid\", \"label\", \"version\", \"dev_idx\", \"nr_devices\" and\n"
+	     "\"encrypted\".\n"
+	     "Report bugs to <linux-bcache@vger.kernel.org>");
+	exit(EXIT_SUCCESS);
+}
+
+int cmd_installer_api_probe(int argc, char *argv[])
+{
+	static const struct option longopts[] = {
+		{ "help",		no_argument,		NULL, 'h' },
+		{ NULL }
+	};
+	int opt, i;
+
+	while ((opt = getopt_long(argc, argv, "h", longopts, NULL)) != -1)
+		switch (opt) {
+		case 'h':
+			installer_api_probe_usage();
+		}
+	args_shift(optind);
+
+	if (!argc)
+		die("Please supply device(s)");
+
+	json_out_init();
+	json_reply_start();
+	fprintf(json_out, ",\"devices\":[");
+	for (i = 0; i < argc; i++) {
+		if (i)
+			fputc(',', json_out);
+		probe_dev(argv[i]);
+	}
+	fprintf(json_out, "]");
+	json_reply_end();
+	return 0;
+}
+
+static void installer_api_format_usage(void)
+{
+	puts("bcachefs installer-api format - format devices\n"
+	     "Usage: bcachefs installer-api format [OPTION]... <devices>...\n"
+	     "\n"
+	     "Formats the devices as one filesystem, destroying whatever was on them,\n"
+	     "and prints {\"api_version\", \"uuid\"}.\n"
+	     "\n"
+	     "Options:\n"
+	     "  -L, --label=label           Filesystem label\n"
+	     "  -p, --passphrase-file=file  Encrypt the filesystem, with the passphrase\n"
+	     "                              in file\n"
+	     "  -h, --help                  display this help and exit\n"
+	     "Report bugs to <linux-bcache@vger.kernel.org>");
+	exit(EXIT_SUCCESS);
+}
+
+int cmd_installer_api_format(int argc, char *argv[])
+{
+	static const struct option longopts[] = {
+		{ "label",		required_argument,	NULL, 'L' },
+		{ "passphrase-file",	required_argument,	NULL, 'p' },
+		{ "help",		no_argument,		NULL, 'h' },
+		{ NULL }
+	};
+	struct format_opts opts = format_opts_default();
+	struct bch_opt_strs fs_opt_strs;
+	struct dev_opts *devs;
+	int opt, i;
+
+	while ((opt = getopt_long(argc, argv, "L:p:h", longopts, NULL)) != -1)
+		switch (opt) {
+		case 'L':
+			opts.label = optarg;
+			break;
+		case 'p':
+			opts.encrypted	= true;
+			opts.passphrase	= read_keyfile(optarg);
+			break;
+		case 'h':
+			installer_api_format_usage();
+		}
+	args_shift(optind);
+
+	if (!argc)
+		die("Please supply device(s)");
+
+	json_out_init();
+
+	devs = xcalloc(argc, sizeof(*devs));
+	for (i = 0; i < argc; i++) {
+		devs[i] = dev_opts_default();
+		devs[i].path	= argv[i];
+		devs[i].fd	= open_for_format(argv[i], true);
+	}
+
+	memset(&fs_opt_strs, 0, sizeof(fs_opt_strs));
+
+	struct bch_sb *sb = bch2_format(fs_opt_strs, bch2_opts_empty(),
+					opts, devs, argc);
+
+	/* Start it once, to allocate the journal and create the root: */
+	if (opts.passphrase)
+		bch2_add_key(sb, opts.passphrase);
+
+	struct bch_fs *c = bch2_fs_open(argv, argc, bch2_opts_empty());
+	if (IS_ERR(c))
+		die("error opening %s: %s", argv[0], strerror(-PTR_ERR(c)));
+	bch2_fs_stop(c);
+
+	json_reply_start();
+	fprintf(json_out, ",\"uuid\":");
+	json_uuid(sb->user_uuid.b);
+	json_reply_end();
+
+	passphrase_free(opts.passphrase);
+	free(sb);
+	free(devs);
+	return 0;
+}
+
+static void installer_api_mount_usage(void)
+{
+	puts("bcachefs installer-api mount - mount a filesystem\n"
+	     "Usage: bcachefs installer-api mount [OPTION]... <devices>... <mountpoint>\n"
+	     "\n"
+	     "Unlocks the filesystem if it's encrypted, mounts it and prints\n"
+	     "{\"api_version\", \"uuid\", \"mountpoint\"}.\n"
+	     "\n"
+	     "Options:\n"
+	     "  -o, --options=options       Mount options, passed to the kernel\n"
+	     "  -r, --read-only             Mount read only\n"
+	     "  -p, --passphrase-file=file  Unlock with the passphrase in file\n"
+	     "  -h, --help                  display this help and exit\n"
+	     "Report bugs to <linux-bcache@vger.kernel.org>");
+	exit(EXIT_SUCCESS);
+}
+
+int cmd_installer_api_mount(int argc, char *argv[])
+{
+	static const struct option longopts[] = {
+		{ "options",		required_argument,	NULL, 'o' },
+		{ "read-only",		no_argument,		NULL, 'r' },
+		{ "passphrase-file",	required_argument,	NULL, 'p' },
+		{ "help",		no_argument,		NULL, 'h' },
+		{ NULL }
+	};
+	struct bch_opts opts = bch2_opts_empty();
+	struct bch_sb_handle sb;
+	char *passphrase_file = NULL, *mount_opts = "";
+	unsigned long flags = 0;
+	int opt, i, ret;
+
+	while ((opt = getopt_long(argc, argv, "o:rp:h", longopts, NULL)) != -1)
+		switch (opt) {
+		case 'o':
+			mount_opts = optarg;
+			break;
+		case 'r':
+			flags |= MS_RDONLY;
+			break;
+		case 'p':
+			passphrase_file = optarg;
+			break;
+		case 'h':
+			installer_api_mount_usage();
+		}
+	args_shift(optind);
+
+	if (argc < 2)
+		die("Please supply device(s) and a mountpoint");
+
+	char *mountpoint = argv[--argc];
+
+	json_out_init();
+
+	opt_set(opts, noexcl,		true);
+	opt_set(opts, nochanges,	true);
+
+	ret = bch2_read_super(argv[0], &opts, &sb);
+	if (ret)
+		die("Error opening %s: %s", argv[0], strerror(-ret));
+
+	if (bch2_sb_is_encrypted(sb.sb)) {
+		char *passphrase = passphrase_read(passphrase_file);
+
+		bch2_add_key(sb.sb, passphrase);
+		passphrase_free(passphrase);
+	}
+
+	char *devs = strdup(argv[0]);
+	for (i = 1; i < argc; i++) {
+		char *n = mprintf("%s:%s", devs, argv[i]);
+
+		free(devs);
+		devs = n;
+	}
+
+	if (mount(devs, mountpoint, "bcachefs", flags, mount_opts))
+		die("error mounting %s on %s: %m", devs, mountpoint);
+
+	json_reply_start();
+	fprintf(json_out, ",\"uuid\":");
+	json_uuid(sb.sb->user_uuid.b);
+	fprintf(json_out, ",\"mountpoint\":");
+	json_str(json_out, mountpoint);
+	json_reply_end();
+
+	free(devs);
+	bch2_free_super(&sb);
+	return 0;
+}
+
+static void installer_api_set_passphrase_usage(void)
+{
+	puts("bcachefs installer-api set-passphrase - change the passphrase of an\n"
+	     "unmounted encrypted filesystem\n"
+	     "Usage: bcachefs installer-api set-passphrase [OPTION]... <devices>...\n"
+	     "\n"
+	     "Prints {\"api_version\", \"uuid\"}.\n"
+	     "\n"
+	     "Options:\n"
+	     "  -p, --passphrase-file=file  The current passphrase, if there is one\n"
+	     "  -n, --new-passphrase-file=file\n"
+	     "                              The new passphrase\n"
+	     "  -h, --help                  display this help and exit\n"
+	     "Report bugs to <linux-bcache@vger.kernel.org>");
+	exit(EXIT_SUCCESS);
+}
+
+int cmd_installer_api_set_passphrase(int argc, char *argv[])
+{
+	static const struct option longopts[] = {
+		{ "passphrase-file",	required_argument,	NULL, 'p' },
+		{ "new-passphrase-file",required_argument,	NULL, 'n' },
+		{ "help",		no_argument,		NULL, 'h' },
+		{ NULL }
+	};
+	struct bch_opts opts = bch2_opts_empty();
+	char *passphrase_file = NULL, *new_passphrase_file = NULL;
+	int opt, ret;
+
+	while ((opt = getopt_long(argc, argv, "p:n:h", longopts, NULL)) != -1)
+		switch (opt) {
+		case 'p':
+			passphrase_file = optarg;
+			break;
+		case 'n':
+			new_passphrase_file = optarg;
+			break;
+		case 'h':
+			installer_api_set_passphrase_usage();
+		}
+	args_shift(optind);
+
+	if (!argc)
+		die("Please supply device(s)");
+
+	if (!new_passphrase_file)
+		die("Please supply --new-passphrase-file");
+
+	json_out_init();
+
+	bch2_add_key_offline(argv[0], passphrase_file);
+
+	opt_set(opts, nostart, true);
+
+	struct bch_fs *c = bch2_fs_open(argv, argc, opts);
+	if (IS_ERR(c))
+		die("Error opening %s: %s", argv[0], strerror(-PTR_ERR(c)));
+
+	struct bch_sb_field_crypt *crypt = bch2_sb_get_crypt(c->disk_sb.sb);
+	if (!crypt)
+		die("Filesystem does not have encryption enabled");
+
+	struct bch_encrypted_key new_key;
+	new_key.magic = BCH_KEY_MAGIC;
+
+	ret = bch2_decrypt_sb_key(c, crypt, &new_key.key);
+	if (ret)
+		die("Error getting current key");
+
+	char *new_passphrase = read_keyfile(new_passphrase_file);
+	struct bch_key passphrase_key = derive_passphrase(crypt, new_passphrase);
+
+	passphrase_free(new_passphrase);
+
+	if (bch2_chacha_encrypt_key(&passphrase_key, __bch2_sb_key_nonce(c->disk_sb.sb),
+				    &new_key, sizeof(new_key)))
+		die("error encrypting key");
+	crypt->key = new_key;
+
+	bch2_write_super(c);
+
+	json_reply_start();
+	fprintf(json_out, ",\"uuid\":");
+	json_uuid(c->disk_sb.sb->user_uuid.b);
+	json_reply_end();
+
+	bch2_fs_stop(c);
+	return 0;
+}
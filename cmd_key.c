@@ -1,4 +1,5 @@
 #include <errno.h>
+#include <getopt.h>
 #include <unistd.h>
 #include <uuid/uuid.h>
 
@@ -14,20 +15,25 @@ static void unlock_usage(void)
 	     "\n"
 	     "Options:\n"
 	     "  -c                     Check if a device is encrypted\n"
+	     "  -k keyfile             Read the passphrase from a keyfile\n"
 	     "  -h                     Display this help and exit\n"
 	     "Report bugs to <linux-bcache@vger.kernel.org>");
 }
 
 int cmd_unlock(int argc, char *argv[])
 {
+	char *keyfile = NULL;
 	bool check = false;
 	int opt;
 
-	while ((opt = getopt(argc, argv, "ch")) != -1)
+	while ((opt = getopt(argc, argv, "ck:h")) != -1)
 		switch (opt) {
 		case 'c':
 			check = true;
 			break;
+		case 'k':
+			keyfile = optarg;
+			break;
 		case 'h':
 			unlock_usage();
 			exit(EXIT_SUCCESS);
@@ -57,7 +63,9 @@ int cmd_unlock(int argc, char *argv[])
 	if (check)
 		exit(EXIT_SUCCESS);
 
-	char *passphrase = read_passphrase("Enter passphrase: ");
+	char *passphrase = keyfile
+		? read_keyfile(keyfile)
+		: read_passphrase("Enter passphrase: ");
 
 	bch2_add_key(sb.sb, passphrase);
 
@@ -67,12 +75,47 @@ int cmd_unlock(int argc, char *argv[])
 	return 0;
 }
 
+static void set_passphrase_usage(void)
+{
+	puts("bcachefs set-passphrase - change passphrase on an existing (unmounted) filesystem\n"
+	     "Usage: bcachefs set-passphrase [OPTION] devices\n"
+	     "\n"
+	     "Options:\n"
+	     "  -k keyfile             Use the contents of a keyfile (see bcachefs key\n"
+	     "                         generate-keyfile) as the new passphrase\n"
+	     "  --verify-after-write   Reread every copy of the superblock on every device\n"
+	     "                         afterwards, checking they were all updated\n"
+	     "  -h                     Display this help and exit\n"
+	     "Report bugs to <linux-bcache@vger.kernel.org>");
+}
+
 int cmd_set_passphrase(int argc, char *argv[])
 {
+	static const struct option longopts[] = {
+		{ "verify-after-write",	no_argument,		NULL, 'V' },
+		{ NULL }
+	};
 	struct bch_opts opts = bch2_opts_empty();
 	struct bch_fs *c;
+	char *keyfile = NULL;
+	bool verify = false;
+	int opt;
 
-	if (argc < 2)
+	while ((opt = getopt_long(argc, argv, "k:h", longopts, NULL)) != -1)
+		switch (opt) {
+		case 'k':
+			keyfile = optarg;
+			break;
+		case 'V':
+			verify = true;
+			break;
+		case 'h':
+			set_passphrase_usage();
+			exit(EXIT_SUCCESS);
+		}
+	args_shift(optind);
+
+	if (!argc)
 		die("Please supply one or more devices");
 
 	opt_set(opts, nostart, true);
@@ -82,9 +125,9 @@ int cmd_set_passphrase(int argc, char *argv[])
 	 * to make sure we're opening and updating every component device:
 	 */
 
-	c = bch2_fs_open(argv + 1, argc - 1, opts);
+	c = bch2_fs_open(argv, argc, opts);
 	if (IS_ERR(c))
-		die("Error opening %s: %s", argv[1], strerror(-PTR_ERR(c)));
+		die("Error opening %s: %s", argv[0], strerror(-PTR_ERR(c)));
 
 	struct bch_sb_field_crypt *crypt = bch2_sb_get_crypt(c->disk_sb.sb);
 	if (!crypt)
@@ -97,31 +140,70 @@ int cmd_set_passphrase(int argc, char *argv[])
 	if (ret)
 		die("Error getting current key");
 
-	char *new_passphrase = read_passphrase_twice("Enter new passphrase: ");
+	char *new_passphrase = keyfile
+		? read_keyfile(keyfile)
+		: read_passphrase_twice("Enter new passphrase: ");
 	struct bch_key passphrase_key = derive_passphrase(crypt, new_passphrase);
 
+	memzero_explicit(new_passphrase, strlen(new_passphrase));
+	free(new_passphrase);
+
 	if (bch2_chacha_encrypt_key(&passphrase_key, __bch2_sb_key_nonce(c->disk_sb.sb),
 				    &new_key, sizeof(new_key)))
 		die("error encrypting key");
 	crypt->key = new_key;
 
 	bch2_write_super(c);
+
+	u64 seq = le64_to_cpu(c->disk_sb.sb->seq);
 	bch2_fs_stop(c);
+
+	if (verify)
+		bch2_super_verify(argv, argc, seq);
 	return 0;
 }
 
+static void remove_passphrase_usage(void)
+{
+	puts("bcachefs remove-passphrase - remove passphrase on an existing (unmounted) filesystem\n"
+	     "Usage: bcachefs remove-passphrase [OPTION] devices\n"
+	     "\n"
+	     "Options:\n"
+	     "  --verify-after-write   Reread every copy of the superblock on every device\n"
+	     "                         afterwards, checking they were all updated\n"
+	     "  -h                     Display this help and exit\n"
+	     "Report bugs to <linux-bcache@vger.kernel.org>");
+}
+
 int cmd_remove_passphrase(int argc, char *argv[])
 {
+	static const struct option longopts[] = {
+		{ "verify-after-write",	no_argument,		NULL, 'V' },
+		{ NULL }
+	};
 	struct bch_opts opts = bch2_opts_empty();
 	struct bch_fs *c;
+	bool verify = false;
+	int opt;
 
-	if (argc < 2)
+	while ((opt = getopt_long(argc, argv, "h", longopts, NULL)) != -1)
+		switch (opt) {
+		case 'V':
+			verify = true;
+			break;
+		case 'h':
+			remove_passphrase_usage();
+			exit(EXIT_SUCCESS);
+		}
+	args_shift(optind);
+
+	if (!argc)
 		die("Please supply one or more devices");
 
 	opt_set(opts, nostart, true);
-	c = bch2_fs_open(argv + 1, argc - 1, opts);
+	c = bch2_fs_open(argv, argc, opts);
 	if (IS_ERR(c))
-		die("Error opening %s: %s", argv[1], strerror(-PTR_ERR(c)));
+		die("Error opening %s: %s", argv[0], strerror(-PTR_ERR(c)));
 
 	struct bch_sb_field_crypt *crypt = bch2_sb_get_crypt(c->disk_sb.sb);
 	if (!crypt)
@@ -137,6 +219,48 @@ int cmd_remove_passphrase(int argc, char *argv[])
 	crypt->key = new_key;
 
 	bch2_write_super(c);
+
+	u64 seq = le64_to_cpu(c->disk_sb.sb->seq);
 	bch2_fs_stop(c);
+
+	if (verify)
+		bch2_super_verify(argv, argc, seq);
+	return 0;
+}
+
+static void generate_keyfile_usage(void)
+{
+	puts("bcachefs key generate-keyfile - generate a random keyfile\n"
+	     "Usage: bcachefs key generate-keyfile [OPTION] file\n"
+	     "\n"
+	     "The keyfile can be used instead of a passphrase with format --keyfile,\n"
+	     "set-passphrase -k, unlock -k, and by mount.bcachefs with\n"
+	     "-o unlock=keyfile:<file>\n"
+	     "\n"
+	     "Options:\n"
+	     "  -h                     Display this help and exit\n"
+	     "Report bugs to <linux-bcache@vger.kernel.org>");
+}
+
+int cmd_key_generate_keyfile(int argc, char *argv[])
+{
+	int opt;
+
+	while ((opt = getopt(argc, argv, "h")) != -1)
+		switch (opt) {
+		case 'h':
+			generate_keyfile_usage();
+			exit(EXIT_SUCCESS);
+		}
+	args_shift(optind);
+
+	char *path = arg_pop();
+	if (!path)
+		die("Please supply a file");
+
+	if (argc)
+		die("Too many arguments");
+
+	generate_keyfile(path);
 	return 0;
 }
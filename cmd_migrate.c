@@ -270,13 +270,15 @@ static void write_data(struct bch_fs *c,
 
 static void copy_data(struct bch_fs *c,
 		      struct bch_inode_unpacked *dst_inode,
-		      int src_fd, u64 start, u64 end)
+		      int src_fd, u64 start, u64 end,
+		      struct io_rate_limit *limit)
 {
 	while (start < end) {
 		unsigned len = min_t(u64, end - start, sizeof(buf));
 		unsigned pad = round_up(len, block_bytes(c)) - len;
 
 		xpread(src_fd, buf, len, start);
+		io_rate_limit(limit, len);
 		memset(buf + len, 0, pad);
 
 		write_data(c, dst_inode, start, buf, len + pad);
@@ -344,18 +346,21 @@ static void link_data(struct bch_fs *c, struct bch_inode_unpacked *dst,
 }
 
 static void copy_link(struct bch_fs *c, struct bch_inode_unpacked *dst,
-		      char *src)
+		      char *src, struct io_rate_limit *limit)
 {
 	ssize_t ret = readlink(src, buf, sizeof(buf));
 	if (ret < 0)
 		die("readlink error: %m");
 
+	io_rate_limit(limit, ret);
+
 	write_data(c, dst, 0, buf, round_up(ret, block_bytes(c)));
 }
 
 static void copy_file(struct bch_fs *c, struct bch_inode_unpacked *dst,
 		      int src_fd, u64 src_size,
-		      char *src_path, ranges *extents)
+		      char *src_path, ranges *extents,
+		      struct io_rate_limit *limit)
 {
 	struct fiemap_iter iter;
 	struct fiemap_extent e;
@@ -377,7 +382,7 @@ static void copy_file(struct bch_fs *c, struct bch_inode_unpacked *dst,
 				  FIEMAP_EXTENT_DATA_INLINE)) {
 			copy_data(c, dst, src_fd, e.fe_logical,
 				  min(src_size - e.fe_logical,
-				      e.fe_length));
+				      e.fe_length), limit);
 			continue;
 		}
 
@@ -388,7 +393,7 @@ static void copy_file(struct bch_fs *c, struct bch_inode_unpacked *dst,
 		if (e.fe_physical < 1 << 20) {
 			copy_data(c, dst, src_fd, e.fe_logical,
 				  min(src_size - e.fe_logical,
-				      e.fe_length));
+				      e.fe_length), limit);
 			continue;
 		}
 
@@ -406,6 +411,12 @@ struct copy_fs_state {
 
 	GENRADIX(u64)		hardlinks;
 	ranges			extents;
+
+	/* bytes allocated to the files copied so far, of the total in use: */
+	u64			bytes_done;
+	u64			bytes_total;
+	struct progress_fd	*progress;
+	struct io_rate_limit	*limit;
 };
 
 static void copy_dir(struct copy_fs_state *s,
@@ -468,13 +479,13 @@ static void copy_dir(struct copy_fs_state *s,
 
 			fd = xopen(d->d_name, O_RDONLY|O_NOATIME);
 			copy_file(c, &inode, fd, stat.st_size,
-				  child_path, &s->extents);
+				  child_path, &s->extents, s->limit);
 			close(fd);
 			break;
 		case DT_LNK:
 			inode.bi_size = stat.st_size;
 
-			copy_link(c, &inode, d->d_name);
+			copy_link(c, &inode, d->d_name, s->limit);
 			break;
 		case DT_FIFO:
 		case DT_CHR:
@@ -489,6 +500,8 @@ static void copy_dir(struct copy_fs_state *s,
 
 		update_inode(c, &inode);
 next:
+		s->bytes_done += stat.st_blocks << 9;
+		progress_update(s->progress, s->bytes_done, s->bytes_total);
 		free(child_path);
 	}
 
@@ -564,7 +577,9 @@ static void reserve_old_fs_space(struct bch_fs *c,
 }
 
 static void copy_fs(struct bch_fs *c, int src_fd, const char *src_path,
-		    u64 bcachefs_inum, ranges *extents)
+		    u64 bcachefs_inum, ranges *extents,
+		    struct io_rate_limit *limit,
+		    struct progress_fd *progress)
 {
 	syncfs(src_fd);
 
@@ -584,10 +599,26 @@ static void copy_fs(struct bch_fs *c, int src_fd, const char *src_path,
 		.bcachefs_inum	= bcachefs_inum,
 		.dev		= stat.st_dev,
 		.extents	= *extents,
+		.progress	= progress,
+		.limit		= limit,
 	};
 
+	/* Everything in use except the new filesystem's metadata file: */
+	struct statfs statfs;
+	if (fstatfs(src_fd, &statfs))
+		die("statfs error: %m");
+
+	struct range *r;
+	u64 reserved = 0;
+	darray_foreach(r, *extents)
+		reserved += r->end - r->start;
+
+	s.bytes_total = (u64) (statfs.f_blocks - statfs.f_bfree) * statfs.f_bsize;
+	s.bytes_total -= min(s.bytes_total, reserved);
+
 	/* now, copy: */
 	copy_dir(&s, c, &root_inode, src_fd, src_path);
+	progress_update(progress, s.bytes_total, s.bytes_total);
 
 	reserve_old_fs_space(c, &root_inode, &s.extents);
 
@@ -632,6 +663,11 @@ static void migrate_usage(void)
 	     "      --encrypted        Enable whole filesystem encryption (chacha20/poly1305)\n"
 	     "      --no_passphrase    Don't encrypt master encryption key\n"
 	     "  -F                     Force, even if metadata file already exists\n"
+	     "      --progress-fd=fd   Also write progress copying files to fd, as a\n"
+	     "                         JSON object per line\n"
+	     "      --rate=rate        Read at most rate bytes per second copying\n"
+	     "                         files, e.g. 100M\n"
+	     "      --iops=iops        Do at most iops reads per second copying files\n"
 	     "  -h                     Display this help and exit\n"
 	     "Report bugs to <linux-bcache@vger.kernel.org>");
 }
@@ -639,6 +675,9 @@ static void migrate_usage(void)
 static const struct option migrate_opts[] = {
 	{ "encrypted",		no_argument, NULL, 'e' },
 	{ "no_passphrase",	no_argument, NULL, 'p' },
+	{ "progress-fd",	required_argument, NULL, 'P' },
+	{ "rate",		required_argument, NULL, 'r' },
+	{ "iops",		required_argument, NULL, 'i' },
 	{ NULL }
 };
 
@@ -646,6 +685,8 @@ static int migrate_fs(const char		*fs_path,
 		      struct bch_opt_strs	fs_opt_strs,
 		      struct bch_opts		fs_opts,
 		      struct format_opts	format_opts,
+		      struct io_rate_limit	*limit,
+		      struct progress_fd	*progress,
 		      bool force)
 {
 	if (!path_is_fs_root(fs_path))
@@ -705,9 +746,10 @@ static int migrate_fs(const char		*fs_path,
 	if (ret)
 		die("Error starting new filesystem: %s", strerror(-ret));
 
-	copy_fs(c, fs_fd, fs_path, bcachefs_inum, &extents);
+	copy_fs(c, fs_fd, fs_path, bcachefs_inum, &extents, limit, progress);
 
 	bch2_fs_stop(c);
+	progress_done(progress);
 
 	printf("Migrate complete, running fsck:\n");
 	opt_set(opts, nostart,	false);
@@ -740,6 +782,8 @@ int cmd_migrate(int argc, char *argv[])
 {
 	struct format_opts format_opts = format_opts_default();
 	char *fs_path = NULL;
+	struct io_rate_limit limit = { 0 };
+	struct progress_fd progress = { .fd = -1 };
 	bool no_passphrase = false, force = false;
 	int opt;
 
@@ -762,6 +806,19 @@ int cmd_migrate(int argc, char *argv[])
 		case 'F':
 			force = true;
 			break;
+		case 'P':
+			if (kstrtoint(optarg, 10, &progress.fd) ||
+			    fcntl(progress.fd, F_GETFD) < 0)
+				die("invalid progress fd %s", optarg);
+			break;
+		case 'r':
+			if (bch2_strtoull_h(optarg, &limit.bytes_per_sec))
+				die("invalid rate %s", optarg);
+			break;
+		case 'i':
+			if (kstrtoull(optarg, 10, &limit.ios_per_sec))
+				die("invalid iops %s", optarg);
+			break;
 		case 'h':
 			migrate_usage();
 			exit(EXIT_SUCCESS);
@@ -776,7 +833,7 @@ int cmd_migrate(int argc, char *argv[])
 	int ret = migrate_fs(fs_path,
 			     fs_opt_strs,
 			     fs_opts,
-			     format_opts, force);
+			     format_opts, &limit, &progress, force);
 	bch2_opt_strs_free(&fs_opt_strs);
 	return ret;
 }
@@ -0,0 +1,199 @@
+#include <errno.h>
+#include <fcntl.h>
+#include <getopt.h>
+#include <stdio.h>
+#include <string.h>
+#include <sys/stat.h>
+#include <sys/types.h>
+#include <unistd.h>
+
+#include "cmds.h"
+#include "libbcachefs.h"
+
+#include "libbcachefs/bcachefs.h"
+#include "libbcachefs/opts.h"
+#include "libbcachefs/super-io.h"
+
+struct opt_val {
+	int		id;
+	const char	*name;
+	const char	*val;
+};
+
+/* Splits a name=value argument, checking the option can be set this way: */
+static struct opt_val opt_val_parse(char *arg, bool persist)
+{
+	char *val = strchr(arg, '=');
+
+	if (!val)
+		die("%s: expected <option>=<value>", arg);
+	*val++ = '\0';
+
+	int id = bch2_opt_lookup(arg);
+	if (id < 0)
+		die("Unknown option %s", arg);
+
+	const struct bch_option *opt = bch2_opt_table + id;
+	bool in_sb = opt->set_sb != SET_NO_SB_OPT;
+
+	if (persist && !in_sb)
+		die("%s isn't stored in the superblock", arg);
+	if (!persist && in_sb)
+		die("%s is stored in the superblock when changed; use --persist", arg);
+	if (!(opt->mode & (OPT_MOUNT|OPT_RUNTIME)))
+		die("%s can only be set at format time", arg);
+
+	return (struct opt_val) { .id = id, .name = arg, .val = val };
+}
+
+/*
+ * On a mounted filesystem options are set via sysfs; the kernel writes the
+ * options it stores in the superblock there too:
+ */
+static void set_options_online(const char *path, struct opt_val *opts,
+			       unsigned nr)
+{
+	struct bchfs_handle fs = bcache_fs_open(path);
+	unsigned i;
+
+	for (i = 0; i < nr; i++) {
+		if (!(bch2_opt_table[opts[i].id].mode & OPT_RUNTIME))
+			die("%s can't be changed while mounted", opts[i].name);
+
+		char *attr = mprintf("options/%s", opts[i].name);
+		int fd = openat(fs.sysfs_fd, attr, O_WRONLY);
+		if (fd < 0)
+			die("error opening %s: %m", attr);
+
+		if (write(fd, opts[i].val, strlen(opts[i].val)) < 0)
+			die("error setting %s=%s: %m", opts[i].name, opts[i].val);
+
+		close(fd);
+		free(attr);
+	}
+
+	bcache_fs_close(fs);
+}
+
+static void set_options_offline(char **devs, unsigned nr_devs,
+				struct opt_val *opts, unsigned nr, bool verify)
+{
+	struct bch_opts fs_opts = bch2_opts_empty();
+	unsigned i;
+
+	opt_set(fs_opts, nostart, true);
+
+	/* bch2_fs_open() so that every member's superblock gets updated: */
+	struct bch_fs *c = bch2_fs_open(devs, nr_devs, fs_opts);
+	if (IS_ERR(c))
+		die("Error opening %s: %s", devs[0], strerror(-PTR_ERR(c)));
+
+	for (i = 0; i < nr; i++) {
+		const struct bch_option *opt = bch2_opt_table + opts[i].id;
+		u64 v;
+		int ret;
+
+		ret = bch2_opt_parse(c, opt, opts[i].val, &v);
+		if (ret < 0)
+			die("Invalid value %s for %s: %s",
+			    opts[i].val, opts[i].name, strerror(-ret));
+
+		ret = bch2_opt_check_may_set(c, opts[i].id, v);
+		if (ret < 0)
+			die("Can't set %s=%s: %s",
+			    opts[i].name, opts[i].val, strerror(-ret));
+
+		mutex_lock(&c->sb_lock);
+		opt->set_sb(c->disk_sb.sb, v);
+		mutex_unlock(&c->sb_lock);
+	}
+
+	mutex_lock(&c->sb_lock);
+	bch2_write_super(c);
+	mutex_unlock(&c->sb_lock);
+
+	u64 seq = le64_to_cpu(c->disk_sb.sb->seq);
+	bch2_fs_stop(c);
+
+	if (verify)
+		bch2_super_verify(devs, nr_devs, seq);
+}
+
+static void set_fs_option_usage(void)
+{
+	puts("bcachefs set-fs-option - set filesystem wide options\n"
+	     "Usage: bcachefs set-fs-option [OPTION]... <option>=<value>... <mountpoint>\n"
+	     "   or: bcachefs set-fs-option [OPTION]... <option>=<value>... <devices>...\n"
+	     "\n"
+	     "Sets options on a mounted filesystem - given its mountpoint or one of its\n"
+	     "devices - or on an unmounted filesystem, given all of its devices.\n"
+	     "\n"
+	     "Options:\n"
+	     "  -p, --persist               Store the values in the superblock, so that they\n"
+	     "                              apply on every future mount. Required for options\n"
+	     "                              stored in the superblock: the kernel always\n"
+	     "                              stores them there when changed\n"
+	     "      --verify-after-write    On an unmounted filesystem, reread every copy of\n"
+	     "                              the superblock on every device afterwards,\n"
+	     "                              checking they were all updated\n"
+	     "  -h, --help                  display this help and exit\n"
+	     "Report bugs to <linux-bcache@vger.kernel.org>");
+	exit(EXIT_SUCCESS);
+}
+
+int cmd_set_fs_option(int argc, char *argv[])
+{
+	static const struct option longopts[] = {
+		{ "persist",		no_argument,		NULL, 'p' },
+		{ "verify-after-write",	no_argument,		NULL, 'V' },
+		{ "help",		no_argument,		NULL, 'h' },
+		{ NULL }
+	};
+	struct opt_val *opts;
+	bool persist = false, verify = false;
+	unsigned nr = 0;
+	int opt;
+
+	while ((opt = getopt_long(argc, argv, "ph", longopts, NULL)) != -1)
+		switch (opt) {
+		case 'p':
+			persist = true;
+			break;
+		case 'V':
+			verify = true;
+			break;
+		case 'h':
+			set_fs_option_usage();
+		}
+	args_shift(optind);
+
+	opts = xcalloc(argc, sizeof(*opts));
+
+	while (argc && strchr(argv[0], '='))
+		opts[nr++] = opt_val_parse(arg_pop(), persist);
+
+	if (!nr)
+		die("Please supply one or more options");
+	if (!argc)
+		die("Please supply a mountpoint or devices");
+
+	struct stat st = xstat(argv[0]);
+	struct mntent *mount;
+
+	if (S_ISDIR(st.st_mode)) {
+		if (argc > 1)
+			die("Too many arguments");
+		set_options_online(argv[0], opts, nr);
+	} else if ((mount = dev_to_mount(argv[0]))) {
+		if (argc > 1)
+			die("Too many arguments");
+		set_options_online(mount->mnt_dir, opts, nr);
+	} else {
+		if (!persist)
+			die("Options can only be set on an unmounted filesystem with --persist");
+		set_options_offline(argv, argc, opts, nr, verify);
+	}
+
+	free(opts);
+	return 0;
+}
@@ -0,0 +1,271 @@
+#include <getopt.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <sys/stat.h>
+#include <sys/types.h>
+#include <time.h>
+
+#include "cmds.h"
+#include "crypto.h"
+#include "libbcachefs.h"
+
+#include "libbcachefs/bcachefs.h"
+#include "libbcachefs/btree_iter.h"
+#include "libbcachefs/btree_update.h"
+#include "libbcachefs/dirent.h"
+#include "libbcachefs/error.h"
+#include "libbcachefs/inode.h"
+#include "libbcachefs/str_hash.h"
+
+#define QSTR(n) { { { .len = strlen(n) } }, .name = n }
+
+typedef darray(u64) inums;
+typedef darray(struct bch_inode_unpacked) inodes;
+
+static int inum_cmp(const void *_l, const void *_r)
+{
+	const u64 *l = _l, *r = _r;
+
+	return cmp_int(*l, *r);
+}
+
+/* Every inode some dirent points to, sorted: */
+static inums dirent_targets(struct bch_fs *c)
+{
+	struct btree_trans trans;
+	struct btree_iter *iter;
+	struct bkey_s_c k;
+	inums targets;
+	int ret;
+
+	darray_init(targets);
+	bch2_trans_init(&trans, c, 0, 0);
+
+	for_each_btree_key(&trans, iter, BTREE_ID_dirents, POS_MIN,
+			   BTREE_ITER_PREFETCH, k, ret)
+		if (k.k->type == KEY_TYPE_dirent)
+			darray_append(targets,
+				le64_to_cpu(bkey_s_c_to_dirent(k).v->d_inum));
+	bch2_trans_iter_put(&trans, iter);
+
+	ret = bch2_trans_exit(&trans) ?: ret;
+	if (ret)
+		die("error walking dirents: %s", strerror(-ret));
+
+	qsort(targets.item, darray_size(targets), sizeof(u64), inum_cmp);
+	return targets;
+}
+
+static inodes find_orphans(struct bch_fs *c)
+{
+	struct btree_trans trans;
+	struct btree_iter *iter;
+	struct bkey_s_c k;
+	inums targets = dirent_targets(c);
+	inodes orphans;
+	int ret;
+
+	darray_init(orphans);
+	bch2_trans_init(&trans, c, 0, 0);
+
+	for_each_btree_key(&trans, iter, BTREE_ID_inodes, POS_MIN,
+			   BTREE_ITER_PREFETCH, k, ret) {
+		struct bch_inode_unpacked u;
+		u64 inum = k.k->p.offset;
+
+		if (k.k->type != KEY_TYPE_inode ||
+		    inum == BCACHEFS_ROOT_INO ||
+		    bsearch(&inum, targets.item, darray_size(targets),
+			    sizeof(u64), inum_cmp))
+			continue;
+
+		if (bch2_inode_unpack(bkey_s_c_to_inode(k), &u))
+			die("error unpacking inode %llu", inum);
+
+		darray_append(orphans, u);
+	}
+	bch2_trans_iter_put(&trans, iter);
+
+	ret = bch2_trans_exit(&trans) ?: ret;
+	if (ret)
+		die("error walking inodes: %s", strerror(-ret));
+
+	darray_free(targets);
+	return orphans;
+}
+
+static void print_orphan(struct bch_fs *c, struct bch_inode_unpacked *u)
+{
+	struct timespec64 ts = bch2_time_to_timespec(c, u->bi_mtime);
+	time_t secs = ts.tv_sec;
+	char mtime[64];
+
+	strftime(mtime, sizeof(mtime), "%F %T", localtime(&secs));
+
+	printf("%-12llu %-8s %12llu  %s%s\n",
+	       u->bi_inum,
+	       S_ISDIR(u->bi_mode) ? "dir" :
+	       S_ISREG(u->bi_mode) ? "file" :
+	       S_ISLNK(u->bi_mode) ? "symlink" : "special",
+	       u->bi_size, mtime,
+	       u->bi_flags & BCH_INODE_UNLINKED ? "  (unlinked, pending deletion)" : "");
+}
+
+static int __reattach(struct btree_trans *trans,
+		      struct bch_inode_unpacked dir,
+		      struct bch_hash_info *dir_hash,
+		      struct bch_inode_unpacked inode)
+{
+	struct btree_iter *iter;
+	char name_buf[20];
+	struct qstr name;
+	u64 dir_offset = 0;
+	int ret;
+
+	snprintf(name_buf, sizeof(name_buf), "%llu", inode.bi_inum);
+	name = (struct qstr) QSTR(name_buf);
+
+	ret = bch2_dirent_create(trans, dir.bi_inum, dir_hash,
+				 mode_to_type(inode.bi_mode),
+				 &name, inode.bi_inum, &dir_offset,
+				 BCH_HASH_SET_MUST_CREATE);
+	if (ret)
+		return ret;
+
+	inode.bi_dir		= dir.bi_inum;
+	inode.bi_dir_offset	= dir_offset;
+
+	iter = bch2_trans_get_iter(trans, BTREE_ID_inodes,
+				   POS(0, inode.bi_inum), BTREE_ITER_INTENT);
+	ret = bch2_inode_write(trans, iter, &inode);
+	bch2_trans_iter_put(trans, iter);
+	if (ret || !S_ISDIR(inode.bi_mode))
+		return ret;
+
+	dir.bi_nlink++;
+
+	iter = bch2_trans_get_iter(trans, BTREE_ID_inodes,
+				   POS(0, dir.bi_inum), BTREE_ITER_INTENT);
+	ret = bch2_inode_write(trans, iter, &dir);
+	bch2_trans_iter_put(trans, iter);
+	return ret;
+}
+
+/* Like fsck, orphans are linked into the directory under their inode number: */
+static void reattach(struct bch_fs *c, inodes *orphans, const char *dir_name)
+{
+	struct bch_inode_unpacked dir, *u;
+	struct bch_hash_info hash;
+	u64 dir_inum = bch2_path_to_inum(c, dir_name);
+	int ret;
+
+	darray_foreach(u, *orphans) {
+		if (u->bi_flags & BCH_INODE_UNLINKED)
+			continue;
+
+		/* Reread every time, for its nlink: */
+		ret = bch2_inode_find_by_inum(c, dir_inum, &dir);
+		if (ret)
+			die("error looking up %s: %s", dir_name, strerror(-ret));
+
+		if (!S_ISDIR(dir.bi_mode))
+			die("%s: not a directory", dir_name);
+
+		if (u->bi_inum == dir_inum)
+			die("%s is itself unreachable", dir_name);
+
+		hash = bch2_hash_info_init(c, &dir);
+
+		ret = bch2_trans_do(c, NULL, NULL, BTREE_INSERT_NOFAIL,
+				    __reattach(&trans, dir, &hash, *u));
+		if (ret)
+			die("error reattaching inode %llu: %s",
+			    u->bi_inum, strerror(-ret));
+
+		printf("reattached %llu as %s/%llu\n",
+		       u->bi_inum, dir_name, u->bi_inum);
+	}
+}
+
+static void orphans_usage(void)
+{
+	puts("bcachefs orphans - list inodes not reachable from any directory\n"
+	     "Usage: bcachefs orphans [OPTION]... <devices>...\n"
+	     "\n"
+	     "Lists the inodes of an unmounted filesystem that no dirent points to,\n"
+	     "with their type, size and mtime. Inodes marked unlinked are files that\n"
+	     "were deleted while still open, and are deleted on the next mount.\n"
+	     "\n"
+	     "Options:\n"
+	     "  -r, --reattach-to=dir       Link the orphans into dir, a directory in\n"
+	     "                              the root of the filesystem (e.g. lost+found),\n"
+	     "                              named by inode number\n"
+	     "  -k, --passphrase-file=file  Read the passphrase of an encrypted filesystem\n"
+	     "                              from file\n"
+	     "  -h, --help                  display this help and exit\n"
+	     "Report bugs to <linux-bcache@vger.kernel.org>");
+	exit(EXIT_SUCCESS);
+}
+
+int cmd_orphans(int argc, char *argv[])
+{
+	static const struct option longopts[] = {
+		{ "reattach-to",	required_argument,	NULL, 'r' },
+		{ "passphrase-file",	required_argument,	NULL, 'k' },
+		{ "help",		no_argument,		NULL, 'h' },
+		{ NULL }
+	};
+	struct bch_opts opts = bch2_opts_empty();
+	char *passphrase_file = NULL, *reattach_to = NULL;
+	struct bch_inode_unpacked *u;
+	int opt;
+
+	while ((opt = getopt_long(argc, argv, "r:k:h", longopts, NULL)) != -1)
+		switch (opt) {
+		case 'r':
+			if (strchr(optarg, '/'))
+				die("%s: must be a directory in the root of the filesystem",
+				    optarg);
+			reattach_to = optarg;
+			break;
+		case 'k':
+			passphrase_file = optarg;
+			break;
+		case 'h':
+			orphans_usage();
+		}
+	args_shift(optind);
+
+	if (!argc)
+		die("Please supply device(s)");
+
+	if (!reattach_to) {
+		opt_set(opts, nochanges,	true);
+		opt_set(opts, norecovery,	true);
+		opt_set(opts, degraded,		true);
+		opt_set(opts, errors,		BCH_ON_ERROR_continue);
+	}
+
+	bch2_add_key_offline(argv[0], passphrase_file);
+
+	struct bch_fs *c = bch2_fs_open(argv, argc, opts);
+	if (IS_ERR(c))
+		die("error opening %s: %s", argv[0], strerror(-PTR_ERR(c)));
+
+	inodes orphans = find_orphans(c);
+
+	if (darray_size(orphans)) {
+		printf("%-12s %-8s %12s  %s\n", "inode", "type", "size", "mtime");
+		darray_foreach(u, orphans)
+			print_orphan(c, u);
+	}
+	printf("%zu unreachable inodes\n", darray_size(orphans));
+
+	if (reattach_to)
+		reattach(c, &orphans, reattach_to);
+
+	darray_free(orphans);
+	bch2_fs_stop(c);
+	return 0;
+}
@@ -0,0 +1,397 @@
+#include <errno.h>
+#include <fcntl.h>
+#include <getopt.h>
+#include <grp.h>
+#include <pwd.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <sys/quota.h>
+#include <sys/syscall.h>
+#include <time.h>
+#include <unistd.h>
+
+#include <uuid/uuid.h>
+
+#include "ccan/darray/darray.h"
+
+#include "cmds.h"
+#include "libbcachefs.h"
+#include "notify.h"
+#include "sandbox.h"
+
+#ifndef __NR_quotactl_fd
+#define __NR_quotactl_fd	443
+#endif
+
+/*
+ * The kernel enforces hard limits, and soft limits once their grace period
+ * is over, but doesn't tell anyone when a soft limit is first exceeded: so
+ * usage is polled, and events are reported when it crosses a limit.
+ */
+
+static const char * const quota_types[] = {
+	[USRQUOTA]	= "user",
+	[GRPQUOTA]	= "group",
+	[PRJQUOTA]	= "project",
+};
+
+enum quota_resource {
+	QUOTA_SPACE,
+	QUOTA_INODES,
+};
+
+static const char * const quota_resources[] = {
+	[QUOTA_SPACE]	= "space",
+	[QUOTA_INODES]	= "inodes",
+};
+
+/* In increasing order of severity: */
+enum quota_state {
+	QUOTA_OK,
+	QUOTA_SOFT,
+	QUOTA_GRACE_EXPIRED,
+	QUOTA_HARD,
+};
+
+static const char * const quota_events[] = {
+	[QUOTA_OK]		= "quota-ok",
+	[QUOTA_SOFT]		= "quota-soft-exceeded",
+	[QUOTA_GRACE_EXPIRED]	= "quota-grace-expired",
+	[QUOTA_HARD]		= "quota-hard-reached",
+};
+
+struct quota_usage {
+	u64		cur;
+	u64		soft;
+	u64		hard;
+	u64		grace;		/* when the soft limit is enforced */
+};
+
+/* Only ids that have been over a limit are tracked: */
+struct quota_tracked {
+	unsigned		type;
+	enum quota_resource	res;
+	u32			id;
+	enum quota_state	state;
+	time_t			notified;
+};
+
+struct quota_warnd {
+	int			fd;
+	char			uuid[40];
+	struct notify_opts	notify;
+	unsigned		repeat;
+	darray(struct quota_tracked) tracked;
+};
+
+static int quotactl_fd(int fd, int cmd, int id, void *addr)
+{
+	return syscall(__NR_quotactl_fd, fd, cmd, id, addr);
+}
+
+/* Accounting may be turned on or off while we run: */
+static bool quota_type_enabled(struct quota_warnd *d, unsigned type)
+{
+	struct if_dqinfo info;
+
+	if (!quotactl_fd(d->fd, QCMD(Q_GETINFO, type), 0, &info))
+		return true;
+
+	if (errno != ESRCH)
+		die("error reading %s quota info: %m", quota_types[type]);
+	return false;
+}
+
+static enum quota_state quota_state(struct quota_usage u, time_t now)
+{
+	if (u.hard && u.cur >= u.hard)
+		return QUOTA_HARD;
+	if (u.soft && u.cur > u.soft)
+		return u.grace && now >= u.grace
+			? QUOTA_GRACE_EXPIRED
+			: QUOTA_SOFT;
+	return QUOTA_OK;
+}
+
+static char *quota_id_name(unsigned type, u32 id)
+{
+	struct passwd *pw;
+	struct group *gr;
+
+	switch (type) {
+	case USRQUOTA:
+		if ((pw = getpwuid(id)))
+			return mprintf("%s (%u)", pw->pw_name, id);
+		break;
+	case GRPQUOTA:
+		if ((gr = getgrgid(id)))
+			return mprintf("%s (%u)", gr->gr_name, id);
+		break;
+	}
+
+	return mprintf("%u", id);
+}
+
+static void setenv_u64(const char *name, u64 v)
+{
+	char buf[24];
+
+	snprintf(buf, sizeof(buf), "%llu", v);
+	setenv(name, buf, 1);
+}
+
+static char *quota_amount(enum quota_resource res, u64 v)
+{
+	return res == QUOTA_SPACE
+		? strdup(pr_units(v >> 9, HUMAN_READABLE))
+		: mprintf("%llu", v);
+}
+
+static void quota_notify(struct quota_warnd *d, unsigned type,
+			 enum quota_resource res, u32 id,
+			 struct quota_usage u, enum quota_state state)
+{
+	char *name	= quota_id_name(type, id);
+	char *cur	= quota_amount(res, u.cur);
+	char *soft	= quota_amount(res, u.soft);
+	char *hard	= quota_amount(res, u.hard);
+	char *msg	= mprintf("%s %s %s: %s %s used, soft limit %s, hard limit %s",
+				  quota_events[state], quota_types[type], name,
+				  quota_resources[res], cur,
+				  u.soft ? soft : "none", u.hard ? hard : "none");
+
+	setenv("BCACHEFS_QUOTA_TYPE",		quota_types[type], 1);
+	setenv("BCACHEFS_QUOTA_RESOURCE",	quota_resources[res], 1);
+	setenv_u64("BCACHEFS_QUOTA_ID",		id);
+	setenv_u64("BCACHEFS_QUOTA_USAGE",	u.cur);
+	setenv_u64("BCACHEFS_QUOTA_SOFT_LIMIT",	u.soft);
+	setenv_u64("BCACHEFS_QUOTA_HARD_LIMIT",	u.hard);
+	setenv_u64("BCACHEFS_QUOTA_GRACE",	u.grace);
+
+	bch_notify_msg(&d->notify, d->uuid, quota_events[state], msg);
+
+	free(msg);
+	free(hard);
+	free(soft);
+	free(cur);
+	free(name);
+}
+
+static void quota_check_one(struct quota_warnd *d, unsigned type,
+			    enum quota_resource res, u32 id,
+			    struct quota_usage u, time_t now)
+{
+	enum quota_state state = quota_state(u, now);
+	struct quota_tracked *t, new = {
+		.type	= type,
+		.res	= res,
+		.id	= id,
+		.state	= QUOTA_OK,
+	};
+
+	darray_foreach(t, d->tracked)
+		if (t->type == type && t->res == res && t->id == id)
+			goto found;
+
+	if (state == QUOTA_OK)
+		return;
+
+	darray_append(d->tracked, new);
+	t = &darray_item(d->tracked, darray_size(d->tracked) - 1);
+found:
+	if (state != t->state ||
+	    (state != QUOTA_OK && d->repeat && now - t->notified >= d->repeat)) {
+		quota_notify(d, type, res, id, u, state);
+		t->notified = now;
+	}
+
+	t->state = state;
+}
+
+/*
+ * Ids not returned by Q_GETNEXTQUOTA had their limits taken away, or no
+ * longer use anything:
+ */
+static void quota_tracked_ok(struct quota_warnd *d, unsigned type,
+			     bool *seen, size_t nr_seen, time_t now)
+{
+	size_t i;
+
+	for (i = 0; i < nr_seen; i++) {
+		struct quota_tracked *t = &darray_item(d->tracked, i);
+
+		if (t->type == type && !seen[i] && t->state != QUOTA_OK)
+			quota_check_one(d, type, t->res, t->id,
+					(struct quota_usage) { 0 }, now);
+	}
+}
+
+static void quota_check_type(struct quota_warnd *d, unsigned type)
+{
+	struct if_nextdqblk q;
+	time_t now = time(NULL);
+	size_t nr_tracked = darray_size(d->tracked);
+	bool *seen = xcalloc(nr_tracked + 1, sizeof(*seen));
+	struct quota_tracked *t;
+	u32 id = 0;
+	int ret;
+
+	while (!(ret = quotactl_fd(d->fd, QCMD(Q_GETNEXTQUOTA, type), id, &q))) {
+		struct quota_usage space = {
+			.cur	= q.dqb_curspace,
+			.soft	= q.dqb_bsoftlimit * QIF_DQBLKSIZE,
+			.hard	= q.dqb_bhardlimit * QIF_DQBLKSIZE,
+			.grace	= q.dqb_btime,
+		};
+		struct quota_usage inodes = {
+			.cur	= q.dqb_curinodes,
+			.soft	= q.dqb_isoftlimit,
+			.hard	= q.dqb_ihardlimit,
+			.grace	= q.dqb_itime,
+		};
+
+		for (t = d->tracked.item; t < d->tracked.item + nr_tracked; t++)
+			if (t->type == type && t->id == q.dqb_id)
+				seen[t - d->tracked.item] = true;
+
+		quota_check_one(d, type, QUOTA_SPACE,  q.dqb_id, space, now);
+		quota_check_one(d, type, QUOTA_INODES, q.dqb_id, inodes, now);
+
+		if (q.dqb_id == U32_MAX)
+			break;
+		id = q.dqb_id + 1;
+	}
+
+	if (ret && errno != ENOENT)
+		die("error reading %s quotas: %m", quota_types[type]);
+
+	quota_tracked_ok(d, type, seen, nr_tracked, now);
+	free(seen);
+}
+
+static void quota_warnd_usage(void)
+{
+	puts("bcachefs quota-warnd - report usage over quota soft limits\n"
+	     "Usage: bcachefs quota-warnd [OPTION]... <mountpoint>\n"
+	     "\n"
+	     "Checks the space and inode usage of every user, group and project with a\n"
+	     "quota on a mounted filesystem, and reports when one goes over its soft\n"
+	     "limit, its grace period runs out, it reaches its hard limit, or it's\n"
+	     "back under its limits: the kernel doesn't send quota warnings itself.\n"
+	     "\n"
+	     "Options:\n"
+	     "  -i, --interval=seconds      How often to check (default 60)\n"
+	     "  -r, --repeat=seconds        Report ids still over a limit again this often;\n"
+	     "                              by default, only changes are reported\n"
+	     "  -1, --once                  Check once and exit, with status 1 if any id\n"
+	     "                              is over a limit\n"
+	     "  -e, --exec=command          Run command on every event, with the\n"
+	     "                              environment variables BCACHEFS_EVENT,\n"
+	     "                              BCACHEFS_UUID, BCACHEFS_QUOTA_TYPE,\n"
+	     "                              BCACHEFS_QUOTA_ID, BCACHEFS_QUOTA_RESOURCE,\n"
+	     "                              BCACHEFS_QUOTA_USAGE, BCACHEFS_QUOTA_SOFT_LIMIT,\n"
+	     "                              BCACHEFS_QUOTA_HARD_LIMIT and\n"
+	     "                              BCACHEFS_QUOTA_GRACE set. Events are\n"
+	     "                              quota-soft-exceeded, quota-grace-expired,\n"
+	     "                              quota-hard-reached and quota-ok\n"
+	     "  -u, --user=user             Once the filesystem is open, run as user,\n"
+	     "                              keeping only CAP_SYS_ADMIN\n"
+	     "  -g, --group=group           Once the filesystem is open, run as group\n"
+	     "  -s, --syslog                Log events to syslog too\n"
+	     "  -h, --help                  display this help and exit\n"
+	     "Report bugs to <linux-bcache@vger.kernel.org>");
+	exit(EXIT_SUCCESS);
+}
+
+int cmd_quota_warnd(int argc, char *argv[])
+{
+	static const struct option longopts[] = {
+		{ "interval",		required_argument,	NULL, 'i' },
+		{ "repeat",		required_argument,	NULL, 'r' },
+		{ "once",		no_argument,		NULL, '1' },
+		{ "exec",		required_argument,	NULL, 'e' },
+		{ "syslog",		no_argument,		NULL, 's' },
+		{ "user",		required_argument,	NULL, 'u' },
+		{ "group",		required_argument,	NULL, 'g' },
+		{ "help",		no_argument,		NULL, 'h' },
+		{ NULL }
+	};
+	struct quota_warnd d = { 0 };
+	const char *user = NULL, *group = NULL;
+	struct quota_tracked *t;
+	unsigned type, interval = 60;
+	bool once = false, enabled = false, over = false;
+	int opt;
+
+	darray_init(d.tracked);
+
+	while ((opt = getopt_long(argc, argv, "i:r:1e:su:g:h", longopts, NULL)) != -1)
+		switch (opt) {
+		case 'i':
+			if (kstrtouint(optarg, 10, &interval) || !interval)
+				die("invalid interval %s", optarg);
+			break;
+		case 'r':
+			if (kstrtouint(optarg, 10, &d.repeat))
+				die("invalid repeat interval %s", optarg);
+			break;
+		case '1':
+			once = true;
+			break;
+		case 'e':
+			d.notify.exec = optarg;
+			break;
+		case 's':
+			d.notify.syslog = true;
+			break;
+		case 'u':
+			user = optarg;
+			break;
+		case 'g':
+			group = optarg;
+			break;
+		case 'h':
+			quota_warnd_usage();
+		}
+	args_shift(optind);
+
+	char *path = arg_pop();
+	if (!path)
+		die("Please supply a mountpoint");
+
+	if (argc)
+		die("Too many arguments");
+
+	struct bchfs_handle fs = bcache_fs_open(path);
+	uuid_unparse(fs.uuid.b, d.uuid);
+
+	d.fd = xopen(path, O_RDONLY|O_DIRECTORY);
+
+	/* Reading other users' quotas needs it: */
+	drop_privs(user, group, 1ULL << CAP_SYS_ADMIN);
+
+	for (type = 0; type < ARRAY_SIZE(quota_types); type++)
+		enabled |= quota_type_enabled(&d, type);
+
+	if (!enabled)
+		die("quotas aren't enabled on %s", path);
+
+	while (1) {
+		for (type = 0; type < ARRAY_SIZE(quota_types); type++)
+			if (quota_type_enabled(&d, type))
+				quota_check_type(&d, type);
+
+		if (once)
+			break;
+		sleep(interval);
+	}
+
+	darray_foreach(t, d.tracked)
+		over |= t->state != QUOTA_OK;
+
+	darray_free(d.tracked);
+	close(d.fd);
+	bcache_fs_close(fs);
+	return over;
+}
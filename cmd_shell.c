@@ -0,0 +1,452 @@
+#include <getopt.h>
+#include <stdio.h>
+#include <string.h>
+#include <sys/stat.h>
+#include <sys/types.h>
+#include <time.h>
+#include <unistd.h>
+
+#include "ccan/darray/darray.h"
+
+#include "cmds.h"
+#include "crypto.h"
+#include "libbcachefs.h"
+#include "tools-util.h"
+
+#include "libbcachefs/bcachefs.h"
+#include "libbcachefs/btree_iter.h"
+#include "libbcachefs/dirent.h"
+#include "libbcachefs/error.h"
+#include "libbcachefs/inode.h"
+#include "libbcachefs/io.h"
+#include "libbcachefs/opts.h"
+#include "libbcachefs/super.h"
+
+/*
+ * Interactive shell for inspecting an unmounted filesystem: opens the
+ * filesystem once, in nochanges mode, instead of once per command.
+ */
+
+struct shell {
+	struct bch_fs	*c;
+	char		*cwd;
+};
+
+typedef darray(char *) shell_args;
+
+/* Canonical form of a path, for prompting: */
+static char *path_normalize(const char *cwd, const char *path)
+{
+	char *p = path[0] == '/'
+		? strdup(path)
+		: mprintf("%s/%s", cwd, path);
+	char *buf = p, *name, *out = xcalloc(strlen(p) + 2, 1);
+
+	while ((name = strsep(&buf, "/"))) {
+		if (!*name || !strcmp(name, "."))
+			continue;
+
+		if (!strcmp(name, "..")) {
+			char *slash = strrchr(out, '/');
+			if (slash)
+				*slash = '\0';
+			continue;
+		}
+
+		strcat(out, "/");
+		strcat(out, name);
+	}
+
+	if (!*out)
+		strcpy(out, "/");
+	free(p);
+	return out;
+}
+
+/*
+ * Paths are resolved lexically, from the root: that way ".." works without
+ * relying on inode backpointers.
+ */
+static int path_lookup(struct shell *s, const char *path, u64 *inum)
+{
+	char *p = path_normalize(s->cwd, path);
+	int ret = __bch2_path_to_inum(s->c, p, inum);
+
+	free(p);
+	return ret;
+}
+
+static int shell_inode_get(struct shell *s, const char *path,
+			   struct bch_inode_unpacked *inode)
+{
+	u64 inum;
+	int ret = path_lookup(s, path, &inum) ?:
+		bch2_inode_find_by_inum(s->c, inum, inode);
+
+	if (ret)
+		fprintf(stderr, "%s: %s\n", path, strerror(-ret));
+	return ret;
+}
+
+static int shell_filldir(struct dir_context *_ctx,
+			 const char *name, int namelen,
+			 loff_t pos, u64 ino, unsigned type)
+{
+	static const char type_chars[] = {
+		[DT_FIFO]	= 'p',
+		[DT_CHR]	= 'c',
+		[DT_DIR]	= 'd',
+		[DT_BLK]	= 'b',
+		[DT_REG]	= '-',
+		[DT_LNK]	= 'l',
+		[DT_SOCK]	= 's',
+	};
+
+	printf("%c %10llu  %.*s\n",
+	       type < ARRAY_SIZE(type_chars) && type_chars[type]
+	       ? type_chars[type] : '?',
+	       ino, namelen, name);
+	return 0;
+}
+
+static void shell_ls(struct shell *s, shell_args *args)
+{
+	const char *path = darray_size(*args) > 1 ? darray_item(*args, 1) : ".";
+	struct bch_inode_unpacked inode;
+	struct dir_context ctx = { .actor = shell_filldir };
+	int ret;
+
+	if (shell_inode_get(s, path, &inode))
+		return;
+
+	if (!S_ISDIR(inode.bi_mode)) {
+		fprintf(stderr, "%s: %s\n", path, strerror(ENOTDIR));
+		return;
+	}
+
+	ret = bch2_readdir(s->c, inode.bi_inum, &ctx);
+	if (ret)
+		fprintf(stderr, "error reading %s: %s\n", path, strerror(-ret));
+}
+
+static void shell_cd(struct shell *s, shell_args *args)
+{
+	const char *path = darray_size(*args) > 1 ? darray_item(*args, 1) : "/";
+	struct bch_inode_unpacked inode;
+
+	if (shell_inode_get(s, path, &inode))
+		return;
+
+	if (!S_ISDIR(inode.bi_mode)) {
+		fprintf(stderr, "%s: %s\n", path, strerror(ENOTDIR));
+		return;
+	}
+
+	char *cwd = path_normalize(s->cwd, path);
+	free(s->cwd);
+	s->cwd = cwd;
+}
+
+static void print_time(struct bch_fs *c, const char *name, s64 t)
+{
+	struct timespec64 ts = bch2_time_to_timespec(c, t);
+	time_t secs = ts.tv_sec;
+	char buf[64];
+
+	strftime(buf, sizeof(buf), "%F %T", localtime(&secs));
+	printf("%-12s%s.%09lu\n", name, buf, (unsigned long) ts.tv_nsec);
+}
+
+static void shell_stat(struct shell *s, shell_args *args)
+{
+	struct bch_inode_unpacked inode;
+
+	if (darray_size(*args) < 2) {
+		fprintf(stderr, "Usage: stat <path>\n");
+		return;
+	}
+
+	if (shell_inode_get(s, darray_item(*args, 1), &inode))
+		return;
+
+	printf("%-12s%llu\n",	"inode:",	inode.bi_inum);
+	printf("%-12s%o\n",	"mode:",	inode.bi_mode);
+	printf("%-12s%llu\n",	"size:",	inode.bi_size);
+	printf("%-12s%llu\n",	"sectors:",	inode.bi_sectors);
+	printf("%-12s%u\n",	"nlink:",	bch2_inode_nlink_get(&inode));
+	printf("%-12s%u\n",	"uid:",		inode.bi_uid);
+	printf("%-12s%u\n",	"gid:",		inode.bi_gid);
+	printf("%-12s%u\n",	"generation:",	inode.bi_generation);
+	print_time(s->c, "atime:",	inode.bi_atime);
+	print_time(s->c, "mtime:",	inode.bi_mtime);
+	print_time(s->c, "ctime:",	inode.bi_ctime);
+	print_time(s->c, "otime:",	inode.bi_otime);
+}
+
+static void shell_read_endio(struct bio *bio)
+{
+	closure_put(bio->bi_private);
+}
+
+static int shell_read(struct bch_fs *c, struct bch_inode_unpacked *inode,
+		      void *buf, size_t size, u64 offset)
+{
+	struct bch_io_opts io_opts = bch2_opts_to_inode_opts(c->opts);
+	struct bch_read_bio rbio;
+	struct bio_vec bv;
+	struct closure cl;
+
+	bch2_io_opts_apply(&io_opts, bch2_inode_opts_get(inode));
+
+	bio_init(&rbio.bio, &bv, 1);
+	rbio.bio.bi_iter.bi_size	= size;
+	bv.bv_page			= buf;
+	bv.bv_len			= size;
+	bv.bv_offset			= 0;
+	bio_set_op_attrs(&rbio.bio, REQ_OP_READ, REQ_SYNC);
+	rbio.bio.bi_iter.bi_sector	= offset >> 9;
+
+	closure_init_stack(&cl);
+	closure_get(&cl);
+	rbio.bio.bi_end_io		= shell_read_endio;
+	rbio.bio.bi_private		= &cl;
+
+	bch2_read(c, rbio_init(&rbio.bio, io_opts), inode->bi_inum);
+
+	closure_sync(&cl);
+
+	return -blk_status_to_errno(rbio.bio.bi_status);
+}
+
+static void shell_cat(struct shell *s, shell_args *args)
+{
+	struct bch_inode_unpacked inode;
+	size_t bufsize = 1 << 20;
+	void *buf;
+	u64 offset;
+	int ret;
+
+	if (darray_size(*args) < 2) {
+		fprintf(stderr, "Usage: cat <path>\n");
+		return;
+	}
+
+	if (shell_inode_get(s, darray_item(*args, 1), &inode))
+		return;
+
+	if (!S_ISREG(inode.bi_mode)) {
+		fprintf(stderr, "%s: not a regular file\n", darray_item(*args, 1));
+		return;
+	}
+
+	buf = aligned_alloc(PAGE_SIZE, bufsize);
+
+	for (offset = 0; offset < inode.bi_size; offset += bufsize) {
+		size_t len = min_t(u64, bufsize, inode.bi_size - offset);
+
+		ret = shell_read(s->c, &inode, buf,
+				 round_up(len, block_bytes(s->c)), offset);
+		if (ret) {
+			fprintf(stderr, "read error: %s\n", strerror(-ret));
+			break;
+		}
+
+		fwrite(buf, len, 1, stdout);
+	}
+
+	fflush(stdout);
+	free(buf);
+}
+
+static void shell_btree(struct shell *s, shell_args *args)
+{
+	struct btree_trans trans;
+	struct btree_iter *iter;
+	struct bkey_s_c k;
+	struct bpos start = POS_MIN, end = POS_MAX;
+	char buf[512];
+	ssize_t btree_id;
+	int ret;
+
+	if (darray_size(*args) < 3 ||
+	    strcmp(darray_item(*args, 1), "list")) {
+		fprintf(stderr, "Usage: btree list <btree> [start [end]]\n");
+		return;
+	}
+
+	btree_id = match_string(bch2_btree_ids, -1, darray_item(*args, 2));
+	if (btree_id < 0) {
+		fprintf(stderr, "invalid btree %s\n", darray_item(*args, 2));
+		return;
+	}
+
+	if (darray_size(*args) > 3 &&
+	    __bpos_parse(darray_item(*args, 3), &start)) {
+		fprintf(stderr, "invalid bpos %s\n", darray_item(*args, 3));
+		return;
+	}
+	if (darray_size(*args) > 4 &&
+	    __bpos_parse(darray_item(*args, 4), &end)) {
+		fprintf(stderr, "invalid bpos %s\n", darray_item(*args, 4));
+		return;
+	}
+
+	bch2_trans_init(&trans, s->c, 0, 0);
+
+	for_each_btree_key(&trans, iter, btree_id, start,
+			   BTREE_ITER_PREFETCH, k, ret) {
+		if (bkey_cmp(k.k->p, end) > 0)
+			break;
+
+		bch2_bkey_val_to_text(&PBUF(buf), s->c, k);
+		puts(buf);
+	}
+	bch2_trans_iter_put(&trans, iter);
+
+	bch2_trans_exit(&trans);
+}
+
+static void shell_sb(struct shell *s, shell_args *args)
+{
+	if (darray_size(*args) < 2 ||
+	    strcmp(darray_item(*args, 1), "show")) {
+		fprintf(stderr, "Usage: sb show\n");
+		return;
+	}
+
+	bch2_sb_print(s->c->disk_sb.sb, false,
+		      1 << BCH_SB_FIELD_members, HUMAN_READABLE);
+}
+
+static void shell_help(struct shell *s, shell_args *args);
+
+static const struct shell_cmd {
+	const char	*name;
+	const char	*help;
+	void		(*fn)(struct shell *, shell_args *);
+} shell_cmds[] = {
+	{ "ls",		"ls [path]                 List directory contents",	shell_ls },
+	{ "cd",		"cd [path]                 Change current directory",	shell_cd },
+	{ "stat",	"stat <path>               Show inode",			shell_stat },
+	{ "cat",	"cat <path>                Print file contents",		shell_cat },
+	{ "btree",	"btree list <btree> [start [end]]\n"
+			"                          List keys in a btree",	shell_btree },
+	{ "sb",		"sb show                   Show superblock",		shell_sb },
+	{ "help",	"help                      Display this help",		shell_help },
+	{ "quit",	"quit                      Exit the shell",		NULL },
+};
+
+static void shell_help(struct shell *s, shell_args *args)
+{
+	unsigned i;
+
+	for (i = 0; i < ARRAY_SIZE(shell_cmds); i++)
+		printf("  %s\n", shell_cmds[i].help);
+}
+
+static void shell_usage(void)
+{
+	puts("bcachefs shell - interactively inspect an unmounted filesystem\n"
+	     "Usage: bcachefs shell [OPTION]... <devices>\n"
+	     "\n"
+	     "The filesystem is opened once, read only (in nochanges mode); commands\n"
+	     "are read from standard input. Type help for a list of commands.\n"
+	     "\n"
+	     "Options:\n"
+	     "  -k, --passphrase-file=file  Read the passphrase of an encrypted filesystem\n"
+	     "                              from file\n"
+	     "  -v                          Verbose mode\n"
+	     "  -h, --help                  display this help and exit\n"
+	     "Report bugs to <linux-bcache@vger.kernel.org>");
+	exit(EXIT_SUCCESS);
+}
+
+int cmd_shell(int argc, char *argv[])
+{
+	static const struct option longopts[] = {
+		{ "passphrase-file",	required_argument,	NULL, 'k' },
+		{ "help",		no_argument,		NULL, 'h' },
+		{ NULL }
+	};
+	struct bch_opts opts = bch2_opts_empty();
+	char *passphrase_file = NULL;
+	struct shell s = { .cwd = strdup("/") };
+	bool tty = isatty(STDIN_FILENO);
+	char *line = NULL;
+	size_t n = 0;
+	int opt;
+
+	opt_set(opts, nochanges,	true);
+	opt_set(opts, norecovery,	true);
+	opt_set(opts, degraded,		true);
+	opt_set(opts, errors,		BCH_ON_ERROR_continue);
+	opt_set(opts, fix_errors,	FSCK_OPT_NO);
+
+	while ((opt = getopt_long(argc, argv, "k:vh", longopts, NULL)) != -1)
+		switch (opt) {
+		case 'k':
+			passphrase_file = optarg;
+			break;
+		case 'v':
+			opt_set(opts, verbose, true);
+			break;
+		case 'h':
+			shell_usage();
+		}
+	args_shift(optind);
+
+	if (!argc)
+		die("Please supply device(s)");
+
+	bch2_add_key_offline(argv[0], passphrase_file);
+
+	s.c = bch2_fs_open(argv, argc, opts);
+	if (IS_ERR(s.c))
+		die("error opening %s: %s", argv[0], strerror(-PTR_ERR(s.c)));
+
+	while (1) {
+		shell_args args;
+		char *buf, *arg;
+		unsigned i;
+
+		if (tty) {
+			printf("bcachefs:%s> ", s.cwd);
+			fflush(stdout);
+		}
+
+		if (getline(&line, &n, stdin) < 0)
+			break;
+
+		darray_init(args);
+		buf = line;
+		while ((arg = strsep(&buf, " \t\n")))
+			if (*arg)
+				darray_append(args, arg);
+
+		if (!darray_size(args))
+			goto next;
+
+		for (i = 0; i < ARRAY_SIZE(shell_cmds); i++)
+			if (!strcmp(darray_item(args, 0), shell_cmds[i].name))
+				break;
+
+		if (i == ARRAY_SIZE(shell_cmds)) {
+			fprintf(stderr, "Unknown command %s\n", darray_item(args, 0));
+		} else if (!shell_cmds[i].fn) {
+			darray_free(args);
+			break;
+		} else {
+			shell_cmds[i].fn(&s, &args);
+		}
+next:
+		darray_free(args);
+	}
+
+	if (tty)
+		putchar('\n');
+
+	free(line);
+	free(s.cwd);
+	bch2_fs_stop(s.c);
+	return 0;
+}
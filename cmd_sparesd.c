@@ -0,0 +1,174 @@
+#include <getopt.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <unistd.h>
+
+#include <uuid/uuid.h>
+
+#include "cmds.h"
+#include "libbcachefs.h"
+#include "notify.h"
+#include "sandbox.h"
+
+#include "libbcachefs/bcachefs_ioctl.h"
+#include "libbcachefs/opts.h"
+
+struct sparesd {
+	struct bchfs_handle	fs;
+	char			uuid[40];
+	struct notify_opts	notify;
+};
+
+static void sparesd_event(struct sparesd *d, const char *event,
+			  struct dev_name *dev)
+{
+	bch_notify(&d->notify, d->uuid, event,
+		   dev ? dev->dev : NULL, dev ? dev->idx : -1);
+}
+
+static void sparesd_device_failed(struct sparesd *d, dev_names *devs,
+				  struct dev_name *failed)
+{
+	struct dev_name *dev, *spare = NULL;
+
+	sparesd_event(d, "device-failed", failed);
+
+	darray_foreach(dev, *devs)
+		if (bchu_dev_state_read(d->fs, dev->idx) == BCH_MEMBER_STATE_spare) {
+			spare = dev;
+			break;
+		}
+
+	if (!spare) {
+		sparesd_event(d, "no-spare", NULL);
+		return;
+	}
+
+	bchu_disk_set_state(d->fs, spare->idx, BCH_MEMBER_STATE_rw, 0);
+	sparesd_event(d, "spare-activated", spare);
+
+	int ret = bchu_data(d->fs, (struct bch_ioctl_data) {
+		.op		= BCH_DATA_OP_REREPLICATE,
+		.start_btree	= 0,
+		.start_pos	= POS_MIN,
+		.end_btree	= BTREE_ID_NR,
+		.end_pos	= POS_MAX,
+	});
+
+	sparesd_event(d, ret ? "rereplicate-failed" : "rereplicate-done", NULL);
+}
+
+static void sparesd_usage(void)
+{
+	puts("bcachefs sparesd - activate spare devices when a device fails\n"
+	     "Usage: bcachefs sparesd [OPTION]... <filesystem>\n"
+	     "\n"
+	     "Watches the members of a mounted filesystem. When one is set to failed,\n"
+	     "sets a member in the spare state to rw and rereplicates. Members that are\n"
+	     "already failed when sparesd starts are reported, but not acted on.\n"
+	     "\n"
+	     "Options:\n"
+	     "  -i, --interval=seconds      How often to check (default 10)\n"
+	     "  -e, --exec=command          Run command on every event, with the\n"
+	     "                              environment variables BCACHEFS_EVENT,\n"
+	     "                              BCACHEFS_UUID, BCACHEFS_DEVICE and\n"
+	     "                              BCACHEFS_DEVICE_IDX set. Events are\n"
+	     "                              device-failed, no-spare, spare-activated,\n"
+	     "                              rereplicate-done and rereplicate-failed\n"
+	     "  -u, --user=user             Once the filesystem is open, run as user,\n"
+	     "                              keeping only CAP_SYS_ADMIN\n"
+	     "  -g, --group=group           Once the filesystem is open, run as group\n"
+	     "  -s, --syslog                Log events to syslog too\n"
+	     "  -h, --help                  display this help and exit\n"
+	     "Report bugs to <linux-bcache@vger.kernel.org>");
+	exit(EXIT_SUCCESS);
+}
+
+int cmd_sparesd(int argc, char *argv[])
+{
+	static const struct option longopts[] = {
+		{ "interval",		required_argument,	NULL, 'i' },
+		{ "exec",		required_argument,	NULL, 'e' },
+		{ "syslog",		no_argument,		NULL, 's' },
+		{ "user",		required_argument,	NULL, 'u' },
+		{ "group",		required_argument,	NULL, 'g' },
+		{ "help",		no_argument,		NULL, 'h' },
+		{ NULL }
+	};
+	struct sparesd d = { 0 };
+	const char *user = NULL, *group = NULL;
+	int prev_state[BCH_SB_MEMBERS_MAX];
+	unsigned i, interval = 10;
+	bool first = true;
+	int opt;
+
+	for (i = 0; i < ARRAY_SIZE(prev_state); i++)
+		prev_state[i] = -1;
+
+	while ((opt = getopt_long(argc, argv, "i:e:su:g:h", longopts, NULL)) != -1)
+		switch (opt) {
+		case 'i':
+			if (kstrtouint(optarg, 10, &interval) || !interval)
+				die("invalid interval %s", optarg);
+			break;
+		case 'e':
+			d.notify.exec = optarg;
+			break;
+		case 's':
+			d.notify.syslog = true;
+			break;
+		case 'u':
+			user = optarg;
+			break;
+		case 'g':
+			group = optarg;
+			break;
+		case 'h':
+			sparesd_usage();
+		}
+	args_shift(optind);
+
+	char *path = arg_pop();
+	if (!path)
+		die("Please supply a filesystem");
+
+	if (argc)
+		die("Too many arguments");
+
+	d.fs = bcache_fs_open(path);
+	uuid_unparse(d.fs.uuid.b, d.uuid);
+
+	/* The ioctls for changing device state and rereplicating need it: */
+	drop_privs(user, group, 1ULL << CAP_SYS_ADMIN);
+
+	/* Devices may be added while we run, so reread the list every time: */
+	while (1) {
+		dev_names devs = bchu_fs_get_devices(d.fs);
+		struct dev_name *dev;
+
+		darray_foreach(dev, devs) {
+			int state = bchu_dev_state_read(d.fs, dev->idx);
+
+			if (state == BCH_MEMBER_STATE_failed) {
+				if (first)
+					printf("%s: %s (device %u) is already failed\n",
+					       d.uuid, dev->dev ?: "(offline)", dev->idx);
+				else if (prev_state[dev->idx] != BCH_MEMBER_STATE_failed)
+					sparesd_device_failed(&d, &devs, dev);
+			}
+
+			prev_state[dev->idx] = state;
+		}
+
+		darray_foreach(dev, devs) {
+			free(dev->dev);
+			free(dev->label);
+		}
+		darray_free(devs);
+
+		first = false;
+		fflush(stdout);
+		sleep(interval);
+	}
+}
@@ -0,0 +1,183 @@
+#include <getopt.h>
+#include <stdio.h>
+#include <string.h>
+#include <sys/types.h>
+
+#include "cmds.h"
+#include "crypto.h"
+#include "libbcachefs.h"
+
+#include "libbcachefs/bcachefs.h"
+#include "libbcachefs/error.h"
+#include "libbcachefs/inode.h"
+#include "libbcachefs/opts.h"
+#include "libbcachefs/super.h"
+
+static const char * const inode_flags[] = {
+	"sync",
+	"immutable",
+	"append",
+	"nodump",
+	"noatime",
+	"i_size_dirty",
+	"i_sectors_dirty",
+	"unlinked",
+	"backptr_untrusted",
+	NULL
+};
+
+static void print_flags(u32 flags)
+{
+	unsigned i;
+	bool first = true;
+
+	for (i = 0; inode_flags[i]; i++)
+		if (flags & (1U << i)) {
+			printf("%s%s", first ? "" : ",", inode_flags[i]);
+			first = false;
+		}
+
+	if (first)
+		printf("(none)");
+	printf("\n");
+}
+
+static void print_time(struct bch_fs *c, const char *name, s64 time)
+{
+	struct timespec64 ts = bch2_time_to_timespec(c, time);
+
+	printf("%-24s %lli.%09li\n", name, (s64) ts.tv_sec, ts.tv_nsec);
+}
+
+/* Inode options are stored plus one, with 0 meaning not set on this inode: */
+static void print_opt(struct bch_fs *c, const char *name, u64 v)
+{
+	char buf[100];
+
+	printf("%-24s ", name);
+
+	if (!v) {
+		printf("(not set)\n");
+		return;
+	}
+
+	int id = bch2_opt_lookup(name);
+	if (id < 0) {
+		printf("%llu\n", v - 1);
+		return;
+	}
+
+	bch2_opt_to_text(&PBUF(buf), c, bch2_opt_table + id, v - 1, 0);
+	printf("%s\n", buf);
+}
+
+static void print_inode(struct bch_fs *c, struct bch_inode_unpacked *inode)
+{
+	printf("%-24s %llu\n",	"inum",		inode->bi_inum);
+	printf("%-24s %llx\n",	"hash_seed",	inode->bi_hash_seed);
+	printf("%-24s ",	"flags");
+	print_flags(inode->bi_flags);
+	printf("%-24s %o\n",	"mode",		inode->bi_mode);
+
+	print_time(c, "atime",	inode->bi_atime);
+	print_time(c, "ctime",	inode->bi_ctime);
+	print_time(c, "mtime",	inode->bi_mtime);
+	print_time(c, "otime",	inode->bi_otime);
+
+	printf("%-24s %llu\n",	"size",		inode->bi_size);
+	printf("%-24s %llu\n",	"sectors",	inode->bi_sectors);
+	printf("%-24s %u\n",	"uid",		inode->bi_uid);
+	printf("%-24s %u\n",	"gid",		inode->bi_gid);
+	printf("%-24s %u\n",	"nlink",	inode->bi_nlink);
+	printf("%-24s %u\n",	"generation",	inode->bi_generation);
+	printf("%-24s %u\n",	"dev",		inode->bi_dev);
+
+#define x(_name, _bits)	print_opt(c, #_name, inode->bi_##_name);
+	BCH_INODE_OPTS()
+#undef x
+
+	printf("%-24s %x\n",	"fields_set",	inode->bi_fields_set);
+	printf("%-24s %llu\n",	"dir",		inode->bi_dir);
+	printf("%-24s %llu\n",	"dir_offset",	inode->bi_dir_offset);
+}
+
+static void stat_usage(void)
+{
+	puts("bcachefs stat - show the bcachefs inode of a file\n"
+	     "Usage: bcachefs stat [OPTION]... <devices>... <path>\n"
+	     "   or: bcachefs stat [OPTION]... -i inum <devices>...\n"
+	     "\n"
+	     "Looks up path, relative to the root of the (unmounted) filesystem, and\n"
+	     "prints every field of its inode. Options show the value set on the\n"
+	     "inode itself; unset ones are inherited from the parent directory or the\n"
+	     "filesystem.\n"
+	     "\n"
+	     "Options:\n"
+	     "  -i, --inum=inum             Show this inode instead of looking up a path\n"
+	     "  -k, --passphrase-file=file  Read the passphrase of an encrypted filesystem\n"
+	     "                              from file\n"
+	     "  -h, --help                  display this help and exit\n"
+	     "Report bugs to <linux-bcache@vger.kernel.org>");
+	exit(EXIT_SUCCESS);
+}
+
+int cmd_stat(int argc, char *argv[])
+{
+	static const struct option longopts[] = {
+		{ "inum",		required_argument,	NULL, 'i' },
+		{ "passphrase-file",	required_argument,	NULL, 'k' },
+		{ "help",		no_argument,		NULL, 'h' },
+		{ NULL }
+	};
+	struct bch_opts opts = bch2_opts_empty();
+	struct bch_inode_unpacked inode;
+	char *passphrase_file = NULL, *path = NULL;
+	u64 inum = 0;
+	int opt, ret;
+
+	opt_set(opts, nochanges,	true);
+	opt_set(opts, norecovery,	true);
+	opt_set(opts, degraded,		true);
+	opt_set(opts, errors,		BCH_ON_ERROR_continue);
+
+	while ((opt = getopt_long(argc, argv, "i:k:h", longopts, NULL)) != -1)
+		switch (opt) {
+		case 'i':
+			if (kstrtoull(optarg, 10, &inum) || !inum)
+				die("invalid inode %s", optarg);
+			break;
+		case 'k':
+			passphrase_file = optarg;
+			break;
+		case 'h':
+			stat_usage();
+		}
+	args_shift(optind);
+
+	if (!inum) {
+		if (argc < 2)
+			die("Please supply device(s) and a path");
+		path = argv[--argc];
+	}
+
+	if (!argc)
+		die("Please supply device(s)");
+
+	bch2_add_key_offline(argv[0], passphrase_file);
+
+	struct bch_fs *c = bch2_fs_open(argv, argc, opts);
+	if (IS_ERR(c))
+		die("error opening %s: %s", argv[0], strerror(-PTR_ERR(c)));
+
+	if (path)
+		inum = bch2_path_to_inum(c, path);
+
+	ret = bch2_inode_find_by_inum(c, inum, &inode);
+	if (ret)
+		die("error looking up inode %llu: %s", inum, strerror(-ret));
+
+	print_inode(c, &inode);
+
+	bch2_fs_stop(c);
+	return 0;
+}
@@ -0,0 +1,247 @@
+#include <fcntl.h>
+#include <getopt.h>
+#include <stdio.h>
+#include <string.h>
+#include <sys/stat.h>
+#include <sys/types.h>
+#include <unistd.h>
+
+#include "cmds.h"
+#include "libbcachefs.h"
+
+#include <linux/dcache.h>
+#include "libbcachefs/bcachefs.h"
+#include "libbcachefs/alloc_foreground.h"
+#include "libbcachefs/btree_update.h"
+#include "libbcachefs/buckets.h"
+#include "libbcachefs/dirent.h"
+#include "libbcachefs/inode.h"
+#include "libbcachefs/fs-common.h"
+#include "libbcachefs/io.h"
+#include "libbcachefs/super.h"
+
+/* XXX cut and pasted from fsck.c */
+#define QSTR(n) { { { .len = strlen(n) } }, .name = n }
+
+struct test_image_opts {
+	u64		size;
+	unsigned	nr_files;
+	u64		file_size;
+	bool		fragment;
+};
+
+static struct bch_inode_unpacked create_file(struct bch_fs *c,
+					     struct bch_inode_unpacked *parent,
+					     const char *name, mode_t mode)
+{
+	struct qstr qstr = QSTR(name);
+	struct bch_inode_unpacked new_inode;
+
+	int ret = bch2_trans_do(c, NULL, NULL, 0,
+		bch2_create_trans(&trans,
+				  parent->bi_inum, parent,
+				  &new_inode, &qstr,
+				  0, 0, mode, 0, NULL, NULL));
+	if (ret)
+		die("error creating %s: %s", name, strerror(-ret));
+
+	return new_inode;
+}
+
+static void update_inode(struct bch_fs *c, struct bch_inode_unpacked *inode)
+{
+	struct bkey_inode_buf packed;
+	int ret;
+
+	bch2_inode_pack(c, &packed, inode);
+	ret = bch2_btree_insert(c, BTREE_ID_inodes, &packed.inode.k_i,
+				NULL, NULL, 0);
+	if (ret)
+		die("error updating inode: %s", strerror(-ret));
+}
+
+/*
+ * File contents are a function of the inode number and offset, so that tests
+ * can check what was read back against what should be there:
+ */
+static void fill_block(u64 *buf, size_t len, u64 inum, u64 offset)
+{
+	size_t i;
+
+	for (i = 0; i < len / sizeof(u64); i++)
+		buf[i] = (inum << 40) ^ (offset + i * sizeof(u64));
+}
+
+static void write_block(struct bch_fs *c, struct bch_inode_unpacked *inode,
+			u64 offset, void *buf, size_t len)
+{
+	struct {
+		struct bch_write_op op;
+		struct bio_vec bv[(64 << 10) / PAGE_SIZE];
+	} o;
+	struct closure cl;
+
+	closure_init_stack(&cl);
+
+	bio_init(&o.op.wbio.bio, o.bv, ARRAY_SIZE(o.bv));
+	bch2_bio_map(&o.op.wbio.bio, buf, len);
+
+	bch2_write_op_init(&o.op, c, bch2_opts_to_inode_opts(c->opts));
+	o.op.write_point	= writepoint_hashed(0);
+	o.op.nr_replicas	= 1;
+	o.op.pos		= POS(inode->bi_inum, offset >> 9);
+
+	int ret = bch2_disk_reservation_get(c, &o.op.res, len >> 9,
+					    c->opts.data_replicas, 0);
+	if (ret)
+		die("error reserving space: %s", strerror(-ret));
+
+	closure_call(&o.op.cl, bch2_write, NULL, &cl);
+	closure_sync(&cl);
+
+	inode->bi_sectors += len >> 9;
+	inode->bi_size = max(inode->bi_size, offset + len);
+}
+
+static void populate_image(struct bch_fs *c, struct test_image_opts *opts)
+{
+	struct bch_inode_unpacked root, *files;
+	unsigned i, block_size = block_bytes(c);
+	u64 *buf = aligned_alloc(PAGE_SIZE, block_size);
+	u64 offset;
+
+	int ret = bch2_inode_find_by_inum(c, BCACHEFS_ROOT_INO, &root);
+	if (ret)
+		die("error looking up root directory: %s", strerror(-ret));
+
+	files = xcalloc(opts->nr_files, sizeof(*files));
+
+	for (i = 0; i < opts->nr_files; i++) {
+		char name[32];
+
+		snprintf(name, sizeof(name), "file.%u", i);
+		files[i] = create_file(c, &root, name, S_IFREG|0644);
+	}
+
+	/*
+	 * Writing every file a block at a time, round robin, interleaves their
+	 * extents on disk:
+	 */
+	if (opts->fragment) {
+		for (offset = 0; offset < opts->file_size; offset += block_size)
+			for (i = 0; i < opts->nr_files; i++) {
+				fill_block(buf, block_size, files[i].bi_inum, offset);
+				write_block(c, &files[i], offset, buf, block_size);
+			}
+	} else {
+		for (i = 0; i < opts->nr_files; i++)
+			for (offset = 0; offset < opts->file_size; offset += block_size) {
+				fill_block(buf, block_size, files[i].bi_inum, offset);
+				write_block(c, &files[i], offset, buf, block_size);
+			}
+	}
+
+	for (i = 0; i < opts->nr_files; i++)
+		update_inode(c, &files[i]);
+
+	free(files);
+	free(buf);
+}
+
+static void create_test_image_usage(void)
+{
+	puts("bcachefs create-test-image - create a small filesystem image for testing\n"
+	     "Usage: bcachefs create-test-image [OPTION]... <image>\n"
+	     "\n"
+	     "Formats a sparse image file, and fills it with files named file.<n>. File\n"
+	     "contents are derived from the inode number and offset, so that they can be\n"
+	     "checked after recovery. Use bcachefs corrupt to then inject corruptions.\n"
+	     "\n"
+	     "Options:\n"
+	     "  -s, --size=size             Image size (default 64M)\n"
+	     "  -n, --files=n               Number of files to create (default 100)\n"
+	     "  -S, --file-size=size        Size of each file, rounded up to the block\n"
+	     "                              size (default 16k)\n"
+	     "  -F, --fragment              Interleave file writes, so that every file is\n"
+	     "                              fragmented on disk\n"
+	     "  -f, --force                 Overwrite an existing image\n"
+	     "  -h, --help                  display this help and exit\n"
+	     "Report bugs to <linux-bcache@vger.kernel.org>");
+	exit(EXIT_SUCCESS);
+}
+
+int cmd_create_test_image(int argc, char *argv[])
+{
+	static const struct option longopts[] = {
+		{ "size",		required_argument,	NULL, 's' },
+		{ "files",		required_argument,	NULL, 'n' },
+		{ "file-size",		required_argument,	NULL, 'S' },
+		{ "fragment",		no_argument,		NULL, 'F' },
+		{ "force",		no_argument,		NULL, 'f' },
+		{ "help",		no_argument,		NULL, 'h' },
+		{ NULL }
+	};
+	struct test_image_opts opts = {
+		.size		= 64 << 20,
+		.nr_files	= 100,
+		.file_size	= 16 << 10,
+	};
+	bool force = false;
+	int opt;
+
+	while ((opt = getopt_long(argc, argv, "s:n:S:Ffh",
+				  longopts, NULL)) != -1)
+		switch (opt) {
+		case 's':
+			if (bch2_strtoull_h(optarg, &opts.size))
+				die("invalid image size %s", optarg);
+			break;
+		case 'n':
+			if (kstrtouint(optarg, 10, &opts.nr_files))
+				die("invalid number of files %s", optarg);
+			break;
+		case 'S':
+			if (bch2_strtoull_h(optarg, &opts.file_size))
+				die("invalid file size %s", optarg);
+			break;
+		case 'F':
+			opts.fragment = true;
+			break;
+		case 'f':
+			force = true;
+			break;
+		case 'h':
+			create_test_image_usage();
+		}
+	args_shift(optind);
+
+	char *path = arg_pop();
+	if (!path)
+		die("Please supply an image file");
+
+	if (argc)
+		die("Too many arguments");
+
+	struct dev_opts dev = dev_opts_default();
+
+	dev.path	= path;
+	dev.fd		= xopen(path, O_RDWR|O_CREAT|(force ? O_TRUNC : O_EXCL), 0644);
+	if (ftruncate(dev.fd, opts.size))
+		die("error sizing %s: %m", path);
+
+	struct bch_opt_strs fs_opt_strs;
+	memset(&fs_opt_strs, 0, sizeof(fs_opt_strs));
+
+	struct bch_sb *sb = bch2_format(fs_opt_strs, bch2_opts_empty(),
+					format_opts_default(), &dev, 1);
+	free(sb);
+
+	struct bch_fs *c = bch2_fs_open(&path, 1, bch2_opts_empty());
+	if (IS_ERR(c))
+		die("error opening %s: %s", path, strerror(-PTR_ERR(c)));
+
+	populate_image(c, &opts);
+
+	bch2_fs_stop(c);
+	return 0;
+}
@@ -0,0 +1,184 @@
+#include <getopt.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+#include "cmds.h"
+#include "libbcachefs.h"
+#include "testdev.h"
+
+static void testdev_create_usage(void)
+{
+	puts("bcachefs testdev create - set up a test device\n"
+	     "Usage: bcachefs testdev create <device> <name>\n"
+	     "\n"
+	     "Creates /dev/mapper/" TESTDEV_PREFIX "<name>, passing IO through to device\n"
+	     "(a block device: use a loop device for an image file). Format or add it\n"
+	     "to a filesystem in place of device, then inject failures with\n"
+	     "bcachefs testdev set.\n"
+	     "\n"
+	     "Options:\n"
+	     "  -h, --help                  display this help and exit\n"
+	     "Report bugs to <linux-bcache@vger.kernel.org>");
+	exit(EXIT_SUCCESS);
+}
+
+int cmd_testdev_create(int argc, char *argv[])
+{
+	static const struct option longopts[] = {
+		{ "help",		no_argument,		NULL, 'h' },
+		{ NULL }
+	};
+	int opt;
+
+	while ((opt = getopt_long(argc, argv, "h", longopts, NULL)) != -1)
+		switch (opt) {
+		case 'h':
+			testdev_create_usage();
+		}
+	args_shift(optind);
+
+	char *dev = arg_pop();
+	char *name = arg_pop();
+	if (!dev || !name)
+		die("Please supply a device and a name");
+
+	if (argc)
+		die("Too many arguments");
+
+	char *path = testdev_create(dev, name);
+	printf("%s\n", path);
+	free(path);
+	return 0;
+}
+
+static void testdev_set_usage(void)
+{
+	puts("bcachefs testdev set - inject failures on a test device\n"
+	     "Usage: bcachefs testdev set [OPTION]... <name> <mode>\n"
+	     "\n"
+	     "Modes:\n"
+	     "  linear                      Pass all IO through\n"
+	     "  error                       Fail all IO\n"
+	     "  flakey                      Alternate between passing all IO through and\n"
+	     "                              failing it\n"
+	     "  drop-writes                 Alternate between passing all IO through and\n"
+	     "                              silently dropping writes, as in a crash\n"
+	     "  dust                        Fail reads of the blocks given with -b, until\n"
+	     "                              they're written\n"
+	     "\n"
+	     "Options:\n"
+	     "  -u, --up=seconds            flakey, drop-writes: how long IO passes through\n"
+	     "                              (default 1); 0 fails IO from now on\n"
+	     "  -d, --down=seconds          flakey, drop-writes: how long IO fails next\n"
+	     "                              (default 1)\n"
+	     "  -b, --bad-block=block       dust: a block to fail reads of; may be given\n"
+	     "                              multiple times\n"
+	     "  -B, --block-size=size       dust: size of a block (default 512)\n"
+	     "  -h, --help                  display this help and exit\n"
+	     "Report bugs to <linux-bcache@vger.kernel.org>");
+	exit(EXIT_SUCCESS);
+}
+
+int cmd_testdev_set(int argc, char *argv[])
+{
+	static const struct option longopts[] = {
+		{ "up",			required_argument,	NULL, 'u' },
+		{ "down",		required_argument,	NULL, 'd' },
+		{ "bad-block",		required_argument,	NULL, 'b' },
+		{ "block-size",		required_argument,	NULL, 'B' },
+		{ "help",		no_argument,		NULL, 'h' },
+		{ NULL }
+	};
+	struct testdev_opts opts = {
+		.up		= 1,
+		.down		= 1,
+		.block_size	= 512,
+	};
+	u64 v;
+	int opt;
+
+	while ((opt = getopt_long(argc, argv, "u:d:b:B:h", longopts, NULL)) != -1)
+		switch (opt) {
+		case 'u':
+			if (kstrtouint(optarg, 10, &opts.up))
+				die("invalid time %s", optarg);
+			break;
+		case 'd':
+			if (kstrtouint(optarg, 10, &opts.down) || !opts.down)
+				die("invalid time %s", optarg);
+			break;
+		case 'b':
+			if (kstrtoull(optarg, 10, &v))
+				die("invalid block %s", optarg);
+
+			opts.bad_blocks = xrealloc(opts.bad_blocks,
+				sizeof(u64) * (opts.nr_bad_blocks + 1));
+			opts.bad_blocks[opts.nr_bad_blocks++] = v;
+			break;
+		case 'B':
+			if (bch2_strtoull_h(optarg, &v) ||
+			    !v || v & 511 || v > U32_MAX)
+				die("invalid block size %s", optarg);
+			opts.block_size = v;
+			break;
+		case 'h':
+			testdev_set_usage();
+		}
+	args_shift(optind);
+
+	char *name = arg_pop();
+	char *mode_str = arg_pop();
+	if (!name || !mode_str)
+		die("Please supply a name and a mode");
+
+	if (argc)
+		die("Too many arguments");
+
+	enum testdev_mode mode =
+		read_string_list_or_die(mode_str, testdev_modes, "mode");
+
+	if (opts.nr_bad_blocks && mode != TESTDEV_DUST)
+		die("--bad-block is only for dust mode");
+
+	testdev_set(name, mode, &opts);
+	free(opts.bad_blocks);
+	return 0;
+}
+
+static void testdev_remove_usage(void)
+{
+	puts("bcachefs testdev remove - tear down a test device\n"
+	     "Usage: bcachefs testdev remove <name>\n"
+	     "\n"
+	     "Options:\n"
+	     "  -h, --help                  display this help and exit\n"
+	     "Report bugs to <linux-bcache@vger.kernel.org>");
+	exit(EXIT_SUCCESS);
+}
+
+int cmd_testdev_remove(int argc, char *argv[])
+{
+	static const struct option longopts[] = {
+		{ "help",		no_argument,		NULL, 'h' },
+		{ NULL }
+	};
+	int opt;
+
+	while ((opt = getopt_long(argc, argv, "h", longopts, NULL)) != -1)
+		switch (opt) {
+		case 'h':
+			testdev_remove_usage();
+		}
+	args_shift(optind);
+
+	char *name = arg_pop();
+	if (!name)
+		die("Please supply a name");
+
+	if (argc)
+		die("Too many arguments");
+
+	testdev_remove(name);
+	return 0;
+}
@@ -0,0 +1,398 @@
+#include <errno.h>
+#include <fcntl.h>
+#include <getopt.h>
+#include <stdarg.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <sys/ioctl.h>
+#include <sys/stat.h>
+#include <sys/types.h>
+#include <time.h>
+#include <unistd.h>
+
+#include "cmds.h"
+#include "libbcachefs.h"
+#include "testdev.h"
+
+#include "libbcachefs/bcachefs_ioctl.h"
+
+struct torture_file {
+	bool		exists;
+	u64		size;
+	u64		seed;
+};
+
+struct torture {
+	u64			rng;
+	u64			step;
+	bool			verbose;
+	bool			device_events;
+
+	struct bchfs_handle	fs;
+	int			dir_fd;
+	FILE			*log;
+
+	unsigned		nr_files;
+	u64			max_file_size;
+	struct torture_file	*files;
+
+	/* device we offlined, to bring back online: */
+	int			offline_idx;
+	char			*offline_dev;
+
+	/* test devices (see testdev.h) to inject errors on: */
+	char			**testdevs;
+	unsigned		nr_testdevs;
+	char			*failing_testdev;
+};
+
+/* Our own generator, so that a run can be reproduced from its seed: */
+static u64 splitmix64(u64 *state)
+{
+	u64 z = (*state += 0x9e3779b97f4a7c15ULL);
+
+	z = (z ^ (z >> 30)) * 0xbf58476d1ce4e5b9ULL;
+	z = (z ^ (z >> 27)) * 0x94d049bb133111ebULL;
+	return z ^ (z >> 31);
+}
+
+static u64 torture_rand(struct torture *t, u64 max)
+{
+	return splitmix64(&t->rng) % max;
+}
+
+static void torture_log(struct torture *t, const char *fmt, ...)
+{
+	va_list args;
+
+	if (t->log) {
+		fprintf(t->log, "%llu: ", t->step);
+		va_start(args, fmt);
+		vfprintf(t->log, fmt, args);
+		va_end(args);
+		fputc('\n', t->log);
+		fflush(t->log);
+	}
+
+	if (t->verbose) {
+		printf("%llu: ", t->step);
+		va_start(args, fmt);
+		vprintf(fmt, args);
+		va_end(args);
+		putchar('\n');
+	}
+}
+
+/* File contents are generated from the file's seed, so they can be checked: */
+static void fill_buf(u64 *buf, size_t words, u64 seed, u64 offset)
+{
+	u64 state = seed + offset / sizeof(u64);
+	size_t i;
+
+	for (i = 0; i < words; i++)
+		buf[i] = splitmix64(&state);
+}
+
+static char *file_name(unsigned i)
+{
+	return mprintf("%u", i);
+}
+
+static void torture_write(struct torture *t, unsigned i, u64 size, u64 seed)
+{
+	static u64 buf[1 << 13];
+	char *name = file_name(i);
+	u64 offset;
+
+	torture_log(t, "write %s size %llu seed %llx", name, size, seed);
+
+	int fd = xopenat(t->dir_fd, name, O_WRONLY|O_CREAT|O_TRUNC, 0644);
+
+	for (offset = 0; offset < size; offset += sizeof(buf)) {
+		size_t n = min_t(u64, size - offset, sizeof(buf));
+
+		fill_buf(buf, DIV_ROUND_UP(n, sizeof(u64)), seed, offset);
+		xpwrite(fd, buf, n, offset);
+	}
+
+	if (fsync(fd))
+		die("step %llu: error writing %s: %m", t->step, name);
+	close(fd);
+	free(name);
+
+	t->files[i] = (struct torture_file) {
+		.exists	= true,
+		.size	= size,
+		.seed	= seed,
+	};
+}
+
+static void torture_verify(struct torture *t, unsigned i)
+{
+	static u64 buf[1 << 13], expect[1 << 13];
+	struct torture_file *f = t->files + i;
+	char *name = file_name(i);
+	u64 offset;
+
+	torture_log(t, "verify %s", name);
+
+	int fd = xopenat(t->dir_fd, name, O_RDONLY);
+	struct stat st = xfstat(fd);
+
+	if (st.st_size != f->size)
+		die("step %llu: %s has size %llu, should be %llu",
+		    t->step, name, (u64) st.st_size, f->size);
+
+	for (offset = 0; offset < f->size; offset += sizeof(buf)) {
+		size_t n = min_t(u64, f->size - offset, sizeof(buf));
+
+		xpread(fd, buf, n, offset);
+		fill_buf(expect, DIV_ROUND_UP(n, sizeof(u64)), f->seed, offset);
+
+		if (memcmp(buf, expect, n))
+			die("step %llu: %s has wrong data between %llu and %llu",
+			    t->step, name, offset, offset + n);
+	}
+
+	close(fd);
+	free(name);
+}
+
+static void torture_delete(struct torture *t, unsigned i)
+{
+	char *name = file_name(i);
+
+	torture_log(t, "delete %s", name);
+
+	if (unlinkat(t->dir_fd, name, 0))
+		die("step %llu: error deleting %s: %m", t->step, name);
+
+	t->files[i].exists = false;
+	free(name);
+}
+
+/*
+ * Fails IO to a test device or offlines a device, allowing the filesystem to go
+ * degraded - or undoes the last of those. The kernel refuses to offline if data
+ * would become unavailable, which isn't an error here:
+ */
+static void torture_device_event(struct torture *t, u64 r)
+{
+	struct testdev_opts opts = { 0 };
+
+	if (t->failing_testdev) {
+		torture_log(t, "testdev %s: linear", t->failing_testdev);
+		testdev_set(t->failing_testdev, TESTDEV_LINEAR, &opts);
+		t->failing_testdev = NULL;
+		return;
+	}
+
+	/* Half the events fail all IO to a test device for a step: */
+	if (t->nr_testdevs && !t->offline_dev && (r & 1)) {
+		t->failing_testdev = t->testdevs[(r >> 1) % t->nr_testdevs];
+
+		torture_log(t, "testdev %s: error", t->failing_testdev);
+		testdev_set(t->failing_testdev, TESTDEV_ERROR, &opts);
+		return;
+	}
+
+	if (t->offline_dev) {
+		char *path = mprintf("/dev/%s", t->offline_dev);
+		struct bch_ioctl_disk i = { .dev = (unsigned long) path };
+
+		torture_log(t, "online device %u (%s)", t->offline_idx, path);
+
+		if (ioctl(t->fs.ioctl_fd, BCH_IOCTL_DISK_ONLINE, &i))
+			die("step %llu: error onlining %s: %m", t->step, path);
+
+		free(t->offline_dev);
+		t->offline_dev = NULL;
+		free(path);
+		return;
+	}
+
+	dev_names devs = bchu_fs_get_devices(t->fs);
+	struct dev_name *dev;
+
+	if (t->device_events && darray_size(devs) > 1) {
+		dev = &darray_item(devs, r % darray_size(devs));
+
+		struct bch_ioctl_disk i = {
+			.flags	= BCH_BY_INDEX|BCH_FORCE_IF_DEGRADED,
+			.dev	= dev->idx,
+		};
+
+		if (!dev->dev) {
+			torture_log(t, "offline device %u: skipped, no block device", dev->idx);
+		} else if (ioctl(t->fs.ioctl_fd, BCH_IOCTL_DISK_OFFLINE, &i)) {
+			torture_log(t, "offline device %u (%s): refused: %m",
+				    dev->idx, dev->dev);
+		} else {
+			torture_log(t, "offline device %u (%s)", dev->idx, dev->dev);
+			t->offline_idx	= dev->idx;
+			t->offline_dev	= strdup(dev->dev);
+		}
+	}
+
+	darray_foreach(dev, devs) {
+		free(dev->dev);
+		free(dev->label);
+	}
+	darray_free(devs);
+}
+
+/*
+ * Every step draws the same random numbers whatever happens, so the same seed
+ * gives the same sequence of operations:
+ */
+static void torture_step(struct torture *t)
+{
+	u64 op		= torture_rand(t, 100);
+	unsigned i	= torture_rand(t, t->nr_files);
+	u64 size	= torture_rand(t, t->max_file_size + 1);
+	u64 seed	= splitmix64(&t->rng);
+
+	if (op < 40)
+		torture_write(t, i, size, seed);
+	else if (op < 80 && t->files[i].exists)
+		torture_verify(t, i);
+	else if (op < 95 && t->files[i].exists)
+		torture_delete(t, i);
+	else if (op >= 95 && (t->device_events || t->nr_testdevs))
+		torture_device_event(t, seed);
+}
+
+static void torture_usage(void)
+{
+	puts("bcachefs torture - stress test a mounted filesystem\n"
+	     "Usage: bcachefs torture [OPTION]... <mountpoint>\n"
+	     "\n"
+	     "Runs a random sequence of file writes, verifies and deletes in a new\n"
+	     "directory, checking everything read back, optionally mixed with taking\n"
+	     "member devices offline and back online. Only use on a test filesystem.\n"
+	     "\n"
+	     "The seed is printed at the start; running again with the same seed and\n"
+	     "options repeats the same operations.\n"
+	     "\n"
+	     "Options:\n"
+	     "  -s, --seed=seed             Seed for the random number generator\n"
+	     "                              (default: from the time)\n"
+	     "  -n, --steps=nr              Number of operations (default 10000)\n"
+	     "  -f, --files=nr              Number of files to use (default 100)\n"
+	     "  -S, --max-file-size=size    Largest file to write (default 1M)\n"
+	     "  -D, --device-events         Offline devices, with the filesystem allowed\n"
+	     "                              to go degraded, and bring them back online\n"
+	     "  -T, --testdev=name          Fail all IO to this test device (see\n"
+	     "                              bcachefs testdev) a step at a time; may be\n"
+	     "                              given multiple times\n"
+	     "  -l, --log=file              Log every operation to file, to reproduce\n"
+	     "                              a failure\n"
+	     "  -v, --verbose               Print every operation\n"
+	     "  -h, --help                  display this help and exit\n"
+	     "Report bugs to <linux-bcache@vger.kernel.org>");
+	exit(EXIT_SUCCESS);
+}
+
+int cmd_torture(int argc, char *argv[])
+{
+	static const struct option longopts[] = {
+		{ "seed",		required_argument,	NULL, 's' },
+		{ "steps",		required_argument,	NULL, 'n' },
+		{ "files",		required_argument,	NULL, 'f' },
+		{ "max-file-size",	required_argument,	NULL, 'S' },
+		{ "device-events",	no_argument,		NULL, 'D' },
+		{ "testdev",		required_argument,	NULL, 'T' },
+		{ "log",		required_argument,	NULL, 'l' },
+		{ "verbose",		no_argument,		NULL, 'v' },
+		{ "help",		no_argument,		NULL, 'h' },
+		{ NULL }
+	};
+	struct torture t = {
+		.nr_files	= 100,
+		.max_file_size	= 1 << 20,
+	};
+	u64 seed = time(NULL), nr_steps = 10000;
+	int opt;
+
+	while ((opt = getopt_long(argc, argv, "s:n:f:S:DT:l:vh",
+				  longopts, NULL)) != -1)
+		switch (opt) {
+		case 's':
+			if (kstrtoull(optarg, 0, &seed))
+				die("invalid seed %s", optarg);
+			break;
+		case 'n':
+			if (kstrtoull(optarg, 10, &nr_steps))
+				die("invalid number of steps %s", optarg);
+			break;
+		case 'f':
+			if (kstrtouint(optarg, 10, &t.nr_files) || !t.nr_files)
+				die("invalid number of files %s", optarg);
+			break;
+		case 'S':
+			if (bch2_strtoull_h(optarg, &t.max_file_size))
+				die("invalid file size %s", optarg);
+			break;
+		case 'D':
+			t.device_events = true;
+			break;
+		case 'T':
+			t.testdevs = xrealloc(t.testdevs,
+				sizeof(char *) * (t.nr_testdevs + 1));
+			t.testdevs[t.nr_testdevs++] = optarg;
+			break;
+		case 'l':
+			t.log = fopen(optarg, "w");
+			if (!t.log)
+				die("error opening %s: %m", optarg);
+			break;
+		case 'v':
+			t.verbose = true;
+			break;
+		case 'h':
+			torture_usage();
+		}
+	args_shift(optind);
+
+	char *path = arg_pop();
+	if (!path)
+		die("Please supply a mountpoint");
+
+	if (argc)
+		die("Too many arguments");
+
+	t.fs	= bcache_fs_open(path);
+	t.rng	= seed;
+	t.files	= xcalloc(t.nr_files, sizeof(*t.files));
+
+	char *dir = mprintf("%s/torture-%llu", path, seed);
+	if (mkdir(dir, 0755))
+		die("error creating %s: %m", dir);
+	t.dir_fd = xopen(dir, O_RDONLY|O_DIRECTORY);
+
+	printf("seed %llu, in %s\n", seed, dir);
+	if (t.log)
+		fprintf(t.log, "seed %llu, in %s\n", seed, dir);
+
+	for (t.step = 0; t.step < nr_steps; t.step++)
+		torture_step(&t);
+
+	/* Leave every device working: */
+	t.step = nr_steps;
+	if (t.failing_testdev)
+		torture_device_event(&t, 0);
+	if (t.offline_dev)
+		torture_device_event(&t, 0);
+
+	printf("%llu steps completed\n", nr_steps);
+
+	close(t.dir_fd);
+	free(dir);
+	free(t.files);
+	free(t.testdevs);
+	if (t.log)
+		fclose(t.log);
+	bcache_fs_close(t.fs);
+	return 0;
+}
@@ -0,0 +1,356 @@
+#include <dirent.h>
+#include <errno.h>
+#include <fcntl.h>
+#include <fnmatch.h>
+#include <getopt.h>
+#include <signal.h>
+#include <stdio.h>
+#include <string.h>
+#include <sys/stat.h>
+#include <sys/types.h>
+#include <unistd.h>
+
+#include "ccan/darray/darray.h"
+
+#include "cmds.h"
+#include "libbcachefs.h"
+
+static const char * const tracefs_paths[] = {
+	"/sys/kernel/tracing",
+	"/sys/kernel/debug/tracing",
+};
+
+static int tracefs_open(void)
+{
+	unsigned i;
+
+	for (i = 0; i < ARRAY_SIZE(tracefs_paths); i++) {
+		int fd = open(tracefs_paths[i], O_RDONLY|O_DIRECTORY);
+
+		if (fd >= 0 && !faccessat(fd, "events/bcachefs", F_OK, 0))
+			return fd;
+		if (fd >= 0)
+			close(fd);
+	}
+
+	die("tracefs not mounted, or bcachefs tracepoints not available");
+}
+
+static void tracefs_write(int dirfd, const char *path, const char *str)
+{
+	int fd = xopenat(dirfd, path, O_WRONLY|O_TRUNC);
+
+	if (write(fd, str, strlen(str)) != strlen(str))
+		die("error writing %s to %s: %m", str, path);
+	close(fd);
+}
+
+typedef darray(char *) event_list;
+
+/* Returns bcachefs events matching any of the given patterns: */
+static event_list tracefs_events(int tracefs, char **patterns, unsigned nr)
+{
+	DIR *dir = fdopendir(xopenat(tracefs, "events/bcachefs",
+				     O_RDONLY|O_DIRECTORY));
+	struct dirent *d;
+	event_list events;
+	unsigned i;
+
+	darray_init(events);
+
+	while ((errno = 0), (d = readdir(dir))) {
+		if (d->d_type != DT_DIR ||
+		    !strcmp(d->d_name, ".") ||
+		    !strcmp(d->d_name, ".."))
+			continue;
+
+		for (i = 0; i < nr; i++)
+			if (!fnmatch(patterns[i], d->d_name, 0))
+				break;
+
+		if (!nr || i < nr)
+			darray_append(events, strdup(d->d_name));
+	}
+
+	if (errno)
+		die("readdir error: %m");
+	closedir(dir);
+
+	return events;
+}
+
+/* Returns the first line of a tracefs file, or NULL on error: */
+static char *tracefs_read(int dirfd, const char *path)
+{
+	char buf[4096];
+	int fd = openat(dirfd, path, O_RDONLY);
+	ssize_t ret;
+
+	if (fd < 0)
+		return NULL;
+
+	ret = read(fd, buf, sizeof(buf) - 1);
+	close(fd);
+	if (ret < 0)
+		return NULL;
+
+	buf[ret] = '\0';
+	buf[strcspn(buf, "\n")] = '\0';
+	return strdup(buf);
+}
+
+/*
+ * The state of each event before we changed it, to put back on exit however
+ * we exit - tracepoints left enabled slow down the whole system:
+ */
+struct event_state {
+	char		*enable_path;
+	char		*enable;
+	char		*filter_path;
+	char		*filter;
+};
+
+static int trace_tracefs = -1;
+static darray(struct event_state) trace_saved;
+
+static void tracefs_restore(const char *path, const char *str)
+{
+	int fd = openat(trace_tracefs, path, O_WRONLY|O_TRUNC);
+
+	if (fd < 0 || write(fd, str, strlen(str)) != strlen(str))
+		fprintf(stderr, "error restoring %s to %s: %m\n", path, str);
+	if (fd >= 0)
+		close(fd);
+}
+
+static void events_restore(void)
+{
+	struct event_state *s;
+
+	darray_foreach(s, trace_saved) {
+		tracefs_restore(s->enable_path, s->enable);
+		/* Reads back as "none" when there's no filter: */
+		if (s->filter_path)
+			tracefs_restore(s->filter_path,
+					strcmp(s->filter, "none") ? s->filter : "0");
+
+		free(s->enable_path);
+		free(s->enable);
+		free(s->filter_path);
+		free(s->filter);
+	}
+	darray_free(trace_saved);
+	darray_init(trace_saved);
+}
+
+static void events_enable(int tracefs, event_list events, const char *filter)
+{
+	char **e;
+
+	trace_tracefs = tracefs;
+	atexit(events_restore);
+
+	darray_foreach(e, events) {
+		struct event_state s = {
+			.enable_path	= mprintf("events/bcachefs/%s/enable", *e),
+			.filter_path	= filter
+				? mprintf("events/bcachefs/%s/filter", *e)
+				: NULL,
+		};
+
+		s.enable = tracefs_read(tracefs, s.enable_path);
+		if (!s.enable)
+			die("error reading %s: %m", s.enable_path);
+
+		if (s.filter_path) {
+			s.filter = tracefs_read(tracefs, s.filter_path);
+			if (!s.filter)
+				die("error reading %s: %m", s.filter_path);
+		}
+
+		darray_append(trace_saved, s);
+
+		if (s.filter_path)
+			tracefs_write(tracefs, s.filter_path, filter);
+		tracefs_write(tracefs, s.enable_path, "1");
+	}
+}
+
+/*
+ * Decode a line from trace_pipe:
+ *	<task>-<pid> [<cpu>] <flags> <timestamp>: <event>: <data>
+ * where the flags column is only present with the irq-info trace option:
+ */
+static void print_event_json(char *line)
+{
+	char *orig = strdupa(line), *cpu, *ts, *event, *data, *pid;
+
+	cpu = strchr(line, '[');
+	if (!cpu)
+		goto raw;
+	*cpu++ = '\0';
+
+	ts = strchr(cpu, ']');
+	if (!ts)
+		goto raw;
+	*ts++ = '\0';
+
+	event = strstr(ts, ": ");
+	if (!event)
+		goto raw;
+	*event = '\0';
+	event += 2;
+
+	/* skip the flags column, if present: */
+	ts = strrchr(ts, ' ') ?: ts;
+	ts += strspn(ts, " ");
+
+	data = strstr(event, ": ");
+	if (data) {
+		*data = '\0';
+		data += 2;
+	} else {
+		data = "";
+	}
+
+	line = strim(line);
+	pid = strrchr(line, '-');
+	if (pid)
+		*pid++ = '\0';
+
+	printf("{\"task\":");
+	json_str(stdout, line);
+	printf(",\"pid\":%s,\"cpu\":%u,\"timestamp\":%s,\"event\":",
+	       pid ?: "null", atoi(cpu), ts);
+	json_str(stdout, event);
+	printf(",\"data\":");
+	json_str(stdout, data);
+	printf("}\n");
+	return;
+raw:
+	printf("{\"raw\":");
+	json_str(stdout, orig);
+	printf("}\n");
+}
+
+static volatile sig_atomic_t trace_stop;
+
+static void trace_sig_handler(int sig)
+{
+	trace_stop = 1;
+}
+
+static void trace_usage(void)
+{
+	puts("bcachefs trace - stream bcachefs kernel tracepoints\n"
+	     "Usage: bcachefs trace [OPTION]...\n"
+	     "\n"
+	     "Enables bcachefs tracepoints via tracefs, and streams events until\n"
+	     "interrupted; tracepoints are put back as they were on exit.\n"
+	     "\n"
+	     "Options:\n"
+	     "  -e, --event=event           Trace only events matching event, which\n"
+	     "                              may be a glob (e.g. 'btree_node_split',\n"
+	     "                              'journal_*', 'bucket_alloc*'); may be\n"
+	     "                              given multiple times\n"
+	     "  -f, --filter=expr           Kernel event filter expression, applied to\n"
+	     "                              all enabled events\n"
+	     "  -l, --list                  List available events and exit\n"
+	     "  -j, --json                  Decode events as JSON objects, one per line\n"
+	     "  -h, --help                  display this help and exit\n"
+	     "Report bugs to <linux-bcache@vger.kernel.org>");
+	exit(EXIT_SUCCESS);
+}
+
+int cmd_trace(int argc, char *argv[])
+{
+	static const struct option longopts[] = {
+		{ "event",		required_argument,	NULL, 'e' },
+		{ "filter",		required_argument,	NULL, 'f' },
+		{ "list",		no_argument,		NULL, 'l' },
+		{ "json",		no_argument,		NULL, 'j' },
+		{ "help",		no_argument,		NULL, 'h' },
+		{ NULL }
+	};
+	darray(char *) patterns;
+	char *filter = NULL, **e;
+	bool list = false, json = false;
+	int opt;
+
+	darray_init(patterns);
+
+	while ((opt = getopt_long(argc, argv, "e:f:ljh",
+				  longopts, NULL)) != -1)
+		switch (opt) {
+		case 'e':
+			darray_append(patterns, optarg);
+			break;
+		case 'f':
+			filter = optarg;
+			break;
+		case 'l':
+			list = true;
+			break;
+		case 'j':
+			json = true;
+			break;
+		case 'h':
+			trace_usage();
+		}
+	args_shift(optind);
+
+	if (argc)
+		die("Too many arguments");
+
+	int tracefs = tracefs_open();
+	event_list events = tracefs_events(tracefs,
+					   &darray_item(patterns, 0),
+					   darray_size(patterns));
+
+	if (!darray_size(events))
+		die("No matching events");
+
+	if (list) {
+		darray_foreach(e, events)
+			printf("%s\n", *e);
+		goto out;
+	}
+
+	/* Without SA_RESTART, so that reading trace_pipe is interrupted: */
+	struct sigaction sa = { .sa_handler = trace_sig_handler };
+	sigaction(SIGINT,	&sa, NULL);
+	sigaction(SIGTERM,	&sa, NULL);
+	sigaction(SIGHUP,	&sa, NULL);
+	/* A closed stdout is an error from fputs() instead: */
+	signal(SIGPIPE, SIG_IGN);
+
+	events_enable(tracefs, events, filter);
+
+	FILE *pipe = fdopen(xopenat(tracefs, "trace_pipe", O_RDONLY), "r");
+	char *line = NULL;
+	size_t n = 0;
+
+	while (!trace_stop && getline(&line, &n, pipe) >= 0) {
+		if (json) {
+			line[strcspn(line, "\n")] = '\0';
+			print_event_json(line);
+		} else {
+			fputs(line, stdout);
+		}
+
+		if (ferror(stdout))
+			break;
+	}
+
+	free(line);
+	fclose(pipe);
+
+	events_restore();
+out:
+	darray_foreach(e, events)
+		free(*e);
+	darray_free(events);
+	darray_free(patterns);
+	close(tracefs);
+	return 0;
+}
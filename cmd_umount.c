@@ -0,0 +1,202 @@
+#include <dirent.h>
+#include <errno.h>
+#include <getopt.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <sys/mount.h>
+#include <sys/stat.h>
+#include <sys/sysmacros.h>
+#include <sys/types.h>
+#include <unistd.h>
+
+#include "cmds.h"
+#include "libbcachefs.h"
+
+static bool path_on_dev(const char *path, dev_t dev)
+{
+	struct stat st;
+
+	return !stat(path, &st) && st.st_dev == dev;
+}
+
+/* Does the process map a file from @dev? */
+static bool maps_on_dev(const char *pid, dev_t dev)
+{
+	char *path = mprintf("/proc/%s/maps", pid);
+	FILE *f = fopen(path, "r");
+	char *line = NULL;
+	size_t n = 0;
+	bool ret = false;
+
+	free(path);
+	if (!f)
+		return false;
+
+	while (!ret && getline(&line, &n, f) >= 0) {
+		unsigned maj, min;
+
+		/* address perms offset dev inode path */
+		if (sscanf(line, "%*s %*s %*s %x:%x", &maj, &min) == 2 &&
+		    makedev(maj, min) == dev)
+			ret = true;
+	}
+
+	free(line);
+	fclose(f);
+	return ret;
+}
+
+static bool fds_on_dev(const char *pid, dev_t dev)
+{
+	char *path = mprintf("/proc/%s/fd", pid);
+	DIR *dir = opendir(path);
+	struct dirent *d;
+	bool ret = false;
+
+	if (!dir)
+		goto out;
+
+	while (!ret && (d = readdir(dir))) {
+		char *fd = mprintf("%s/%s", path, d->d_name);
+
+		if (d->d_name[0] != '.')
+			ret = path_on_dev(fd, dev);
+		free(fd);
+	}
+
+	closedir(dir);
+out:
+	free(path);
+	return ret;
+}
+
+/* procfs files have no size, so read_file_str() doesn't work on them: */
+static char *proc_comm(const char *pid)
+{
+	char *path = mprintf("/proc/%s/comm", pid);
+	FILE *f = fopen(path, "r");
+	char comm[64] = "?";
+
+	free(path);
+	if (f) {
+		if (!fgets(comm, sizeof(comm), f))
+			strcpy(comm, "?");
+		fclose(f);
+	}
+
+	return strdup(strim(comm));
+}
+
+/*
+ * Print processes using the filesystem on @dev, in the same way as fuser -m:
+ * returns the number found
+ */
+static unsigned print_busy_processes(dev_t dev)
+{
+	static const char * const links[] = { "cwd", "root", "exe" };
+	DIR *dir = opendir("/proc");
+	struct dirent *d;
+	unsigned i, nr = 0;
+
+	if (!dir)
+		die("error opening /proc: %m");
+
+	while ((d = readdir(dir))) {
+		char uses[64] = "";
+
+		if (!strtoul(d->d_name, NULL, 10))
+			continue;
+
+		for (i = 0; i < ARRAY_SIZE(links); i++) {
+			char *path = mprintf("/proc/%s/%s", d->d_name, links[i]);
+
+			if (path_on_dev(path, dev)) {
+				strcat(uses, " ");
+				strcat(uses, links[i]);
+			}
+			free(path);
+		}
+
+		if (fds_on_dev(d->d_name, dev))
+			strcat(uses, " open-files");
+		if (maps_on_dev(d->d_name, dev))
+			strcat(uses, " mmap");
+
+		if (!uses[0])
+			continue;
+
+		if (!nr++)
+			printf("%8s %-16s %s\n", "PID", "COMMAND", "USE");
+
+		char *comm = proc_comm(d->d_name);
+		printf("%8s %-16s%s\n", d->d_name, comm, uses);
+		free(comm);
+	}
+
+	closedir(dir);
+	return nr;
+}
+
+static void umount_usage(void)
+{
+	puts("bcachefs umount - unmount a filesystem\n"
+	     "Usage: bcachefs umount [OPTION]... <mountpoint>\n"
+	     "\n"
+	     "If the filesystem is busy, lists the processes keeping it busy.\n"
+	     "\n"
+	     "Options:\n"
+	     "  -l, --lazy                  Detach the filesystem now, and clean up once\n"
+	     "                              it's no longer busy\n"
+	     "  -f, --force                 Force unmount\n"
+	     "  -h, --help                  display this help and exit\n"
+	     "Report bugs to <linux-bcache@vger.kernel.org>");
+	exit(EXIT_SUCCESS);
+}
+
+int cmd_umount(int argc, char *argv[])
+{
+	static const struct option longopts[] = {
+		{ "lazy",		no_argument,		NULL, 'l' },
+		{ "force",		no_argument,		NULL, 'f' },
+		{ "help",		no_argument,		NULL, 'h' },
+		{ NULL }
+	};
+	int opt, flags = 0;
+
+	while ((opt = getopt_long(argc, argv, "lfh", longopts, NULL)) != -1)
+		switch (opt) {
+		case 'l':
+			flags |= MNT_DETACH;
+			break;
+		case 'f':
+			flags |= MNT_FORCE;
+			break;
+		case 'h':
+			umount_usage();
+		}
+	args_shift(optind);
+
+	char *path = arg_pop();
+	if (!path)
+		die("Please supply a mountpoint");
+
+	if (argc)
+		die("Too many arguments");
+
+	struct stat st = xstat(path);
+
+	if (!umount2(path, flags))
+		return 0;
+
+	if (errno != EBUSY)
+		die("error unmounting %s: %m", path);
+
+	fprintf(stderr, "%s: filesystem is busy\n", path);
+
+	if (!print_busy_processes(st.st_dev))
+		fprintf(stderr, "No processes found using %s; it may be in use by the kernel\n"
+			"(e.g. a loop device, swapfile, or bind mount)\n", path);
+
+	exit(EXIT_FAILURE);
+}
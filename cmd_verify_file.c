@@ -0,0 +1,286 @@
+#include <errno.h>
+#include <getopt.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <sys/types.h>
+#include <unistd.h>
+
+#include "cmds.h"
+#include "crypto.h"
+#include "libbcachefs.h"
+
+#include "libbcachefs/bcachefs.h"
+#include "libbcachefs/btree_iter.h"
+#include "libbcachefs/buckets.h"
+#include "libbcachefs/checksum.h"
+#include "libbcachefs/error.h"
+#include "libbcachefs/extents.h"
+#include "libbcachefs/inode.h"
+#include "libbcachefs/super.h"
+
+struct verify_stats {
+	u64		extents;
+	u64		replicas;
+	u64		unchecksummed;
+	u64		unavailable;
+	u64		skipped;
+	u64		bad;
+	u64		repaired;
+};
+
+enum replica_state {
+	REPLICA_GOOD,
+	REPLICA_BAD,
+	REPLICA_UNCHECKED,
+};
+
+struct replica {
+	struct extent_ptr_decoded	p;
+	struct bch_dev			*ca;
+	void				*buf;
+	enum replica_state		state;
+};
+
+/* Replicas can only be copied if they're the same data, encoded the same way: */
+static bool replicas_match(struct replica *l, struct replica *r)
+{
+	return l->p.crc.csum_type	== r->p.crc.csum_type &&
+		l->p.crc.compression_type == r->p.crc.compression_type &&
+		l->p.crc.compressed_size == r->p.crc.compressed_size &&
+		l->p.crc.uncompressed_size == r->p.crc.uncompressed_size &&
+		l->p.crc.nonce		== r->p.crc.nonce &&
+		!bch2_crc_cmp(l->p.crc.csum, r->p.crc.csum);
+}
+
+static void replica_read(struct bch_fs *c, struct bkey_s_c k,
+			 struct replica *r, struct verify_stats *s)
+{
+	size_t bytes = r->p.crc.compressed_size << 9;
+
+	r->state = REPLICA_UNCHECKED;
+
+	if (!r->p.crc.csum_type) {
+		s->unchecksummed++;
+		return;
+	}
+
+	if (!r->ca->disk_sb.bdev) {
+		s->unavailable++;
+		return;
+	}
+
+	/* Cached data may have been overwritten since: */
+	if (r->p.ptr.cached && ptr_stale(r->ca, &r->p.ptr)) {
+		s->skipped++;
+		return;
+	}
+
+	r->buf = xmalloc(bytes);
+
+	if (pread(r->ca->disk_sb.bdev->bd_fd, r->buf, bytes,
+		  r->p.ptr.offset << 9) != bytes) {
+		fprintf(stderr, "%s: error reading sector %llu: %m\n",
+			r->ca->name, (u64) r->p.ptr.offset);
+		r->state = REPLICA_BAD;
+		return;
+	}
+
+	struct nonce nonce = extent_nonce(k.k->version, r->p.crc);
+	struct bch_csum csum = bch2_checksum(c, r->p.crc.csum_type,
+					     nonce, r->buf, bytes);
+
+	r->state = bch2_crc_cmp(csum, r->p.crc.csum)
+		? REPLICA_BAD
+		: REPLICA_GOOD;
+}
+
+static void verify_extent(struct bch_fs *c, struct bkey_s_c k,
+			  bool repair, struct verify_stats *s)
+{
+	struct bkey_ptrs_c ptrs = bch2_bkey_ptrs_c(k);
+	const union bch_extent_entry *entry;
+	struct extent_ptr_decoded p;
+	struct replica replicas[BCH_BKEY_PTRS_MAX], *r, *good;
+	unsigned nr = 0;
+
+	s->extents++;
+
+	bkey_for_each_ptr_decode(k.k, ptrs, p, entry) {
+		r = replicas + nr++;
+		memset(r, 0, sizeof(*r));
+		r->p	= p;
+		r->ca	= bch_dev_bkey_exists(c, p.ptr.dev);
+
+		replica_read(c, k, r, s);
+		s->replicas++;
+	}
+
+	for (r = replicas; r < replicas + nr; r++) {
+		if (r->state != REPLICA_BAD)
+			continue;
+
+		s->bad++;
+		printf("inode %llu sectors %llu-%llu: bad replica on %s (dev %u) at sector %llu",
+		       k.k->p.inode, bkey_start_offset(k.k), k.k->p.offset,
+		       r->ca->name, r->p.ptr.dev, (u64) r->p.ptr.offset);
+
+		for (good = replicas; good < replicas + nr; good++)
+			if (good->state == REPLICA_GOOD &&
+			    replicas_match(good, r))
+				break;
+
+		if (good == replicas + nr) {
+			printf(", no good replica to repair it from\n");
+			continue;
+		}
+
+		if (!repair) {
+			printf(", good replica on %s\n", good->ca->name);
+			continue;
+		}
+
+		xpwrite(r->ca->disk_sb.bdev->bd_fd, good->buf,
+			good->p.crc.compressed_size << 9,
+			r->p.ptr.offset << 9);
+		if (fsync(r->ca->disk_sb.bdev->bd_fd))
+			die("error writing %s: %m", r->ca->name);
+
+		printf(", rewritten from %s\n", good->ca->name);
+		s->repaired++;
+	}
+
+	for (r = replicas; r < replicas + nr; r++)
+		free(r->buf);
+}
+
+static void verify_inode(struct bch_fs *c, u64 inum, bool repair,
+			 struct verify_stats *s)
+{
+	struct btree_trans trans;
+	struct btree_iter *iter;
+	struct bkey_s_c k;
+	int ret;
+
+	bch2_trans_init(&trans, c, 0, 0);
+
+	for_each_btree_key(&trans, iter, BTREE_ID_extents, POS(inum, 0),
+			   BTREE_ITER_PREFETCH, k, ret) {
+		if (k.k->p.inode != inum)
+			break;
+
+		switch (k.k->type) {
+		case KEY_TYPE_extent:
+			verify_extent(c, k, repair, s);
+			break;
+		case KEY_TYPE_reflink_p:
+			/* Indirect extents may be shared with other files: */
+			s->skipped++;
+			break;
+		}
+	}
+	bch2_trans_iter_put(&trans, iter);
+
+	bch2_trans_exit(&trans);
+
+	if (ret)
+		die("error walking extents of inode %llu: %s", inum, strerror(-ret));
+}
+
+static void verify_file_usage(void)
+{
+	puts("bcachefs verify-file - verify the checksums of every replica of a file\n"
+	     "Usage: bcachefs verify-file [OPTION]... <devices>... <path>\n"
+	     "   or: bcachefs verify-file [OPTION]... -i inum <devices>...\n"
+	     "\n"
+	     "Reads every replica of each extent of a file in an (unmounted)\n"
+	     "filesystem, path being relative to its root, and reports the device\n"
+	     "and sector of each replica that doesn't match its checksum. Reflinked\n"
+	     "extents, and cached replicas that have since been overwritten, are\n"
+	     "skipped. Exits with status 1 if bad replicas were left unrepaired.\n"
+	     "\n"
+	     "Options:\n"
+	     "  -i, --inum=inum             Verify this inode instead of looking up a path\n"
+	     "  -r, --repair                Rewrite bad replicas from a good replica of\n"
+	     "                              the same extent; the filesystem must have\n"
+	     "                              been shut down cleanly\n"
+	     "  -k, --passphrase-file=file  Read the passphrase of an encrypted filesystem\n"
+	     "                              from file\n"
+	     "  -h, --help                  display this help and exit\n"
+	     "Report bugs to <linux-bcache@vger.kernel.org>");
+	exit(EXIT_SUCCESS);
+}
+
+int cmd_verify_file(int argc, char *argv[])
+{
+	static const struct option longopts[] = {
+		{ "inum",		required_argument,	NULL, 'i' },
+		{ "repair",		no_argument,		NULL, 'r' },
+		{ "passphrase-file",	required_argument,	NULL, 'k' },
+		{ "help",		no_argument,		NULL, 'h' },
+		{ NULL }
+	};
+	struct bch_opts opts = bch2_opts_empty();
+	struct verify_stats s = { 0 };
+	char *passphrase_file = NULL, *path = NULL;
+	bool repair = false;
+	u64 inum = 0;
+	int opt;
+
+	while ((opt = getopt_long(argc, argv, "i:rk:h", longopts, NULL)) != -1)
+		switch (opt) {
+		case 'i':
+			if (kstrtoull(optarg, 10, &inum) || !inum)
+				die("invalid inode %s", optarg);
+			break;
+		case 'r':
+			repair = true;
+			break;
+		case 'k':
+			passphrase_file = optarg;
+			break;
+		case 'h':
+			verify_file_usage();
+		}
+	args_shift(optind);
+
+	if (!inum) {
+		if (argc < 2)
+			die("Please supply device(s) and a path");
+		path = argv[--argc];
+	}
+
+	if (!argc)
+		die("Please supply device(s)");
+
+	/* Repairing writes to the devices directly, so they're opened for writing: */
+	opt_set(opts, nochanges,	!repair);
+	opt_set(opts, read_only,	true);
+	opt_set(opts, norecovery,	true);
+	opt_set(opts, degraded,		true);
+	opt_set(opts, errors,		BCH_ON_ERROR_continue);
+
+	bch2_add_key_offline(argv[0], passphrase_file);
+
+	struct bch_fs *c = bch2_fs_open(argv, argc, opts);
+	if (IS_ERR(c))
+		die("error opening %s: %s", argv[0], strerror(-PTR_ERR(c)));
+
+	/* Unreplayed journal entries may have moved extents elsewhere: */
+	if (repair && !c->sb.clean)
+		die("%s wasn't shut down cleanly: mount it once, or run fsck, first",
+		    argv[0]);
+
+	if (path)
+		inum = bch2_path_to_inum(c, path);
+
+	verify_inode(c, inum, repair, &s);
+
+	printf("%llu extents, %llu replicas: %llu bad, %llu repaired; "
+	       "%llu not checksummed, %llu on missing devices, %llu skipped\n",
+	       s.extents, s.replicas, s.bad, s.repaired,
+	       s.unchecksummed, s.unavailable, s.skipped);
+
+	bch2_fs_stop(c);
+	return s.bad != s.repaired;
+}
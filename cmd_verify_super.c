@@ -0,0 +1,319 @@
+#include <getopt.h>
+#include <stdarg.h>
+#include <stdio.h>
+#include <string.h>
+#include <sys/types.h>
+
+#include <uuid/uuid.h>
+
+#include "cmds.h"
+#include "libbcachefs.h"
+
+#include "libbcachefs/bcachefs.h"
+#include "libbcachefs/opts.h"
+#include "libbcachefs/super-io.h"
+
+struct verify_sb {
+	const char		*path;
+	struct bch_sb_handle	sb;
+	unsigned		nr_diffs;
+};
+
+static void diff(struct verify_sb *v, const char *fmt, ...)
+{
+	va_list args;
+
+	if (!v->nr_diffs++)
+		printf("%s (device %u):\n", v->path, v->sb.sb->dev_idx);
+
+	printf("  ");
+	va_start(args, fmt);
+	vprintf(fmt, args);
+	va_end(args);
+	printf("\n");
+}
+
+/* Targets can't be printed by name without a running filesystem: */
+static void opt_val_to_text(struct printbuf *out,
+			    const struct bch_option *opt, u64 v)
+{
+	if (opt->type == BCH_OPT_FN)
+		pr_buf(out, "%llu", v);
+	else
+		bch2_opt_to_text(out, NULL, opt, v, 0);
+}
+
+static void verify_header(struct verify_sb *v, struct bch_sb *ref)
+{
+	struct bch_sb *sb = v->sb.sb;
+	unsigned i;
+
+	if (sb->version != ref->version)
+		diff(v, "version %u, should be %u",
+		     le16_to_cpu(sb->version), le16_to_cpu(ref->version));
+
+	if (sb->block_size != ref->block_size)
+		diff(v, "block_size %u, should be %u",
+		     le16_to_cpu(sb->block_size), le16_to_cpu(ref->block_size));
+
+	if (sb->nr_devices != ref->nr_devices)
+		diff(v, "nr_devices %u, should be %u",
+		     sb->nr_devices, ref->nr_devices);
+
+	if (memcmp(sb->label, ref->label, sizeof(sb->label)))
+		diff(v, "label %.*s, should be %.*s",
+		     (int) sizeof(sb->label), sb->label,
+		     (int) sizeof(ref->label), ref->label);
+
+	for (i = 0; i < ARRAY_SIZE(sb->features); i++)
+		if (sb->features[i] != ref->features[i])
+			diff(v, "features[%u] %llx, should be %llx", i,
+			     le64_to_cpu(sb->features[i]),
+			     le64_to_cpu(ref->features[i]));
+
+	for (i = 0; i < ARRAY_SIZE(sb->compat); i++)
+		if (sb->compat[i] != ref->compat[i])
+			diff(v, "compat[%u] %llx, should be %llx", i,
+			     le64_to_cpu(sb->compat[i]),
+			     le64_to_cpu(ref->compat[i]));
+}
+
+static void verify_options(struct verify_sb *v, struct bch_sb *ref)
+{
+	struct bch_opts opts = bch2_opts_from_sb(v->sb.sb);
+	struct bch_opts ref_opts = bch2_opts_from_sb(ref);
+	unsigned id;
+
+	for (id = 0; id < bch2_opts_nr; id++) {
+		const struct bch_option *opt = bch2_opt_table + id;
+		char buf[100], ref_buf[100];
+
+		if (opt->set_sb == SET_NO_SB_OPT)
+			continue;
+
+		u64 val = bch2_opt_get_by_id(&opts, id);
+		u64 ref_val = bch2_opt_get_by_id(&ref_opts, id);
+
+		if (val == ref_val)
+			continue;
+
+		opt_val_to_text(&PBUF(buf), opt, val);
+		opt_val_to_text(&PBUF(ref_buf), opt, ref_val);
+
+		diff(v, "option %s=%s, should be %s",
+		     opt->attr.name, buf, ref_buf);
+	}
+}
+
+static void verify_members(struct verify_sb *v, struct bch_sb *ref)
+{
+	struct bch_sb_field_members *mi = bch2_sb_get_members(v->sb.sb);
+	struct bch_sb_field_members *ref_mi = bch2_sb_get_members(ref);
+	unsigned i, nr = max(v->sb.sb->nr_devices, ref->nr_devices);
+
+	if (!mi || !ref_mi) {
+		if (mi != ref_mi)
+			diff(v, "members field %s", mi ? "present" : "missing");
+		return;
+	}
+
+	for (i = 0; i < nr; i++) {
+		bool exists	= bch2_dev_exists(v->sb.sb, mi, i);
+		bool ref_exists	= bch2_dev_exists(ref, ref_mi, i);
+		struct bch_member *m = mi->members + i;
+		struct bch_member *r = ref_mi->members + i;
+
+		if (exists != ref_exists) {
+			diff(v, "member %u %s", i, exists ? "exists" : "missing");
+			continue;
+		}
+
+		if (!exists)
+			continue;
+
+		if (memcmp(&m->uuid, &r->uuid, sizeof(m->uuid))) {
+			diff(v, "member %u is a different device", i);
+			continue;
+		}
+
+		if (BCH_MEMBER_STATE(m) != BCH_MEMBER_STATE(r))
+			diff(v, "member %u state %s, should be %s", i,
+			     BCH_MEMBER_STATE(m) < BCH_MEMBER_STATE_NR
+			     ? bch2_member_states[BCH_MEMBER_STATE(m)] : "unknown",
+			     BCH_MEMBER_STATE(r) < BCH_MEMBER_STATE_NR
+			     ? bch2_member_states[BCH_MEMBER_STATE(r)] : "unknown");
+
+		if (m->nbuckets != r->nbuckets)
+			diff(v, "member %u nbuckets %llu, should be %llu", i,
+			     le64_to_cpu(m->nbuckets), le64_to_cpu(r->nbuckets));
+
+		if (m->bucket_size != r->bucket_size ||
+		    m->first_bucket != r->first_bucket)
+			diff(v, "member %u bucket layout differs", i);
+
+		if (BCH_MEMBER_GROUP(m) != BCH_MEMBER_GROUP(r) ||
+		    BCH_MEMBER_DURABILITY(m) != BCH_MEMBER_DURABILITY(r) ||
+		    BCH_MEMBER_DATA_ALLOWED(m) != BCH_MEMBER_DATA_ALLOWED(r) ||
+		    BCH_MEMBER_DISCARD(m) != BCH_MEMBER_DISCARD(r))
+			diff(v, "member %u options differ", i);
+	}
+}
+
+static void verify_crypt(struct verify_sb *v, struct bch_sb *ref)
+{
+	struct bch_sb_field_crypt *crypt = bch2_sb_get_crypt(v->sb.sb);
+	struct bch_sb_field_crypt *ref_crypt = bch2_sb_get_crypt(ref);
+
+	if (!crypt != !ref_crypt)
+		diff(v, "encryption %s", crypt ? "enabled" : "disabled");
+	else if (crypt &&
+		 (crypt->flags != ref_crypt->flags ||
+		  memcmp(&crypt->key, &ref_crypt->key, sizeof(crypt->key))))
+		diff(v, "encryption key differs");
+}
+
+/*
+ * Everything else is compared as a whole - except the journal field, which
+ * lists the journal buckets of that device only:
+ */
+static void verify_fields(struct verify_sb *v, struct bch_sb *ref)
+{
+	unsigned type;
+
+	for (type = 0; type < BCH_SB_FIELD_NR; type++) {
+		if (type == BCH_SB_FIELD_journal ||
+		    type == BCH_SB_FIELD_members ||
+		    type == BCH_SB_FIELD_crypt)
+			continue;
+
+		struct bch_sb_field *f = bch2_sb_field_get(v->sb.sb, type);
+		struct bch_sb_field *r = bch2_sb_field_get(ref, type);
+
+		if (!f != !r)
+			diff(v, "%s field %s", bch2_sb_fields[type],
+			     f ? "present" : "missing");
+		else if (f &&
+			 (vstruct_bytes(f) != vstruct_bytes(r) ||
+			  memcmp(f, r, vstruct_bytes(f))))
+			diff(v, "%s field differs", bch2_sb_fields[type]);
+	}
+}
+
+static void verify_super_usage(void)
+{
+	puts("bcachefs verify-super - check members of a filesystem agree on the superblock\n"
+	     "Usage: bcachefs verify-super [OPTION]... <devices>...\n"
+	     "\n"
+	     "Reads the superblock of each device, and compares each against the newest\n"
+	     "one (highest seq) - which is the one used when the filesystem is opened.\n"
+	     "Prints every difference, and what to do about it. Exits with status 1 if\n"
+	     "any are found.\n"
+	     "\n"
+	     "Options:\n"
+	     "  -h, --help                  display this help and exit\n"
+	     "Report bugs to <linux-bcache@vger.kernel.org>");
+	exit(EXIT_SUCCESS);
+}
+
+int cmd_verify_super(int argc, char *argv[])
+{
+	static const struct option longopts[] = {
+		{ "help",		no_argument,		NULL, 'h' },
+		{ NULL }
+	};
+	struct verify_sb *v;
+	unsigned i, ref = 0;
+	bool diverged = false;
+	int opt;
+
+	while ((opt = getopt_long(argc, argv, "h", longopts, NULL)) != -1)
+		switch (opt) {
+		case 'h':
+			verify_super_usage();
+		}
+	args_shift(optind);
+
+	if (!argc)
+		die("Please supply one or more devices");
+
+	v = xcalloc(argc, sizeof(*v));
+
+	for (i = 0; i < argc; i++) {
+		struct bch_opts opts = bch2_opts_empty();
+
+		opt_set(opts, noexcl,	true);
+		opt_set(opts, nochanges, true);
+
+		v[i].path = argv[i];
+
+		int ret = bch2_read_super(argv[i], &opts, &v[i].sb);
+		if (ret)
+			die("Error opening %s: %s", argv[i], strerror(-ret));
+
+		if (memcmp(&v[i].sb.sb->user_uuid,
+			   &v[0].sb.sb->user_uuid,
+			   sizeof(v[0].sb.sb->user_uuid)))
+			die("%s is not a member of the same filesystem as %s",
+			    argv[i], argv[0]);
+
+		if (le64_to_cpu(v[i].sb.sb->seq) > le64_to_cpu(v[ref].sb.sb->seq))
+			ref = i;
+	}
+
+	struct bch_sb *ref_sb = v[ref].sb.sb;
+	u64 ref_seq = le64_to_cpu(ref_sb->seq);
+
+	for (i = 0; i < argc; i++) {
+		u64 seq = le64_to_cpu(v[i].sb.sb->seq);
+
+		if (i == ref)
+			continue;
+
+		if (seq != ref_seq)
+			diff(&v[i], "seq %llu, newest is %llu on %s",
+			     seq, ref_seq, v[ref].path);
+
+		verify_header(&v[i], ref_sb);
+		verify_options(&v[i], ref_sb);
+		verify_members(&v[i], ref_sb);
+		verify_crypt(&v[i], ref_sb);
+		verify_fields(&v[i], ref_sb);
+
+		if (!v[i].nr_diffs)
+			continue;
+
+		diverged = true;
+
+		if (seq < ref_seq)
+			printf("  Resolution: older superblock, it will be rewritten from %s\n"
+			       "  the next time the filesystem is mounted with both devices\n",
+			       v[ref].path);
+		else
+			printf("  Resolution: superblocks with the same seq differ, so the\n"
+			       "  members were written separately. Mount with the members that\n"
+			       "  are trusted, after bcachefs device detach of the others\n");
+	}
+
+	struct bch_sb_field_members *mi = bch2_sb_get_members(ref_sb);
+	for (i = 0; mi && i < ref_sb->nr_devices; i++) {
+		unsigned j;
+
+		if (!bch2_dev_exists(ref_sb, mi, i))
+			continue;
+
+		for (j = 0; j < argc; j++)
+			if (v[j].sb.sb->dev_idx == i)
+				break;
+
+		if (j == argc)
+			printf("member %u not checked: no device given\n", i);
+	}
+
+	if (!diverged)
+		printf("All %u superblocks agree (seq %llu)\n", argc, ref_seq);
+
+	for (i = 0; i < argc; i++)
+		bch2_free_super(&v[i].sb);
+	free(v);
+	return diverged;
+}
@@ -0,0 +1,269 @@
+#include <errno.h>
+#include <fcntl.h>
+#include <getopt.h>
+#include <limits.h>
+#include <signal.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+/* Our linux/types.h doesn't define __kernel_fsid_t, which fanotify needs: */
+#include <linux/posix_types.h>
+#include <sys/fanotify.h>
+#include <sys/stat.h>
+#include <sys/types.h>
+#include <time.h>
+#include <unistd.h>
+
+#include <uuid/uuid.h>
+
+#include "cmds.h"
+#include "libbcachefs.h"
+#include "sandbox.h"
+
+/*
+ * fanotify, reporting the directory and name of each change as a file handle
+ * (FAN_REPORT_DFID_NAME), instead of an fd: so that creates, deletes and
+ * renames are reported, and unlike inotify one mark covers the whole
+ * filesystem.
+ */
+
+static const struct {
+	u64		mask;
+	const char	*name;
+} watch_events[] = {
+	{ FAN_CREATE,		"create"	},
+	{ FAN_DELETE,		"delete"	},
+	{ FAN_MOVED_FROM,	"moved_from"	},
+	{ FAN_MOVED_TO,		"moved_to"	},
+	{ FAN_MODIFY,		"modify"	},
+	{ FAN_ATTRIB,		"attrib"	},
+	{ FAN_CLOSE_WRITE,	"close_write"	},
+	{ FAN_DELETE_SELF,	"delete_self"	},
+	{ FAN_MOVE_SELF,	"move_self"	},
+};
+
+static u64 watch_events_parse(char *s)
+{
+	char *name;
+	u64 mask = 0;
+	unsigned i;
+
+	while ((name = strsep(&s, ","))) {
+		for (i = 0; i < ARRAY_SIZE(watch_events); i++)
+			if (!strcmp(name, watch_events[i].name))
+				break;
+
+		if (i == ARRAY_SIZE(watch_events))
+			die("invalid event %s", name);
+
+		mask |= watch_events[i].mask;
+	}
+
+	return mask;
+}
+
+/*
+ * Opening a directory by its handle needs CAP_DAC_READ_SEARCH, and fails once
+ * the directory has been deleted: then only its inode number is known.
+ */
+static void print_dir(int mount_fd, struct file_handle *fh, const char *name)
+{
+	char proc_path[64], path[PATH_MAX];
+	struct stat st;
+	ssize_t len;
+
+	int fd = open_by_handle_at(mount_fd, fh, O_PATH);
+	if (fd < 0) {
+		printf(",\"dir\":null");
+		return;
+	}
+
+	snprintf(proc_path, sizeof(proc_path), "/proc/self/fd/%d", fd);
+	len = readlink(proc_path, path, sizeof(path) - 1);
+	if (len >= 0) {
+		path[len] = '\0';
+		printf(",\"dir\":");
+		json_str(stdout, path);
+	}
+
+	if (!fstat(fd, &st))
+		printf(",\"dir_inum\":%llu", (u64) st.st_ino);
+
+	/* Not there for deletes, or the old name of a rename: */
+	if (name && !fstatat(fd, name, &st, AT_SYMLINK_NOFOLLOW))
+		printf(",\"inum\":%llu", (u64) st.st_ino);
+
+	close(fd);
+}
+
+static void print_watch_event(int mount_fd, struct fanotify_event_metadata *m,
+			      const char *uuid)
+{
+	struct fanotify_event_info_header *info;
+	struct timespec now;
+	bool first = true;
+	unsigned i;
+
+	clock_gettime(CLOCK_REALTIME, &now);
+	printf("{\"time\":%lld.%09ld,\"uuid\":\"%s\",\"events\":[",
+	       (long long) now.tv_sec, now.tv_nsec, uuid);
+
+	if (m->mask & FAN_Q_OVERFLOW) {
+		/* Events were lost: consumers should rescan */
+		printf("\"overflow\"]}\n");
+		return;
+	}
+
+	for (i = 0; i < ARRAY_SIZE(watch_events); i++)
+		if (m->mask & watch_events[i].mask) {
+			printf("%s\"%s\"", first ? "" : ",", watch_events[i].name);
+			first = false;
+		}
+	printf("],\"isdir\":%s", m->mask & FAN_ONDIR ? "true" : "false");
+
+	for (info = (void *) (m + 1);
+	     (void *) info < (void *) m + m->event_len;
+	     info = (void *) info + info->len) {
+		struct fanotify_event_info_fid *fid = (void *) info;
+		struct file_handle *fh = (void *) fid->handle;
+		const char *name = NULL;
+
+		switch (info->info_type) {
+		case FAN_EVENT_INFO_TYPE_DFID_NAME:
+			name = (char *) fh->f_handle + fh->handle_bytes;
+			printf(",\"name\":");
+			json_str(stdout, name);
+			/* fallthrough */
+		case FAN_EVENT_INFO_TYPE_DFID:
+			print_dir(mount_fd, fh, name);
+			break;
+		}
+	}
+
+	printf("}\n");
+}
+
+static volatile sig_atomic_t watch_stop;
+
+static void watch_sig_handler(int sig)
+{
+	watch_stop = 1;
+}
+
+static void watch_usage(void)
+{
+	puts("bcachefs watch - stream changes to a mounted filesystem as JSON\n"
+	     "Usage: bcachefs watch [OPTION]... <mountpoint>\n"
+	     "\n"
+	     "Watches every file and directory of a mounted filesystem with fanotify,\n"
+	     "and prints each change as a JSON object per line, until interrupted:\n"
+	     "the events, the directory changed and its inode number, and the name\n"
+	     "and inode number of the file in it, for indexing and backup tools.\n"
+	     "Needs CAP_SYS_ADMIN, and CAP_DAC_READ_SEARCH to resolve directories.\n"
+	     "\n"
+	     "If the kernel's queue of events overflows, an overflow event is\n"
+	     "printed: changes were missed, and the filesystem should be rescanned.\n"
+	     "\n"
+	     "Options:\n"
+	     "  -e, --events=list           Events to watch for, comma separated: create,\n"
+	     "                              delete, moved_from, moved_to, modify, attrib,\n"
+	     "                              close_write, delete_self, move_self. Default\n"
+	     "                              all but modify\n"
+	     "  -u, --user=user             Once the filesystem is open, run as user,\n"
+	     "                              keeping only CAP_DAC_READ_SEARCH\n"
+	     "  -g, --group=group           Once the filesystem is open, run as group\n"
+	     "  -h, --help                  display this help and exit\n"
+	     "Report bugs to <linux-bcache@vger.kernel.org>");
+	exit(EXIT_SUCCESS);
+}
+
+int cmd_watch(int argc, char *argv[])
+{
+	static const struct option longopts[] = {
+		{ "events",		required_argument,	NULL, 'e' },
+		{ "user",		required_argument,	NULL, 'u' },
+		{ "group",		required_argument,	NULL, 'g' },
+		{ "help",		no_argument,		NULL, 'h' },
+		{ NULL }
+	};
+	const char *user = NULL, *group = NULL;
+	u64 mask = FAN_CREATE|FAN_DELETE|FAN_MOVED_FROM|FAN_MOVED_TO|
+		FAN_ATTRIB|FAN_CLOSE_WRITE|FAN_DELETE_SELF|FAN_MOVE_SELF;
+	char uuid[40];
+	int opt;
+
+	while ((opt = getopt_long(argc, argv, "e:u:g:h", longopts, NULL)) != -1)
+		switch (opt) {
+		case 'e':
+			mask = watch_events_parse(optarg);
+			break;
+		case 'u':
+			user = optarg;
+			break;
+		case 'g':
+			group = optarg;
+			break;
+		case 'h':
+			watch_usage();
+		}
+	args_shift(optind);
+
+	char *path = arg_pop();
+	if (!path)
+		die("Please supply a mountpoint");
+
+	if (argc)
+		die("Too many arguments");
+
+	/* Checks that it's bcachefs: */
+	struct bchfs_handle fs = bcache_fs_open(path);
+	uuid_unparse(fs.uuid.b, uuid);
+
+	int mount_fd = xopen(path, O_RDONLY|O_DIRECTORY);
+
+	int fan_fd = fanotify_init(FAN_CLASS_NOTIF|FAN_CLOEXEC|
+				   FAN_REPORT_DFID_NAME,
+				   O_RDONLY|O_LARGEFILE);
+	if (fan_fd < 0)
+		die("error initializing fanotify: %m");
+
+	if (fanotify_mark(fan_fd, FAN_MARK_ADD|FAN_MARK_FILESYSTEM,
+			  mask|FAN_ONDIR, mount_fd, NULL))
+		die("error watching %s: %m", path);
+
+	/* Only needed to resolve directories from now on: */
+	drop_privs(user, group, 1ULL << CAP_DAC_READ_SEARCH);
+
+	/* No SA_RESTART, so that read() returns: */
+	struct sigaction sa = { .sa_handler = watch_sig_handler };
+	sigaction(SIGINT,	&sa, NULL);
+	sigaction(SIGTERM,	&sa, NULL);
+
+	/* Events are variable length: */
+	static char buf[1 << 16] __aligned(__alignof__(struct fanotify_event_metadata));
+
+	while (!watch_stop) {
+		struct fanotify_event_metadata *m = (void *) buf;
+		ssize_t len = read(fan_fd, buf, sizeof(buf));
+
+		if (len < 0) {
+			if (errno == EINTR)
+				continue;
+			die("error reading events: %m");
+		}
+
+		for (; FAN_EVENT_OK(m, len); m = FAN_EVENT_NEXT(m, len)) {
+			if (m->vers != FANOTIFY_METADATA_VERSION)
+				die("unsupported fanotify version %u", m->vers);
+
+			print_watch_event(mount_fd, m, uuid);
+		}
+
+		fflush(stdout);
+	}
+
+	close(fan_fd);
+	close(mount_fd);
+	bcache_fs_close(fs);
+	return 0;
+}
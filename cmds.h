@@ -11,6 +11,8 @@
 
 int cmd_format(int argc, char *argv[]);
 int cmd_show_super(int argc, char *argv[]);
+int cmd_set_fs_option(int argc, char *argv[]);
+int cmd_verify_super(int argc, char *argv[]);
 
 #if 0
 int cmd_assemble(int argc, char *argv[]);
@@ -20,8 +22,18 @@ int cmd_stop(int argc, char *argv[]);
 #endif
 
 int cmd_fs_usage(int argc, char *argv[]);
+int cmd_fs_latency(int argc, char *argv[]);
+int cmd_fs_freeze(int argc, char *argv[]);
+int cmd_fs_thaw(int argc, char *argv[]);
+int cmd_journal_status(int argc, char *argv[]);
+int cmd_umount(int argc, char *argv[]);
+int cmd_du(int argc, char *argv[]);
+int cmd_sparesd(int argc, char *argv[]);
+int cmd_quota_warnd(int argc, char *argv[]);
+int cmd_discard(int argc, char *argv[]);
 
 int cmd_device_add(int argc, char *argv[]);
+int cmd_device_add_cache(int argc, char *argv[]);
 int cmd_device_remove(int argc, char *argv[]);
 int cmd_device_online(int argc, char *argv[]);
 int cmd_device_offline(int argc, char *argv[]);
@@ -29,6 +41,14 @@ int cmd_device_evacuate(int argc, char *argv[]);
 int cmd_device_set_state(int argc, char *argv[]);
 int cmd_device_resize(int argc, char *argv[]);
 int cmd_device_resize_journal(int argc, char *argv[]);
+int cmd_device_detach(int argc, char *argv[]);
+int cmd_device_attach(int argc, char *argv[]);
+int cmd_device_stats(int argc, char *argv[]);
+int cmd_device_inspect_layout(int argc, char *argv[]);
+
+int cmd_disk_groups_show(int argc, char *argv[]);
+int cmd_disk_groups_create(int argc, char *argv[]);
+int cmd_disk_groups_move(int argc, char *argv[]);
 
 int cmd_data_rereplicate(int argc, char *argv[]);
 int cmd_data_job(int argc, char *argv[]);
@@ -36,19 +56,50 @@ int cmd_data_job(int argc, char *argv[]);
 int cmd_unlock(int argc, char *argv[]);
 int cmd_set_passphrase(int argc, char *argv[]);
 int cmd_remove_passphrase(int argc, char *argv[]);
+int cmd_key_generate_keyfile(int argc, char *argv[]);
 
 int cmd_fsck(int argc, char *argv[]);
+int cmd_verify_file(int argc, char *argv[]);
+int cmd_check_free_space(int argc, char *argv[]);
+int cmd_explain(int argc, char *argv[]);
 
 int cmd_dump(int argc, char *argv[]);
 int cmd_list(int argc, char *argv[]);
 int cmd_list_journal(int argc, char *argv[]);
+int cmd_apply_journal(int argc, char *argv[]);
+int cmd_compare_images(int argc, char *argv[]);
+int cmd_shell(int argc, char *argv[]);
+int cmd_stat(int argc, char *argv[]);
+int cmd_orphans(int argc, char *argv[]);
+int cmd_export_checksums(int argc, char *argv[]);
+int cmd_trace(int argc, char *argv[]);
+int cmd_bug_report(int argc, char *argv[]);
+int cmd_create_test_image(int argc, char *argv[]);
+int cmd_corrupt(int argc, char *argv[]);
+int cmd_find_btree_nodes(int argc, char *argv[]);
+int cmd_bench(int argc, char *argv[]);
+int cmd_torture(int argc, char *argv[]);
+int cmd_testdev_create(int argc, char *argv[]);
+int cmd_testdev_set(int argc, char *argv[]);
+int cmd_testdev_remove(int argc, char *argv[]);
 
 int cmd_migrate(int argc, char *argv[]);
 int cmd_migrate_superblock(int argc, char *argv[]);
 
 int cmd_version(int argc, char *argv[]);
 
+int cmd_installer_api_version(int argc, char *argv[]);
+int cmd_installer_api_probe(int argc, char *argv[]);
+int cmd_installer_api_format(int argc, char *argv[]);
+int cmd_installer_api_mount(int argc, char *argv[]);
+int cmd_installer_api_set_passphrase(int argc, char *argv[]);
+
 int cmd_setattr(int argc, char *argv[]);
+int cmd_targets(int argc, char *argv[]);
+int cmd_cp(int argc, char *argv[]);
+int cmd_dedupe(int argc, char *argv[]);
+int cmd_check_tree(int argc, char *argv[]);
+int cmd_watch(int argc, char *argv[]);
 
 int cmd_fusemount(int argc, char *argv[]);
 
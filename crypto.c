@@ -13,10 +13,13 @@
 #include <keyutils.h>
 #include <linux/random.h>
 #include <libscrypt.h>
+#include <sodium/crypto_hash_sha256.h>
 #include <uuid/uuid.h>
 
 #include "libbcachefs/checksum.h"
+#include "libbcachefs/super-io.h"
 #include "crypto.h"
+#include "tools-util.h"
 
 char *read_passphrase(const char *prompt)
 {
@@ -75,6 +78,90 @@ char *read_passphrase_twice(const char *prompt)
 	return pass;
 }
 
+/*
+ * Keyfiles are used in place of a passphrase: if they're text, the contents,
+ * minus a trailing newline, are the passphrase. Anything containing NUL bytes
+ * is a binary key, and the passphrase is the hex SHA-256 of the whole file.
+ *
+ * Shared with mount.bcachefs, so that both agree on what a keyfile means;
+ * returns NULL for an empty keyfile:
+ */
+char *keyfile_to_passphrase(const void *buf, size_t len)
+{
+	char *pass;
+	unsigned i;
+
+	if (memchr(buf, '\0', len)) {
+		u8 hash[crypto_hash_sha256_BYTES];
+
+		crypto_hash_sha256(hash, buf, len);
+
+		pass = xmalloc(sizeof(hash) * 2 + 1);
+		for (i = 0; i < sizeof(hash); i++)
+			sprintf(pass + i * 2, "%02x", hash[i]);
+
+		memzero_explicit(hash, sizeof(hash));
+		return pass;
+	}
+
+	if (len && ((const char *) buf)[len - 1] == '\n')
+		len--;
+	if (!len)
+		return NULL;
+
+	pass = xmalloc(len + 1);
+	memcpy(pass, buf, len);
+	pass[len] = '\0';
+	return pass;
+}
+
+char *read_keyfile(const char *path)
+{
+	int fd = xopen(path, O_RDONLY);
+	struct stat st = xfstat(fd);
+
+	if (st.st_mode & (S_IRWXG|S_IRWXO))
+		fprintf(stderr, "warning: keyfile %s is accessible by other users\n",
+			path);
+
+	size_t len = st.st_size;
+	char *buf = xmalloc(len + 1);
+
+	xpread(fd, buf, len, 0);
+	close(fd);
+
+	char *pass = keyfile_to_passphrase(buf, len);
+
+	memzero_explicit(buf, len);
+	free(buf);
+
+	if (!pass)
+		die("keyfile %s is empty", path);
+	return pass;
+}
+
+void generate_keyfile(const char *path)
+{
+	u8 key[32];
+	char buf[sizeof(key) * 2 + 2];
+	unsigned i;
+
+	get_random_bytes(key, sizeof(key));
+
+	for (i = 0; i < sizeof(key); i++)
+		sprintf(buf + i * 2, "%02x", key[i]);
+	strcat(buf, "\n");
+
+	int fd = xopen(path, O_WRONLY|O_CREAT|O_EXCL, 0400);
+	xpwrite(fd, buf, strlen(buf), 0);
+	if (fsync(fd))
+		die("error writing %s: %m", path);
+	close(fd);
+
+	memzero_explicit(key, sizeof(key));
+	memzero_explicit(buf, sizeof(buf));
+}
+
 struct bch_key derive_passphrase(struct bch_sb_field_crypt *crypt,
 				 const char *passphrase)
 {
@@ -160,6 +247,53 @@ void bch2_add_key(struct bch_sb *sb, const char *passphrase)
 	memzero_explicit(&sb_key, sizeof(sb_key));
 }
 
+/*
+ * For tools that open an unmounted filesystem: make sure the key for @dev is
+ * in the keyring, if it's encrypted - from @passphrase_file if given, or else
+ * by prompting, if it's not there already:
+ */
+void bch2_add_key_offline(char *dev, const char *passphrase_file)
+{
+	struct bch_opts opts = bch2_opts_empty();
+	struct bch_sb_handle sb;
+	char *passphrase = NULL;
+
+	opt_set(opts, noexcl,	true);
+	opt_set(opts, nochanges, true);
+
+	int ret = bch2_read_super(dev, &opts, &sb);
+	if (ret)
+		die("Error opening %s: %s", dev, strerror(-ret));
+
+	if (!bch2_sb_is_encrypted(sb.sb))
+		goto out;
+
+	if (passphrase_file) {
+		passphrase = read_keyfile(passphrase_file);
+	} else {
+		char uuid[40];
+		uuid_unparse_lower(sb.sb->user_uuid.b, uuid);
+
+		char *description = mprintf("bcachefs:%s", uuid);
+		bool have_key = keyctl_search(KEY_SPEC_USER_KEYRING, "user",
+					      description, 0) >= 0;
+		free(description);
+
+		/* If we can't prompt, bch2_fs_open() reports the missing key: */
+		if (have_key || !isatty(STDIN_FILENO))
+			goto out;
+
+		passphrase = read_passphrase("Enter passphrase: ");
+	}
+
+	bch2_add_key(sb.sb, passphrase);
+
+	memzero_explicit(passphrase, strlen(passphrase));
+	free(passphrase);
+out:
+	bch2_free_super(&sb);
+}
+
 void bch_sb_crypt_init(struct bch_sb *sb,
 		       struct bch_sb_field_crypt *crypt,
 		       const char *passphrase)
@@ -10,12 +10,16 @@ struct bch_encrypted_key;
 
 char *read_passphrase(const char *);
 char *read_passphrase_twice(const char *);
+char *keyfile_to_passphrase(const void *, size_t);
+char *read_keyfile(const char *);
+void generate_keyfile(const char *);
 
 struct bch_key derive_passphrase(struct bch_sb_field_crypt *, const char *);
 bool bch2_sb_is_encrypted(struct bch_sb *);
 void bch2_passphrase_check(struct bch_sb *, const char *,
 			   struct bch_key *, struct bch_encrypted_key *);
 void bch2_add_key(struct bch_sb *, const char *);
+void bch2_add_key_offline(char *, const char *);
 void bch_sb_crypt_init(struct bch_sb *sb, struct bch_sb_field_crypt *,
 		       const char *);
 
@@ -0,0 +1,103 @@
+#include <string.h>
+
+#include "fsck_errors.h"
+#include "tools-util.h"
+
+#include "libbcachefs/error.h"
+
+const char * const fsck_err_categories[] = {
+#define x(n)	#n,
+	FSCK_ERR_CATEGORIES()
+#undef x
+	NULL
+};
+
+/* fsck_err(): */
+#define FIX		(FSCK_CAN_FIX|FSCK_CAN_IGNORE)
+/* mustfix_fsck_err(): */
+#define MUSTFIX		FSCK_CAN_FIX
+#define SAFE		FSCK_AUTOFIX_SAFE
+
+/*
+ * Matched in order, so where one match is part of another message the more
+ * specific one goes first:
+ */
+const struct fsck_err_type fsck_err_types[] = {
+#define t(_name, _match, _cat, _flags)					\
+	{ #_name, _match, FSCK_ERR_CAT_##_cat, _flags }
+	t(sb_clean_missing,		"marked clean but clean section not present",	superblock, FIX),
+	t(sb_clean_mismatch,		"after clean shutdown",				superblock, MUSTFIX|SAFE),
+	t(replicas_not_marked,		"superblock not marked as containing replicas",	superblock, FIX|SAFE),
+	t(key_version_too_high,		"key version number higher than recorded",	superblock, FIX|SAFE),
+
+	t(journal_empty,		"entries found",				journal, FIX),
+	t(journal_entries_mismatch,	"non identical journal entries",		journal, FIX),
+	t(journal_entries_missing,	"journal entries",				journal, FIX),
+	t(journal_entry_blacklisted,	"found blacklisted journal entry",		journal, FIX|SAFE),
+	t(bkey_invalid,			"entry offset",					journal, MUSTFIX),
+
+	t(btree_node_unreadable,	"Unreadable btree node",			btree, MUSTFIX),
+	t(btree_node_bad_bounds,	"btree node with incorrect",			btree, MUSTFIX),
+	t(btree_node_empty,		"empty interior btree node",			btree, MUSTFIX),
+	t(btree_root_bad_bounds,	"btree root with incorrect",			btree, MUSTFIX),
+	t(btree_root_bad,		"btree root",					btree, 0),
+
+	t(bucket_ptr_gen_stale,		"stale dirty ptr",				alloc, FIX),
+	t(bucket_ptr_gen_newer,		"ptr gen in the future",			alloc, FIX|SAFE),
+	t(bucket_ptr_missing_alloc,	"missing in alloc btree",			alloc, FIX|SAFE),
+	t(bucket_data_type_mismatch,	"different types of data in same bucket",	alloc, FIX),
+
+	t(stripe_ptr_missing,		"pointer to nonexistent stripe",		stripes, FIX),
+	t(stripe_ptr_mismatch,		"pointer does not match stripe",		stripes, FIX),
+
+	t(extent_for_missing_inode,	"for missing inode",				extents, FIX),
+	t(extent_in_non_reg,		"for non regular file",				extents, FIX),
+	t(extents_overlapping,		"overlapping extents",				extents, FIX),
+
+	t(inode_i_sectors_wrong,	"has incorrect i_sectors",			inodes, FIX|SAFE),
+	t(inode_i_size_dirty,		"has i_size dirty",				inodes, FIX),
+	t(inode_i_sectors_dirty,	"has i_sectors dirty",				inodes, FIX|SAFE),
+	t(inode_unlinked_clean,		"unlinked",					inodes, FIX|SAFE),
+	t(inode_nlink_zero_linked,	"has multiple links but i_nlink 0",		inodes, FIX|SAFE),
+	t(inode_i_nlink_wrong,		"wrong i_nlink",				inodes, FIX|SAFE),
+	t(inode_backpointer_wrong,	"has wrong backpointer",			inodes, FIX|SAFE),
+	t(inode_unreachable,		"unreachable inode",				inodes, FIX),
+	t(root_missing,			"root directory missing",			inodes, FIX),
+	t(root_not_dir,			"root inode not a directory",			inodes, FIX),
+
+	/* After the inode errors, which are also "has wrong": */
+	t(alloc_key_wrong,		"has wrong ",					alloc, FIX|SAFE),
+
+	t(dirent_to_missing_inode,	"dirent points to missing inode",		dirents, FIX),
+	t(dirent_in_missing_dir,	"dirent in nonexisting directory",		dirents, FIX),
+	t(dirent_in_non_dir,		"dirent in non directory inode",		dirents, FIX),
+	t(dirent_d_type_wrong,		"incorrect d_type",				dirents, FIX|SAFE),
+	t(hash_table_key_wrong_offset,	"hash table key at wrong offset",		dirents, FIX|SAFE),
+	t(hash_table_key_duplicate,	"duplicate hash table keys",			dirents, FIX),
+	t(dir_multiple_links,		"with multiple links",				dirents, FIX),
+	t(dir_loop,			"directory structure loop",			dirents, FIX),
+#undef t
+};
+
+const unsigned fsck_err_types_nr = ARRAY_SIZE(fsck_err_types);
+
+const struct fsck_err_type *fsck_err_type_by_name(const char *name)
+{
+	const struct fsck_err_type *t;
+
+	for (t = fsck_err_types; t < fsck_err_types + fsck_err_types_nr; t++)
+		if (!strcmp(name, t->name))
+			return t;
+	return NULL;
+}
+
+/* Which error an fsck message is: */
+const struct fsck_err_type *fsck_err_type_find(const char *msg)
+{
+	const struct fsck_err_type *t;
+
+	for (t = fsck_err_types; t < fsck_err_types + fsck_err_types_nr; t++)
+		if (strstr(msg, t->match))
+			return t;
+	return NULL;
+}
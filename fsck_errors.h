@@ -0,0 +1,48 @@
+#ifndef _FSCK_ERRORS_H
+#define _FSCK_ERRORS_H
+
+#include <stdbool.h>
+
+/*
+ * The errors fsck reports, with the FSCK_CAN_* flags from the fsck_err()
+ * variant each is reported with. The kernel gives them no names or numbers,
+ * only the message: they're told apart by part of that message.
+ */
+
+/* Fixing it loses nothing, only recomputes counts, indexes and the like: */
+#define FSCK_AUTOFIX_SAFE	(1 << 8)
+
+#define FSCK_ERR_CATEGORIES()	\
+	x(superblock)		\
+	x(journal)		\
+	x(btree)		\
+	x(alloc)		\
+	x(stripes)		\
+	x(extents)		\
+	x(inodes)		\
+	x(dirents)
+
+enum fsck_err_category {
+#define x(n)	FSCK_ERR_CAT_##n,
+	FSCK_ERR_CATEGORIES()
+#undef x
+	FSCK_ERR_CAT_NR
+};
+
+extern const char * const fsck_err_categories[];
+
+struct fsck_err_type {
+	const char		*name;
+	/* Literal part of the message: */
+	const char		*match;
+	enum fsck_err_category	category;
+	unsigned		flags;
+};
+
+extern const struct fsck_err_type fsck_err_types[];
+extern const unsigned fsck_err_types_nr;
+
+const struct fsck_err_type *fsck_err_type_by_name(const char *);
+const struct fsck_err_type *fsck_err_type_find(const char *);
+
+#endif /* _FSCK_ERRORS_H */
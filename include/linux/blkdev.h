@@ -83,6 +83,7 @@ sector_t get_capacity(struct gendisk *disk);
 void blkdev_put(struct block_device *bdev, fmode_t mode);
 void bdput(struct block_device *bdev);
 struct block_device *blkdev_get_by_path(const char *path, fmode_t mode, void *holder);
+int blkdev_preopen(const char *path, fmode_t mode);
 int lookup_bdev(const char *path, dev_t *);
 
 struct super_block {
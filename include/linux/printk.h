@@ -44,8 +44,14 @@ static inline int scnprintf(char * buf, size_t size, const char * fmt, ...)
 	return i;
 }
 
-#define printk(...)	printf(__VA_ARGS__)
-#define vprintk(...)	vprintf(__VA_ARGS__)
+int printk(const char *, ...) __attribute__((format(printf, 1, 2)));
+int vprintk(const char *, va_list);
+
+/*
+ * The last message printed, for ask_yn() to see what it's being asked: fsck
+ * prints the error, then asks whether to fix it
+ */
+extern __thread char printk_last[512];
 
 #define no_printk(fmt, ...)				\
 ({							\
@@ -25,6 +25,9 @@ struct shrinker {
 int register_shrinker(struct shrinker *);
 void unregister_shrinker(struct shrinker *);
 
+extern u64 shrinker_memory_limit;
+
 void run_shrinkers(void);
+void shrinker_open_proc_files(void);
 
 #endif /* __TOOLS_LINUX_SHRINKER_H */
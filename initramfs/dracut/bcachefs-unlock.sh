@@ -0,0 +1,26 @@
+#!/bin/sh
+
+# Nothing to do if the root filesystem isn't bcachefs
+[ -n "$fstype" -a "$fstype" != bcachefs ] && return 0
+
+type ask_for_password >/dev/null 2>&1 || . /lib/dracut-crypt-lib.sh
+
+DEV=${root#block:}
+[ -b "$DEV" ] || return 0
+
+# Check if the root device needs unlocking:
+bcachefs unlock -c "$DEV" >/dev/null 2>&1 || return 0
+
+# Prompts through plymouth if it's running, on the console otherwise:
+for try in 1 2 3; do
+    if ask_for_password --ply-tries 1 --tty-tries 1 \
+            --ply-cmd "bcachefs unlock $DEV" \
+            --tty-cmd "bcachefs unlock $DEV" \
+            --ply-prompt "Please unlock $DEV:" \
+            --tty-prompt "Please unlock $DEV:"; then
+        info "Bcachefs: $DEV successfully unlocked"
+        return 0
+    fi
+done
+
+die "Bcachefs: maximum number of tries exceeded for $DEV"
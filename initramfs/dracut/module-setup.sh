@@ -0,0 +1,33 @@
+#!/bin/bash
+
+# Only included when the root filesystem, or another one needed to boot, is
+# bcachefs - or when building a generic initramfs:
+check()
+{
+    [[ $hostonly ]] || [[ $mount_needs ]] || return 0
+
+    for fs in "${host_fs_types[@]}"; do
+        [[ $fs == bcachefs ]] && return 0
+    done
+    return 255
+}
+
+depends()
+{
+    echo crypt
+    return 0
+}
+
+installkernel()
+{
+    instmods bcachefs
+
+    # chacha20 and poly1305 are used for encrypted bcachefs filesystems.
+    hostonly='' instmods chacha20 poly1305
+}
+
+install()
+{
+    inst_multiple bcachefs
+    inst_hook pre-mount 90 "$moddir/bcachefs-unlock.sh"
+}
@@ -2,6 +2,8 @@
 #include <dirent.h>
 #include <errno.h>
 #include <fcntl.h>
+#include <limits.h>
+#include <signal.h>
 #include <stdbool.h>
 #include <stdint.h>
 #include <stdio.h>
@@ -20,10 +22,13 @@
 #include "libbcachefs/bcachefs_format.h"
 #include "libbcachefs/btree_cache.h"
 #include "libbcachefs/checksum.h"
+#include "libbcachefs/dirent.h"
 #include "libbcachefs/disk_groups.h"
+#include "libbcachefs/inode.h"
 #include "libbcachefs/journal_seq_blacklist.h"
 #include "libbcachefs/opts.h"
 #include "libbcachefs/replicas.h"
+#include "libbcachefs/str_hash.h"
 #include "libbcachefs/super-io.h"
 #include "tools-util.h"
 
@@ -62,11 +67,46 @@ static void init_layout(struct bch_sb_layout *l,
 		die("insufficient space for superblocks");
 }
 
+/*
+ * Buckets are written sequentially and reused whole, so on a zoned device each
+ * one must be made of whole zones:
+ */
+static void zoned_pick_bucket_size(struct dev_opts *dev,
+				   struct dev_queue_info *q)
+{
+	if (q->host_managed)
+		die("%s is a host-managed zoned device, which needs all writes to be sequential: not supported",
+		    dev->path);
+
+	if (!q->zone_sectors)
+		return;
+
+	if (q->zone_sectors > USHRT_MAX)
+		die("%s: zones of %llu sectors are larger than the largest bucket size (%u sectors)",
+		    dev->path, q->zone_sectors, USHRT_MAX);
+
+	if (q->start % q->zone_sectors)
+		die("%s: partition doesn't start on a zone boundary", dev->path);
+
+	if (!dev->bucket_size)
+		dev->bucket_size = q->zone_sectors;
+	else if (dev->bucket_size % q->zone_sectors)
+		die("%s: bucket size must be a multiple of the zone size (%llu sectors)",
+		    dev->path, q->zone_sectors);
+
+	dev->size = round_down(dev->size, dev->bucket_size);
+}
+
 void bch2_pick_bucket_size(struct bch_opts opts, struct dev_opts *dev)
 {
+	struct dev_queue_info q = dev_queue_info(dev->fd);
+
 	if (!dev->size)
 		dev->size = get_size(dev->path, dev->fd) >> 9;
 
+	if (q.zoned)
+		zoned_pick_bucket_size(dev, &q);
+
 	if (!dev->bucket_size) {
 		if (dev->size < min_size(opts.block_size))
 			die("cannot format %s, too small (%llu sectors, min %llu)",
@@ -113,26 +153,60 @@ void bch2_pick_bucket_size(struct bch_opts opts, struct dev_opts *dev)
 
 }
 
-static unsigned parse_target(struct bch_sb_handle *sb,
-			     struct dev_opts *devs, size_t nr_devs,
-			     const char *s)
+/*
+ * Parses a target as the kernel does: none, a member device - by path, with
+ * @dev_paths indexed by member index - or a disk group. Returns the target, 0
+ * for none, or -EINVAL:
+ */
+int bch2_sb_target_parse(struct bch_sb_handle *sb,
+			 const char * const *dev_paths, unsigned nr_devs,
+			 const char *s)
 {
-	struct dev_opts *i;
+	char path[PATH_MAX];
+	unsigned i;
 	int idx;
 
-	if (!s)
+	if (!*s || !strcmp(s, "none"))
 		return 0;
 
-	for (i = devs; i < devs + nr_devs; i++)
-		if (!strcmp(s, i->path))
-			return dev_to_target(i - devs);
+	for (i = 0; i < nr_devs; i++)
+		if (dev_paths[i] && !strcmp(s, dev_paths[i]))
+			return dev_to_target(i);
+
+	/* e.g. a symlink under /dev/disk: */
+	if (realpath(s, path))
+		for (i = 0; i < nr_devs; i++)
+			if (dev_paths[i] && !strcmp(path, dev_paths[i]))
+				return dev_to_target(i);
 
 	idx = bch2_disk_path_find(sb, s);
 	if (idx >= 0)
 		return group_to_target(idx);
 
-	die("Invalid target %s", s);
-	return 0;
+	return -EINVAL;
+}
+
+static unsigned parse_target(struct bch_sb_handle *sb,
+			     struct dev_opts *devs, size_t nr_devs,
+			     const char *s)
+{
+	const char **paths;
+	size_t i;
+	int ret;
+
+	if (!s)
+		return 0;
+
+	paths = xcalloc(nr_devs, sizeof(*paths));
+	for (i = 0; i < nr_devs; i++)
+		paths[i] = devs[i].path;
+
+	ret = bch2_sb_target_parse(sb, paths, nr_devs, s);
+	if (ret < 0)
+		die("Invalid target %s", s);
+
+	free(paths);
+	return ret;
 }
 
 struct bch_sb *bch2_format(struct bch_opt_strs	fs_opt_strs,
@@ -346,6 +420,52 @@ struct bch_sb *__bch2_super_read(int fd, u64 sector)
 	return ret;
 }
 
+/*
+ * Rereads every copy of the superblock - primary and backups - on each of
+ * @devs, checking they're all valid and at sequence number @seq. Superblock
+ * writes bump seq and write each copy on every member before the next, and
+ * the member with the highest seq wins when opening, so a crash midway can't
+ * leave members disagreeing - but a device that silently drops writes can:
+ */
+void bch2_super_verify(char * const *devs, unsigned nr_devs, u64 seq)
+{
+	unsigned i, j;
+
+	for (i = 0; i < nr_devs; i++) {
+		struct bch_opts opts = bch2_opts_empty();
+		struct bch_sb_handle sb;
+		int ret;
+
+		opt_set(opts, noexcl,	true);
+		opt_set(opts, nochanges, true);
+
+		ret = bch2_read_super(devs[i], &opts, &sb);
+		if (ret)
+			die("Error rereading superblock on %s: %s",
+			    devs[i], strerror(-ret));
+
+		struct bch_sb_layout layout = sb.sb->layout;
+		bch2_free_super(&sb);
+
+		for (j = 0; j < layout.nr_superblocks; j++) {
+			u64 offset = le64_to_cpu(layout.sb_offset[j]);
+
+			opt_set(opts, sb, offset);
+
+			ret = bch2_read_super(devs[i], &opts, &sb);
+			if (ret)
+				die("Error rereading superblock at sector %llu on %s: %s",
+				    offset, devs[i], strerror(-ret));
+
+			if (le64_to_cpu(sb.sb->seq) != seq)
+				die("Superblock at sector %llu on %s has seq %llu, should be %llu",
+				    offset, devs[i], le64_to_cpu(sb.sb->seq), seq);
+
+			bch2_free_super(&sb);
+		}
+	}
+}
+
 static unsigned get_dev_has_data(struct bch_sb *sb, unsigned dev)
 {
 	struct bch_sb_field_replicas *replicas;
@@ -363,6 +483,25 @@ static unsigned get_dev_has_data(struct bch_sb *sb, unsigned dev)
 	return data_has;
 }
 
+/* Whether a member is in a disk group, directly or through a subgroup: */
+bool bch2_sb_dev_in_group(struct bch_sb *sb, unsigned dev, unsigned group)
+{
+	struct bch_sb_field_members *mi = bch2_sb_get_members(sb);
+	struct bch_sb_field_disk_groups *gi = bch2_sb_get_disk_groups(sb);
+	unsigned g;
+
+	if (!bch2_dev_exists(sb, mi, dev))
+		return false;
+
+	for (g = BCH_MEMBER_GROUP(mi->members + dev);
+	     g && g <= disk_groups_nr(gi);
+	     g = BCH_GROUP_PARENT(gi->entries + g - 1))
+		if (g - 1 == group)
+			return true;
+
+	return false;
+}
+
 static int bch2_sb_get_target(struct bch_sb *sb, char *buf, size_t len, u64 v)
 {
 	struct target t = target_decode(v);
@@ -962,22 +1101,118 @@ int bchu_dev_path_to_idx(struct bchfs_handle fs, const char *dev_path)
 	return idx;
 }
 
-int bchu_data(struct bchfs_handle fs, struct bch_ioctl_data cmd)
+/*
+ * Progress events for scripts, one JSON object per line:
+ *   {"event":"progress","sectors_done":N,"sectors_total":N,"data_type":"user",
+ *    "btree":"extents","inode":N,"offset":N}
+ *   {"event":"paused"}
+ *   {"event":"resumed"}
+ *   {"event":"done"}
+ * btree, inode and offset are only present for btree and user data:
+ */
+static void data_progress_json(int fd, struct bch_ioctl_data_event *e)
+{
+	if (e->p.data_type == U8_MAX) {
+		dprintf(fd, "{\"event\":\"done\"}\n");
+		return;
+	}
+
+	dprintf(fd, "{\"event\":\"progress\",\"sectors_done\":%llu,\"sectors_total\":%llu,\"data_type\":\"%s\"",
+		e->p.sectors_done, e->p.sectors_total,
+		bch2_data_types[e->p.data_type]);
+
+	switch (e->p.data_type) {
+	case BCH_DATA_btree:
+	case BCH_DATA_user:
+		dprintf(fd, ",\"btree\":\"%s\",\"inode\":%llu,\"offset\":%llu",
+			bch2_btree_ids[e->p.btree_id],
+			e->p.pos.inode,
+			e->p.pos.offset);
+	}
+
+	dprintf(fd, "}\n");
+}
+
+static volatile sig_atomic_t data_job_paused;
+
+static void data_job_signal(int sig)
 {
+	data_job_paused = sig == SIGUSR1;
+}
+
+/*
+ * The kernel can't pause a data job, so pausing stops it, and resuming starts
+ * a new one from the last position reported - if that was in the user data
+ * pass; the btree pass is quick to redo:
+ */
+static void data_job_pause(struct bch_ioctl_data *cmd,
+			   struct bch_ioctl_data_progress *p, int json_fd)
+{
+	sigset_t usr, old;
+
+	if ((cmd->op == BCH_DATA_OP_REREPLICATE ||
+	     cmd->op == BCH_DATA_OP_MIGRATE) &&
+	    p->data_type == BCH_DATA_user) {
+		cmd->start_btree	= p->btree_id;
+		cmd->start_pos		= p->pos;
+	}
+
+	printf("\nPaused, send SIGUSR2 to resume\n");
+	if (json_fd >= 0)
+		dprintf(json_fd, "{\"event\":\"paused\"}\n");
+
+	sigemptyset(&usr);
+	sigaddset(&usr, SIGUSR1);
+	sigaddset(&usr, SIGUSR2);
+
+	sigprocmask(SIG_BLOCK, &usr, &old);
+	while (data_job_paused)
+		sigsuspend(&old);
+	sigprocmask(SIG_SETMASK, &old, NULL);
+
+	printf("Resuming\n");
+	if (json_fd >= 0)
+		dprintf(json_fd, "{\"event\":\"resumed\"}\n");
+}
+
+/*
+ * If @json_fd isn't -1, progress is also written there, see above. SIGUSR1
+ * pauses the job, SIGUSR2 resumes it:
+ */
+int __bchu_data(struct bchfs_handle fs, struct bch_ioctl_data cmd, int json_fd)
+{
+	struct bch_ioctl_data_progress last = { .data_type = BCH_DATA_none };
+
+	data_job_paused = false;
+	signal(SIGUSR1, data_job_signal);
+	signal(SIGUSR2, data_job_signal);
+
 	int progress_fd = xioctl(fs.ioctl_fd, BCH_IOCTL_DATA, &cmd);
 
 	while (1) {
 		struct bch_ioctl_data_event e;
 
+		if (data_job_paused) {
+			/* Closing the progress fd stops the job: */
+			close(progress_fd);
+			data_job_pause(&cmd, &last, json_fd);
+			progress_fd = xioctl(fs.ioctl_fd, BCH_IOCTL_DATA, &cmd);
+		}
+
 		if (read(progress_fd, &e, sizeof(e)) != sizeof(e))
 			die("error reading from progress fd %m");
 
 		if (e.type)
 			continue;
 
+		if (json_fd >= 0)
+			data_progress_json(json_fd, &e);
+
 		if (e.p.data_type == U8_MAX)
 			break;
 
+		last = e.p;
+
 		printf("\33[2K\r");
 
 		printf("%llu%% complete: current position %s",
@@ -1001,6 +1236,8 @@ int bchu_data(struct bchfs_handle fs, struct bch_ioctl_data cmd)
 	printf("\nDone\n");
 
 	close(progress_fd);
+	signal(SIGUSR1, SIG_DFL);
+	signal(SIGUSR2, SIG_DFL);
 	return 0;
 }
 
@@ -1165,7 +1402,7 @@ void bch2_opts_usage(unsigned opt_types)
 
 dev_names bchu_fs_get_devices(struct bchfs_handle fs)
 {
-	DIR *dir = fdopendir(fs.sysfs_fd);
+	DIR *dir = fdopendir(dup(fs.sysfs_fd));
 	struct dirent *d;
 	dev_names devs;
 
@@ -1200,3 +1437,335 @@ dev_names bchu_fs_get_devices(struct bchfs_handle fs)
 
 	return devs;
 }
+
+void dev_names_free(dev_names *devs)
+{
+	struct dev_name *d;
+
+	darray_foreach(d, *devs) {
+		free(d->dev);
+		free(d->label);
+	}
+	darray_free(*devs);
+}
+
+/* time_stats: */
+
+const char * const bchu_time_stats_names[] = {
+#define x(name) #name,
+	BCH_TIME_STATS()
+#undef x
+	NULL
+};
+
+static u64 time_unit_parse(const char *unit)
+{
+	if (!strcmp(unit, "ns"))
+		return 1;
+	if (!strcmp(unit, "us"))
+		return 1000ULL;
+	if (!strcmp(unit, "ms"))
+		return 1000ULL * 1000;
+	if (!strcmp(unit, "sec"))
+		return NSEC_PER_SEC;
+
+	die("unknown time unit %s", unit);
+}
+
+static u64 time_parse(const char *s)
+{
+	unsigned long long v;
+	char unit[8];
+
+	if (sscanf(s, "%llu %7s", &v, unit) != 2)
+		die("error parsing time %s", s);
+
+	return v * time_unit_parse(unit);
+}
+
+/*
+ * Parse the output of bch2_time_stats_to_text(), i.e. a sysfs time_stats file:
+ */
+void bchu_time_stats_parse(char *buf, struct bchu_time_stats *s)
+{
+	char *line, *v;
+
+	memset(s, 0, sizeof(*s));
+
+	while ((line = strsep(&buf, "\n"))) {
+		v = strchr(line, ':');
+		if (!v)
+			continue;
+		*v++ = '\0';
+		v += strspn(v, " \t");
+
+		if (!strcmp(line, "count")) {
+			s->count	= strtoull(v, NULL, 10);
+		} else if (!strcmp(line, "rate")) {
+			s->rate		= strtoull(v, NULL, 10);
+		} else if (!strcmp(line, "frequency")) {
+			s->frequency	= time_parse(v);
+		} else if (!strcmp(line, "avg duration")) {
+			s->avg_duration	= time_parse(v);
+		} else if (!strcmp(line, "max duration")) {
+			s->max_duration	= time_parse(v);
+		} else if (!strncmp(line, "quantiles (", 11)) {
+			char *unit = line + 11;
+			unsigned i;
+
+			unit[strcspn(unit, ")")] = '\0';
+
+			u64 mul = time_unit_parse(unit);
+
+			for (i = 0; i < NR_QUANTILES; i++)
+				s->quantiles[i] = strtoull(v, &v, 10) * mul;
+		}
+	}
+}
+
+/*
+ * @path is relative to the filesystem's sysfs directory, e.g.
+ * "time_stats/journal_write" or "dev-0/io_latency_stats_read":
+ */
+struct bchu_time_stats bchu_time_stats_read(struct bchfs_handle fs,
+					    const char *path)
+{
+	struct bchu_time_stats s;
+	char *buf = read_file_str(fs.sysfs_fd, path);
+
+	if (!buf)
+		die("%s: empty time_stats", path);
+
+	bchu_time_stats_parse(buf, &s);
+	free(buf);
+	return s;
+}
+
+/* Parses the state out of dev-<idx>/state, e.g. "rw ro [failed] spare": */
+int bchu_dev_state_read(struct bchfs_handle fs, unsigned idx)
+{
+	char *attr = mprintf("dev-%u/state", idx);
+	char *buf = read_file_str(fs.sysfs_fd, attr);
+	char *start = strchr(buf, '['), *end = start ? strchr(start, ']') : NULL;
+	int ret = -1;
+
+	if (start && end) {
+		*end = '\0';
+		ret = match_string(bch2_member_states, -1, start + 1);
+	}
+
+	free(buf);
+	free(attr);
+	return ret;
+}
+
+/* dev-<idx>/iodone: */
+struct bchu_dev_iodone bchu_dev_iodone_read(struct bchfs_handle fs, unsigned idx)
+{
+	struct bchu_dev_iodone ret = { 0 };
+	char *attr = mprintf("dev-%u/iodone", idx);
+	char *buf = read_file_str(fs.sysfs_fd, attr);
+	char *line, *p = buf;
+	int rw = -1;
+
+	free(attr);
+
+	/* "read:"/"write:", then "<data type>  :<bytes>" for each data type: */
+	while ((line = strsep(&p, "\n"))) {
+		char *v = strchr(line, ':');
+		u64 bytes;
+		int type;
+
+		if (!v)
+			continue;
+		*v++ = '\0';
+
+		if (!strcmp(line, "read")) {
+			rw = READ;
+		} else if (!strcmp(line, "write")) {
+			rw = WRITE;
+		} else if (rw >= 0 &&
+			   (type = match_string(bch2_data_types, -1, strim(line))) >= 0 &&
+			   !kstrtoull(strim(v), 10, &bytes)) {
+			ret.sectors[rw][type] = bytes >> 9;
+		}
+	}
+
+	free(buf);
+	return ret;
+}
+
+/*
+ * A bound given as a prefix of a position - just an inode, or an inode and an
+ * offset - stands for every position starting with it, from first to last:
+ */
+static void key_range_bound_parse(char *s, enum btree_id btree,
+				  struct bpos *first, struct bpos *last)
+{
+	unsigned fields = 1;
+	char *p;
+
+	if (!strcmp(s, "min") || !strcmp(s, "max")) {
+		*first = *last = bpos_parse(s);
+		return;
+	}
+
+	for (p = s; *p; p++)
+		fields += *p == ':';
+
+	*first = *last = bpos_parse(s);
+
+	/* Inodes are indexed by offset, so a single field is an inode number: */
+	if (btree == BTREE_ID_inodes && fields == 1) {
+		u64 inum = first->inode;
+
+		*first	= POS(0, inum);
+		*last	= SPOS(0, inum, KEY_SNAPSHOT_MAX);
+		return;
+	}
+
+	if (fields < 2)
+		last->offset	= KEY_OFFSET_MAX;
+	if (fields < 3)
+		last->snapshot	= KEY_SNAPSHOT_MAX;
+}
+
+/*
+ * Parses btree[bounds], where bounds is one of
+ *
+ *   pos		keys at pos, or starting with it if it's a prefix
+ *   a..b		from a up to but not including b
+ *   a..=b		from a up to and including b
+ *   >a, >=a, <b, <=b	bounded on one side only
+ *
+ * and a or b may be left out of a..b; the bounds are optional, and positions
+ * are as for bpos_parse(). Dies if the range is invalid or empty.
+ */
+struct key_range key_range_parse(char *buf)
+{
+	struct key_range r = {
+		.start	= POS_MIN,
+		.end	= POS_MAX,
+	};
+	struct bpos first, last;
+	char *bounds = strchr(buf, '['), *end;
+
+	if (bounds) {
+		*bounds++ = '\0';
+
+		end = bounds + strlen(bounds);
+		if (end == bounds || end[-1] != ']')
+			die("invalid key range %s[%s: missing ]", buf, bounds);
+		end[-1] = '\0';
+	}
+
+	r.btree = read_string_list_or_die(buf, bch2_btree_ids, "btree id");
+
+	if (!bounds)
+		return r;
+
+	r.bounded = true;
+
+	if (!strncmp(bounds, ">=", 2)) {
+		key_range_bound_parse(bounds + 2, r.btree, &r.start, &last);
+	} else if (bounds[0] == '>') {
+		key_range_bound_parse(bounds + 1, r.btree, &first, &last);
+		if (!bkey_cmp(last, POS_MAX))
+			die("empty key range: nothing is after max");
+		r.start = bpos_successor(last);
+	} else if (!strncmp(bounds, "<=", 2)) {
+		key_range_bound_parse(bounds + 2, r.btree, &first, &r.end);
+	} else if (bounds[0] == '<') {
+		key_range_bound_parse(bounds + 1, r.btree, &first, &last);
+		if (!bkey_cmp(first, POS_MIN))
+			die("empty key range: nothing is before min");
+		r.end = bpos_predecessor(first);
+	} else if ((end = strstr(bounds, ".."))) {
+		bool inclusive = end[2] == '=';
+
+		*end = '\0';
+		end += inclusive ? 3 : 2;
+
+		if (*bounds)
+			key_range_bound_parse(bounds, r.btree, &r.start, &last);
+
+		if (*end) {
+			key_range_bound_parse(end, r.btree, &first, &last);
+
+			if (inclusive)
+				r.end = last;
+			else if (!bkey_cmp(first, POS_MIN))
+				die("empty key range: nothing is before min");
+			else
+				r.end = bpos_predecessor(first);
+		} else if (inclusive) {
+			die("invalid key range: ..= needs an end");
+		}
+	} else {
+		key_range_bound_parse(bounds, r.btree, &r.start, &r.end);
+	}
+
+	if (bkey_cmp(r.start, r.end) > 0)
+		die("empty key range: start is after end");
+
+	return r;
+}
+
+/* Paths within a filesystem opened with bch2_fs_open(): */
+
+#define QSTR(n) { { { .len = strlen(n) } }, .name = n }
+
+/*
+ * Looks up @path, relative to the root directory, modifying it in the process;
+ * returns -ENOENT if it doesn't exist:
+ */
+int __bch2_path_to_inum(struct bch_fs *c, char *path, u64 *ret_inum)
+{
+	u64 inum = BCACHEFS_ROOT_INO;
+	char *name;
+
+	while ((name = strsep(&path, "/"))) {
+		struct bch_inode_unpacked dir;
+
+		if (!*name || !strcmp(name, "."))
+			continue;
+
+		int ret = bch2_inode_find_by_inum(c, inum, &dir);
+		if (ret)
+			return ret;
+
+		if (!S_ISDIR(dir.bi_mode))
+			return -ENOTDIR;
+
+		/* Directories always have a backpointer to their parent: */
+		if (!strcmp(name, "..")) {
+			if (inum != BCACHEFS_ROOT_INO)
+				inum = dir.bi_dir;
+			continue;
+		}
+
+		struct bch_hash_info hash_info = bch2_hash_info_init(c, &dir);
+		struct qstr qstr = QSTR(name);
+
+		inum = bch2_dirent_lookup(c, inum, &hash_info, &qstr);
+		if (!inum)
+			return -ENOENT;
+	}
+
+	*ret_inum = inum;
+	return 0;
+}
+
+/* As above, but dies if @path can't be looked up: */
+u64 bch2_path_to_inum(struct bch_fs *c, const char *path)
+{
+	char *p = strdup(path);
+	u64 inum;
+	int ret = __bch2_path_to_inum(c, p, &inum);
+
+	if (ret)
+		die("error looking up %s: %s", path, strerror(-ret));
+
+	free(p);
+	return inum;
+}
@@ -74,14 +74,19 @@ static inline struct dev_opts dev_opts_default()
 }
 
 void bch2_pick_bucket_size(struct bch_opts, struct dev_opts *);
+struct bch_sb_handle;
+int bch2_sb_target_parse(struct bch_sb_handle *, const char * const *,
+			 unsigned, const char *);
 struct bch_sb *bch2_format(struct bch_opt_strs,
 			   struct bch_opts,
 			   struct format_opts, struct dev_opts *, size_t);
 
 void bch2_super_write(int, struct bch_sb *);
 struct bch_sb *__bch2_super_read(int, u64);
+void bch2_super_verify(char * const *, unsigned, u64);
 
 void bch2_sb_print(struct bch_sb *, bool, unsigned, enum units);
+bool bch2_sb_dev_in_group(struct bch_sb *, unsigned, unsigned);
 
 /* ioctl interface: */
 
@@ -233,7 +238,12 @@ static inline void bchu_disk_resize_journal(struct bchfs_handle fs,
 	xioctl(fs.ioctl_fd, BCH_IOCTL_DISK_RESIZE_JOURNAL, &i);
 }
 
-int bchu_data(struct bchfs_handle, struct bch_ioctl_data);
+int __bchu_data(struct bchfs_handle, struct bch_ioctl_data, int);
+
+static inline int bchu_data(struct bchfs_handle fs, struct bch_ioctl_data cmd)
+{
+	return __bchu_data(fs, cmd, -1);
+}
 
 struct dev_name {
 	unsigned	idx;
@@ -244,5 +254,52 @@ struct dev_name {
 typedef darray(struct dev_name) dev_names;
 
 dev_names bchu_fs_get_devices(struct bchfs_handle);
+void dev_names_free(dev_names *);
+
+/* time_stats, as exported in sysfs: */
+
+extern const char * const bchu_time_stats_names[];
+
+struct bchu_time_stats {
+	u64		count;
+	u64		rate;		/* events/sec */
+	/* all fields below are in nanoseconds */
+	u64		frequency;
+	u64		avg_duration;
+	u64		max_duration;
+	/* duration quantiles, in increasing order: 1/16th, 2/16ths, ... */
+	u64		quantiles[NR_QUANTILES];
+};
+
+void bchu_time_stats_parse(char *, struct bchu_time_stats *);
+struct bchu_time_stats bchu_time_stats_read(struct bchfs_handle, const char *);
+
+/* Per device attributes: */
+
+/* One of BCH_MEMBER_STATE_*, or -1 if it couldn't be parsed */
+int bchu_dev_state_read(struct bchfs_handle, unsigned);
+
+struct bchu_dev_iodone {
+	/* in sectors, indexed by READ/WRITE and data type */
+	u64		sectors[2][BCH_DATA_NR];
+};
+
+struct bchu_dev_iodone bchu_dev_iodone_read(struct bchfs_handle, unsigned);
+
+/* Key ranges, as given on the command line: */
+
+struct key_range {
+	enum btree_id	btree;
+	/* if false, no bounds were given, and start and end are min and max */
+	bool		bounded;
+	struct bpos	start;
+	struct bpos	end;		/* inclusive */
+};
+
+struct key_range key_range_parse(char *);
+
+struct bch_fs;
+int __bch2_path_to_inum(struct bch_fs *, char *, u64 *);
+u64 bch2_path_to_inum(struct bch_fs *, const char *);
 
 #endif /* _LIBBCACHE_H */
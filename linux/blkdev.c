@@ -19,6 +19,8 @@
 #include <linux/completion.h>
 #include <linux/fs.h>
 #include <linux/kthread.h>
+#include <linux/list.h>
+#include <linux/mutex.h>
 
 #include "tools-util.h"
 
@@ -179,11 +181,9 @@ void blkdev_put(struct block_device *bdev, fmode_t mode)
 	free(bdev);
 }
 
-struct block_device *blkdev_get_by_path(const char *path, fmode_t mode,
-					void *holder)
+static int blkdev_open_flags(fmode_t mode)
 {
-	struct block_device *bdev;
-	int fd, sync_fd, flags = O_DIRECT;
+	int flags = O_DIRECT;
 
 	if ((mode & (FMODE_READ|FMODE_WRITE)) == (FMODE_READ|FMODE_WRITE))
 		flags = O_RDWR;
@@ -197,16 +197,96 @@ struct block_device *blkdev_get_by_path(const char *path, fmode_t mode,
 	if (mode & FMODE_EXCL)
 		flags |= O_EXCL;
 #endif
+	return flags;
+}
 
-	fd = open(path, flags);
-	if (fd < 0)
-		return ERR_PTR(-errno);
+static int blkdev_open(const char *path, int flags, int *fd, int *sync_fd)
+{
+	*fd = open(path, flags);
+	if (*fd < 0)
+		return -errno;
+
+	*sync_fd = open(path, flags|O_SYNC);
+	if (*sync_fd < 0) {
+		int ret = -errno;
 
-	sync_fd = open(path, flags|O_SYNC);
-	if (sync_fd < 0) {
 		assert(0);
-		close(fd);
-		return ERR_PTR(-errno);
+		close(*fd);
+		return ret;
+	}
+
+	return 0;
+}
+
+/*
+ * Devices opened by blkdev_preopen(), for tools that enter a sandbox before
+ * the filesystem code gets to open them: blkdev_get_by_path() takes these
+ * instead of opening the same path again.
+ */
+struct preopened_bdev {
+	struct list_head	list;
+	char			*path;
+	int			flags;
+	int			fd;
+	int			sync_fd;
+};
+
+static LIST_HEAD(preopened_bdevs);
+static DEFINE_MUTEX(preopened_bdevs_lock);
+
+int blkdev_preopen(const char *path, fmode_t mode)
+{
+	struct preopened_bdev *p = xmalloc(sizeof(*p));
+	int ret;
+
+	p->path		= strdup(path);
+	p->flags	= blkdev_open_flags(mode);
+
+	ret = blkdev_open(path, p->flags, &p->fd, &p->sync_fd);
+	if (ret) {
+		free(p->path);
+		free(p);
+		return ret;
+	}
+
+	mutex_lock(&preopened_bdevs_lock);
+	list_add_tail(&p->list, &preopened_bdevs);
+	mutex_unlock(&preopened_bdevs_lock);
+	return 0;
+}
+
+static bool blkdev_take_preopened(const char *path, int flags,
+				  int *fd, int *sync_fd)
+{
+	struct preopened_bdev *p;
+	bool found = false;
+
+	mutex_lock(&preopened_bdevs_lock);
+	list_for_each_entry(p, &preopened_bdevs, list)
+		if (!strcmp(p->path, path) && p->flags == flags) {
+			*fd		= p->fd;
+			*sync_fd	= p->sync_fd;
+			list_del(&p->list);
+			free(p->path);
+			free(p);
+			found = true;
+			break;
+		}
+	mutex_unlock(&preopened_bdevs_lock);
+
+	return found;
+}
+
+struct block_device *blkdev_get_by_path(const char *path, fmode_t mode,
+					void *holder)
+{
+	struct block_device *bdev;
+	int fd, sync_fd, flags = blkdev_open_flags(mode), ret;
+
+	if (!blkdev_take_preopened(path, flags, &fd, &sync_fd)) {
+		ret = blkdev_open(path, flags, &fd, &sync_fd);
+		if (ret)
+			return ERR_PTR(ret);
 	}
 
 	bdev = malloc(sizeof(*bdev));
@@ -0,0 +1,29 @@
+#include <stdarg.h>
+#include <stdio.h>
+
+#include <linux/printk.h>
+
+__thread char printk_last[512];
+
+int vprintk(const char *fmt, va_list args)
+{
+	va_list args2;
+
+	va_copy(args2, args);
+	vsnprintf(printk_last, sizeof(printk_last), fmt, args2);
+	va_end(args2);
+
+	return vprintf(fmt, args);
+}
+
+int printk(const char *fmt, ...)
+{
+	va_list args;
+	int ret;
+
+	va_start(args, fmt);
+	ret = vprintk(fmt, args);
+	va_end(args);
+
+	return ret;
+}
@@ -1,5 +1,8 @@
 
+#include <fcntl.h>
+#include <pthread.h>
 #include <stdio.h>
+#include <unistd.h>
 
 #include <linux/list.h>
 #include <linux/mutex.h>
@@ -7,6 +10,12 @@
 
 #include "tools-util.h"
 
+/*
+ * If nonzero, caches are also shrunk to keep the resident memory of this
+ * process below this many bytes:
+ */
+u64 shrinker_memory_limit;
+
 static LIST_HEAD(shrinker_list);
 static DEFINE_MUTEX(shrinker_lock);
 
@@ -39,19 +48,49 @@ static u64 parse_meminfo_line(const char *line)
 	return v << 10;
 }
 
+/*
+ * Opened once and reread with pread(), so that they can still be read after
+ * entering a sandbox that doesn't allow opening files:
+ */
+static int meminfo_fd = -1, statm_fd = -1;
+static pthread_once_t proc_files_once = PTHREAD_ONCE_INIT;
+
+static void proc_files_open(void)
+{
+	meminfo_fd	= open("/proc/meminfo", O_RDONLY|O_CLOEXEC);
+	statm_fd	= open("/proc/self/statm", O_RDONLY|O_CLOEXEC);
+}
+
+void shrinker_open_proc_files(void)
+{
+	pthread_once(&proc_files_once, proc_files_open);
+}
+
+static ssize_t read_proc_file(int fd, char *buf, size_t size)
+{
+	ssize_t len;
+
+	shrinker_open_proc_files();
+
+	if (fd < 0)
+		return -1;
+
+	len = pread(fd, buf, size - 1, 0);
+	if (len >= 0)
+		buf[len] = '\0';
+	return len;
+}
+
 static struct meminfo read_meminfo(void)
 {
 	struct meminfo ret = { 0 };
-	size_t len, n = 0;
-	char *line = NULL;
+	char buf[8192], *p = buf, *line;
 	const char *v;
-	FILE *f;
 
-	f = fopen("/proc/meminfo", "r");
-	if (!f)
+	if (read_proc_file(meminfo_fd, buf, sizeof(buf)) < 0)
 		return ret;
 
-	while ((len = getline(&line, &n, f)) != -1) {
+	while ((line = strsep(&p, "\n"))) {
 		if ((v = strcmp_prefix(line, "MemTotal:")))
 			ret.total = parse_meminfo_line(v);
 
@@ -59,12 +98,21 @@ static struct meminfo read_meminfo(void)
 			ret.available = parse_meminfo_line(v);
 	}
 
-	fclose(f);
-	free(line);
-
 	return ret;
 }
 
+static u64 read_rss(void)
+{
+	unsigned long long size, rss = 0;
+	char buf[128];
+
+	if (read_proc_file(statm_fd, buf, sizeof(buf)) < 0 ||
+	    sscanf(buf, "%llu %llu", &size, &rss) != 2)
+		rss = 0;
+
+	return rss * sysconf(_SC_PAGESIZE);
+}
+
 void run_shrinkers(void)
 {
 	struct shrinker *shrinker;
@@ -79,9 +127,6 @@ void run_shrinkers(void)
 
 	if (info.total && info.available) {
 		want_shrink = (info.total >> 2) - info.available;
-
-		if (want_shrink <= 0)
-			return;
 	} else {
 		/* If we weren't able to read /proc/meminfo, we must be pretty
 		 * low: */
@@ -89,6 +134,13 @@ void run_shrinkers(void)
 		want_shrink = 8 << 20;
 	}
 
+	if (shrinker_memory_limit)
+		want_shrink = max_t(s64, want_shrink,
+				    (s64) (read_rss() - shrinker_memory_limit));
+
+	if (want_shrink <= 0)
+		return;
+
 	mutex_lock(&shrinker_lock);
 	list_for_each_entry(shrinker, &shrinker_list, list) {
 		struct shrink_control sc = {
@@ -24,6 +24,9 @@ fn main() {
 		.clang_arg("-DZSTD_STATIC_LINKING_ONLY")
 		.clang_arg("-DNO_BCACHEFS_FS")
 		.clang_arg("-D_GNU_SOURCE")
+		// Must match the C build, or off_t sized fields differ on 32 bit:
+		.clang_arg("-D_FILE_OFFSET_BITS=64")
+		.size_t_is_usize(true)
 		.derive_debug(false)
 		.derive_default(true)
 		.default_enum_style(bindgen::EnumVariation::Rust {
@@ -33,17 +36,34 @@ fn main() {
 		.whitelist_function("bch2_sb_field_.*")
 		.whitelist_function("bch2_chacha_encrypt_key")
 		.whitelist_function("derive_passphrase")
+		.whitelist_function("keyfile_to_passphrase")
 		.whitelist_function("request_key")
 		.whitelist_function("add_key")
 		.whitelist_function("keyctl_search")
+		.whitelist_function("bch2_opts_from_sb")
+		.whitelist_function("bch2_opt_defined_by_id")
+		.whitelist_function("bch2_opt_get_by_id")
+		.whitelist_function("bch2_opt_set_by_id")
 		.whitelist_var("BCH_.*")
+		.whitelist_var("bch2_sb_features")
+		.whitelist_var("bch2_opt_table")
 		.whitelist_var("KEY_SPEC_.*")
 		.whitelist_type("bch_kdf_types")
 		.whitelist_type("bch_sb_field_.*")
+		.whitelist_type("bch_member")
 		.whitelist_type("bch_encrypted_key")
 		.whitelist_type("nonce")
+		.whitelist_type("bch_sb_feature")
+		.whitelist_type("bch_option")
+		.whitelist_type("bcachefs_metadata_version")
 		.rustified_enum("bch_kdf_types")
+		.bitfield_enum("opt_mode")
+		.constified_enum_module("bch_opt_id")
+		.constified_enum_module("bcachefs_metadata_version")
+		.blacklist_type("__le(16|32|64)")
 		.opaque_type("gendisk")
+		.opaque_type("bch_fs")
+		.opaque_type("bch_opts")
 		.opaque_type("bkey")
 		.generate()
 		.unwrap();
@@ -61,6 +81,7 @@ fn main() {
 				.iter()
 				.map(|p| format!("-I{}", p.display())),
 		)
+		.size_t_is_usize(true)
 		.generate()
 		.unwrap();
 	bindings.write_to_file(out_dir.join("keyutils.rs")).unwrap();
@@ -0,0 +1,62 @@
+//! On disk integers are little endian. bindgen would type `__le64` and
+//! friends as plain integers, which reads them in host byte order: these
+//! types are used instead, so that every read of an on disk field has to go
+//! through a conversion, and is correct on big endian hosts too.
+
+macro_rules! le_type {
+	($name:ident, $t:ty) => {
+		/// A little endian integer, as stored on disk
+		#[repr(transparent)]
+		#[derive(Clone, Copy, Default, PartialEq, Eq)]
+		pub struct $name($t);
+
+		impl $name {
+			#[allow(dead_code)]
+			pub fn new(v: $t) -> Self {
+				Self(v.to_le())
+			}
+
+			/// The value in host byte order
+			pub fn get(self) -> $t {
+				<$t>::from_le(self.0)
+			}
+		}
+
+		impl std::fmt::Debug for $name {
+			fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+				self.get().fmt(f)
+			}
+		}
+	};
+}
+
+le_type!(Le16, u16);
+le_type!(Le32, u32);
+le_type!(Le64, u64);
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn round_trip() {
+		assert_eq!(Le16::new(0x1234).get(), 0x1234);
+		assert_eq!(Le32::new(0x1234_5678).get(), 0x1234_5678);
+		assert_eq!(Le64::new(0x1234_5678_9abc_def0).get(), 0x1234_5678_9abc_def0);
+		assert_eq!(Le64::default().get(), 0);
+	}
+
+	#[test]
+	fn stored_little_endian() {
+		let v: [u8; 4] = unsafe { std::mem::transmute(Le32::new(0x0102_0304)) };
+		assert_eq!(v, [4, 3, 2, 1]);
+
+		let v: Le16 = unsafe { std::mem::transmute([0x34u8, 0x12]) };
+		assert_eq!(v.get(), 0x1234);
+	}
+
+	#[test]
+	fn debug_shows_value() {
+		assert_eq!(format!("{:?}", Le64::new(4096)), "4096");
+	}
+}
@@ -3,6 +3,7 @@ extern "C" {
 }
 
 use getset::{CopyGetters, Getters};
+use log::info;
 use std::path::PathBuf;
 #[derive(Getters, CopyGetters)]
 pub struct FileSystem {
@@ -18,11 +19,57 @@ pub struct FileSystem {
 	/// Member devices for this filesystem
 	#[getset(get = "pub")]
 	devices: Vec<PathBuf>,
+	/// Superblock state of each member device, in the same order as `devices`
+	#[getset(get = "pub")]
+	members: Vec<MemberState>,
+}
+
+/// Superblock state of a single member device, as seen when probing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemberState {
+	/// Superblock sequence number
+	pub seq: u64,
+	/// Journal sequence number from the clean section, if the filesystem was
+	/// shut down cleanly
+	pub clean_journal_seq: Option<u64>,
+}
+
+impl MemberState {
+	fn new(sb: &bcachefs::bch_sb) -> Self {
+		Self {
+			seq: sb.seq.get(),
+			clean_journal_seq: sb
+				.clean()
+				.filter(|_| sb.is_clean())
+				.map(|c| c.journal_seq.get()),
+		}
+	}
+}
+
+/// A superblock feature bit, displayed by name if it's known
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Feature(pub u32);
+
+impl std::fmt::Display for Feature {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		match bcachefs::feature_name(self.0) {
+			Some(name) => write!(f, "{}", name),
+			None => write!(f, "bit {}", self.0),
+		}
+	}
+}
+
+impl Feature {
+	/// Whether `s`, a feature name or bit number given by the user, refers to
+	/// this feature
+	pub fn matches(&self, s: &str) -> bool {
+		s.trim() == self.to_string() || s.trim().parse() == Ok(self.0)
+	}
 }
 
 /// Parse a comma-separated mount options and split out mountflags and filesystem
 /// specific options.
-fn parse_mount_options(options: impl AsRef<str>) -> (Option<String>, u64) {
+fn parse_mount_options(options: impl AsRef<str>) -> (Option<String>, libc::c_ulong) {
 	use either::Either::*;
 	let (opts, flags) = options
 		.as_ref()
@@ -39,6 +86,7 @@ fn parse_mount_options(options: impl AsRef<str>) -> (Option<String>, u64) {
 			"ro" => Left(libc::MS_RDONLY),
 			"rw" => Left(0),
 			"relatime" => Left(libc::MS_RELATIME),
+			"remount" => Left(libc::MS_REMOUNT),
 			"strictatime" => Left(libc::MS_STRICTATIME),
 			"sync" => Left(libc::MS_SYNCHRONOUS),
 			"" => Left(0),
@@ -70,7 +118,82 @@ impl FileSystem {
 			encrypted: sb.sb().crypt().is_some(),
 			sb: sb,
 			devices: vec![],
+			members: vec![],
+		}
+	}
+
+	/// Member devices whose superblock doesn't match the most recent one,
+	/// e.g. because the filesystem was mounted without them, or they were
+	/// mounted separately from the rest of the filesystem.
+	pub fn diverged_members(&self) -> Vec<&PathBuf> {
+		let newest = match self.members.iter().max_by_key(|m| m.seq) {
+			Some(m) => m,
+			None => return vec![],
+		};
+		self.devices
+			.iter()
+			.zip(&self.members)
+			.filter(|(_, m)| *m != newest)
+			.map(|(d, _)| d)
+			.collect()
+	}
+
+	/// Features set in the superblock that this version of libbcachefs
+	/// doesn't know about; the kernel may not support them either.
+	pub fn incompat_features(&self) -> Vec<Feature> {
+		self.sb
+			.sb()
+			.features()
+			.filter(|&bit| bcachefs::feature_name(bit).is_none())
+			.map(Feature)
+			.collect()
+	}
+
+	/// Apply filesystem specific options to a mounted filesystem via sysfs,
+	/// for remount. Options that are already set are skipped; options that
+	/// can only be set at mount time are an error.
+	fn apply_runtime_options(&self, options: &str) -> anyhow::Result<()> {
+		use anyhow::{anyhow, Context};
+		use std::os::unix::fs::PermissionsExt;
+
+		let dir = PathBuf::from(format!("/sys/fs/bcachefs/{}/options", self.uuid));
+		if !dir.is_dir() {
+			return Err(anyhow!("Filesystem {} is not mounted", self.uuid));
 		}
+
+		for o in options.split(',').filter(|o| !o.is_empty()) {
+			let (name, val) = match o.find('=') {
+				Some(i) => (&o[..i], &o[i + 1..]),
+				None if dir.join(o).exists() => (o, "1"),
+				None if o.starts_with("no") && dir.join(&o[2..]).exists() => (&o[2..], "0"),
+				None => (o, "1"),
+			};
+			let path = dir.join(name);
+			let meta = std::fs::metadata(&path)
+				.map_err(|_| anyhow!("Unknown mount option {}", name))?;
+
+			// Choice options are displayed with the current value in brackets:
+			let cur = std::fs::read_to_string(&path)?;
+			let cur = cur
+				.split_whitespace()
+				.find(|v| v.starts_with('['))
+				.map(|v| v.trim_matches(|c| c == '[' || c == ']'))
+				.unwrap_or_else(|| cur.trim());
+			if cur == val {
+				continue;
+			}
+
+			if meta.permissions().mode() & 0o200 == 0 {
+				return Err(anyhow!(
+					"Option {} cannot be changed at runtime (currently {}, requested {})",
+					name, cur, val
+				));
+			}
+			info!("Setting {}={} (was {})", name, val, cur);
+			std::fs::write(&path, val)
+				.with_context(|| format!("setting option {}={}", name, val))?;
+		}
+		Ok(())
 	}
 
 	pub fn mount(
@@ -83,7 +206,14 @@ impl FileSystem {
 		use std::os::raw::c_char;
 		use std::os::unix::ffi::OsStrExt;
 		let src = self.devices.iter().map(|d| d.display()).join(":");
-		let (data, mountflags) = parse_mount_options(options);
+		let (mut data, mountflags) = parse_mount_options(options);
+		if mountflags & libc::MS_REMOUNT != 0 {
+			// The kernel only handles ro/rw on remount; everything else is
+			// changed via sysfs:
+			if let Some(data) = data.take() {
+				self.apply_runtime_options(&data)?;
+			}
+		}
 		let fstype = c_str!("bcachefs");
 
 		let src = std::ffi::CString::new(src)?; // bind the CString to keep it alive
@@ -105,6 +235,143 @@ impl FileSystem {
 	}
 }
 
+impl FileSystem {
+	/// Mount only the directory `subdir` of the filesystem on `target`: the
+	/// whole filesystem is mounted on a temporary directory, `subdir` bind
+	/// mounted from there, and the temporary mount detached again.
+	pub fn mount_subdir(
+		&self,
+		target: impl AsRef<std::path::Path>,
+		subdir: impl AsRef<std::path::Path>,
+		options: impl AsRef<str>,
+	) -> anyhow::Result<()> {
+		use anyhow::Context;
+		use std::os::unix::ffi::OsStrExt;
+
+		let tmp = PathBuf::from(format!("/run/bcachefs-mount-{}", self.uuid));
+		std::fs::create_dir_all(&tmp)
+			.with_context(|| format!("creating {}", tmp.display()))?;
+		let tmp_c = std::ffi::CString::new(tmp.as_os_str().as_bytes())?;
+
+		/// Detaches the temporary mount however the bind mount went: once
+		/// the bind mount exists it keeps the filesystem mounted
+		struct Detach<'a>(&'a std::ffi::CStr);
+		impl Drop for Detach<'_> {
+			fn drop(&mut self) {
+				unsafe { libc::umount2(self.0.as_ptr(), libc::MNT_DETACH) };
+			}
+		}
+
+		let ret = self.mount(&tmp, options).and_then(|_| {
+			let _detach = Detach(&tmp_c);
+
+			let src = tmp.join(subdir.as_ref().strip_prefix("/").unwrap_or(subdir.as_ref()));
+			// Resolve symlinks, which mustn't lead out of the filesystem:
+			let src = src
+				.canonicalize()
+				.with_context(|| format!("resolving {}", src.display()))?;
+			if !src.starts_with(tmp.canonicalize()?) {
+				return Err(anyhow::Error::new(crate::ErrnoError(errno::Errno(libc::EPERM)))
+					.context(format!("{} is outside the filesystem", src.display())));
+			}
+			info!("Bind mounting {} on {}", src.display(), target.as_ref().display());
+
+			let src = std::ffi::CString::new(src.as_os_str().as_bytes())?;
+			let target = std::ffi::CString::new(target.as_ref().as_os_str().as_bytes())?;
+			let ret = unsafe {
+				libc::mount(
+					src.as_ptr(),
+					target.as_ptr(),
+					std::ptr::null(),
+					libc::MS_BIND,
+					std::ptr::null(),
+				)
+			};
+			if ret == 0 {
+				Ok(())
+			} else {
+				Err(crate::ErrnoError(errno::errno()).into())
+			}
+		});
+
+		let _ = std::fs::remove_dir(&tmp);
+		ret.with_context(|| format!("mounting subdirectory {}", subdir.as_ref().display()))
+	}
+}
+
+/// Where the value of a mount option comes from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OptionSource {
+	/// Given with -o
+	MountOption,
+	/// The default persisted in the superblock
+	Superblock,
+	/// Neither: the kernel's built in default
+	Default,
+}
+
+impl std::fmt::Display for OptionSource {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		f.write_str(match self {
+			Self::MountOption => "mount option",
+			Self::Superblock => "superblock",
+			Self::Default => "default",
+		})
+	}
+}
+
+/// The value given for option `name` in a list of mount options, if any; the
+/// last one wins, as in the kernel's option parsing.
+fn mount_option_value(opts: &[&str], name: &str, is_bool: bool) -> Option<String> {
+	opts.iter().rev().find_map(|o| {
+		let mut kv = o.splitn(2, '=');
+		let k = kv.next()?;
+		match kv.next() {
+			Some(v) if k == name => Some(v.to_owned()),
+			None if is_bool && k == name => Some("1".to_owned()),
+			None if is_bool && k.strip_prefix("no") == Some(name) => Some("0".to_owned()),
+			_ => None,
+		}
+	})
+}
+
+impl FileSystem {
+	/// Every mount option, with the value it will have when mounted with
+	/// `options` and where that comes from. The kernel takes options not
+	/// given from the superblock, if they're stored there; the built in
+	/// defaults of the others aren't exported, so their value isn't known.
+	pub fn effective_options(
+		&self,
+		options: impl AsRef<str>,
+	) -> Vec<(&'static str, Option<String>, OptionSource)> {
+		use bcachefs::{bch_opt_id, bch_option, opt_mode, opt_type};
+
+		let (data, _) = parse_mount_options(options);
+		let data = data.unwrap_or_default();
+		let given: Vec<&str> = data.split(',').collect();
+		let sb_opts = unsafe { bcachefs::bch2_opts_from_sb(self.sb.sb) };
+
+		(0..bch_opt_id::bch2_opts_nr)
+			.filter_map(|id| {
+				let opt = bch_option::by_id(id);
+				if (opt.mode & opt_mode::OPT_MOUNT).0 == 0 {
+					return None;
+				}
+
+				let is_bool = opt.type_ == opt_type::BCH_OPT_BOOL;
+				Some(if let Some(v) = mount_option_value(&given, opt.name(), is_bool) {
+					(opt.name(), Some(v), OptionSource::MountOption)
+				} else if unsafe { bcachefs::bch2_opt_defined_by_id(&sb_opts, id) } {
+					let v = unsafe { bcachefs::bch2_opt_get_by_id(&sb_opts, id) };
+					(opt.name(), Some(opt.value_to_string(v)), OptionSource::Superblock)
+				} else {
+					(opt.name(), None, OptionSource::Default)
+				})
+			})
+			.collect()
+	}
+}
+
 use crate::bcachefs;
 use std::collections::HashMap;
 use uuid::Uuid;
@@ -122,8 +389,13 @@ pub fn probe_filesystems() -> anyhow::Result<HashMap<Uuid, FileSystem>> {
 				let path =
 					std::ffi::CString::new(p.as_os_str().as_bytes()).unwrap();
 				let result = unsafe {
+					use bcachefs::bch_opt_id;
 					let mut opts = std::mem::MaybeUninit::zeroed();
 					let mut sb = std::mem::MaybeUninit::zeroed();
+					// Don't open devices exclusively, so that members of
+					// mounted filesystems are found too, e.g. to remount
+					bcachefs::bch2_opt_set_by_id(opts.as_mut_ptr(), bch_opt_id::Opt_noexcl, 1);
+					bcachefs::bch2_opt_set_by_id(opts.as_mut_ptr(), bch_opt_id::Opt_nochanges, 1);
 					let ret = bcachefs::bch2_read_super(
 						path.as_ptr(),
 						opts.as_mut_ptr(),
@@ -147,10 +419,12 @@ pub fn probe_filesystems() -> anyhow::Result<HashMap<Uuid, FileSystem>> {
 					Ok((_, sb)) => match fss.get_mut(&sb.sb().uuid()) {
 						None => {
 							let mut fs = FileSystem::new(sb);
+							fs.members.push(MemberState::new(fs.sb.sb()));
 							fs.devices.push(p.to_owned());
 							fss.insert(fs.uuid, fs);
 						}
 						Some(fs) => {
+							fs.members.push(MemberState::new(sb.sb()));
 							fs.devices.push(p.to_owned());
 						}
 					},
@@ -172,3 +446,100 @@ pub fn probe_filesystems() -> anyhow::Result<HashMap<Uuid, FileSystem>> {
 	}
 	Ok(fss)
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn option_value_last_wins() {
+		let opts = ["compression=lz4", "noatime", "compression=zstd"];
+		assert_eq!(mount_option_value(&opts, "compression", false), Some("zstd".into()));
+		assert_eq!(mount_option_value(&opts, "foreground_target", false), None);
+	}
+
+	#[test]
+	fn option_value_empty() {
+		assert_eq!(mount_option_value(&["compression="], "compression", false), Some("".into()));
+		assert_eq!(mount_option_value(&[], "compression", false), None);
+		assert_eq!(mount_option_value(&[""], "compression", false), None);
+	}
+
+	#[test]
+	fn option_value_bool() {
+		assert_eq!(mount_option_value(&["degraded"], "degraded", true), Some("1".into()));
+		assert_eq!(mount_option_value(&["nodegraded"], "degraded", true), Some("0".into()));
+		assert_eq!(mount_option_value(&["degraded", "nodegraded"], "degraded", true), Some("0".into()));
+		assert_eq!(mount_option_value(&["degraded=0"], "degraded", true), Some("0".into()));
+		// Only boolean options may be given without a value:
+		assert_eq!(mount_option_value(&["compression"], "compression", false), None);
+		assert_eq!(mount_option_value(&["nocompression"], "compression", false), None);
+	}
+
+	const NR_FEATURES: usize = bcachefs::bch_sb_feature::BCH_FEATURE_NR as usize;
+
+	/// libbcachefs isn't linked into the unit tests, so they bring their own
+	/// feature names, all "test"
+	#[repr(transparent)]
+	struct FeatureNames([*const std::os::raw::c_char; NR_FEATURES]);
+	unsafe impl Sync for FeatureNames {}
+
+	#[no_mangle]
+	#[allow(non_upper_case_globals)]
+	static bch2_sb_features: FeatureNames =
+		FeatureNames([b"test\0".as_ptr() as *const _; NR_FEATURES]);
+
+	#[test]
+	fn feature_matches() {
+		let f = Feature(0);
+
+		assert_eq!(f.to_string(), "test");
+		assert!(f.matches("test"));
+		assert!(f.matches(" test "));
+		assert!(f.matches("0"));
+		assert!(!f.matches("1"));
+		assert!(!f.matches("tes"));
+		assert!(!f.matches(""));
+	}
+
+	#[test]
+	fn feature_matches_unknown_bit() {
+		let f = Feature(NR_FEATURES as u32 + 5);
+		let bit = f.0.to_string();
+
+		assert_eq!(f.to_string(), format!("bit {}", bit));
+		assert!(f.matches(&bit));
+		assert!(f.matches(&format!(" {} ", bit)));
+		assert!(f.matches(&format!("bit {}", bit)));
+		assert!(!f.matches(&(f.0 + 1).to_string()));
+		assert!(!f.matches("test"));
+		assert!(!f.matches(""));
+	}
+
+	fn fs_with_members(members: Vec<MemberState>) -> FileSystem {
+		FileSystem {
+			uuid: uuid::Uuid::nil(),
+			encrypted: false,
+			sb: Default::default(),
+			devices: (0..members.len())
+				.map(|i| PathBuf::from(format!("/dev/sd{}", i)))
+				.collect(),
+			members,
+		}
+	}
+
+	#[test]
+	fn diverged_members() {
+		let clean = MemberState { seq: 10, clean_journal_seq: Some(100) };
+
+		assert!(fs_with_members(vec![]).diverged_members().is_empty());
+		assert!(fs_with_members(vec![clean, clean]).diverged_members().is_empty());
+
+		let fs = fs_with_members(vec![clean, MemberState { seq: 9, ..clean }, clean]);
+		assert_eq!(fs.diverged_members(), vec![&PathBuf::from("/dev/sd1")]);
+
+		// Mounted without the first device since:
+		let fs = fs_with_members(vec![clean, MemberState { seq: 11, clean_journal_seq: None }]);
+		assert_eq!(fs.diverged_members(), vec![&PathBuf::from("/dev/sd0")]);
+	}
+}
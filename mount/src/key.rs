@@ -28,69 +28,171 @@ fn wait_for_key(uuid: &uuid::Uuid) -> anyhow::Result<()> {
 	}
 }
 
-const BCH_KEY_MAGIC: &str = "bch**key";
+/// Run a password agent, returning what it printed, or None if it couldn't be
+/// run or failed
+fn ask_password_cmd(cmd: &str, args: &[&str]) -> Option<String> {
+	use std::process::{Command, Stdio};
+
+	let output = Command::new(cmd)
+		.args(args)
+		.stdin(Stdio::inherit())
+		.stderr(Stdio::inherit())
+		.output()
+		.ok()?;
+	if output.status.success() {
+		String::from_utf8(output.stdout).ok()
+	} else {
+		None
+	}
+}
+
+/// Whether plymouth is showing a boot splash
+fn plymouth_running() -> bool {
+	std::process::Command::new("plymouth")
+		.arg("--ping")
+		.status()
+		.is_ok_and(|s| s.success())
+}
+
+/// Ask for the passphrase through plymouth during boot, so the prompt shows up
+/// on the splash screen; otherwise on the terminal, or with
+/// systemd-ask-password when there's no terminal
+fn read_passphrase(fs: &FileSystem) -> anyhow::Result<String> {
+	use anyhow::anyhow;
+
+	let prompt = format!("Enter passphrase for bcachefs {}: ", fs.uuid());
+	if plymouth_running() {
+		info!("Asking for passphrase through plymouth");
+		return ask_password_cmd("plymouth", &["ask-for-password", &format!("--prompt={}", prompt)])
+			.ok_or_else(|| anyhow!("plymouth ask-for-password failed"));
+	}
+
+	match rpassword::read_password_from_tty(Some(&prompt)) {
+		Ok(pass) => Ok(pass),
+		Err(e) => ask_password_cmd("systemd-ask-password", &[&prompt])
+			.ok_or_else(|| anyhow!("no terminal to ask for passphrase on: {}", e)),
+	}
+}
+
+fn is_bad_passphrase(e: &anyhow::Error) -> bool {
+	matches!(e.downcast_ref(), Some(crate::KeyError::BadPassphrase))
+}
+
 use crate::filesystem::FileSystem;
-fn ask_for_key(fs: &FileSystem) -> anyhow::Result<()> {
-	use crate::bcachefs::{self, bch2_chacha_encrypt_key, bch_encrypted_key, bch_key};
+/// Ask for the passphrase up to `attempts` times, waiting twice as long after
+/// each wrong one
+fn ask_for_key(fs: &FileSystem, attempts: u32) -> anyhow::Result<()> {
 	use anyhow::anyhow;
-	use byteorder::{LittleEndian, ReadBytesExt};
-	use std::os::raw::c_char;
 
 	let key_name = std::ffi::CString::new(format!("bcachefs:{}", fs.uuid())).unwrap();
 	if check_for_key(&key_name)? {
 		return Ok(());
 	}
 
-	let bch_key_magic = BCH_KEY_MAGIC.as_bytes().read_u64::<LittleEndian>().unwrap();
-	let crypt = fs.sb().sb().crypt().unwrap();
-	let pass = rpassword::read_password_from_tty(Some("Enter passphrase: "))?;
-	let pass = std::ffi::CString::new(pass.trim_end())?; // bind to keep the CString alive
-	let mut output: bch_key = unsafe {
-		bcachefs::derive_passphrase(
-			crypt as *const _ as *mut _,
-			pass.as_c_str().to_bytes_with_nul().as_ptr() as *const _,
-		)
+	let attempts = attempts.max(1);
+	let mut delay = std::time::Duration::from_secs(1);
+	for attempt in 1.. {
+		let pass = read_passphrase(fs)?;
+		let ret = match std::ffi::CString::new(pass.trim_end()) {
+			Ok(pass) => unlock_with_passphrase(fs, &key_name, &pass),
+			Err(_) => Err(crate::KeyError::BadPassphrase.into()),
+		};
+
+		match ret {
+			Err(e) if is_bad_passphrase(&e) && attempt < attempts => {
+				eprintln!("Wrong passphrase, {} attempts left", attempts - attempt);
+				std::thread::sleep(delay);
+				delay *= 2;
+			}
+			Err(e) if is_bad_passphrase(&e) => {
+				return Err(anyhow!(
+					"wrong passphrase for filesystem {}, giving up after {} attempts",
+					fs.uuid(),
+					attempts
+				))
+			}
+			ret => return ret,
+		}
+	}
+	unreachable!()
+}
+
+/// Unlock with the contents of a keyfile, as generated by
+/// `bcachefs key generate-keyfile`, as the passphrase
+pub(crate) fn unlock_with_keyfile(fs: &FileSystem, path: &std::path::Path) -> anyhow::Result<()> {
+	use anyhow::Context;
+
+	let key_name = std::ffi::CString::new(format!("bcachefs:{}", fs.uuid())).unwrap();
+	if check_for_key(&key_name)? {
+		return Ok(());
+	}
+
+	let mut contents = std::fs::read(path)
+		.with_context(|| format!("reading keyfile {}", path.display()))?;
+	// The same conversion as read_keyfile(), so that keyfiles work the same
+	// here as with the bcachefs tool:
+	let pass = unsafe {
+		crate::bcachefs::keyfile_to_passphrase(contents.as_ptr() as *const _, contents.len())
 	};
+	contents.iter_mut().for_each(|b| *b = 0);
+	if pass.is_null() {
+		return Err(anyhow::anyhow!("keyfile {} is empty", path.display()));
+	}
 
-	let mut key = crypt.key().clone();
+	let ret = unlock_with_passphrase(fs, &key_name, unsafe { std::ffi::CStr::from_ptr(pass) })
+		.with_context(|| format!("unlocking with keyfile {}", path.display()));
+	unsafe {
+		libc::memset(pass as *mut _, 0, libc::strlen(pass));
+		libc::free(pass as *mut _);
+	}
+	ret
+}
+
+fn unlock_with_passphrase(
+	fs: &FileSystem,
+	key_name: &std::ffi::CStr,
+	pass: &std::ffi::CStr,
+) -> anyhow::Result<()> {
+	use crate::bcachefs::bch_key;
+	use anyhow::anyhow;
+	use std::os::raw::c_char;
+
+	let sb = fs.sb().sb();
+	let crypt = sb
+		.crypt()
+		.ok_or_else(|| crate::KeyError::CorruptCrypt("missing".into()))?;
+	crypt.check()?;
+	let output = crypt.derive_key(pass);
+	crypt.decrypt_key(sb, &output)?;
+
+	let key_type = c_str!("logon");
 	let ret = unsafe {
-		bch2_chacha_encrypt_key(
-			&mut output as *mut _,
-			fs.sb().sb().nonce(),
-			&mut key as *mut _ as *mut _,
-			std::mem::size_of::<bch_encrypted_key>() as u64,
+		crate::keyutils::add_key(
+			key_type,
+			key_name.to_bytes_with_nul() as *const _
+				as *const c_char,
+			&output as *const _ as *const _,
+			std::mem::size_of::<bch_key>(),
+			crate::keyutils::KEY_SPEC_USER_KEYRING,
 		)
 	};
-	if ret != 0 {
-		Err(anyhow!("chache decryption failure"))
-	} else if key.magic != bch_key_magic {
-		Err(anyhow!("failed to verify the password"))
+	if ret == -1 {
+		Err(anyhow!("failed to add key to keyring: {}", errno::errno()))
 	} else {
-		let key_type = c_str!("logon");
-		let ret = unsafe {
-			crate::keyutils::add_key(
-				key_type,
-				key_name.as_c_str().to_bytes_with_nul() as *const _
-					as *const c_char,
-				&output as *const _ as *const _,
-				std::mem::size_of::<bch_key>() as u64,
-				crate::keyutils::KEY_SPEC_USER_KEYRING,
-			)
-		};
-		if ret == -1 {
-			Err(anyhow!("failed to add key to keyring: {}", errno::errno()))
-		} else {
-			Ok(())
-		}
+		Ok(())
 	}
 }
 
-pub(crate) fn prepare_key(fs: &FileSystem, password: crate::KeyLocation) -> anyhow::Result<()> {
+pub(crate) fn prepare_key(
+	fs: &FileSystem,
+	password: crate::KeyLocation,
+	attempts: u32,
+) -> anyhow::Result<()> {
 	use crate::KeyLocation::*;
 	use anyhow::anyhow;
 	match password {
 		Fail => Err(anyhow!("no key available")),
 		Wait => Ok(wait_for_key(fs.uuid())?),
-		Ask => ask_for_key(fs),
+		Ask => ask_for_key(fs, attempts),
 	}
 }
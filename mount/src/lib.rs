@@ -19,6 +19,28 @@ impl std::fmt::Display for ErrnoError {
 }
 impl std::error::Error for ErrnoError {}
 
+/// Why unlocking an encrypted filesystem failed. The superblock checksum has
+/// been verified by the time we unlock, so if the crypt field looks sane and
+/// the key still doesn't decrypt, it's the passphrase that's wrong.
+#[derive(Debug)]
+pub(crate) enum KeyError {
+	BadPassphrase,
+	CorruptCrypt(String),
+}
+impl std::fmt::Display for KeyError {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
+		match self {
+			Self::BadPassphrase => write!(f, "wrong passphrase"),
+			Self::CorruptCrypt(why) => write!(
+				f,
+				"the crypt superblock field is corrupt ({}), no passphrase will unlock it",
+				why
+			),
+		}
+	}
+}
+impl std::error::Error for KeyError {}
+
 #[derive(Debug)]
 pub(crate) enum KeyLocation {
 	Fail,
@@ -47,10 +69,16 @@ struct Options {
 	/// Possible values are:
 	/// "fail" - don't ask for password, fail if filesystem is encrypted;
 	/// "wait" - wait for password to become available before mounting;
-	/// "ask" -  prompt the user for password;
+	/// "ask" -  prompt the user for password, through plymouth if it's
+	///          running;
 	#[structopt(short, long, default_value = "fail")]
 	key_location: KeyLocation,
 
+	/// With --key-location=ask, how many times to ask for the passphrase
+	/// before giving up, waiting 1, 2, 4... seconds after each wrong one
+	#[structopt(long, default_value = "3")]
+	key_attempts: u32,
+
 	/// External UUID of the bcachefs filesystem
 	uuid: uuid::Uuid,
 
@@ -59,13 +87,63 @@ struct Options {
 	/// filesystem (e.g. asking for passphrase) will still be performed.
 	mountpoint: Option<std::path::PathBuf>,
 
-	/// Mount options
+	/// Mount options. In addition to the options understood by the kernel,
+	/// "unlock=keyfile:<path>" unlocks an encrypted filesystem with a keyfile
+	/// (see `bcachefs key generate-keyfile`) instead of using --key-location.
+	/// With "remount", options that can be changed at runtime are applied via
+	/// sysfs, and only ro/rw and the generic mount flags are passed to the
+	/// kernel. "X-mount.subdir=<path>" mounts only that directory of the
+	/// filesystem, e.g. to keep root and home in directories of one
+	/// filesystem.
 	#[structopt(short, default_value = "")]
 	options: String,
+
+	/// Comma separated list of incompatible features to allow when mounting,
+	/// by name or by bit number. Filesystems using features not known to this
+	/// version of bcachefs-tools are refused otherwise.
+	#[structopt(long, use_delimiter = true)]
+	allow_incompat_features: Vec<String>,
+
+	/// Mount even if the superblocks of the member devices have diverged,
+	/// e.g. because some members were mounted separately: the kernel will
+	/// silently pick the member with the most recent superblock.
+	#[structopt(long)]
+	force: bool,
+
+	/// Mount read-write a filesystem with a newer metadata version than this
+	/// version of bcachefs-tools knows, which the kernel may not know either.
+	#[structopt(long)]
+	allow_newer_version: bool,
+
+	/// Print every mount option with the value the filesystem will be mounted
+	/// with, and where that comes from: the -o options, the defaults stored in
+	/// the superblock, or the kernel's built in defaults
+	#[structopt(long)]
+	show_effective_options: bool,
+
+	/// If mounting read-write fails, e.g. because the filesystem has errors
+	/// that need fsck to repair, retry mounting it read-only instead of
+	/// failing. Without this, whether to retry read-only is asked when run on
+	/// a terminal.
+	#[structopt(long)]
+	fallback_ro: bool,
+
+	/// Shell command to run when the filesystem is mounted degraded, or read
+	/// only after mounting it read-write failed, with BCACHEFS_EVENT set to
+	/// mount-degraded or mount-fallback-ro, and BCACHEFS_UUID, in its
+	/// environment
+	#[structopt(long)]
+	notify_exec: Option<String>,
+
+	/// Log degraded and read-only fallback mounts to syslog
+	#[structopt(long)]
+	notify_syslog: bool,
 }
 
+mod endian;
 mod filesystem;
 mod key;
+mod notify;
 mod keyutils {
 	#![allow(non_upper_case_globals)]
 	#![allow(non_camel_case_types)]
@@ -83,6 +161,10 @@ mod bcachefs {
 
 	include!(concat!(env!("OUT_DIR"), "/bcachefs.rs"));
 
+	pub type __le16 = crate::endian::Le16;
+	pub type __le32 = crate::endian::Le32;
+	pub type __le64 = crate::endian::Le64;
+
 	use bitfield::bitfield;
 	bitfield! {
 		pub struct bch_scrypt_flags(u64);
@@ -92,21 +174,101 @@ mod bcachefs {
 	}
 	bitfield! {
 		pub struct bch_crypt_flags(u64);
-		TYPE, _: 4, 0;
+		pub TYPE, _: 4, 0;
+	}
+	bitfield! {
+		pub struct bch_sb_flags(u64);
+		pub INITIALIZED, _: 0;
+		pub CLEAN, _: 1;
+		pub CSUM_TYPE, _: 7, 2;
 	}
 	use memoffset::offset_of;
 	impl bch_sb_field_crypt {
 		pub fn scrypt_flags(&self) -> Option<bch_scrypt_flags> {
-			let t = bch_crypt_flags(self.flags);
+			let t = bch_crypt_flags(self.flags.get());
 			if t.TYPE() != bch_kdf_types::BCH_KDF_SCRYPT as u64 {
 				None
 			} else {
-				Some(bch_scrypt_flags(self.kdf_flags))
+				Some(bch_scrypt_flags(self.kdf_flags.get()))
 			}
 		}
 		pub fn key(&self) -> &bch_encrypted_key {
 			&self.key
 		}
+
+		/// Check that the field is usable, so that a key derived with it that
+		/// doesn't decrypt the master key means a wrong passphrase
+		pub fn check(&self) -> Result<(), crate::KeyError> {
+			use crate::KeyError::CorruptCrypt;
+
+			if self.field.u64s.get() as usize * 8 < std::mem::size_of::<Self>() {
+				return Err(CorruptCrypt(format!(
+					"only {} bytes long",
+					self.field.u64s.get() * 8
+				)));
+			}
+			let f = self.scrypt_flags().ok_or_else(|| {
+				CorruptCrypt(format!(
+					"unknown kdf type {}",
+					bch_crypt_flags(self.flags.get()).TYPE()
+				))
+			})?;
+			// Would need more than 16GiB:
+			if f.N() + f.R() > 27 || f.P() > 16 {
+				return Err(CorruptCrypt(format!(
+					"scrypt parameters N=2^{} r=2^{} p=2^{}",
+					f.N(),
+					f.R(),
+					f.P()
+				)));
+			}
+			Ok(())
+		}
+
+		/// Derive the key the master key is encrypted with from a passphrase
+		pub fn derive_key(&self, passphrase: &std::ffi::CStr) -> bch_key {
+			unsafe {
+				derive_passphrase(
+					self as *const _ as *mut _,
+					passphrase.to_bytes_with_nul().as_ptr() as *const _,
+				)
+			}
+		}
+
+		/// Decrypt the master key with a key from `derive_key()`, checking
+		/// that the key was correct
+		pub fn decrypt_key(&self, sb: &bch_sb, key: &bch_key) -> anyhow::Result<bch_key> {
+			use anyhow::anyhow;
+
+			let mut key = *key;
+			let mut master = self.key;
+			let ret = unsafe {
+				bch2_chacha_encrypt_key(
+					&mut key as *mut _,
+					sb.nonce(),
+					&mut master as *mut _ as *mut _,
+					std::mem::size_of::<bch_encrypted_key>(),
+				)
+			};
+			if ret != 0 {
+				Err(anyhow!("chacha decryption failure"))
+			} else if master.magic.get() != key_magic() {
+				Err(crate::KeyError::BadPassphrase.into())
+			} else {
+				Ok(master.key)
+			}
+		}
+	}
+
+	/// The newest metadata version this version of libbcachefs knows, like
+	/// bcachefs_metadata_version_current
+	pub fn metadata_version_current() -> u32 {
+		bcachefs_metadata_version::bcachefs_metadata_version_max - 1
+	}
+
+	/// Magic number of a correctly decrypted master key
+	pub fn key_magic() -> u64 {
+		u64::from_le_bytes(*b"bch**key")
 	}
 	impl bch_sb {
 		pub fn crypt(&self) -> Option<&bch_sb_field_crypt> {
@@ -123,33 +285,219 @@ mod bcachefs {
 				}
 			}
 		}
+		pub fn clean(&self) -> Option<&bch_sb_field_clean> {
+			unsafe {
+				let ptr = bch2_sb_field_get(
+					self as *const _ as *mut _,
+					bch_sb_field_type::BCH_SB_FIELD_clean,
+				) as *const u8;
+				if ptr.is_null() {
+					None
+				} else {
+					let offset = offset_of!(bch_sb_field_clean, field);
+					Some(&*((ptr.sub(offset)) as *const _))
+				}
+			}
+		}
 		pub fn uuid(&self) -> uuid::Uuid {
 			uuid::Uuid::from_bytes(self.user_uuid.b)
 		}
 
-		/// Get the nonce used to encrypt the superblock
+		/// Number of member devices, not counting the slots of removed ones,
+		/// which are left zeroed: like counting bch2_dev_exists()
+		pub fn nr_members(&self) -> usize {
+			unsafe {
+				let ptr = bch2_sb_field_get(
+					self as *const _ as *mut _,
+					bch_sb_field_type::BCH_SB_FIELD_members,
+				) as *const u8;
+				if ptr.is_null() {
+					return 0;
+				}
+				let offset = offset_of!(bch_sb_field_members, members)
+					- offset_of!(bch_sb_field_members, field);
+				let members = ptr.add(offset) as *const bch_member;
+				(0..self.nr_devices as usize)
+					.filter(|&i| (*members.add(i)).uuid.b.iter().any(|&b| b != 0))
+					.count()
+			}
+		}
+
+		/// Whether the filesystem was shut down cleanly, as recorded in this
+		/// member's superblock
+		pub fn is_clean(&self) -> bool {
+			bch_sb_flags(self.flags[0].get()).CLEAN()
+		}
+
+		/// Feature bits set in this superblock
+		pub fn features(&self) -> impl Iterator<Item = u32> + '_ {
+			(0..(self.features.len() * 64) as u32)
+				.filter(move |&bit| self.features[(bit / 64) as usize].get() & (1 << (bit % 64)) != 0)
+		}
+
+		/// Get the nonce the master key is encrypted with
 		pub fn nonce(&self) -> nonce {
 			use byteorder::{ReadBytesExt, LittleEndian};
+			use crate::endian::Le32;
 			let mut internal_uuid = &self.uuid.b[..];
 			let dword1 = internal_uuid.read_u32::<LittleEndian>().unwrap();
 			let dword2 = internal_uuid.read_u32::<LittleEndian>().unwrap();
-			nonce { d: [0, 0, dword1, dword2] }
+			nonce { d: [Le32::new(0), Le32::new(0), Le32::new(dword1), Le32::new(dword2)] }
 		}
 	}
+
+	/// Name of a superblock feature bit, if it's known to this version of
+	/// libbcachefs
+	pub fn feature_name(bit: u32) -> Option<&'static str> {
+		if bit >= bch_sb_feature::BCH_FEATURE_NR as u32 {
+			return None;
+		}
+		unsafe {
+			let names = &bch2_sb_features as *const _ as *const *const std::os::raw::c_char;
+			let name = *names.add(bit as usize);
+			std::ffi::CStr::from_ptr(name).to_str().ok()
+		}
+	}
+
 	impl bch_sb_handle {
 		pub fn sb(&self) -> &bch_sb {
 			unsafe { &*self.sb }
 		}
 	}
+
+	impl bch_option {
+		/// Look up an option in bch2_opt_table
+		pub fn by_id(id: bch_opt_id::Type) -> &'static bch_option {
+			assert!(id < bch_opt_id::bch2_opts_nr);
+			unsafe { &*bch2_opt_table.as_ptr().add(id as usize) }
+		}
+
+		pub fn name(&self) -> &'static str {
+			unsafe { std::ffi::CStr::from_ptr(self.attr.name) }
+				.to_str()
+				.unwrap_or("?")
+		}
+
+		/// Format a value of this option like bch2_opt_to_text(), except that
+		/// targets are printed as numbers, since naming them needs a running
+		/// filesystem
+		pub fn value_to_string(&self, v: u64) -> String {
+			match self.type_ {
+				opt_type::BCH_OPT_SECTORS => (v << 9).to_string(),
+				opt_type::BCH_OPT_STR => unsafe {
+					let mut choice = self.__bindgen_anon_1.__bindgen_anon_2.choices;
+					for _ in 0..v {
+						if (*choice).is_null() {
+							break;
+						}
+						choice = choice.add(1);
+					}
+					if (*choice).is_null() {
+						v.to_string()
+					} else {
+						std::ffi::CStr::from_ptr(*choice).to_string_lossy().into_owned()
+					}
+				},
+				_ => v.to_string(),
+			}
+		}
+	}
+}
+
+/// Split "unlock=keyfile:<path>" out of the mount options, since it's handled
+/// here and not by the kernel
+fn parse_unlock_option(options: &str) -> anyhow::Result<(Option<std::path::PathBuf>, String)> {
+	use itertools::Itertools;
+
+	let mut keyfile = None;
+	let mut rest = Vec::new();
+	for o in options.split(',').filter(|o| !o.is_empty()) {
+		match o.strip_prefix("unlock=") {
+			Some(v) => match v.strip_prefix("keyfile:") {
+				Some(path) if !path.is_empty() => keyfile = Some(path.into()),
+				_ => return Err(anyhow!("invalid unlock option {}", o)),
+			},
+			None => rest.push(o),
+		}
+	}
+	Ok((keyfile, rest.into_iter().join(",")))
+}
+
+/// Split "X-mount.subdir=<path>" out of the mount options: the directory of
+/// the filesystem to mount, instead of its root
+fn parse_subdir_option(options: &str) -> anyhow::Result<(Option<std::path::PathBuf>, String)> {
+	use itertools::Itertools;
+
+	let mut subdir = None;
+	let mut rest = Vec::new();
+	for o in options.split(',').filter(|o| !o.is_empty()) {
+		match o.strip_prefix("X-mount.subdir=") {
+			// The subdir is joined onto where the filesystem is mounted:
+			Some(path)
+				if std::path::Path::new(path)
+					.components()
+					.any(|c| c == std::path::Component::ParentDir) =>
+			{
+				return Err(anyhow!("invalid subdir option {}: must not contain ..", o))
+			}
+			Some(path) if !path.is_empty() => subdir = Some(path.into()),
+			Some(_) => return Err(anyhow!("invalid subdir option {}", o)),
+			None => rest.push(o),
+		}
+	}
+	Ok((subdir, rest.into_iter().join(",")))
+}
+
+/// Whether a failed read-write mount might succeed read-only: not if it
+/// failed for reasons that have nothing to do with the state of the
+/// filesystem, like a missing mountpoint, subdirectory or key
+fn may_mount_ro(e: &anyhow::Error) -> bool {
+	let errno = e
+		.chain()
+		.find_map(|e| {
+			e.downcast_ref::<ErrnoError>()
+				.map(|e| (e.0).0)
+				.or_else(|| e.downcast_ref::<std::io::Error>()?.raw_os_error())
+		});
+	!matches!(
+		errno,
+		Some(libc::EBUSY)
+			| Some(libc::ENOENT)
+			| Some(libc::ENOTDIR)
+			| Some(libc::EPERM)
+			| Some(libc::EACCES)
+			| Some(libc::ENODEV)
+			| Some(libc::ENOKEY)
+	)
+}
+
+/// Ask on the terminal whether to mount read-only; never when stdin isn't a
+/// terminal, e.g. at boot, where --fallback-ro decides
+fn ask_fallback_ro() -> bool {
+	use std::io::Write;
+
+	if unsafe { libc::isatty(libc::STDIN_FILENO) } == 0 {
+		return false;
+	}
+
+	eprint!("Mount it read-only instead, writing nothing to it? [y/N] ");
+	let _ = std::io::stderr().flush();
+
+	let mut answer = String::new();
+	std::io::stdin().read_line(&mut answer).is_ok()
+		&& matches!(answer.trim(), "y" | "Y" | "yes")
 }
 
 fn main_inner() -> anyhow::Result<()> {
+	use anyhow::Context;
 	use itertools::Itertools;
 	use log::{info, trace};
 
 	env_logger::init();
 	let opt = Options::from_args();
 	trace!("{:?}", opt);
+	let (keyfile, options) = parse_unlock_option(&opt.options)?;
+	let (subdir, options) = parse_subdir_option(&options)?;
 
 	let fss = filesystem::probe_filesystems()?;
 	info!("Found {} bcachefs filesystems: ", fss.len());
@@ -167,24 +515,220 @@ fn main_inner() -> anyhow::Result<()> {
 	}
 
 	if let Some(fs) = fss.get(&opt.uuid) {
+		// The filesystem is already mounted and unlocked if we're remounting:
+		if options.split(',').any(|o| o == "remount") {
+			return match opt.mountpoint {
+				Some(p) => fs.mount(&p, &options),
+				None => Err(anyhow!("remount requires a mountpoint")),
+			};
+		}
+
+		let diverged = fs.diverged_members();
+		if !diverged.is_empty() {
+			for (dev, m) in fs.devices().iter().zip(fs.members()) {
+				eprintln!(
+					"Warning: {}: superblock seq {}, {}",
+					dev.display(),
+					m.seq,
+					match m.clean_journal_seq {
+						Some(seq) => format!("clean at journal seq {}", seq),
+						None => "not clean".to_owned(),
+					}
+				);
+			}
+			if !opt.force {
+				return Err(anyhow!(
+					"Members of filesystem {} have diverged ({} out of date), refusing to mount without --force",
+					opt.uuid,
+					diverged.iter().map(|d| d.display()).join(" ")
+				));
+			}
+		}
+
+		info!(
+			"Features: {}",
+			fs.sb().sb().features().map(filesystem::Feature).join(" ")
+		);
+		let (allowed, refused): (Vec<_>, Vec<_>) = fs
+			.incompat_features()
+			.into_iter()
+			.partition(|f| opt.allow_incompat_features.iter().any(|a| f.matches(a)));
+		for f in allowed {
+			eprintln!("Warning: mounting with incompatible feature {}", f);
+		}
+		if !refused.is_empty() {
+			return Err(anyhow!(
+				"Filesystem {} has incompatible features: {} (use --allow-incompat-features to mount anyways)",
+				opt.uuid,
+				refused.iter().join(", ")
+			));
+		}
+
+		// The kernel doesn't say which metadata versions it supports, but it
+		// should be no newer than the libbcachefs these tools are built with:
+		let version = fs.sb().sb().version.get() as u32;
+		let current = bcachefs::metadata_version_current();
+		if version > current {
+			eprintln!(
+				"Warning: filesystem {} has metadata version {}, newer than version {} known to this version of bcachefs-tools",
+				opt.uuid, version, current
+			);
+			if !options.split(',').any(|o| o == "ro") && !opt.allow_newer_version {
+				return Err(anyhow!(
+					"Refusing to mount filesystem {} read-write, since writing it with an older kernel may damage it: mount it with -o ro, or use --allow-newer-version",
+					opt.uuid
+				));
+			}
+		}
+
 		if fs.encrypted() {
 			info!("Making sure key is loaded for this filesystem");
-			key::prepare_key(&fs, opt.key_location)?;
+			match keyfile {
+				Some(path) => key::unlock_with_keyfile(fs, &path)?,
+				None => key::prepare_key(fs, opt.key_location, opt.key_attempts)?,
+			}
+		}
+
+		if opt.show_effective_options {
+			for (name, value, source) in fs.effective_options(&options) {
+				println!(
+					"{:<28} {:<16} {}",
+					name,
+					value.as_deref().unwrap_or("-"),
+					source
+				);
+			}
 		}
 
 		if let Some(p) = opt.mountpoint {
-			fs.mount(&p, &opt.options)
-		} else {
-			Ok(())
+			let notify = notify::Notify {
+				exec: opt.notify_exec,
+				syslog: opt.notify_syslog,
+			};
+			let mount = |options: &str| match &subdir {
+				Some(subdir) => fs.mount_subdir(&p, subdir, options),
+				None => fs.mount(&p, options),
+			};
+
+			match mount(&options) {
+				Ok(()) => {}
+				Err(e) if !options.split(',').any(|o| o == "ro") && may_mount_ro(&e) => {
+					eprintln!(
+						"Error mounting {} read-write: {}\n\
+						 The filesystem may have errors that fsck has to repair before it can be\n\
+						 written to (see dmesg).",
+						opt.uuid, e
+					);
+					if !opt.fallback_ro && !ask_fallback_ro() {
+						return Err(e.context("mount failed (use --fallback-ro to mount read-only)"));
+					}
+
+					let ro_options = options
+						.split(',')
+						.filter(|o| !o.is_empty() && *o != "rw")
+						.chain(std::iter::once("ro"))
+						.join(",");
+					mount(&ro_options).context("mounting read-only")?;
+
+					eprintln!(
+						"Warning: {} mounted read-only, nothing is written to it: data can be\n\
+						 copied off, and fsck run once it's unmounted.",
+						opt.uuid
+					);
+					notify.event(&opt.uuid, "mount-fallback-ro");
+				}
+				Err(e) => return Err(e),
+			}
+
+			let nr_devices = fs.sb().sb().nr_members();
+			if fs.devices().len() < nr_devices {
+				eprintln!(
+					"Warning: {} mounted degraded, with {} of {} devices",
+					opt.uuid,
+					fs.devices().len(),
+					nr_devices
+				);
+				notify.event(&opt.uuid, "mount-degraded");
+			}
 		}
+		Ok(())
 	} else {
 		Err(anyhow!("Filesystem {} is not found", opt.uuid))
 	}
 }
 
-#[no_mangle]
+// The test harness brings its own main:
+#[cfg_attr(not(test), no_mangle)]
 pub extern "C" fn main() {
 	if let Err(e) = main_inner() {
 		println!("Error: {:?}", e);
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn unlock_option() {
+		let (keyfile, rest) = parse_unlock_option("noatime,unlock=keyfile:/etc/key,ro").unwrap();
+		assert_eq!(keyfile, Some("/etc/key".into()));
+		assert_eq!(rest, "noatime,ro");
+
+		let (keyfile, rest) = parse_unlock_option("").unwrap();
+		assert_eq!(keyfile, None);
+		assert_eq!(rest, "");
+
+		// Options we don't handle are passed on, minus empty ones:
+		let (keyfile, rest) = parse_unlock_option("compression=lz4,,unlocked").unwrap();
+		assert_eq!(keyfile, None);
+		assert_eq!(rest, "compression=lz4,unlocked");
+
+		assert!(parse_unlock_option("unlock=keyfile:").is_err());
+		assert!(parse_unlock_option("unlock=").is_err());
+		assert!(parse_unlock_option("unlock=passphrase:foo").is_err());
+	}
+
+	#[test]
+	fn subdir_option() {
+		let (subdir, rest) = parse_subdir_option("ro,X-mount.subdir=home/user").unwrap();
+		assert_eq!(subdir, Some("home/user".into()));
+		assert_eq!(rest, "ro");
+
+		// Absolute paths are relative to the root of the filesystem:
+		let (subdir, _) = parse_subdir_option("X-mount.subdir=/home").unwrap();
+		assert_eq!(subdir, Some("/home".into()));
+
+		let (subdir, rest) = parse_subdir_option("X-mount.foo=bar,noatime").unwrap();
+		assert_eq!(subdir, None);
+		assert_eq!(rest, "X-mount.foo=bar,noatime");
+
+		assert!(parse_subdir_option("X-mount.subdir=").is_err());
+		assert!(parse_subdir_option("X-mount.subdir=..").is_err());
+		assert!(parse_subdir_option("X-mount.subdir=a/../../etc").is_err());
+		assert!(parse_subdir_option("X-mount.subdir=/..").is_err());
+
+		// .. only as a whole component:
+		let (subdir, _) = parse_subdir_option("X-mount.subdir=a..b").unwrap();
+		assert_eq!(subdir, Some("a..b".into()));
+	}
+
+	fn errno_err(e: i32) -> anyhow::Error {
+		ErrnoError(errno::Errno(e)).into()
+	}
+
+	#[test]
+	fn mount_ro() {
+		assert!(may_mount_ro(&errno_err(libc::EIO)));
+		assert!(may_mount_ro(&errno_err(libc::EINVAL)));
+		assert!(may_mount_ro(&anyhow!("something else")));
+
+		assert!(!may_mount_ro(&errno_err(libc::ENOENT)));
+		assert!(!may_mount_ro(&errno_err(libc::EBUSY)));
+		assert!(!may_mount_ro(&errno_err(libc::ENOKEY)));
+		assert!(!may_mount_ro(&errno_err(libc::EPERM).context("mounting subdirectory")));
+
+		let e: anyhow::Error = std::io::Error::from_raw_os_error(libc::ENOTDIR).into();
+		assert!(!may_mount_ro(&e.context("resolving /run/bcachefs-mount-x/a")));
+	}
+}
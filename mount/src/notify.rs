@@ -0,0 +1,45 @@
+//! Reporting of events to an exec hook and to syslog, like `bch_notify()` in
+//! the C tools, so that integrations don't have to poll.
+
+use std::process::Command;
+
+#[derive(Debug)]
+pub(crate) struct Notify {
+	/// Shell command run for each event
+	pub(crate) exec: Option<String>,
+	/// Whether to log events to syslog
+	pub(crate) syslog: bool,
+}
+
+impl Notify {
+	/// Reports `event` on filesystem `uuid`. The hook gets the same
+	/// environment as the C tools' hooks; events from here don't concern a
+	/// particular device, so BCACHEFS_DEVICE and BCACHEFS_DEVICE_IDX are empty.
+	pub(crate) fn event(&self, uuid: &uuid::Uuid, event: &str) {
+		let msg = format!("{}: {}", uuid, event);
+		log::info!("{}", msg);
+
+		if self.syslog {
+			if let Ok(msg) = std::ffi::CString::new(msg) {
+				let fmt = c_str!("%s");
+				unsafe { libc::syslog(libc::LOG_NOTICE, fmt, msg.as_ptr()) };
+			}
+		}
+
+		if let Some(exec) = &self.exec {
+			let status = Command::new("/bin/sh")
+				.arg("-c")
+				.arg(exec)
+				.env("BCACHEFS_EVENT", event)
+				.env("BCACHEFS_UUID", uuid.to_string())
+				.env("BCACHEFS_DEVICE", "")
+				.env("BCACHEFS_DEVICE_IDX", "")
+				.status();
+			match status {
+				Ok(s) if s.success() => {}
+				Ok(s) => log::error!("{}: hook exited with {}", exec, s),
+				Err(e) => log::error!("{}: error running hook: {}", exec, e),
+			}
+		}
+	}
+}
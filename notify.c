@@ -0,0 +1,55 @@
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <sys/wait.h>
+#include <syslog.h>
+
+#include "notify.h"
+#include "tools-util.h"
+
+/*
+ * Reports an event on filesystem @uuid, described by @msg: prints it, logs it
+ * to syslog, and runs the exec hook with BCACHEFS_EVENT and BCACHEFS_UUID in
+ * its environment, along with anything else the caller has set, waiting for it
+ * to finish.
+ */
+void bch_notify_msg(struct notify_opts *n, const char *uuid, const char *event,
+		    const char *msg)
+{
+	printf("%s: %s\n", uuid, msg);
+	fflush(stdout);
+
+	if (n->syslog)
+		syslog(LOG_NOTICE, "%s: %s", uuid, msg);
+
+	if (!n->exec)
+		return;
+
+	setenv("BCACHEFS_EVENT", event, 1);
+	setenv("BCACHEFS_UUID", uuid, 1);
+
+	int ret = system(n->exec);
+	if (ret)
+		fprintf(stderr, "%s: hook exited with status %i\n",
+			n->exec, WEXITSTATUS(ret));
+}
+
+/*
+ * Reports an event optionally concerning device @dev (@dev_idx < 0 if none),
+ * with BCACHEFS_DEVICE and BCACHEFS_DEVICE_IDX in the hook's environment too:
+ */
+void bch_notify(struct notify_opts *n, const char *uuid, const char *event,
+		const char *dev, int dev_idx)
+{
+	char *msg = dev_idx >= 0
+		? mprintf("%s %s (device %i)", event, dev ?: "(offline)", dev_idx)
+		: strdup(event);
+	char *idx = dev_idx >= 0 ? mprintf("%i", dev_idx) : strdup("");
+
+	setenv("BCACHEFS_DEVICE", dev ?: "", 1);
+	setenv("BCACHEFS_DEVICE_IDX", idx, 1);
+	free(idx);
+
+	bch_notify_msg(n, uuid, event, msg);
+	free(msg);
+}
@@ -0,0 +1,17 @@
+#ifndef _NOTIFY_H
+#define _NOTIFY_H
+
+#include <stdbool.h>
+
+/* Where events are reported, besides stdout: */
+struct notify_opts {
+	const char	*exec;
+	bool		syslog;
+};
+
+void bch_notify_msg(struct notify_opts *, const char *, const char *,
+		    const char *);
+void bch_notify(struct notify_opts *, const char *, const char *,
+		const char *, int);
+
+#endif /* _NOTIFY_H */
@@ -64,6 +64,10 @@ mkdir -p $RPM_BUILD_ROOT/usr/local/share/man/man8
 /usr/local/share/man/man8/bcachefs.8
 /etc/initramfs-tools/hooks/bcachefs
 /etc/initramfs-tools/scripts/local-premount/bcachefs
+/usr/lib/dracut/modules.d/90bcachefs/module-setup.sh
+/usr/lib/dracut/modules.d/90bcachefs/bcachefs-unlock.sh
+/etc/initcpio/install/bcachefs
+/etc/initcpio/hooks/bcachefs
 
 %changelog
 * Tue Jan 21 2020 Michael Adams <unquietwiki@gmail.com> - 2020.01.21-1
@@ -9,6 +9,8 @@
 #define QCOW_MAGIC		(('Q' << 24) | ('F' << 16) | ('I' << 8) | 0xfb)
 #define QCOW_VERSION		2
 #define QCOW_OFLAG_COPIED	(1LL << 63)
+#define QCOW_OFLAG_COMPRESSED	(1LL << 62)
+#define QCOW_OFFSET_MASK	0x00fffffffffffe00ULL
 
 struct qcow2_hdr {
 	u32			magic;
@@ -69,7 +71,8 @@ static void add_l2(struct qcow2_image *img, u64 src_blk, u64 dst_offset)
 }
 
 void qcow2_write_image(int infd, int outfd, ranges *data,
-		       unsigned block_size)
+		       unsigned block_size, struct io_rate_limit *limit,
+		       struct progress_fd *progress)
 {
 	u64 image_size = get_size(NULL, infd);
 	unsigned l2_size = block_size / sizeof(u64);
@@ -85,13 +88,16 @@ void qcow2_write_image(int infd, int outfd, ranges *data,
 	};
 	struct range *r;
 	char *buf = xmalloc(block_size);
-	u64 src_offset, dst_offset;
+	u64 src_offset, dst_offset, done = 0, total = 0;
 
 	assert(is_power_of_2(block_size));
 
 	ranges_roundup(data, block_size);
 	ranges_sort_merge(data);
 
+	darray_foreach(r, *data)
+		total += r->end - r->start;
+
 	/* Write data: */
 	darray_foreach(r, *data)
 		for (src_offset = r->start;
@@ -101,9 +107,13 @@ void qcow2_write_image(int infd, int outfd, ranges *data,
 			img.offset += img.block_size;
 
 			xpread(infd, buf, block_size, src_offset);
+			io_rate_limit(limit, block_size);
 			xpwrite(outfd, buf, block_size, dst_offset);
 
 			add_l2(&img, src_offset / block_size, dst_offset);
+
+			done += block_size;
+			progress_update(progress, done, total);
 		}
 
 	flush_l2(&img);
@@ -129,3 +139,68 @@ void qcow2_write_image(int infd, int outfd, ranges *data,
 	free(img.l1_table);
 	free(buf);
 }
+
+/*
+ * Converts an image back to a raw (sparse) image, for opening with
+ * bch2_fs_open(). Only the subset of the format we write is supported:
+ */
+void qcow2_read_image(int infd, int outfd)
+{
+	struct qcow2_hdr hdr;
+	unsigned i, j;
+
+	xpread(infd, &hdr, sizeof(hdr), 0);
+
+	if (be32_to_cpu(hdr.magic) != QCOW_MAGIC)
+		die("not a qcow2 image");
+	if (be32_to_cpu(hdr.version) < QCOW_VERSION)
+		die("unsupported qcow2 version %u", be32_to_cpu(hdr.version));
+	if (hdr.crypt_method)
+		die("encrypted qcow2 images not supported");
+	if (hdr.backing_file_offset)
+		die("qcow2 images with backing files not supported");
+
+	unsigned block_size	= 1U << be32_to_cpu(hdr.block_bits);
+	unsigned l2_size	= block_size / sizeof(u64);
+	unsigned l1_size	= be32_to_cpu(hdr.l1_size);
+	u64 image_size		= be64_to_cpu(hdr.size);
+	u64 *l1_table		= xcalloc(l1_size, sizeof(u64));
+	u64 *l2_table		= xmalloc(block_size);
+	char *buf		= xmalloc(block_size);
+
+	if (ftruncate(outfd, image_size))
+		die("ftruncate error: %m");
+
+	xpread(infd, l1_table, l1_size * sizeof(u64),
+	       be64_to_cpu(hdr.l1_table_offset));
+
+	for (i = 0; i < l1_size; i++) {
+		u64 l2_offset = be64_to_cpu(l1_table[i]) & QCOW_OFFSET_MASK;
+
+		if (!l2_offset)
+			continue;
+
+		xpread(infd, l2_table, block_size, l2_offset);
+
+		for (j = 0; j < l2_size; j++) {
+			u64 entry	= be64_to_cpu(l2_table[j]);
+			u64 src_offset	= entry & QCOW_OFFSET_MASK;
+			u64 dst_offset	= ((u64) i * l2_size + j) * block_size;
+
+			if (entry & QCOW_OFLAG_COMPRESSED)
+				die("compressed qcow2 images not supported");
+
+			if (!src_offset || dst_offset >= image_size)
+				continue;
+
+			xpread(infd, buf, block_size, src_offset);
+			xpwrite(outfd, buf,
+				min_t(u64, block_size, image_size - dst_offset),
+				dst_offset);
+		}
+	}
+
+	free(buf);
+	free(l2_table);
+	free(l1_table);
+}
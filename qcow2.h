@@ -4,6 +4,8 @@
 #include <linux/types.h>
 #include "tools-util.h"
 
-void qcow2_write_image(int, int, ranges *, unsigned);
+void qcow2_write_image(int, int, ranges *, unsigned,
+		       struct io_rate_limit *, struct progress_fd *);
+void qcow2_read_image(int, int);
 
 #endif /* _QCOW2_H */
@@ -0,0 +1,355 @@
+#include <errno.h>
+#include <grp.h>
+#include <pwd.h>
+#include <sched.h>
+#include <stddef.h>
+#include <string.h>
+#include <sys/ioctl.h>
+#include <sys/prctl.h>
+#include <sys/syscall.h>
+#include <time.h>
+#include <unistd.h>
+
+#include <linux/audit.h>
+#include <linux/bio.h>
+#include <linux/blkdev.h>
+#include <linux/filter.h>
+#include <linux/fs.h>
+#include <linux/keyctl.h>
+#include <linux/seccomp.h>
+#include <linux/shrinker.h>
+
+#include "sandbox.h"
+#include "tools-util.h"
+
+/*
+ * A seccomp filter for commands that parse untrusted filesystem images, once
+ * they've opened everything they need (see blkdev_preopen()): a bug in the
+ * parsing code can then only do what's needed to keep reading and printing
+ * metadata - read and write already open fds, allocate memory, run the
+ * threads libbcachefs starts, and read an already loaded key - and, in
+ * particular, can't open files, exec, start processes, or issue ioctls
+ * against the devices other than asking for their size. Anything else fails
+ * with EPERM.
+ */
+
+#if defined(__x86_64__) && !defined(__ILP32__)
+#define SANDBOX_ARCH	AUDIT_ARCH_X86_64
+#elif defined(__i386__)
+#define SANDBOX_ARCH	AUDIT_ARCH_I386
+#elif defined(__aarch64__)
+#define SANDBOX_ARCH	AUDIT_ARCH_AARCH64
+#elif defined(__arm__) && __BYTE_ORDER__ == __ORDER_LITTLE_ENDIAN__
+#define SANDBOX_ARCH	AUDIT_ARCH_ARM
+#elif defined(__powerpc64__) && __BYTE_ORDER__ == __ORDER_LITTLE_ENDIAN__
+#define SANDBOX_ARCH	AUDIT_ARCH_PPC64LE
+#elif defined(__powerpc64__)
+#define SANDBOX_ARCH	AUDIT_ARCH_PPC64
+#elif defined(__s390x__)
+#define SANDBOX_ARCH	AUDIT_ARCH_S390X
+#elif defined(__riscv) && __riscv_xlen == 64
+#define SANDBOX_ARCH	AUDIT_ARCH_RISCV64
+#endif
+
+#ifndef SECCOMP_RET_KILL_PROCESS
+#define SECCOMP_RET_KILL_PROCESS SECCOMP_RET_KILL
+#endif
+
+/* Syscall arguments are 64 bit; ioctl numbers fit in the low half: */
+#if __BYTE_ORDER__ == __ORDER_BIG_ENDIAN__
+#define ARG_LO(n)	(offsetof(struct seccomp_data, args[n]) + 4)
+#else
+#define ARG_LO(n)	offsetof(struct seccomp_data, args[n])
+#endif
+
+#define DENY		BPF_STMT(BPF_RET|BPF_K, SECCOMP_RET_ERRNO|EPERM)
+
+#define ALLOW(name)							\
+	BPF_JUMP(BPF_JMP|BPF_JEQ|BPF_K, __NR_##name, 0, 1),		\
+	BPF_STMT(BPF_RET|BPF_K, SECCOMP_RET_ALLOW)
+
+/* These load an argument, so the syscall number is loaded again first: */
+#define ALLOW_ARG(name, n, v)						\
+	BPF_STMT(BPF_LD|BPF_W|BPF_ABS, offsetof(struct seccomp_data, nr)),\
+	BPF_JUMP(BPF_JMP|BPF_JEQ|BPF_K, __NR_##name, 0, 3),		\
+	BPF_STMT(BPF_LD|BPF_W|BPF_ABS, ARG_LO(n)),			\
+	BPF_JUMP(BPF_JMP|BPF_JEQ|BPF_K, v, 0, 1),			\
+	BPF_STMT(BPF_RET|BPF_K, SECCOMP_RET_ALLOW)
+
+#define ALLOW_ARG_BITS(name, n, bits)					\
+	BPF_STMT(BPF_LD|BPF_W|BPF_ABS, offsetof(struct seccomp_data, nr)),\
+	BPF_JUMP(BPF_JMP|BPF_JEQ|BPF_K, __NR_##name, 0, 3),		\
+	BPF_STMT(BPF_LD|BPF_W|BPF_ABS, ARG_LO(n)),			\
+	BPF_JUMP(BPF_JMP|BPF_JSET|BPF_K, bits, 0, 1),			\
+	BPF_STMT(BPF_RET|BPF_K, SECCOMP_RET_ALLOW)
+
+/* clone()'s flags are its first argument, except on s390: */
+#ifdef __s390x__
+#define CLONE_FLAGS_ARG	1
+#else
+#define CLONE_FLAGS_ARG	0
+#endif
+
+#ifdef SANDBOX_ARCH
+static struct sock_filter sandbox_filter[] = {
+	/* Syscall numbers below are only meaningful for our own ABI: */
+	BPF_STMT(BPF_LD|BPF_W|BPF_ABS, offsetof(struct seccomp_data, arch)),
+	BPF_JUMP(BPF_JMP|BPF_JEQ|BPF_K, SANDBOX_ARCH, 1, 0),
+	BPF_STMT(BPF_RET|BPF_K, SECCOMP_RET_KILL_PROCESS),
+
+	BPF_STMT(BPF_LD|BPF_W|BPF_ABS, offsetof(struct seccomp_data, nr)),
+#ifdef __X32_SYSCALL_BIT
+	BPF_JUMP(BPF_JMP|BPF_JGE|BPF_K, __X32_SYSCALL_BIT, 0, 1),
+	BPF_STMT(BPF_RET|BPF_K, SECCOMP_RET_KILL_PROCESS),
+#endif
+
+	/* IO on fds we already have: */
+	ALLOW(read),
+	ALLOW(write),
+	ALLOW(readv),
+	ALLOW(writev),
+	ALLOW(pread64),
+	ALLOW(pwrite64),
+	ALLOW(preadv),
+	ALLOW(pwritev),
+#ifdef __NR_lseek
+	ALLOW(lseek),
+#endif
+#ifdef __NR__llseek
+	ALLOW(_llseek),
+#endif
+	ALLOW(close),
+	ALLOW(fsync),
+	ALLOW(fdatasync),
+#ifdef __NR_fstat
+	ALLOW(fstat),
+#endif
+#ifdef __NR_fstat64
+	ALLOW(fstat64),
+#endif
+#ifdef __NR_newfstatat
+	ALLOW(newfstatat),
+#endif
+#ifdef __NR_fstatat64
+	ALLOW(fstatat64),
+#endif
+#ifdef __NR_statx
+	ALLOW(statx),
+#endif
+	ALLOW(fcntl),
+#ifdef __NR_fcntl64
+	ALLOW(fcntl64),
+#endif
+	ALLOW(pipe2),
+#ifdef __NR_pipe
+	ALLOW(pipe),
+#endif
+
+	/* Block IO is done with aio: */
+	ALLOW(io_submit),
+	ALLOW(io_getevents),
+#ifdef __NR_io_pgetevents
+	ALLOW(io_pgetevents),
+#endif
+	ALLOW(io_cancel),
+	ALLOW(io_destroy),
+
+	/* Memory: */
+	ALLOW(brk),
+#ifdef __NR_mmap
+	ALLOW(mmap),
+#endif
+#ifdef __NR_mmap2
+	ALLOW(mmap2),
+#endif
+	ALLOW(munmap),
+	ALLOW(mremap),
+	ALLOW(mprotect),
+	ALLOW(madvise),
+
+	/*
+	 * Threads, locking, and time: clone3() hides its flags from seccomp,
+	 * so it fails with ENOSYS and libc falls back to clone(), which is
+	 * only allowed for threads (below):
+	 */
+#ifdef __NR_clone3
+	BPF_JUMP(BPF_JMP|BPF_JEQ|BPF_K, __NR_clone3, 0, 1),
+	BPF_STMT(BPF_RET|BPF_K, SECCOMP_RET_ERRNO|ENOSYS),
+#endif
+	ALLOW(set_robust_list),
+#ifdef __NR_rseq
+	ALLOW(rseq),
+#endif
+	ALLOW(futex),
+#ifdef __NR_futex_time64
+	ALLOW(futex_time64),
+#endif
+	ALLOW(membarrier),
+	ALLOW(sched_yield),
+	ALLOW(sched_getaffinity),
+	ALLOW(gettid),
+	ALLOW(getpid),
+	ALLOW(getrandom),
+	ALLOW(clock_gettime),
+#ifdef __NR_clock_gettime64
+	ALLOW(clock_gettime64),
+#endif
+	ALLOW(clock_getres),
+	ALLOW(clock_nanosleep),
+#ifdef __NR_clock_nanosleep_time64
+	ALLOW(clock_nanosleep_time64),
+#endif
+	ALLOW(nanosleep),
+	ALLOW(gettimeofday),
+
+	/* Signals, and exiting: */
+	ALLOW(rt_sigaction),
+	ALLOW(rt_sigprocmask),
+	ALLOW(rt_sigreturn),
+#ifdef __NR_sigreturn
+	ALLOW(sigreturn),
+#endif
+	ALLOW(sigaltstack),
+	ALLOW(restart_syscall),
+	ALLOW(exit),
+	ALLOW(exit_group),
+
+	/* Looking up an already loaded key: */
+	ALLOW(request_key),
+	ALLOW_ARG(keyctl,	0, KEYCTL_READ),
+
+	ALLOW_ARG_BITS(clone,	CLONE_FLAGS_ARG, CLONE_THREAD),
+
+	/* Naming threads: */
+	ALLOW_ARG(prctl,	0, PR_SET_NAME),
+	ALLOW_ARG(prctl,	0, PR_GET_NAME),
+
+	/* stdio checking whether stdout is a terminal, and device sizes: */
+	ALLOW_ARG(ioctl,	1, TCGETS),
+	ALLOW_ARG(ioctl,	1, BLKGETSIZE64),
+	ALLOW_ARG(ioctl,	1, BLKPBSZGET),
+
+	DENY,
+};
+#endif
+
+/*
+ * Called once every output file is open, and the devices have been opened
+ * with sandbox_open_devices(), before anything parses them: applies to every
+ * thread, and can't be undone.
+ */
+void sandbox_enter(void)
+{
+#ifdef SANDBOX_ARCH
+	struct sock_fprog prog = {
+		.len	= ARRAY_SIZE(sandbox_filter),
+		.filter	= sandbox_filter,
+	};
+
+	/* Timezone info is read on first use, which we won't be able to do: */
+	tzset();
+	/* Nor could the shrinkers open /proc/meminfo: */
+	shrinker_open_proc_files();
+
+	if (prctl(PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0))
+		die("error setting no_new_privs: %m");
+
+	if (syscall(__NR_seccomp, SECCOMP_SET_MODE_FILTER,
+		    SECCOMP_FILTER_FLAG_TSYNC, &prog))
+		die("error installing seccomp filter: %m");
+#else
+	die("--sandbox is not supported on this architecture");
+#endif
+}
+
+/*
+ * Open @devs read only, as bch2_read_super() does with nochanges, for the
+ * filesystem code to use once in the sandbox:
+ */
+void sandbox_open_devices(char * const *devs, unsigned nr)
+{
+	unsigned i;
+	int ret;
+
+	for (i = 0; i < nr; i++)
+		if ((ret = blkdev_preopen(devs[i], FMODE_READ)))
+			die("Error opening %s: %s", devs[i], strerror(-ret));
+}
+
+/*
+ * Loading the key of an encrypted filesystem means reading its superblock,
+ * so it can't be done for commands that sandbox themselves first; the key is
+ * found in the keyring from inside the sandbox instead:
+ */
+void sandbox_check_key(const char *passphrase_file)
+{
+	if (passphrase_file)
+		die("--sandbox can't be used with a passphrase file: unlock the filesystem with bcachefs unlock first");
+}
+
+/*
+ * For daemons, once they've opened everything they need as root: run as @user
+ * (by default with its primary group) and/or @group, without supplementary
+ * groups, keeping only the capabilities in @caps. The capability bounding set
+ * is pruned too, so that hooks they run can't regain the rest.
+ */
+void drop_privs(const char *user, const char *group, u64 caps)
+{
+	struct __user_cap_header_struct hdr = {
+		.version	= _LINUX_CAPABILITY_VERSION_3,
+	};
+	struct __user_cap_data_struct data[_LINUX_CAPABILITY_U32S_3] = {
+		{ .effective = caps, .permitted = caps },
+		{ .effective = caps >> 32, .permitted = caps >> 32 },
+	};
+	uid_t uid = getuid();
+	gid_t gid = getgid();
+	unsigned i, v;
+
+	if (!user && !group)
+		return;
+
+	if (user) {
+		struct passwd *pw = getpwnam(user);
+
+		if (pw) {
+			uid = pw->pw_uid;
+			gid = pw->pw_gid;
+		} else if (!kstrtouint(user, 10, &v)) {
+			uid = v;
+		} else {
+			die("unknown user %s", user);
+		}
+	}
+
+	if (group) {
+		struct group *gr = getgrnam(group);
+
+		if (gr)
+			gid = gr->gr_gid;
+		else if (!kstrtouint(group, 10, &v))
+			gid = v;
+		else
+			die("unknown group %s", group);
+	}
+
+	/* Capabilities the kernel doesn't know about fail with EINVAL: */
+	for (i = 0; prctl(PR_CAPBSET_READ, i, 0, 0, 0) >= 0; i++)
+		if ((i >= 64 || !(caps & (1ULL << i))) &&
+		    prctl(PR_CAPBSET_DROP, i, 0, 0, 0))
+			die("error dropping capabilities: %m");
+
+	if (prctl(PR_SET_KEEPCAPS, 1, 0, 0, 0))
+		die("error keeping capabilities: %m");
+
+	if (setgroups(1, &gid) || setgid(gid))
+		die("error changing to group %u: %m", gid);
+
+	if (setuid(uid))
+		die("error changing to user %u: %m", uid);
+
+	if (syscall(SYS_capset, &hdr, data))
+		die("error dropping capabilities: %m");
+
+	prctl(PR_SET_KEEPCAPS, 0, 0, 0, 0);
+}
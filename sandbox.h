@@ -0,0 +1,12 @@
+#ifndef _SANDBOX_H
+#define _SANDBOX_H
+
+#include <linux/capability.h>
+#include <linux/types.h>
+
+void sandbox_check_key(const char *);
+void sandbox_open_devices(char * const *, unsigned);
+void sandbox_enter(void);
+void drop_privs(const char *, const char *, u64);
+
+#endif /* _SANDBOX_H */
@@ -0,0 +1,159 @@
+#include <fcntl.h>
+#include <stdarg.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <sys/wait.h>
+#include <unistd.h>
+
+#include "testdev.h"
+#include "tools-util.h"
+
+const char * const testdev_modes[] = {
+	"linear",
+	"error",
+	"flakey",
+	"drop-writes",
+	"dust",
+	NULL
+};
+
+static char *testdev_name(const char *name)
+{
+	return mprintf(TESTDEV_PREFIX "%s", name);
+}
+
+char *testdev_path(const char *name)
+{
+	return mprintf("/dev/mapper/" TESTDEV_PREFIX "%s", name);
+}
+
+static void dmsetup(char *arg, ...)
+{
+	char *argv[8] = { "dmsetup", arg };
+	unsigned i = 1;
+	va_list args;
+	int status;
+
+	va_start(args, arg);
+	while (argv[i] && i + 1 < ARRAY_SIZE(argv))
+		argv[++i] = va_arg(args, char *);
+	va_end(args);
+
+	fflush(stdout);
+
+	pid_t pid = fork();
+	if (pid < 0)
+		die("fork error: %m");
+
+	if (!pid) {
+		execvp(argv[0], argv);
+		die("error executing dmsetup: %m");
+	}
+
+	if (waitpid(pid, &status, 0) < 0)
+		die("waitpid error: %m");
+
+	if (!WIFEXITED(status) || WEXITSTATUS(status))
+		die("dmsetup %s %s failed", arg, argv[2] ?: "");
+}
+
+/* Every table starts "0 <sectors> <target> <major:minor>": */
+struct testdev_table {
+	u64		sectors;
+	char		dev[32];
+};
+
+static struct testdev_table testdev_table_read(const char *dm_name)
+{
+	struct testdev_table t;
+	char *cmd = mprintf("dmsetup table %s", dm_name);
+	FILE *f = popen(cmd, "r");
+
+	if (!f)
+		die("error running %s: %m", cmd);
+
+	if (fscanf(f, "0 %llu %*s %31s", &t.sectors, t.dev) != 2)
+		die("%s: not a test device", dm_name);
+
+	if (pclose(f))
+		die("%s failed", cmd);
+	free(cmd);
+	return t;
+}
+
+/* Returns the path of the new device: */
+char *testdev_create(const char *dev, const char *name)
+{
+	char *dm_name = testdev_name(name);
+	int fd = xopen(dev, O_RDONLY);
+	char *table = mprintf("0 %llu linear %s 0", get_size(dev, fd) >> 9, dev);
+
+	close(fd);
+
+	dmsetup("create", dm_name, "--table", table, NULL);
+
+	free(table);
+	free(dm_name);
+	return testdev_path(name);
+}
+
+void testdev_set(const char *name, enum testdev_mode mode,
+		 struct testdev_opts *opts)
+{
+	char *dm_name = testdev_name(name);
+	struct testdev_table t = testdev_table_read(dm_name);
+	char *table;
+	unsigned i;
+
+	switch (mode) {
+	case TESTDEV_LINEAR:
+		table = mprintf("0 %llu linear %s 0", t.sectors, t.dev);
+		break;
+	case TESTDEV_ERROR:
+		/* flakey, always down - error targets have no device: */
+		table = mprintf("0 %llu flakey %s 0 0 1", t.sectors, t.dev);
+		break;
+	case TESTDEV_FLAKEY:
+		table = mprintf("0 %llu flakey %s 0 %u %u",
+				t.sectors, t.dev, opts->up, opts->down);
+		break;
+	case TESTDEV_DROP_WRITES:
+		table = mprintf("0 %llu flakey %s 0 %u %u 1 drop_writes",
+				t.sectors, t.dev, opts->up, opts->down);
+		break;
+	case TESTDEV_DUST:
+		table = mprintf("0 %llu dust %s 0 %u",
+				t.sectors, t.dev, opts->block_size);
+		break;
+	default:
+		BUG();
+	}
+
+	/* Switched while suspended, so no IO sees a half set up device: */
+	dmsetup("suspend", dm_name, NULL);
+	dmsetup("load", dm_name, "--table", table, NULL);
+	dmsetup("resume", dm_name, NULL);
+
+	if (mode == TESTDEV_DUST) {
+		for (i = 0; i < opts->nr_bad_blocks; i++) {
+			char *msg = mprintf("addbadblock %llu", opts->bad_blocks[i]);
+
+			dmsetup("message", dm_name, "0", msg, NULL);
+			free(msg);
+		}
+
+		dmsetup("message", dm_name, "0", "enable", NULL);
+	}
+
+	free(table);
+	free(dm_name);
+}
+
+void testdev_remove(const char *name)
+{
+	char *dm_name = testdev_name(name);
+
+	dmsetup("remove", dm_name, NULL);
+	free(dm_name);
+}
@@ -0,0 +1,40 @@
+#ifndef _TESTDEV_H
+#define _TESTDEV_H
+
+#include <linux/types.h>
+
+/*
+ * Device-mapper targets stacked on member devices, for failure testing: set
+ * up, switched and torn down with dmsetup.
+ */
+
+#define TESTDEV_PREFIX	"bcachefs-test-"
+
+enum testdev_mode {
+	TESTDEV_LINEAR,		/* pass everything through */
+	TESTDEV_ERROR,		/* fail all IO */
+	TESTDEV_FLAKEY,		/* fail all IO while down */
+	TESTDEV_DROP_WRITES,	/* silently drop writes while down */
+	TESTDEV_DUST,		/* fail reads of bad blocks */
+	TESTDEV_NR,
+};
+
+extern const char * const testdev_modes[];
+
+struct testdev_opts {
+	/* flakey, drop-writes: seconds up, then seconds down, repeating */
+	unsigned	up;
+	unsigned	down;
+
+	/* dust: */
+	unsigned	block_size;	/* bytes */
+	u64		*bad_blocks;
+	unsigned	nr_bad_blocks;
+};
+
+char *testdev_path(const char *);
+char *testdev_create(const char *, const char *);
+void testdev_set(const char *, enum testdev_mode, struct testdev_opts *);
+void testdev_remove(const char *);
+
+#endif /* _TESTDEV_H */
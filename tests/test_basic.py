@@ -2,7 +2,9 @@
 #
 # Basic bcachefs functionality tests.
 
+import json
 import re
+import tarfile
 import util
 
 def test_help():
@@ -62,3 +64,118 @@ def test_list_dirent(tmpdir):
     # snap 0 len 0 ver 0: lost+found -> 4097
     last = ret.stdout.splitlines()[-1]
     assert re.match(r'^.*type dirent.*: lost\+found ->.*$', last)
+
+def test_list_reflink(tmpdir):
+    dev = util.format_1g(tmpdir)
+
+    ret = util.run_bch('list', '-m', 'reflink', dev, valgrind=True)
+
+    assert ret.returncode == 0
+    assert len(ret.stderr) == 0
+    assert "references found" not in ret.stdout # no reflinked extents
+
+def test_bug_report_metadata(tmpdir):
+    dev = util.format_1g(tmpdir)
+    out = tmpdir / 'report.tar.gz'
+
+    ret = util.run_bch('bug-report', '--metadata', '-o', out, dev)
+
+    assert ret.returncode == 0
+    assert "Bug report written to" in ret.stdout
+    assert "error collecting" not in ret.stderr
+
+    with tarfile.open(out) as tar:
+        assert tar.getmember('./metadata.qcow2').size > 0
+
+def test_bug_report_redact_filenames(tmpdir):
+    dev = util.format_1g(tmpdir)
+    out = tmpdir / 'report.tar.gz'
+
+    # Implies --metadata:
+    ret = util.run_bch('bug-report', '--redact-filenames', '-o', out, dev)
+
+    assert ret.returncode == 0
+    assert "error collecting" not in ret.stderr
+
+    with tarfile.open(out) as tar:
+        names = tar.getnames()
+        assert './metadata.txt' in names
+        assert './metadata.qcow2' not in names
+
+        listing = tar.extractfile('./metadata.txt').read().decode()
+        assert 'redacted:' in listing
+        assert 'lost+found' not in listing
+
+def test_shell_invalid_bpos(tmpdir):
+    dev = util.format_1g(tmpdir)
+
+    ret = util.run_bch('shell', dev, valgrind=True,
+                       input='btree list inodes 1:x\nsb show\nquit\n')
+
+    assert ret.returncode == 0
+    assert "invalid bpos 1:x" in ret.stderr
+    assert "External UUID" in ret.stdout
+
+def test_format_binary_keyfile(tmpdir):
+    dev = util.device_1g(tmpdir)
+    keyfile = tmpdir / 'keyfile'
+    keyfile.write_binary(b'\0\1\2\3' * 8)
+    keyfile.chmod(0o400)
+
+    ret = util.run_bch('format', '--encrypted', '--keyfile', keyfile, dev,
+                       valgrind=True)
+
+    assert ret.returncode == 0
+    assert len(ret.stderr) == 0
+
+def test_apply_journal_clean(tmpdir):
+    dev = util.format_1g(tmpdir)
+
+    ret = util.run_bch('apply-journal', dev, valgrind=True)
+
+    assert ret.returncode == 0
+    assert "filesystem is clean, nothing to replay" in ret.stdout
+
+def test_dump_progress_fd(tmpdir):
+    dev = util.format_1g(tmpdir)
+    out = tmpdir / 'metadata.qcow2'
+
+    ret = util.run_bch('dump', '--progress-fd=1', '-o', out, dev)
+
+    assert ret.returncode == 0
+    events = [json.loads(l) for l in ret.stdout.splitlines()
+              if l.startswith('{')]
+    assert events[0] == {'event': 'device', 'dev': 0}
+    assert events[-1] == {'event': 'done'}
+
+    last = events[-2]
+    assert last['event'] == 'progress'
+    assert last['bytes_done'] == last['bytes_total'] > 0
+
+def test_verify_super(tmpdir):
+    dev = util.format_1g(tmpdir)
+
+    ret = util.run_bch('verify-super', dev, valgrind=True)
+
+    assert ret.returncode == 0
+    assert len(ret.stderr) == 0
+
+def test_check_free_space(tmpdir):
+    dev = util.format_1g(tmpdir)
+
+    ret = util.run_bch('check-free-space', dev, valgrind=True)
+
+    assert ret.returncode == 0
+    assert len(ret.stderr) == 0
+
+def test_explain():
+    ret = util.run_bch('explain', '--json', 'EROFS', valgrind=True)
+
+    assert ret.returncode == 0
+    e = json.loads(ret.stdout)
+    assert e['name'] == 'EROFS'
+    assert e['kind'] == 'errno'
+
+    ret = util.run_bch('explain', 'ENOTANERROR')
+    assert ret.returncode == 1
+    assert "No explanation for ENOTANERROR" in ret.stderr
@@ -29,6 +29,10 @@ def test_segfault():
     ret = util.run(helper, 'segfault')
     assert ret.returncode == -signal.SIGSEGV
 
+def test_rate_limit_delay():
+    ret = util.run(helper, 'rate_limit_delay')
+    assert ret.returncode == 0
+
 @pytest.mark.skipif(not util.ENABLE_VALGRIND, reason="no valgrind")
 def test_check():
     with pytest.raises(subprocess.CalledProcessError):
@@ -0,0 +1,25 @@
+#!/usr/bin/python3
+#
+# Tests of freezing a filesystem mounted with the kernel's bcachefs.
+
+import pytest
+import util
+
+pytestmark = pytest.mark.skipif(
+    not util.have_kernel_bcachefs(), reason="needs root and bcachefs in the kernel.")
+
+def test_freeze_thaw(tmpdir):
+    dev = util.format_1g(tmpdir)
+    mnt = util.mountpoint(tmpdir)
+
+    with util.KernelMount(dev, mnt):
+        (mnt / 'file').write_text('data')
+
+        ret = util.run_bch('fs', 'freeze', mnt)
+        assert ret.returncode == 0, ret.stderr
+        assert "journal seq" in ret.stdout
+
+        ret = util.run_bch('fs', 'thaw', mnt)
+        assert ret.returncode == 0, ret.stderr
+
+        (mnt / 'file').write_text('more data')
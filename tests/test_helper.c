@@ -6,6 +6,8 @@
 #include <stdlib.h>
 #include <string.h>
 
+#include "tools-util.h"
+
 void trick_compiler(int *x);
 
 static void test_abort(void)
@@ -57,6 +59,36 @@ static void test_write_after_free(void)
 	printf("%d\n", *p);
 }
 
+static void test_rate_limit_delay(void)
+{
+	u64 next = 0;
+
+	/* No limit: */
+	assert(!rate_limit_delay(&next, 0, 1000, 10 * NSEC_PER_SEC));
+	assert(!next);
+
+	/* Up to a second of unused allowance is saved up: */
+	assert(!rate_limit_delay(&next, 1000, 500, 10 * NSEC_PER_SEC));
+	assert(next == 9 * NSEC_PER_SEC + NSEC_PER_SEC / 2);
+
+	assert(rate_limit_delay(&next, 1000, 1000, 10 * NSEC_PER_SEC) ==
+	       NSEC_PER_SEC / 2);
+
+	/* But no more than a second, however long we've been idle: */
+	assert(rate_limit_delay(&next, 1000, 2000, 100 * NSEC_PER_SEC) ==
+	       NSEC_PER_SEC);
+
+	/* Less than a second after the clock started: */
+	next = 0;
+	assert(rate_limit_delay(&next, 1000, 1000, NSEC_PER_SEC / 2) ==
+	       NSEC_PER_SEC / 2);
+
+	/* IOs rather than bytes: */
+	next = 0;
+	assert(!rate_limit_delay(&next, 10, 1, 10 * NSEC_PER_SEC));
+	assert(next == 9 * NSEC_PER_SEC + NSEC_PER_SEC / 10);
+}
+
 typedef void (*test_fun)(void);
 
 struct test {
@@ -73,6 +105,7 @@ static struct test tests[] = {
 	TEST(undefined_branch),
 	TEST(read_after_free),
 	TEST(write_after_free),
+	TEST(rate_limit_delay),
 };
 #define ntests (sizeof tests / sizeof *tests)
 
@@ -0,0 +1,62 @@
+#!/usr/bin/python3
+#
+# Tests of create-test-image, corrupt and compare-images: the tools for
+# testing fsck.
+
+import shutil
+import util
+
+def create_test_image(tmpdir, *args):
+    img = tmpdir / 'test.img'
+
+    ret = util.run_bch('create-test-image', *args, img, valgrind=True)
+    assert ret.returncode == 0
+
+    return img
+
+def test_create_test_image_fsck(tmpdir):
+    img = create_test_image(tmpdir)
+
+    ret = util.run_bch('fsck', '-n', '-f', img, valgrind=True)
+    assert ret.returncode == 0
+    assert "errors" not in ret.stderr
+
+def test_create_test_image_fragmented(tmpdir):
+    img = create_test_image(tmpdir, '--fragment', '--files=20')
+
+    ret = util.run_bch('fsck', '-n', '-f', img)
+    assert ret.returncode == 0
+
+    ret = util.run_bch('list', '-b', 'dirents', img)
+    assert ret.returncode == 0
+    assert ret.stdout.count('file.') == 20
+
+def test_corrupt_btree_node_fsck(tmpdir):
+    img = create_test_image(tmpdir)
+
+    ret = util.run_bch('corrupt', '-t', 'btree-node', '-b', 'extents', img,
+                       valgrind=True)
+    assert ret.returncode == 0
+    assert "flipped bit" in ret.stdout
+
+    ret = util.run_bch('fsck', '-n', '-f', img)
+    assert ret.returncode != 0
+
+def test_compare_images_identical(tmpdir):
+    img = create_test_image(tmpdir)
+    copy = tmpdir / 'copy.img'
+    shutil.copy(img, copy)
+
+    ret = util.run_bch('compare-images', img, copy, valgrind=True)
+    assert ret.returncode == 0
+    assert "No differences" in ret.stdout
+
+def test_compare_images_summary(tmpdir):
+    old = tmpdir / 'old.img'
+    new = tmpdir / 'new.img'
+    assert util.run_bch('create-test-image', '--files=10', old).returncode == 0
+    assert util.run_bch('create-test-image', '--files=20', new).returncode == 0
+
+    ret = util.run_bch('compare-images', '--summary', old, new)
+    assert ret.returncode == 1
+    assert "added" in ret.stdout
@@ -0,0 +1,24 @@
+#!/usr/bin/python3
+#
+# Tests of failure injection with "bcachefs testdev".
+
+import pytest
+import util
+
+pytestmark = pytest.mark.skipif(
+    not util.have_testdev(), reason="needs root, dmsetup and dm-flakey.")
+
+def test_testdev_error(tmpdir):
+    image = util.device_1g(tmpdir)
+
+    with util.LoopDev(image) as loop, \
+         util.TestDev(loop.path, 'pytest') as testdev:
+        util.run_bch('format', testdev.path, check=True)
+
+        testdev.set('error')
+        ret = util.run_bch('show-super', testdev.path)
+        assert ret.returncode != 0
+
+        testdev.set('linear')
+        ret = util.run_bch('show-super', testdev.path)
+        assert ret.returncode == 0
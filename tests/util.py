@@ -3,6 +3,7 @@
 import os
 import pytest
 import re
+import shutil
 import subprocess
 import sys
 import tempfile
@@ -35,12 +36,12 @@ def check_valgrind(log):
     if errors > 0:
         raise ValgrindFailedError(log)
 
-def run(cmd, *args, valgrind=False, check=False):
+def run(cmd, *args, valgrind=False, check=False, input=None):
     """Run an external program via subprocess, optionally with valgrind.
 
-    This subprocess wrapper will capture the stdout and stderr. If valgrind is
-    requested, it will be checked for errors and raise a
-    ValgrindFailedError if there's a problem.
+    This subprocess wrapper will capture the stdout and stderr, and feed it
+    input on stdin if given. If valgrind is requested, it will be checked for
+    errors and raise a ValgrindFailedError if there's a problem.
     """
     cmds = [cmd] + list(args)
     valgrind = valgrind and ENABLE_VALGRIND
@@ -54,7 +55,7 @@ def run(cmd, *args, valgrind=False, check=False):
 
     print("Running '{}'".format(cmds))
     res = subprocess.run(cmds, stdout=subprocess.PIPE, stderr=subprocess.PIPE,
-                         encoding='utf-8', check=check)
+                         encoding='utf-8', check=check, input=input)
 
     if valgrind:
         check_valgrind(vout.read().decode('utf-8'))
@@ -127,6 +128,80 @@ class Timestamp:
         '''True iff the test time is within the range.'''
         return self.start <= test <= self.end
 
+class LoopDev:
+    '''Context manager for a loop device on top of an image file.'''
+    def __init__(self, image):
+        self.image = image
+        self.path = None
+
+    def __enter__(self):
+        ret = run('losetup', '--find', '--show', self.image, check=True)
+        self.path = Path(ret.stdout.strip())
+        return self
+
+    def __exit__(self, type, value, traceback):
+        run('losetup', '--detach', self.path, check=True)
+
+class TestDev:
+    '''Context manager for a device-mapper test device on top of a block
+    device, set up and torn down with "bcachefs testdev".
+
+    Use path in place of the underlying device, and set() to inject failures:
+    e.g. set('drop-writes', '-u', '0') to simulate a crash.
+
+    Needs root, and the dm-flakey and dm-dust kernel modules.
+    '''
+    def __init__(self, dev, name):
+        self.dev = dev
+        self.name = name
+        self.path = None
+
+    def __enter__(self):
+        ret = run_bch('testdev', 'create', self.dev, self.name, check=True)
+        self.path = Path(ret.stdout.strip())
+        return self
+
+    def __exit__(self, type, value, traceback):
+        run_bch('testdev', 'remove', self.name, check=True)
+
+    def set(self, mode, *args):
+        run_bch('testdev', 'set', *args, self.name, mode, check=True)
+
+def have_testdev():
+    if os.geteuid() != 0:
+        return False
+    if not shutil.which('dmsetup') or not shutil.which('losetup'):
+        return False
+    res = run('dmsetup', 'targets')
+    return res.returncode == 0 and 'flakey' in res.stdout
+
+def have_kernel_bcachefs():
+    if os.geteuid() != 0 or not shutil.which('losetup'):
+        return False
+    with open('/proc/filesystems') as f:
+        return any(l.split()[-1] == 'bcachefs' for l in f if l.strip())
+
+class KernelMount:
+    '''Context manager mounting an image with the kernel's bcachefs, through
+    a loop device.'''
+    def __init__(self, image, mnt):
+        self.loop = LoopDev(image)
+        self.mnt = mnt
+
+    def __enter__(self):
+        self.loop.__enter__()
+        try:
+            run('mount', '-t', 'bcachefs', self.loop.path, self.mnt,
+                check=True)
+        except:
+            self.loop.__exit__(None, None, None)
+            raise
+        return self
+
+    def __exit__(self, type, value, traceback):
+        run('umount', self.mnt, check=True)
+        self.loop.__exit__(type, value, traceback)
+
 class FuseError(Exception):
     def __init__(self, msg):
         self.msg = msg
@@ -3,6 +3,7 @@
 #include <errno.h>
 #include <fcntl.h>
 #include <limits.h>
+#include <linux/blkzoned.h>
 #include <linux/fs.h>
 #include <math.h>
 #include <stdbool.h>
@@ -12,6 +13,7 @@
 #include <sys/stat.h>
 #include <sys/sysmacros.h>
 #include <sys/types.h>
+#include <time.h>
 #include <unistd.h>
 
 #include <blkid.h>
@@ -207,6 +209,16 @@ char *read_file_str(int dirfd, const char *path)
 	return buf;
 }
 
+void write_file_str(int dirfd, const char *path, const char *str)
+{
+	int fd = xopenat(dirfd, path, O_WRONLY);
+
+	if (write(fd, str, strlen(str)) < 0)
+		die("error writing %s to %s: %m", str, path);
+
+	close(fd);
+}
+
 u64 read_file_u64(int dirfd, const char *path)
 {
 	char *buf = read_file_str(dirfd, path);
@@ -242,6 +254,109 @@ u64 get_size(const char *path, int fd)
 	return ret;
 }
 
+/*
+ * Reads how the request queue of a block device behaves from sysfs; partitions
+ * share the queue of the whole disk. Everything is zero for files:
+ */
+struct dev_queue_info dev_queue_info(int fd)
+{
+	struct stat statbuf = xfstat(fd);
+	struct dev_queue_info ret = { 0 };
+
+	if (!S_ISBLK(statbuf.st_mode))
+		return ret;
+
+	char *sysfs = mprintf("/sys/dev/block/%u:%u/queue",
+			      major(statbuf.st_rdev), minor(statbuf.st_rdev));
+	int dirfd = open(sysfs, O_RDONLY);
+	free(sysfs);
+
+	if (dirfd < 0) {
+		sysfs = mprintf("/sys/dev/block/%u:%u",
+				major(statbuf.st_rdev), minor(statbuf.st_rdev));
+		dirfd = open(sysfs, O_RDONLY);
+		free(sysfs);
+
+		if (dirfd >= 0 && !faccessat(dirfd, "start", R_OK, 0))
+			ret.start = read_file_u64(dirfd, "start");
+
+		if (dirfd >= 0) {
+			int part_fd = dirfd;
+
+			dirfd = openat(part_fd, "../queue", O_RDONLY);
+			close(part_fd);
+		}
+	}
+
+	if (dirfd < 0)
+		return ret;
+
+	ret.rotational		= read_file_u64(dirfd, "rotational");
+	ret.discard_granularity	= read_file_u64(dirfd, "discard_granularity") >> 9;
+
+	if (!faccessat(dirfd, "zoned", R_OK, 0)) {
+		char *zoned = read_file_str(dirfd, "zoned");
+
+		ret.zoned = zoned && strcmp(zoned, "none");
+		ret.host_managed = zoned && !strcmp(zoned, "host-managed");
+		free(zoned);
+	}
+
+	/* For zoned devices, chunk_sectors is the zone size: */
+	if (ret.zoned)
+		ret.zone_sectors = read_file_u64(dirfd, "chunk_sectors");
+
+	close(dirfd);
+	return ret;
+}
+
+/* Counts the zones of a zoned block device by state: */
+struct dev_zones dev_zones_read(int fd)
+{
+	unsigned nr = 256;
+	struct blk_zone_report *report =
+		xmalloc(sizeof(*report) + nr * sizeof(struct blk_zone));
+	struct dev_zones ret = { 0 };
+	u64 sector = 0;
+	unsigned i;
+
+	while (1) {
+		report->sector		= sector;
+		report->nr_zones	= nr;
+
+		if (ioctl(fd, BLKREPORTZONE, report))
+			die("error reporting zones: %m");
+
+		if (!report->nr_zones)
+			break;
+
+		for (i = 0; i < report->nr_zones; i++) {
+			struct blk_zone *z = report->zones + i;
+
+			switch (z->cond) {
+			case BLK_ZONE_COND_EMPTY:
+				ret.empty++;
+				break;
+			case BLK_ZONE_COND_IMP_OPEN:
+			case BLK_ZONE_COND_EXP_OPEN:
+			case BLK_ZONE_COND_CLOSED:
+				ret.open++;
+				break;
+			case BLK_ZONE_COND_FULL:
+				ret.full++;
+				break;
+			default:
+				ret.other++;
+			}
+			ret.nr++;
+			sector = z->start + z->len;
+		}
+	}
+
+	free(report);
+	return ret;
+}
+
 /* Returns blocksize in units of 512 byte sectors: */
 unsigned get_blocksize(const char *path, int fd)
 {
@@ -295,6 +410,8 @@ int open_for_format(const char *dev, bool force)
 	return fd;
 }
 
+bool (*ask_yn_answer)(const char *);
+
 bool ask_yn(void)
 {
 	const char *short_yes = "yY";
@@ -305,6 +422,12 @@ bool ask_yn(void)
 	fputs(" (y,n) ", stdout);
 	fflush(stdout);
 
+	if (ask_yn_answer) {
+		ret = ask_yn_answer(printk_last);
+		puts(ret ? "y" : "n");
+		return ret;
+	}
+
 	if (getline(&buf, &buflen, stdin) < 0)
 		die("error reading from standard input");
 
@@ -417,6 +540,53 @@ unsigned hatoi_validate(const char *s, const char *msg)
 	return v;
 }
 
+/* IO rate limiting */
+
+static u64 now_ns(void)
+{
+	struct timespec ts;
+
+	clock_gettime(CLOCK_MONOTONIC, &ts);
+	return ts.tv_sec * NSEC_PER_SEC + ts.tv_nsec;
+}
+
+/* Call after each IO of @bytes, to sleep for as long as needed: */
+void io_rate_limit(struct io_rate_limit *l, u64 bytes)
+{
+	u64 now = now_ns();
+	u64 delay = max(rate_limit_delay(&l->next_bytes, l->bytes_per_sec, bytes, now),
+			rate_limit_delay(&l->next_ios, l->ios_per_sec, 1, now));
+
+	if (delay) {
+		struct timespec ts = {
+			.tv_sec		= delay / NSEC_PER_SEC,
+			.tv_nsec	= delay % NSEC_PER_SEC,
+		};
+
+		nanosleep(&ts, NULL);
+	}
+}
+
+/* Progress for --progress-fd */
+
+void progress_update(struct progress_fd *p, u64 done, u64 total)
+{
+	u64 now = now_ns();
+
+	if (p->fd < 0 || (now < p->next && done < total))
+		return;
+
+	p->next = now + NSEC_PER_SEC;
+	dprintf(p->fd, "{\"event\":\"progress\",\"bytes_done\":%llu,\"bytes_total\":%llu}\n",
+		min(done, total), total);
+}
+
+void progress_done(struct progress_fd *p)
+{
+	if (p->fd >= 0)
+		dprintf(p->fd, "{\"event\":\"done\"}\n");
+}
+
 /* crc32c */
 
 static u32 crc32c_default(u32 crc, const void *buf, size_t size)
@@ -664,21 +834,138 @@ int dev_mounted(char *dev)
 	return 2;
 }
 
-struct bpos bpos_parse(char *buf)
+/*
+ * Parses inode[:offset[:snapshot]], or min or max; fields left out are 0, so
+ * that a position given as just an inode is where its keys start. Returns
+ * -EINVAL if @buf isn't a valid position:
+ */
+int __bpos_parse(const char *buf, struct bpos *pos)
 {
-	char *s = buf, *field;
+	char *copy = strdup(buf), *s = copy, *field;
 	u64 inode_v = 0, offset_v = 0;
+	u32 snapshot_v = 0;
+	int ret = -EINVAL;
+
+	if (!copy)
+		die("insufficient memory");
+
+	if (!strcmp(buf, "min")) {
+		*pos = POS_MIN;
+		ret = 0;
+		goto out;
+	}
+	if (!strcmp(buf, "max")) {
+		*pos = POS_MAX;
+		ret = 0;
+		goto out;
+	}
 
 	if (!(field = strsep(&s, ":")) ||
 	    kstrtoull(field, 10, &inode_v))
-		die("invalid bpos %s", buf);
+		goto out;
 
 	if ((field = strsep(&s, ":")) &&
 	    kstrtoull(field, 10, &offset_v))
-		die("invalid bpos %s", buf);
+		goto out;
+
+	if ((field = strsep(&s, ":")) &&
+	    kstrtouint(field, 10, &snapshot_v))
+		goto out;
 
 	if (s)
+		goto out;
+
+	*pos = (struct bpos) {
+		.inode		= inode_v,
+		.offset		= offset_v,
+		.snapshot	= snapshot_v,
+	};
+	ret = 0;
+out:
+	free(copy);
+	return ret;
+}
+
+struct bpos bpos_parse(char *buf)
+{
+	struct bpos pos;
+
+	if (__bpos_parse(buf, &pos))
 		die("invalid bpos %s", buf);
+	return pos;
+}
+
+/* Length of the valid UTF-8 sequence at @s, or 0: */
+static unsigned utf8_seq_len(const unsigned char *s)
+{
+	unsigned i, len;
+	unsigned char min = 0x80, max = 0xbf;
+
+	if (s[0] < 0x80)
+		return 1;
+	else if (s[0] >= 0xc2 && s[0] <= 0xdf)
+		len = 2;
+	else if (s[0] >= 0xe0 && s[0] <= 0xef)
+		len = 3;
+	else if (s[0] >= 0xf0 && s[0] <= 0xf4)
+		len = 4;
+	else
+		return 0;
+
+	/* No overlong encodings, surrogates, or codepoints past U+10FFFF: */
+	if (s[0] == 0xe0)
+		min = 0xa0;
+	else if (s[0] == 0xed)
+		max = 0x9f;
+	else if (s[0] == 0xf0)
+		min = 0x90;
+	else if (s[0] == 0xf4)
+		max = 0x8f;
+
+	if (s[1] < min || s[1] > max)
+		return 0;
 
-	return (struct bpos) { .inode = inode_v, .offset = offset_v };
+	for (i = 2; i < len; i++)
+		if (s[i] < 0x80 || s[i] > 0xbf)
+			return 0;
+	return len;
+}
+
+/*
+ * Write @str as a JSON string: filenames and the like needn't be UTF-8, so
+ * bytes that aren't part of a valid sequence are written as U+FFFD, keeping
+ * the output valid JSON.
+ */
+void json_str(FILE *f, const char *str)
+{
+	const unsigned char *s = (const unsigned char *) str;
+	unsigned len;
+
+	fputc('"', f);
+	while (*s) {
+		switch (*s) {
+		case '"':
+		case '\\':
+			fprintf(f, "\\%c", *s);
+			break;
+		case '\n':
+			fprintf(f, "\\n");
+			break;
+		case '\t':
+			fprintf(f, "\\t");
+			break;
+		default:
+			if (*s < 0x20) {
+				fprintf(f, "\\u%04x", *s);
+			} else if (!(len = utf8_seq_len(s))) {
+				fprintf(f, "\\ufffd");
+			} else {
+				fwrite(s, len, 1, f);
+				s += len;
+				continue;
+			}
+		}
+		s++;
+	}
+	fputc('"', f);
 }
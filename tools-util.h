@@ -16,6 +16,7 @@
 #include <linux/kernel.h>
 #include <linux/log2.h>
 #include <linux/string.h>
+#include <linux/time64.h>
 #include <linux/types.h>
 #include <linux/uuid.h>
 #include "ccan/darray/darray.h"
@@ -69,6 +70,7 @@ struct units_buf {
 #define pr_units(_v, _u)	&(__pr_units(_v, _u).b[0])
 
 char *read_file_str(int, const char *);
+void write_file_str(int, const char *, const char *);
 u64 read_file_u64(int, const char *);
 
 ssize_t read_string_list_or_die(const char *, const char * const[],
@@ -76,10 +78,39 @@ ssize_t read_string_list_or_die(const char *, const char * const[],
 
 u64 get_size(const char *, int);
 unsigned get_blocksize(const char *, int);
+
+struct dev_queue_info {
+	bool		rotational;
+	bool		zoned;
+	/* Zones must be written sequentially, as opposed to host-aware: */
+	bool		host_managed;
+	u64		discard_granularity;	/* sectors */
+	u64		zone_sectors;
+	/* Of a partition, within the whole disk: */
+	u64		start;
+};
+
+struct dev_queue_info dev_queue_info(int);
+
+struct dev_zones {
+	u64		nr;
+	u64		empty;
+	u64		open;
+	u64		full;
+	u64		other;
+};
+
+struct dev_zones dev_zones_read(int);
 int open_for_format(const char *, bool);
 
 bool ask_yn(void);
 
+/*
+ * If set, ask_yn() calls this with what was last printed by printk(), instead
+ * of asking the user
+ */
+extern bool (*ask_yn_answer)(const char *);
+
 struct range {
 	u64		start;
 	u64		end;
@@ -152,6 +183,52 @@ char *strcmp_prefix(char *, const char *);
 
 unsigned hatoi_validate(const char *, const char *);
 
+/*
+ * Limits the rate of IO, in bytes and in IOs per second; a limit of 0 means
+ * unlimited. Up to a second of unused allowance can be saved up:
+ */
+struct io_rate_limit {
+	u64		bytes_per_sec;
+	u64		ios_per_sec;
+
+	/* in nanoseconds: */
+	u64		next_bytes;
+	u64		next_ios;
+};
+
+/*
+ * Returns how long to wait at time @now, in nanoseconds, before doing @done
+ * more units of work at @rate per second; @next is when the work done so far
+ * is paid for:
+ */
+static inline u64 rate_limit_delay(u64 *next, u64 rate, u64 done, u64 now)
+{
+	if (!rate)
+		return 0;
+
+	*next = max(*next, now - min(now, (u64) NSEC_PER_SEC));
+	*next += done * NSEC_PER_SEC / rate;
+
+	return *next > now ? *next - now : 0;
+}
+
+void io_rate_limit(struct io_rate_limit *, u64);
+
+/*
+ * Progress for scripts, written to a file descriptor as one JSON object per
+ * line - at most one progress event a second, and one when done == total:
+ *   {"event":"progress","bytes_done":N,"bytes_total":N}
+ *   {"event":"done"}
+ * Nothing is written if fd is -1:
+ */
+struct progress_fd {
+	int		fd;
+	u64		next;	/* in nanoseconds */
+};
+
+void progress_update(struct progress_fd *, u64, u64);
+void progress_done(struct progress_fd *);
+
 u32 crc32c(u32, const void *, size_t);
 
 char *dev_to_name(dev_t);
@@ -174,6 +251,10 @@ do {									\
 	_ret;								\
 })
 
+struct bpos;
+int __bpos_parse(const char *, struct bpos *);
 struct bpos bpos_parse(char *);
 
+void json_str(FILE *, const char *);
+
 #endif /* _TOOLS_UTIL_H */